@@ -0,0 +1,57 @@
+//! Serve the private storage of an Android app through memfs, the files are
+//! reported as owned by the app and hidden from other apps. There is no
+//! fusermount on Android, so it runs as root, e.g. from a privileged service,
+//! with `app_storage <app uid> <source> <mountpoint>`, where source is e.g.
+//! `/data/user/0/<package>/files`. It also runs on Linux as root.
+
+use fuse_ll::fuse;
+use fuse_ll::memfs::backend::LocalBackend;
+use fuse_ll::memfs::{self, AttrMap, MemoryFilesystem};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+
+/// Print the error and exit
+fn exit_with(context: &str, err: impl Display) -> ! {
+    eprintln!("{}: {}", context, err);
+    process::exit(1);
+}
+
+fn main() {
+    env_logger::init();
+    let (uid, source, mountpoint) = match env::args().collect::<Vec<_>>().as_slice() {
+        [_, uid, source, mountpoint] => (uid.clone(), source.clone(), mountpoint.clone()),
+        _ => exit_with("usage", "app_storage <app uid> <source> <mountpoint>"),
+    };
+    let uid: u32 = uid
+        .parse()
+        .unwrap_or_else(|e| exit_with(&format!("invalid app uid {:?}", uid), e));
+    let uid_option = format!("uid={}", uid);
+    let gid_option = format!("gid={}", uid);
+    let options = [
+        "fsname=app_storage",
+        // the app runs as another user than the server
+        "allow_other",
+        // as the storage mounted by vold
+        "noexec",
+        "noatime",
+        // only the app gets in, the kernel checks the mode bits
+        "kernel:default_permissions",
+        "umask=077",
+        &uid_option,
+        &gid_option,
+    ];
+    let source = fs::canonicalize(&source)
+        .unwrap_or_else(|e| exit_with(&format!("invalid source {:?}", source), e));
+    let mut fs = MemoryFilesystem::new_with_backend(&source, Arc::new(LocalBackend::new()));
+    let (memfs_options, mount_options) = memfs::split_mount_options(&options);
+    fs.set_attr_map(
+        AttrMap::from_options(&memfs_options)
+            .unwrap_or_else(|e| exit_with("invalid mount options", e)),
+    );
+    fuse::mount(fs, Path::new(&mountpoint), &mount_options)
+        .unwrap_or_else(|e| exit_with(&format!("couldn't mount {:?}", mountpoint), e));
+}
@@ -0,0 +1,120 @@
+//! Static filesystem with a single read-only file, mount it with
+//! `cargo run --example hello <mountpoint>` and `cat <mountpoint>/hello.txt`
+
+use fuse_ll::fuse::{
+    self, Context, FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEntry, FUSE_ROOT_ID,
+};
+use libc::ENOENT;
+use std::env;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+const TTL: Duration = Duration::from_secs(1);
+const HELLO_INO: u64 = 2;
+const HELLO_NAME: &str = "hello.txt";
+const HELLO_TEXT: &str = "Hello World!\n";
+
+fn attr(ino: u64, kind: FileType, perm: u16, size: u64) -> FileAttr {
+    FileAttr {
+        ino,
+        size,
+        blocks: (size + 511) / 512,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind,
+        perm,
+        nlink: if kind == FileType::Directory { 2 } else { 1 },
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+struct HelloFilesystem;
+
+impl Filesystem for HelloFilesystem {
+    fn lookup(&mut self, _ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        if parent == FUSE_ROOT_ID && name == HELLO_NAME {
+            let size = HELLO_TEXT.len() as u64;
+            reply.entry(
+                &TTL,
+                &attr(HELLO_INO, FileType::RegularFile, 0o444, size),
+                0,
+            );
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn getattr(&mut self, _ctx: &Context, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match ino {
+            FUSE_ROOT_ID => reply.attr(&TTL, &attr(ino, FileType::Directory, 0o555, 0)),
+            HELLO_INO => {
+                let size = HELLO_TEXT.len() as u64;
+                reply.attr(&TTL, &attr(ino, FileType::RegularFile, 0o444, size));
+            }
+            _ => reply.error(ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _ctx: &Context,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        if ino != HELLO_INO {
+            reply.error(ENOENT);
+            return;
+        }
+        let data = HELLO_TEXT.as_bytes();
+        let start = (offset as usize).min(data.len());
+        let end = (start + size as usize).min(data.len());
+        reply.data(&data[start..end]);
+    }
+
+    fn readdir(
+        &mut self,
+        _ctx: &Context,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != FUSE_ROOT_ID {
+            reply.error(ENOENT);
+            return;
+        }
+        let entries = [
+            (FUSE_ROOT_ID, FileType::Directory, "."),
+            (FUSE_ROOT_ID, FileType::Directory, ".."),
+            (HELLO_INO, FileType::RegularFile, HELLO_NAME),
+        ];
+        // the offset of an entry is the offset to continue reading after it
+        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(*ino, (i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let mountpoint = env::args_os().nth(1).expect("usage: hello <mountpoint>");
+    fuse::mount(
+        HelloFilesystem,
+        Path::new(&mountpoint),
+        &["ro", "fsname=hello"],
+    )
+    .unwrap();
+}
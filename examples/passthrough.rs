@@ -0,0 +1,37 @@
+//! Mirror a directory at another mount point through memfs, files and
+//! directories created under the mount point go to the source directory, run
+//! it with `cargo run --example passthrough [--no-open] <source> <mountpoint>`
+//!
+//! With `--no-open` the mirror is read-only and the kernel skips the opens of
+//! files and directories, keeping what is read cached.
+
+use fuse_ll::fuse;
+use fuse_ll::memfs::backend::LocalBackend;
+use fuse_ll::memfs::MemoryFilesystem;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+fn main() {
+    env_logger::init();
+    let mut args: Vec<_> = env::args_os().skip(1).collect();
+    let no_open = args.first().map_or(false, |arg| arg == "--no-open");
+    if no_open {
+        args.remove(0);
+    }
+    if args.len() != 2 {
+        eprintln!("usage: passthrough [--no-open] <source> <mountpoint>");
+        std::process::exit(1);
+    }
+    let source = fs::canonicalize(&args[0]).unwrap();
+    let mut fs = MemoryFilesystem::new_with_backend(&source, Arc::new(LocalBackend::new()));
+    fs.set_no_open(no_open);
+    let options: &[&str] = if no_open {
+        // the access is checked by memfs in open, which the kernel then skips
+        &["fsname=passthrough", "ro", "kernel:default_permissions"]
+    } else {
+        &["fsname=passthrough"]
+    };
+    fuse::mount(fs, Path::new(&args[1]), options).unwrap();
+}
@@ -0,0 +1,21 @@
+//! Mount a zip archive read-only, run it with
+//! `cargo run --example zipfs <archive.zip> <mountpoint>`
+//!
+//! The archive is served by `ArchiveFilesystem`, the same as the
+//! `mount-archive` subcommand.
+
+use fuse_ll::archivefs::ArchiveFilesystem;
+use fuse_ll::fuse;
+use std::env;
+use std::path::Path;
+
+fn main() {
+    env_logger::init();
+    let args: Vec<_> = env::args_os().collect();
+    if args.len() != 3 {
+        eprintln!("usage: zipfs <archive.zip> <mountpoint>");
+        std::process::exit(1);
+    }
+    let fs = ArchiveFilesystem::new(&args[1]).unwrap();
+    fuse::mount(fs, Path::new(&args[2]), &["fsname=zipfs", "ro"]).unwrap();
+}
@@ -0,0 +1,418 @@
+use crate::fuse::{
+    Context, FileAttr, FileType, Filesystem, FsError, FsInitConfig, FsReleaseParam,
+    OverflowArithmetic, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
+    FUSE_ROOT_ID,
+};
+use crate::readonly::{self, NodeTable, OpenFiles};
+use libc::{EIO, ENOENT, ENOTDIR, EROFS};
+use log::{debug, error}; // info, warn
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Archive module
+mod archive;
+
+use archive::{Archive, ArchiveEntry};
+
+/// TTL sec, the archive never changes once mounted
+const ARCHIVE_TTL_SEC: u64 = 3600;
+/// Generation
+const ARCHIVE_GENERATION: u64 = 1;
+/// Permission of directories
+const ARCHIVE_DIR_MODE: u16 = 0o555;
+/// Permission of files
+const ARCHIVE_FILE_MODE: u16 = 0o444;
+
+/// Node of the directory tree of the archive
+#[derive(Debug)]
+struct ArchiveNode {
+    /// Parent ino
+    parent: u64,
+    /// The entry of a file, `None` for a directory
+    entry: Option<ArchiveEntry>,
+    /// Children of a directory, in name order
+    children: BTreeMap<OsString, u64>,
+}
+
+impl ArchiveNode {
+    /// New directory node
+    fn new_dir(parent: u64) -> Self {
+        Self {
+            parent,
+            entry: None,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// Kind
+    const fn kind(&self) -> FileType {
+        if self.entry.is_some() {
+            FileType::RegularFile
+        } else {
+            FileType::Directory
+        }
+    }
+}
+
+/// Read-only filesystem of the contents of an archive
+#[derive(Debug)]
+pub struct ArchiveFilesystem {
+    /// Archive
+    archive: Archive,
+    /// Nodes
+    nodes: NodeTable<ArchiveNode>,
+    /// Attribute template, the times and owner of the archive file
+    attr: FileAttr,
+    /// Files open, decompressed on first open and dropped on last release
+    open_files: OpenFiles,
+    /// Whether the kernel is to skip the opendirs
+    no_opendir: bool,
+}
+
+impl ArchiveFilesystem {
+    /// Open a zip, tar or gzipped tar archive and index its directory tree, directories
+    /// only implied by the paths of their files get synthetic inodes
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path)?;
+        let mut archive = Archive::open(path)?;
+        let entries = archive.entries()?;
+
+        let mtime = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        let mut fs = Self {
+            archive,
+            nodes: NodeTable::new(ArchiveNode::new_dir(FUSE_ROOT_ID)),
+            attr: FileAttr {
+                ino: FUSE_ROOT_ID,
+                size: 0,
+                blocks: 0,
+                atime: mtime,
+                mtime,
+                ctime: mtime,
+                crtime: mtime,
+                kind: FileType::Directory,
+                perm: ARCHIVE_DIR_MODE,
+                nlink: 2,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                rdev: 0,
+                flags: 0,
+            },
+            open_files: OpenFiles::default(),
+            no_opendir: false,
+        };
+        let num_entries = entries.len();
+        for entry in entries {
+            fs.helper_add_entry(entry);
+        }
+        debug!(
+            "ArchiveFilesystem::new() indexed {} entries of archive {:?} into {} nodes",
+            num_entries,
+            path,
+            fs.nodes.len(),
+        );
+        Ok(fs)
+    }
+
+    /// Let the kernel skip the opendirs and keep the listings cached, the
+    /// opens are still needed to decompress the file data
+    pub fn set_no_open(&mut self, enabled: bool) {
+        self.no_opendir = enabled;
+    }
+
+    /// Helper add entry, creating the directories on its path as needed, a
+    /// file replaces an earlier entry of the same path, as when extracting
+    fn helper_add_entry(&mut self, entry: ArchiveEntry) {
+        let mut parent = FUSE_ROOT_ID;
+        let num_names = entry.names.len();
+        for (i, name) in entry.names.iter().enumerate() {
+            let is_file = !entry.is_dir && i.overflow_add(1) == num_names;
+            let existing = self.nodes.helper_get(parent).children.get(name).copied();
+            parent = match existing {
+                Some(ino) => {
+                    let node = self.nodes.helper_get_mut(ino);
+                    if !is_file {
+                        // a file is never the parent of other entries
+                        node.entry = None;
+                    } else if node.children.is_empty() {
+                        node.entry = Some(entry.clone());
+                    } else {
+                        // keep a directory with children
+                    }
+                    ino
+                }
+                None => {
+                    let mut node = ArchiveNode::new_dir(parent);
+                    if is_file {
+                        node.entry = Some(entry.clone());
+                    }
+                    let ino = self.nodes.add(node);
+                    self.nodes
+                        .helper_get_mut(parent)
+                        .children
+                        .insert(name.clone(), ino);
+                    ino
+                }
+            };
+        }
+    }
+
+    /// Attr of a node
+    fn helper_get_attr(&self, ino: u64) -> FileAttr {
+        let node = self.nodes.helper_get(ino);
+        let mut attr = self.attr;
+        attr.ino = ino;
+        attr.kind = node.kind();
+        if let Some(ref entry) = node.entry {
+            attr.size = entry.size;
+            attr.blocks = entry.size.overflow_add(511).overflow_div(512);
+            attr.perm = ARCHIVE_FILE_MODE;
+            attr.nlink = 1;
+        }
+        attr
+    }
+}
+
+impl Filesystem for ArchiveFilesystem {
+    fn init(&mut self, _ctx: &Context, config: &mut FsInitConfig) -> Result<(), FsError> {
+        config.no_opendir = self.no_opendir;
+        Ok(())
+    }
+
+    fn lookup(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name={:?}, ctx={:?})", parent, name, ctx,);
+        match self
+            .nodes
+            .get(parent)
+            .and_then(|node| node.children.get(name))
+        {
+            Some(ino) => {
+                let ttl = Duration::new(ARCHIVE_TTL_SEC, 0);
+                reply.entry(&ttl, &self.helper_get_attr(*ino), ARCHIVE_GENERATION);
+            }
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, ctx: &Context, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        debug!("getattr(ino={}, fh={:?}, ctx={:?})", ino, fh, ctx);
+        if self.nodes.get(ino).is_some() {
+            let ttl = Duration::new(ARCHIVE_TTL_SEC, 0);
+            reply.attr(&ttl, &self.helper_get_attr(ino));
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn open(&mut self, ctx: &Context, ino: u64, flags: u32, mut reply: ReplyOpen) {
+        debug!("open(ino={}, flags={}, ctx={:?})", ino, flags, ctx);
+        if readonly::opens_for_write(flags) {
+            reply.error(EROFS);
+            return;
+        }
+        let entry = match self.nodes.get(ino).and_then(|node| node.entry.as_ref()) {
+            Some(entry) => entry,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let archive = &mut self.archive;
+        let opened = self.open_files.open(ino, || {
+            let data = archive.read(entry).map_err(|e| {
+                error!(
+                    "open() failed to read the file of ino={} from the archive, the error is: {:?}",
+                    ino, e,
+                );
+                EIO
+            })?;
+            debug!(
+                "open() decompressed {} byte data of the file of ino={}",
+                data.len(),
+                ino,
+            );
+            Ok(data)
+        });
+        if let Err(errno) = opened {
+            reply.error(errno);
+            return;
+        }
+        // the data never changes while mounted
+        reply.keep_cache(true);
+        reply.opened(0, 0);
+    }
+
+    fn read(&mut self, ctx: &Context, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        debug!(
+            "read(ino={}, fh={}, offset={}, size={}, ctx={:?})",
+            ino, fh, offset, size, ctx,
+        );
+        match self.open_files.read(ino, offset, size) {
+            Some(data) => reply.data(data),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn release(&mut self, ctx: &Context, param: FsReleaseParam, reply: ReplyEmpty) {
+        debug!("release(ino={}, fh={}, ctx={:?})", param.ino, param.fh, ctx,);
+        self.open_files.release(param.ino);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        ctx: &Context,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(
+            "readdir(ino={}, fh={}, offset={}, ctx={:?})",
+            ino, fh, offset, ctx,
+        );
+        let node = match self.nodes.get(ino) {
+            Some(node) if node.entry.is_none() => node,
+            Some(_) => {
+                reply.error(ENOTDIR);
+                return;
+            }
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let nodes = &self.nodes;
+        let children = node.children.iter().map(|(name, child_ino)| {
+            (
+                *child_ino,
+                nodes.helper_get(*child_ino).kind(),
+                name.as_os_str(),
+            )
+        });
+        readonly::add_dir_entries(&mut reply, ino, node.parent, offset, children);
+        reply.ok();
+    }
+
+    impl_read_only!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::ArchiveFilesystem;
+    use crate::fuse::{Cast, FileType};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::ffi::OsStr;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+    use zip::write::{FileOptions, ZipWriter};
+
+    /// Look up a path, returns the ino
+    fn lookup_path(fs: &ArchiveFilesystem, path: &str) -> Option<u64> {
+        let mut ino = 1;
+        for name in path.split('/') {
+            ino = *fs.nodes.get(ino)?.children.get(OsStr::new(name))?;
+        }
+        Some(ino)
+    }
+
+    /// Check the directory tree and the file data indexed from an archive
+    fn check_archive(path: &Path) {
+        let mut fs = ArchiveFilesystem::new(path).unwrap_or_else(|_| panic!());
+        let one = lookup_path(&fs, "a/b/one.txt").unwrap_or_else(|| panic!());
+        let two = lookup_path(&fs, "two.txt").unwrap_or_else(|| panic!());
+        let dir_b = lookup_path(&fs, "a/b").unwrap_or_else(|| panic!());
+        assert_eq!(fs.helper_get_attr(dir_b).kind, FileType::Directory);
+        assert_eq!(fs.helper_get_attr(one).kind, FileType::RegularFile);
+        assert_eq!(fs.helper_get_attr(one).size, 4);
+        assert!(lookup_path(&fs, "escaped.txt").is_none());
+
+        let node = fs.nodes.helper_get(two);
+        let entry = node.entry.clone().unwrap_or_else(|| panic!());
+        assert_eq!(fs.archive.read(&entry).ok(), Some(b"second file".to_vec()));
+        let node = fs.nodes.helper_get(one);
+        let entry = node.entry.clone().unwrap_or_else(|| panic!());
+        assert_eq!(fs.archive.read(&entry).ok(), Some(b"one\n".to_vec()));
+        // a size claimed by a crafted header fails the read rather than
+        // allocating it
+        let mut entry = entry;
+        entry.size = 1 << 40;
+        assert!(fs.archive.read(&entry).is_err());
+    }
+
+    #[test]
+    fn test_archive_index() {
+        const ARCHIVE_DIR: &str = "/tmp/fuse_test_archive";
+        let archive_dir = Path::new(ARCHIVE_DIR);
+        if archive_dir.exists() {
+            fs::remove_dir_all(archive_dir).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir(archive_dir).unwrap_or_else(|_| panic!());
+        let files: [(&str, &[u8]); 3] = [
+            ("a/b/one.txt", b"one\n"),
+            ("two.txt", b"second file"),
+            ("../escaped.txt", b"escaped"),
+        ];
+
+        // a/ and a/b/ are implied by the path of one.txt
+        let zip_path = archive_dir.join("test.zip");
+        let mut zip = ZipWriter::new(File::create(&zip_path).unwrap_or_else(|_| panic!()));
+        for (name, data) in &files {
+            zip.start_file(*name, FileOptions::default())
+                .unwrap_or_else(|_| panic!());
+            zip.write_all(data).unwrap_or_else(|_| panic!());
+        }
+        zip.finish().unwrap_or_else(|_| panic!());
+        check_archive(&zip_path);
+
+        let build_tar = |writer: &mut dyn Write| {
+            let mut tar = tar::Builder::new(writer);
+            for (name, data) in &files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len().cast());
+                header.set_mode(0o644);
+                // bypass the path checks of the builder to write the escaping entry
+                let name_field = &mut header.as_old_mut().name;
+                name_field
+                    .get_mut(..name.len())
+                    .unwrap_or_else(|| panic!())
+                    .copy_from_slice(name.as_bytes());
+                header.set_cksum();
+                tar.append(&header, *data).unwrap_or_else(|_| panic!());
+            }
+            tar.finish().unwrap_or_else(|_| panic!());
+        };
+        let tar_path = archive_dir.join("test.tar");
+        build_tar(&mut File::create(&tar_path).unwrap_or_else(|_| panic!()));
+        check_archive(&tar_path);
+
+        let tar_gz_path = archive_dir.join("test.tar.gz");
+        let mut gz = GzEncoder::new(
+            File::create(&tar_gz_path).unwrap_or_else(|_| panic!()),
+            Compression::default(),
+        );
+        build_tar(&mut gz);
+        gz.finish().unwrap_or_else(|_| panic!());
+        check_archive(&tar_gz_path);
+        // the entries are read from the stream decompressed while listing
+        let mut fs = ArchiveFilesystem::new(&tar_gz_path).unwrap_or_else(|_| panic!());
+        fs::remove_file(&tar_gz_path).unwrap_or_else(|_| panic!());
+        let two = lookup_path(&fs, "two.txt").unwrap_or_else(|| panic!());
+        let entry = fs
+            .nodes
+            .helper_get(two)
+            .entry
+            .clone()
+            .unwrap_or_else(|| panic!());
+        assert_eq!(fs.archive.read(&entry).ok(), Some(b"second file".to_vec()));
+
+        fs::remove_dir_all(archive_dir).unwrap_or_else(|_| panic!());
+    }
+}
@@ -0,0 +1,224 @@
+//! Readers of the supported archive formats, zip, tar and gzipped tar
+//!
+//! Listing the entries only reads the headers, the data of an entry is read
+//! when asked for. Plain tar entries are read in place, while gzipped tar has
+//! no random access, so the stream is decompressed once while listing it into
+//! an unlinked file under the temporary directory, where the entries are then
+//! read in place as plain tar.
+
+use crate::fuse::{Cast, TryCast};
+use flate2::read::GzDecoder;
+use log::debug;
+use nix::unistd;
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::FromRawFd;
+use std::path::{Component, Path, PathBuf};
+use tar::EntryType;
+use zip::ZipArchive;
+
+/// The magic number at the start of a zip archive
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+/// The magic number at the start of a gzip stream
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+/// Name template of the file of a decompressed tar under the temporary
+/// directory
+const UNPACKED_FILE_TEMPLATE: &str = "sync_fuse_archive.XXXXXX";
+
+/// Entry of an archive
+#[derive(Clone, Debug)]
+pub struct ArchiveEntry {
+    /// Path components, `..` and the root are never included
+    pub names: Vec<OsString>,
+    /// Whether it is a directory
+    pub is_dir: bool,
+    /// Size of the data
+    pub size: u64,
+    /// Where the data is, the index of the entry in a zip archive, the offset
+    /// of the data in a tar archive, decompressed if gzipped
+    location: u64,
+}
+
+/// Archive
+#[derive(Debug)]
+pub enum Archive {
+    /// Zip archive
+    Zip(ZipArchive<File>),
+    /// Tar archive, or the decompressed gzipped tar archive once listed
+    Tar(File),
+    /// Gzipped tar archive not listed yet
+    TarGz(PathBuf),
+}
+
+/// Reader copying the data read into a file
+struct TeeReader<'a, R> {
+    /// Reader
+    reader: R,
+    /// File of the data read
+    file: &'a File,
+}
+
+impl<R: Read> Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.file.write_all(buf.get(..size).unwrap_or(&[]))?;
+        Ok(size)
+    }
+}
+
+/// Create an unlinked file under the temporary directory
+fn unlinked_file() -> io::Result<File> {
+    let (fd, path) = unistd::mkstemp(&env::temp_dir().join(UNPACKED_FILE_TEMPLATE))
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    // Safety: the fd is just created and owned by nothing else
+    #[allow(unsafe_code)]
+    let file = unsafe { File::from_raw_fd(fd) };
+    unistd::unlink(&path).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    Ok(file)
+}
+
+impl Archive {
+    /// Open an archive, the format is told by the magic number
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut magic = [0_u8; 4];
+        let magic_len = file.read(&mut magic)?;
+        let magic = magic.get(..magic_len).unwrap_or(&[]);
+        if magic.starts_with(ZIP_MAGIC) {
+            Ok(Self::Zip(ZipArchive::new(file)?))
+        } else if magic.starts_with(GZIP_MAGIC) {
+            Ok(Self::TarGz(path.to_owned()))
+        } else {
+            Ok(Self::Tar(file))
+        }
+    }
+
+    /// List the entries, skipping those escaping the archive and those neither
+    /// a directory nor a regular file
+    pub fn entries(&mut self) -> io::Result<Vec<ArchiveEntry>> {
+        match *self {
+            Self::Zip(ref mut archive) => {
+                let mut entries = Vec::with_capacity(archive.len());
+                for index in 0..archive.len() {
+                    let file = archive.by_index_raw(index)?;
+                    if let Some(names) = normal_names(Path::new(file.name())) {
+                        entries.push(ArchiveEntry {
+                            names,
+                            is_dir: file.is_dir(),
+                            size: file.size(),
+                            location: index.cast(),
+                        });
+                    }
+                }
+                Ok(entries)
+            }
+            Self::Tar(ref mut file) => {
+                // the offsets of the data are counted from where the listing starts
+                file.seek(SeekFrom::Start(0))?;
+                tar_entries(tar::Archive::new(file))
+            }
+            Self::TarGz(ref path) => {
+                let path = path.clone();
+                let unpacked = unlinked_file()?;
+                let entries = tar_entries(tar::Archive::new(TeeReader {
+                    reader: GzDecoder::new(File::open(&path)?),
+                    file: &unpacked,
+                }))?;
+                debug!(
+                    "Archive::entries() decompressed {:?} into an unlinked file",
+                    path
+                );
+                *self = Self::Tar(unpacked);
+                Ok(entries)
+            }
+        }
+    }
+
+    /// Read the data of an entry. The size in the header of the entry is not
+    /// trusted, a crafted archive may claim any size, so a zip entry is read
+    /// into a growing buffer up to the size, and a tar entry is read only if it
+    /// ends within the archive. The data not of the size is invalid.
+    pub fn read(&mut self, entry: &ArchiveEntry) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match *self {
+            Self::Zip(ref mut archive) => {
+                archive
+                    .by_index(entry.location.cast())?
+                    .take(entry.size)
+                    .read_to_end(&mut data)?;
+            }
+            Self::Tar(ref file) => {
+                let archive_len = file.metadata()?.len();
+                let size = entry
+                    .location
+                    .checked_add(entry.size)
+                    .filter(|&end| end <= archive_len)
+                    .and_then(|_| entry.size.try_cast::<usize>().ok())
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("the entry of {} bytes ends past the archive", entry.size),
+                        )
+                    })?;
+                data.resize(size, 0);
+                file.read_exact_at(&mut data, entry.location)?;
+            }
+            // the entries are located only by listing
+            Self::TarGz(_) => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+        if data.len().cast::<u64>() != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the entry of {} bytes has {} bytes of data",
+                    entry.size,
+                    data.len()
+                ),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// List the entries of a tar archive, the location of an entry is the offset
+/// of its data
+fn tar_entries<R: Read>(mut archive: tar::Archive<R>) -> io::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    for tar_entry in archive.entries()? {
+        let tar_entry = tar_entry?;
+        let is_dir = match tar_entry.header().entry_type() {
+            EntryType::Directory => true,
+            EntryType::Regular | EntryType::Continuous => false,
+            _ => continue,
+        };
+        if let Some(names) = normal_names(&tar_entry.path()?) {
+            entries.push(ArchiveEntry {
+                names,
+                is_dir,
+                size: tar_entry.size(),
+                location: tar_entry.raw_file_position(),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// The names of the components of a path, `None` if the path escapes the archive
+fn normal_names(path: &Path) -> Option<Vec<OsString>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_owned()),
+            Component::CurDir | Component::RootDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if names.is_empty() {
+        None
+    } else {
+        Some(names)
+    }
+}
@@ -0,0 +1,1203 @@
+//! Command line interface
+//!
+//! Besides the bare `fuse_ll <mountpoint>` form, which mounts a memory
+//! filesystem, there are subcommands to mount, unmount, check, benchmark and
+//! inspect mount points, and to list the mount options and print shell
+//! completions. The mount settings may also come from a config file
+//! given by `--config`, of `key = value` lines keyed by the long names of the
+//! command line options, and from environment variables named after the keys,
+//! e.g. `SYNC_FUSE_IO_TIMEOUT=30s` for `io-timeout`. The command line
+//! overrides the environment, which overrides the config file.
+
+use clap::{App, AppSettings, Arg, ArgMatches, Shell, SubCommand};
+use nix::sys::statvfs;
+use nix::unistd::{Gid, Group, Uid, User};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::fuse::{self, Cast, MountOptionInfo, OverflowArithmetic, SeccompAction, UnmountFlags};
+use crate::memfs::{self, NameEncoding, SpaceReserve};
+#[cfg(feature = "abi-7-11")]
+use crate::memfs::{ByteStats, CacheStats, HandleStats, IoStats};
+
+/// Prefix of the environment variables overriding the config file
+const ENV_PREFIX: &str = "SYNC_FUSE_";
+
+/// Keys of the mount settings in the config file
+const MOUNT_KEYS: [&str; 39] = [
+    "options",
+    "dedup",
+    "chunk-size",
+    "supervise",
+    "dry-run-mount",
+    "selftest",
+    "preflight",
+    "slow-op-threshold",
+    "io-timeout",
+    "cache-limit",
+    "flush-concurrency",
+    "mmap-threshold",
+    "private-backing",
+    "huge-pages",
+    "snapshot-dir",
+    "spill-dir",
+    "spill-limit",
+    "max-inodes",
+    "max-file-size",
+    "reserve-space",
+    "prealloc-size",
+    "readdir-ino-order",
+    "no-open",
+    "writeback-cache",
+    "evict-forgotten",
+    "preload",
+    "preload-data",
+    "revalidate-interval",
+    "revalidate-sample",
+    "debug-refcounts",
+    "state-file",
+    "strict",
+    "seccomp",
+    "setuid",
+    "setgid",
+    "confine",
+    "non-utf8-names",
+    "no-cloexec",
+    "update-atime",
+];
+
+/// Validate a duration argument
+#[allow(clippy::needless_pass_by_value)] // clap passes the value by value
+fn duration_validator(duration: String) -> Result<(), String> {
+    humantime::parse_duration(&duration)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Validate a count argument
+#[allow(clippy::needless_pass_by_value)] // clap passes the value by value
+fn count_validator(count: String) -> Result<(), String> {
+    count
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Validate a positive count argument
+#[allow(clippy::needless_pass_by_value)] // clap passes the value by value
+fn positive_count_validator(count: String) -> Result<(), String> {
+    match count.parse::<usize>() {
+        Ok(0) => Err("the count must be positive".to_owned()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Validate a reserve of space argument
+#[allow(clippy::needless_pass_by_value)] // clap passes the value by value
+fn reserve_validator(reserve: String) -> Result<(), String> {
+    reserve.parse::<SpaceReserve>().map(|_| ())
+}
+
+/// The arguments of mounting a memory filesystem, shared by the bare form and
+/// the `mount` subcommand
+#[allow(clippy::too_many_lines)]
+fn mount_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("mountpoint").required(true).index(1),
+        Arg::with_name("options")
+            .short("o")
+            .value_name("OPTIONS")
+            .help("Mount options, run the options subcommand to list the supported ones")
+            .multiple(true)
+            .takes_value(true)
+            .validator(|option| memfs::options_validator(option.as_str()))
+            .number_of_values(1),
+        Arg::with_name("dedup")
+            .long("dedup")
+            .help("Deduplicate identical file data chunks in the cache"),
+        Arg::with_name("chunk-size")
+            .long("chunk-size")
+            .value_name("BYTES")
+            .help("Split the deduplicated data into chunks of this size, picked from 64K to 1M after the sizes of the requests by default")
+            .takes_value(true)
+            .validator(positive_count_validator),
+        Arg::with_name("supervise")
+            .long("supervise")
+            .help("Check the health of the mount point and remount it if it is wedged"),
+        Arg::with_name("dry-run-mount")
+            .long("dry-run-mount")
+            .help("Skip the mount and serve the FUSE requests written to the socket on stdin, for the test harnesses without /dev/fuse")
+            .conflicts_with("supervise"),
+        Arg::with_name("selftest")
+            .long("selftest")
+            .help("Check a probe file round trips through the mount point once mounted, and exit with an error if not")
+            .conflicts_with("dry-run-mount"),
+        Arg::with_name("preflight")
+            .long("preflight")
+            .help("Check the FUSE driver, the device, fusermount, allow_other and the kernel ABI before mounting, print the results and exit with an error if a check fails, on Linux and Android")
+            .conflicts_with("dry-run-mount"),
+        Arg::with_name("slow-op-threshold")
+            .long("slow-op-threshold")
+            .value_name("DURATION")
+            .help("Log requests taking longer than this to handle, e.g. 50ms")
+            .takes_value(true)
+            .validator(duration_validator),
+        Arg::with_name("io-timeout")
+            .long("io-timeout")
+            .value_name("DURATION")
+            .help("Fail file reads and writes on the backing store taking longer than this with EIO, e.g. 30s")
+            .takes_value(true)
+            .validator(duration_validator),
+        Arg::with_name("cache-limit")
+            .long("cache-limit")
+            .value_name("BYTES")
+            .help("Drop the cached data of the files not open once the cache takes more memory than this")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("flush-concurrency")
+            .long("flush-concurrency")
+            .value_name("COUNT")
+            .help("Number of the files synced to disk in parallel at unmount, 8 by default")
+            .takes_value(true)
+            .validator(positive_count_validator),
+        Arg::with_name("mmap-threshold")
+            .long("mmap-threshold")
+            .value_name("BYTES")
+            .help("Read the files of at least this size from disk instead of caching them, from a memory mapping of the backing file with private-backing")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("private-backing")
+            .long("private-backing")
+            .help("Promise that no other process changes the backing directory, so the files over the mmap threshold are read from memory mappings, a mapped file truncated behind the mount kills the daemon with SIGBUS"),
+        Arg::with_name("huge-pages")
+            .long("huge-pages")
+            .help("Back the buffer receiving requests from the kernel with transparent huge pages"),
+        Arg::with_name("snapshot-dir")
+            .long("snapshot-dir")
+            .value_name("NAME")
+            .help("Serve read-only snapshots under the hidden directory of this name in the root, e.g. .snapshots, taken by mkdir and dropped by rmdir of its subdirectories")
+            .takes_value(true),
+        Arg::with_name("spill-dir")
+            .long("spill-dir")
+            .value_name("DIR")
+            .help("Spill the snapshot data to a temporary file under this directory once the cache is beyond its limit")
+            .takes_value(true),
+        Arg::with_name("spill-limit")
+            .long("spill-limit")
+            .value_name("BYTES")
+            .help("Fail taking snapshots once the spilled data takes more space than this")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("max-inodes")
+            .long("max-inodes")
+            .value_name("COUNT")
+            .help("Fail creating files and directories with ENOSPC once this many are created through the mount")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("max-file-size")
+            .long("max-file-size")
+            .value_name("BYTES")
+            .help("Fail writing or truncating files beyond this size with EFBIG")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("reserve-space")
+            .long("reserve-space")
+            .value_name("AMOUNT")
+            .help("Keep this many bytes or this percentage of the backing filesystem free, e.g. 5%, report the free space less it and fail writing into it with ENOSPC")
+            .takes_value(true)
+            .validator(reserve_validator),
+        Arg::with_name("prealloc-size")
+            .long("prealloc-size")
+            .value_name("BYTES")
+            .help("Preallocate the backing files in extents of this size ahead of sequential writers, 0 by default, which disables it")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("readdir-ino-order")
+            .long("readdir-ino-order")
+            .help("List directory entries in i-node order instead of name order, for the tools stating every entry"),
+        Arg::with_name("no-open")
+            .long("no-open")
+            .help("Let the kernel skip the open requests and keep the files and directories read cached, needs the ro option"),
+        Arg::with_name("writeback-cache")
+            .long("writeback-cache")
+            .help("Let the kernel cache the writes and flush them later, faster for small writes, but the kernel keeps the sizes and times of the cached files, so the changes made to the backing directory behind the mount are not seen and O_APPEND is not atomic against other writers of the backing files"),
+        Arg::with_name("evict-forgotten")
+            .long("evict-forgotten")
+            .help("Drop the i-nodes the kernel forgets and no one opens from the cache, closing their fds"),
+        Arg::with_name("preload")
+            .long("preload")
+            .value_name("GLOB")
+            .help("Walk the backing tree in the background and cache the directories of the entries matching this path glob, e.g. src/**/*.rs")
+            .takes_value(true),
+        Arg::with_name("preload-data")
+            .long("preload-data")
+            .value_name("BYTES")
+            .help("Also read the preloaded files of at most this size to warm the backing store, 0 by default")
+            .takes_value(true)
+            .validator(count_validator),
+        Arg::with_name("non-utf8-names")
+            .long("non-utf8-names")
+            .value_name("ENCODING")
+            .help("Pass the names not in UTF-8 as is, hide them or percent-encode them, e.g. caf\\xE9 as caf%E9, for the clients requiring UTF-8")
+            .takes_value(true)
+            .possible_values(&["raw", "hide", "percent"]),
+        Arg::with_name("revalidate-interval")
+            .long("revalidate-interval")
+            .value_name("DURATION")
+            .help("Check a sample of the cached files and directories against the backing store this often while idle for changes made behind the mount, e.g. 1s")
+            .takes_value(true)
+            .validator(duration_validator),
+        Arg::with_name("revalidate-sample")
+            .long("revalidate-sample")
+            .value_name("COUNT")
+            .help("Check this many cached files and directories per revalidate interval, 64 by default")
+            .takes_value(true)
+            .validator(positive_count_validator),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("End the session once handling a request panics, instead of failing the request with EIO and poisoning its i-node"),
+        Arg::with_name("seccomp")
+            .long("seccomp")
+            .value_name("ACTION")
+            .help("Restrict the daemon to the syscalls it needs once mounted, taking this action on the others")
+            .takes_value(true)
+            .possible_values(&["log", "errno", "kill"])
+            .conflicts_with("supervise"),
+        Arg::with_name("setuid")
+            .long("setuid")
+            .value_name("USER")
+            .help("Switch to this user by name or id once mounted as root, before serving requests")
+            .takes_value(true)
+            .conflicts_with("supervise"),
+        Arg::with_name("setgid")
+            .long("setgid")
+            .value_name("GROUP")
+            .help("Switch to this group by name or id once mounted as root, the primary group of the setuid user by default")
+            .takes_value(true)
+            .conflicts_with("supervise"),
+        Arg::with_name("confine")
+            .long("confine")
+            .help("Confine the I/O on the backing directory to beneath it, refusing the names leading out of it"),
+        Arg::with_name("no-cloexec")
+            .long("no-cloexec")
+            .help("Let the processes spawned by the daemon inherit the fds of the backing files, which are closed on exec by default"),
+        Arg::with_name("update-atime")
+            .long("update-atime")
+            .help("Update the access times of the backing files on reading, which are left alone by default where permitted"),
+        Arg::with_name("debug-refcounts")
+            .long("debug-refcounts")
+            .value_name("FILE")
+            .help("Trace the lookup and open counts of the i-nodes to this file, the live ones are dumped on unmount and by the MEMFS_IOC_DUMP_REFCOUNTS ioctl")
+            .takes_value(true),
+        Arg::with_name("state-file")
+            .long("state-file")
+            .value_name("FILE")
+            .help("Save the i-nodes looked up by the kernel to this file when the daemon exits without unmounting, and restore them when taking over the mount on stdin with --dry-run-mount, to keep the inode numbers across a restart")
+            .takes_value(true),
+    ]
+}
+
+/// Build the command line app
+#[allow(clippy::too_many_lines)]
+pub fn app() -> App<'static, 'static> {
+    let app =
+        App::new("Fuse Low Level")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .value_name("FILE")
+                    .help("Read mount settings from a file of key = value lines")
+                    .takes_value(true)
+                    .global(true),
+            )
+            .args(&mount_args())
+            .subcommand(
+                SubCommand::with_name("mount")
+                    .about("Mount a memory filesystem")
+                    .args(&mount_args()),
+            )
+            .subcommand(
+                SubCommand::with_name("umount")
+                    .about("Unmount a mount point")
+                    .arg(Arg::with_name("mountpoint").required(true).index(1))
+                    .arg(Arg::with_name("lazy").long("lazy").help(
+                        "Detach the mount point at once and clean up once it is no longer busy",
+                    ))
+                    .arg(Arg::with_name("force").long("force").help(
+                        "Abort pending requests and unmount even if the mount point is busy",
+                    )),
+            )
+            .subcommand(
+                SubCommand::with_name("check")
+                    .about("Check whether a mount point answers, exit with 1 if it is wedged")
+                    .arg(Arg::with_name("mountpoint").required(true).index(1))
+                    .arg(
+                        Arg::with_name("timeout")
+                            .long("timeout")
+                            .value_name("DURATION")
+                            .help("Time the mount point may take to answer")
+                            .takes_value(true)
+                            .default_value("5s")
+                            .validator(duration_validator),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("bench")
+                    .about("Measure the throughput of writing and reading files in a directory")
+                    .arg(Arg::with_name("dir").required(true).index(1))
+                    .arg(
+                        Arg::with_name("files")
+                            .long("files")
+                            .value_name("COUNT")
+                            .help("Number of files")
+                            .takes_value(true)
+                            .default_value("16")
+                            .validator(count_validator),
+                    )
+                    .arg(
+                        Arg::with_name("size")
+                            .long("size")
+                            .value_name("BYTES")
+                            .help("Size of each file")
+                            .takes_value(true)
+                            .default_value("1048576")
+                            .validator(count_validator),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("stats")
+                    .about("Print the usage and the mount entry of a mount point")
+                    .arg(Arg::with_name("mountpoint").required(true).index(1))
+                    .arg(
+                        Arg::with_name("files")
+                            .help("Files of the mount to print the bytes read and written of")
+                            .multiple(true)
+                            .index(2),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("options")
+                    .about("List the supported mount options and the platforms supporting them"),
+            )
+            .subcommand(
+                SubCommand::with_name("completions")
+                    .about("Print the completion script of a shell")
+                    .arg(
+                        Arg::with_name("shell")
+                            .required(true)
+                            .index(1)
+                            .possible_values(&Shell::variants()),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("mount-archive")
+                    .about("Mount the contents of a zip, tar or gzipped tar archive read-only")
+                    .arg(Arg::with_name("archive").required(true).index(1))
+                    .arg(Arg::with_name("mountpoint").required(true).index(2))
+                    .arg(no_open_arg()),
+            );
+    #[cfg(feature = "git")]
+    let app = app.subcommand(
+        SubCommand::with_name("mount-git")
+            .about("Mount the tree of a commit of a git repository read-only")
+            .arg(Arg::with_name("repository").required(true).index(1))
+            .arg(Arg::with_name("revision").required(true).index(2))
+            .arg(Arg::with_name("mountpoint").required(true).index(3))
+            .arg(no_open_arg()),
+    );
+    #[cfg(feature = "http")]
+    let app = app.subcommand(
+        SubCommand::with_name("mount-http")
+            .about("Mount a remote HTTP directory tree or WebDAV share read-only")
+            .arg(Arg::with_name("url").required(true).index(1))
+            .arg(Arg::with_name("mountpoint").required(true).index(2))
+            .arg(no_open_arg())
+            .arg(
+                Arg::with_name("webdav")
+                    .long("webdav")
+                    .help("List directories by WebDAV PROPFIND instead of index pages"),
+            )
+            .arg(
+                Arg::with_name("cache-chunks")
+                    .long("cache-chunks")
+                    .value_name("COUNT")
+                    .help("Maximum number of downloaded 64KB chunks cached")
+                    .takes_value(true)
+                    .validator(count_validator),
+            ),
+    );
+    app
+}
+
+/// The flag of the read-only mounts letting the kernel skip the opens
+fn no_open_arg() -> Arg<'static, 'static> {
+    Arg::with_name("no-open").long("no-open").help(
+        "Let the kernel skip the open requests where the filesystem needs none, \
+         the files and directories read are then kept cached by the kernel",
+    )
+}
+
+/// Name of the environment variable overriding a setting
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.to_uppercase().replace('-', "_"))
+}
+
+/// The environment variables overriding the settings, by name
+fn env_overrides() -> HashMap<String, String> {
+    env::vars_os()
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect()
+}
+
+/// Mount settings read from a config file, overridden by the environment
+#[derive(Debug)]
+pub struct Config {
+    /// Settings by key
+    values: HashMap<String, String>,
+    /// The environment variables overriding the settings, by name
+    env: HashMap<String, String>,
+}
+
+impl Config {
+    /// Load a config file
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        Self::parse(&content, env_overrides())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    /// No config file, only the settings of the environment
+    pub fn from_env() -> Self {
+        Self {
+            values: HashMap::new(),
+            env: env_overrides(),
+        }
+    }
+
+    /// Parse the lines of a config file, `#` starts a comment, the variables
+    /// of `env` override them
+    fn parse(content: &str, env: HashMap<String, String>) -> Result<Self, String> {
+        let mut values = HashMap::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: expected key = value", idx.overflow_add(1)))?;
+            let key = key.trim();
+            if !MOUNT_KEYS.contains(&key) {
+                return Err(format!(
+                    "line {}: unknown setting {:?}",
+                    idx.overflow_add(1),
+                    key
+                ));
+            }
+            values.insert(key.to_owned(), value.trim().to_owned());
+        }
+        Ok(Self { values, env })
+    }
+
+    /// The value of a setting, the environment overrides the config file
+    fn get(&self, key: &str) -> Option<String> {
+        self.env
+            .get(&env_name(key))
+            .or_else(|| self.values.get(key))
+            .cloned()
+    }
+
+    /// The value of a switch setting
+    fn get_bool(&self, key: &str) -> Result<bool, String> {
+        match self.get(key).as_deref() {
+            None | Some("false" | "no" | "off" | "0") => Ok(false),
+            Some("true" | "yes" | "on" | "1") => Ok(true),
+            Some(value) => Err(format!(
+                "invalid {} {:?}, expected true or false",
+                key, value
+            )),
+        }
+    }
+
+    /// The value of a count setting
+    fn get_count(&self, key: &str) -> Result<Option<usize>, String> {
+        self.get(key)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|e| format!("invalid {} {:?}: {}", key, value, e))
+            })
+            .transpose()
+    }
+
+    /// The value of a duration setting
+    fn get_duration(&self, key: &str) -> Result<Option<Duration>, String> {
+        self.get(key)
+            .map(|value| {
+                humantime::parse_duration(&value)
+                    .map_err(|e| format!("invalid {} {:?}: {}", key, value, e))
+            })
+            .transpose()
+    }
+}
+
+/// Settings of mounting a filesystem
+#[derive(Debug)]
+pub struct MountSettings {
+    /// Mount options
+    pub options: Vec<String>,
+    /// Whether to deduplicate file data chunks
+    pub dedup: bool,
+    /// Fixed chunk size of the deduplicated data, picked after the sizes of
+    /// the requests if not set
+    pub chunk_size: Option<usize>,
+    /// Whether to supervise the mount point
+    pub supervise: bool,
+    /// Whether to serve the requests on stdin instead of mounting
+    pub dry_run_mount: bool,
+    /// Whether to check the mount point with a probe file once mounted
+    pub selftest: bool,
+    /// Whether to check the prerequisites of mounting first
+    pub preflight: bool,
+    /// Threshold of logging slow requests
+    pub slow_op_threshold: Option<Duration>,
+    /// Timeout of file I/O on the backing store
+    pub io_timeout: Option<Duration>,
+    /// Limit of the memory of the cached data
+    pub cache_limit: Option<usize>,
+    /// Number of the files synced to disk in parallel
+    pub flush_concurrency: Option<usize>,
+    /// Minimum size of the files read from disk or a memory mapping
+    pub mmap_threshold: Option<usize>,
+    /// Whether no other process changes the backing directory
+    pub private_backing: bool,
+    /// Whether to back the request buffer with huge pages
+    pub huge_pages: bool,
+    /// Name of the directory of the snapshots under the root
+    pub snapshot_dir: Option<OsString>,
+    /// Directory of the spill file of the snapshot data
+    pub spill_dir: Option<PathBuf>,
+    /// Limit of the space of the spilled data
+    pub spill_limit: Option<usize>,
+    /// Limit of the number of the i-nodes in memory
+    pub max_inodes: Option<usize>,
+    /// Limit of the file size
+    pub max_file_size: Option<usize>,
+    /// Space kept free on the backing filesystem
+    pub reserve_space: Option<SpaceReserve>,
+    /// Size of the extents preallocated ahead of sequential writers
+    pub prealloc_size: Option<usize>,
+    /// Whether to list directory entries in i-node order
+    pub readdir_ino_order: bool,
+    /// Whether to let the kernel skip the opens
+    pub no_open: bool,
+    /// Whether to let the kernel cache the writes
+    pub writeback_cache: bool,
+    /// Whether to drop the forgotten i-nodes from the cache
+    pub evict_forgotten: bool,
+    /// Glob of the paths of the entries to preload
+    pub preload: Option<String>,
+    /// Maximum size of the preloaded files whose data is cached
+    pub preload_data: Option<usize>,
+    /// Interval of revalidating the cached i-nodes against the backing store
+    pub revalidate_interval: Option<Duration>,
+    /// Number of the cached i-nodes revalidated per interval
+    pub revalidate_sample: Option<usize>,
+    /// File tracing the reference counts of the i-nodes
+    pub debug_refcounts: Option<PathBuf>,
+    /// File the i-nodes looked up by the kernel are saved to across restarts
+    pub state_file: Option<PathBuf>,
+    /// Whether a panic handling a request ends the session
+    pub strict: bool,
+    /// The action of the seccomp filter on the syscalls not needed, no filter if none
+    pub seccomp: Option<SeccompAction>,
+    /// The user switched to once mounted
+    pub setuid: Option<Uid>,
+    /// The group switched to once mounted
+    pub setgid: Option<Gid>,
+    /// Whether to confine the I/O to beneath the backing directory
+    pub confine: bool,
+    /// Encoding of the names not in UTF-8
+    pub name_encoding: Option<NameEncoding>,
+    /// Whether the fds of the backing files are inherited on exec
+    pub no_cloexec: bool,
+    /// Whether reading the backing files updates their access times
+    pub update_atime: bool,
+}
+
+impl MountSettings {
+    /// Resolve the settings from the command line, the environment and the
+    /// config file, in that order of precedence
+    pub fn resolve(matches: &ArgMatches<'_>, config: &Config) -> Result<Self, String> {
+        let options = resolve_options(matches, config)?;
+        let duration = |key: &str| match matches.value_of(key) {
+            // safe to use panic!() here, because the duration is validated
+            Some(value) => Ok(Some(
+                humantime::parse_duration(value)
+                    .unwrap_or_else(|_| panic!("Invalid {} {:?}", key, value)),
+            )),
+            None => config.get_duration(key),
+        };
+        let count = |key: &str| {
+            matches.value_of(key).map_or_else(
+                || config.get_count(key),
+                // safe to use panic!() here, because the count is validated
+                |value| {
+                    Ok(Some(
+                        value
+                            .parse()
+                            .unwrap_or_else(|_| panic!("Invalid {} {:?}", key, value)),
+                    ))
+                },
+            )
+        };
+        let flag = |key: &str| Ok::<_, String>(matches.is_present(key) || config.get_bool(key)?);
+        let positive_count = |key: &str| match count(key)? {
+            Some(0) => Err(format!("invalid {} 0, expected a positive count", key)),
+            value => Ok(value),
+        };
+        let supervise = flag("supervise")?;
+        let dry_run_mount = flag("dry-run-mount")?;
+        let selftest = flag("selftest")?;
+        let preflight = flag("preflight")?;
+        let no_open = flag("no-open")?;
+        let writeback_cache = flag("writeback-cache")?;
+        let setting = |key: &str| {
+            matches
+                .value_of(key)
+                .map(str::to_owned)
+                .or_else(|| config.get(key))
+        };
+        let seccomp = setting("seccomp")
+            .map(|action| resolve_seccomp(&action))
+            .transpose()?;
+        let (setuid, setgid) = resolve_credentials(setting("setuid"), setting("setgid"))?;
+        // the filter forbids the remount of the supervisor
+        if supervise && seccomp.is_some() {
+            return Err("seccomp cannot be used along with supervise".to_owned());
+        }
+        // the remount of the supervisor needs the privileges
+        if supervise && (setuid.is_some() || setgid.is_some()) {
+            return Err("setuid and setgid cannot be used along with supervise".to_owned());
+        }
+        // the supervisor checks a mount point, which a dry run has not
+        if supervise && dry_run_mount {
+            return Err("dry-run-mount cannot be used along with supervise".to_owned());
+        }
+        // the probe file goes through the mount point, which a dry run has not
+        if selftest && dry_run_mount {
+            return Err("selftest cannot be used along with dry-run-mount".to_owned());
+        }
+        // the checks are of the prerequisites on Linux and Android
+        if preflight && !cfg!(any(target_os = "linux", target_os = "android")) {
+            return Err("preflight is only supported on Linux and Android".to_owned());
+        }
+        // the preflight checks a real mount, which a dry run skips
+        if preflight && dry_run_mount {
+            return Err("preflight cannot be used along with dry-run-mount".to_owned());
+        }
+        // the kernel asks for the writeback cache only from ABI 7.23 on
+        if writeback_cache && !cfg!(feature = "abi-7-23") {
+            return Err("writeback-cache needs the abi-7-23 feature".to_owned());
+        }
+        // the writes need the fds of the opens
+        if no_open && !options.iter().any(|option| option == "ro") {
+            return Err("no-open needs the ro option".to_owned());
+        }
+        Ok(Self {
+            options,
+            dedup: flag("dedup")?,
+            chunk_size: positive_count("chunk-size")?,
+            supervise,
+            dry_run_mount,
+            selftest,
+            preflight,
+            slow_op_threshold: duration("slow-op-threshold")?,
+            io_timeout: duration("io-timeout")?,
+            cache_limit: count("cache-limit")?,
+            flush_concurrency: positive_count("flush-concurrency")?,
+            mmap_threshold: count("mmap-threshold")?,
+            private_backing: flag("private-backing")?,
+            huge_pages: flag("huge-pages")?,
+            snapshot_dir: matches
+                .value_of_os("snapshot-dir")
+                .map(OsStr::to_os_string)
+                .or_else(|| config.get("snapshot-dir").map(OsString::from)),
+            spill_dir: matches
+                .value_of_os("spill-dir")
+                .map(PathBuf::from)
+                .or_else(|| config.get("spill-dir").map(PathBuf::from)),
+            spill_limit: count("spill-limit")?,
+            max_inodes: count("max-inodes")?,
+            max_file_size: count("max-file-size")?,
+            reserve_space: setting("reserve-space")
+                .map(|reserve| reserve.parse())
+                .transpose()?,
+            prealloc_size: count("prealloc-size")?,
+            readdir_ino_order: flag("readdir-ino-order")?,
+            no_open,
+            writeback_cache,
+            evict_forgotten: flag("evict-forgotten")?,
+            preload: matches
+                .value_of("preload")
+                .map(str::to_owned)
+                .or_else(|| config.get("preload")),
+            preload_data: count("preload-data")?,
+            revalidate_interval: duration("revalidate-interval")?,
+            revalidate_sample: positive_count("revalidate-sample")?,
+            debug_refcounts: matches
+                .value_of_os("debug-refcounts")
+                .map(PathBuf::from)
+                .or_else(|| config.get("debug-refcounts").map(PathBuf::from)),
+            state_file: matches
+                .value_of_os("state-file")
+                .map(PathBuf::from)
+                .or_else(|| config.get("state-file").map(PathBuf::from)),
+            strict: flag("strict")?,
+            seccomp,
+            setuid,
+            setgid,
+            confine: flag("confine")?,
+            name_encoding: setting("non-utf8-names")
+                .map(|encoding| encoding.parse())
+                .transpose()?,
+            no_cloexec: flag("no-cloexec")?,
+            update_atime: flag("update-atime")?,
+        })
+    }
+}
+
+/// Resolve the mount options, split at the commas
+fn resolve_options(matches: &ArgMatches<'_>, config: &Config) -> Result<Vec<String>, String> {
+    Ok(match matches.values_of("options") {
+        Some(options) => options
+            .flat_map(|o| o.split(','))
+            .map(str::to_owned)
+            .collect(),
+        None => match config.get("options") {
+            Some(options) => {
+                memfs::options_validator(&options)?;
+                options.split(',').map(str::to_owned).collect()
+            }
+            None => Vec::new(),
+        },
+    })
+}
+
+/// Resolve the action of the seccomp filter
+fn resolve_seccomp(action: &str) -> Result<SeccompAction, String> {
+    match action {
+        "log" => Ok(SeccompAction::Log),
+        "errno" => Ok(SeccompAction::Errno),
+        "kill" => Ok(SeccompAction::Kill),
+        _ => Err(format!(
+            "invalid seccomp {:?}, expected log, errno or kill",
+            action
+        )),
+    }
+}
+
+/// Resolve the user and the group to switch to by name or id, the group
+/// defaults to the primary group of the user
+fn resolve_credentials(
+    user: Option<String>,
+    group: Option<String>,
+) -> Result<(Option<Uid>, Option<Gid>), String> {
+    let user = user.map(|user| resolve_user(&user)).transpose()?;
+    let gid = match group {
+        Some(group) => Some(resolve_group(&group)?),
+        None => match user {
+            Some((uid, None)) => {
+                return Err(format!(
+                    "no primary group of setuid {}, expected setgid",
+                    uid
+                ))
+            }
+            Some((_, primary)) => primary,
+            None => None,
+        },
+    };
+    Ok((user.map(|(uid, _)| uid), gid))
+}
+
+/// Resolve a user by name or id along with its primary group, which is
+/// unknown for an id missing from the user database
+fn resolve_user(user: &str) -> Result<(Uid, Option<Gid>), String> {
+    let id = user.parse().ok().map(Uid::from_raw);
+    let found = id.map_or_else(|| User::from_name(user), User::from_uid);
+    match (found, id) {
+        (Ok(Some(found)), _) => Ok((found.uid, Some(found.gid))),
+        (Ok(None), Some(uid)) => Ok((uid, None)),
+        (Ok(None) | Err(_), _) => Err(format!("invalid setuid {:?}, no such user", user)),
+    }
+}
+
+/// Resolve a group by name or id
+fn resolve_group(group: &str) -> Result<Gid, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    match Group::from_name(group) {
+        Ok(Some(entry)) => Ok(entry.gid),
+        Ok(None) | Err(_) => Err(format!("invalid setgid {:?}, no such group", group)),
+    }
+}
+
+/// The path of a required argument
+fn path_arg<'a>(matches: &'a ArgMatches<'_>, name: &str) -> &'a Path {
+    // safe to use panic!() here, because the argument is required
+    Path::new(
+        matches
+            .value_of_os(name)
+            .unwrap_or_else(|| panic!("Couldn't get {} {:?}", name, matches)),
+    )
+}
+
+/// The value of a validated argument, which is required or has a default
+fn parsed_arg<T: std::str::FromStr>(matches: &ArgMatches<'_>, name: &str) -> T {
+    // safe to use panic!() here, because the argument is validated
+    matches
+        .value_of(name)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("Couldn't get {} {:?}", name, matches))
+}
+
+/// Run the `umount` subcommand
+pub fn umount(matches: &ArgMatches<'_>) -> io::Result<()> {
+    let mut flags = UnmountFlags::empty();
+    if matches.is_present("lazy") {
+        flags = flags | UnmountFlags::LAZY;
+    }
+    if matches.is_present("force") {
+        flags = flags | UnmountFlags::FORCE;
+    }
+    fuse::unmount_options(path_arg(matches, "mountpoint"), flags)
+}
+
+/// Run the `check` subcommand, return whether the mount point is healthy
+pub fn check(matches: &ArgMatches<'_>) -> bool {
+    let mountpoint = path_arg(matches, "mountpoint");
+    let timeout = humantime::parse_duration(matches.value_of("timeout").unwrap_or("5s"))
+        .unwrap_or_else(|_| panic!("Invalid timeout {:?}", matches)); // safe to use panic!() here, because the timeout is validated
+    let healthy = fuse::check_mountpoint(mountpoint, timeout);
+    println!(
+        "{:?}: {}",
+        mountpoint,
+        if healthy { "healthy" } else { "wedged" }
+    );
+    healthy
+}
+
+/// Run the `options` subcommand, list the mount options from the option table
+pub fn options() {
+    let options: Vec<MountOptionInfo> = memfs::mount_options_info();
+    let width = options.iter().map(|op| op.name.len()).max().unwrap_or(0);
+    for op in options {
+        println!(
+            "{:<width$}  {:<11}  {}",
+            op.name,
+            op.platforms.join(","),
+            op.description,
+            width = width
+        );
+    }
+}
+
+/// Run the `completions` subcommand, print the completion script of the shell
+pub fn completions(matches: &ArgMatches<'_>) {
+    let shell: Shell = parsed_arg(matches, "shell");
+    app().gen_completions_to(env!("CARGO_PKG_NAME"), shell, &mut io::stdout());
+}
+
+/// Throughput in KiB per second
+fn throughput(bytes: u64, elapsed: Duration) -> u128 {
+    u128::from(bytes)
+        .overflow_mul(1_000_000)
+        .overflow_div(elapsed.as_micros().max(1))
+        .overflow_div(1024)
+}
+
+/// Run the `bench` subcommand, write files to the directory, read them back
+/// and remove them
+pub fn bench(matches: &ArgMatches<'_>) -> io::Result<()> {
+    let dir = path_arg(matches, "dir");
+    let files: usize = parsed_arg(matches, "files");
+    let size: usize = parsed_arg(matches, "size");
+    let total = files.cast::<u64>().overflow_mul(size.cast());
+    let data = vec![0xA5_u8; size];
+    let paths: Vec<PathBuf> = (0..files)
+        .map(|idx| dir.join(format!("fuse_ll_bench_{}", idx)))
+        .collect();
+
+    let start = Instant::now();
+    for path in &paths {
+        let mut file = File::create(path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "write: {} files of {} bytes in {:?}, {} KiB/s",
+        files,
+        size,
+        elapsed,
+        throughput(total, elapsed)
+    );
+
+    let start = Instant::now();
+    let mut buf = Vec::with_capacity(size);
+    for path in &paths {
+        buf.clear();
+        File::open(path)?.read_to_end(&mut buf)?;
+        if buf != data {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("read back different data from {:?}", path),
+            ));
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "read: {} files of {} bytes in {:?}, {} KiB/s",
+        files,
+        size,
+        elapsed,
+        throughput(total, elapsed)
+    );
+
+    let start = Instant::now();
+    for path in &paths {
+        fs::remove_file(path)?;
+    }
+    println!("remove: {} files in {:?}", files, start.elapsed());
+    Ok(())
+}
+
+/// The entry of the mount point in `/proc/mounts`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mount_entry(mountpoint: &Path) -> io::Result<Option<String>> {
+    let path = mountpoint
+        .canonicalize()
+        .unwrap_or_else(|_| mountpoint.to_path_buf());
+    let escaped = fuse::escape_mount_path(&path.to_string_lossy());
+    let mounts = fs::read_to_string("/proc/mounts")?;
+    // the last entry wins if mounted over
+    Ok(mounts
+        .lines()
+        .filter(|line| line.split(' ').nth(1) == Some(escaped.as_str()))
+        .last()
+        .map(str::to_owned))
+}
+
+/// The results of the init negotiation of the mount point with the kernel,
+/// `None` if the filesystem does not expose them
+#[cfg(feature = "abi-7-11")]
+fn init_info(mountpoint: &Path) -> Option<fuse::InitInfo> {
+    use std::os::unix::io::AsRawFd;
+    nix::ioctl_read_buf!(memfs_init_info, b'm', 7, u8);
+
+    let dir = File::open(mountpoint).ok()?;
+    let mut buf = [0_u8; std::mem::size_of::<fuse::InitInfo>()];
+    // the same cmd as `MEMFS_IOC_INIT_INFO`, other filesystems fail with ENOTTY
+    #[allow(unsafe_code)]
+    let res = unsafe { memfs_init_info(dir.as_raw_fd(), &mut buf) };
+    res.ok().and_then(|_| fuse::InitInfo::from_bytes(&buf))
+}
+
+/// The request statistics of the memory filesystem mounted at `mountpoint`,
+/// `None` if the filesystem does not expose them
+#[cfg(feature = "abi-7-11")]
+fn io_stats(mountpoint: &Path) -> Option<IoStats> {
+    use std::os::unix::io::AsRawFd;
+    nix::ioctl_read_buf!(memfs_io_stats, b'm', 8, u8);
+
+    let dir = File::open(mountpoint).ok()?;
+    let mut buf = [0_u8; std::mem::size_of::<IoStats>()];
+    // the same cmd as `MEMFS_IOC_IO_STATS`, other filesystems fail with ENOTTY
+    #[allow(unsafe_code)]
+    let res = unsafe { memfs_io_stats(dir.as_raw_fd(), &mut buf) };
+    res.ok().and_then(|_| IoStats::from_bytes(&buf))
+}
+
+/// The handles not released of the memory filesystem mounted at
+/// `mountpoint`, `None` if the filesystem does not expose them
+#[cfg(feature = "abi-7-11")]
+fn handle_stats(mountpoint: &Path) -> Option<HandleStats> {
+    use std::os::unix::io::AsRawFd;
+    nix::ioctl_read_buf!(memfs_handle_stats, b'm', 9, u8);
+
+    let dir = File::open(mountpoint).ok()?;
+    let mut buf = [0_u8; std::mem::size_of::<HandleStats>()];
+    // the same cmd as `MEMFS_IOC_HANDLE_STATS`, other filesystems fail with ENOTTY
+    #[allow(unsafe_code)]
+    let res = unsafe { memfs_handle_stats(dir.as_raw_fd(), &mut buf) };
+    res.ok().and_then(|_| HandleStats::from_bytes(&buf))
+}
+
+/// The bytes read and written of the file of `path` on the memory filesystem,
+/// of all its files if `path` is a directory, `None` if the filesystem does
+/// not expose them
+#[cfg(feature = "abi-7-11")]
+fn byte_stats(path: &Path) -> Option<ByteStats> {
+    use std::os::unix::io::AsRawFd;
+    nix::ioctl_read_buf!(memfs_byte_stats, b'm', 10, u8);
+
+    let file = File::open(path).ok()?;
+    let mut buf = [0_u8; std::mem::size_of::<ByteStats>()];
+    // the same cmd as `MEMFS_IOC_BYTE_STATS`, other filesystems fail with ENOTTY
+    #[allow(unsafe_code)]
+    let res = unsafe { memfs_byte_stats(file.as_raw_fd(), &mut buf) };
+    res.ok().and_then(|_| ByteStats::from_bytes(&buf))
+}
+
+/// The cache compaction of the memory filesystem mounted at `mountpoint`,
+/// `None` if the filesystem does not expose it
+#[cfg(feature = "abi-7-11")]
+fn cache_stats(mountpoint: &Path) -> Option<CacheStats> {
+    use std::os::unix::io::AsRawFd;
+    nix::ioctl_read_buf!(memfs_cache_stats, b'm', 11, u8);
+
+    let dir = File::open(mountpoint).ok()?;
+    let mut buf = [0_u8; std::mem::size_of::<CacheStats>()];
+    // the same cmd as `MEMFS_IOC_CACHE_STATS`, other filesystems fail with ENOTTY
+    #[allow(unsafe_code)]
+    let res = unsafe { memfs_cache_stats(dir.as_raw_fd(), &mut buf) };
+    res.ok().and_then(|_| CacheStats::from_bytes(&buf))
+}
+
+/// Run the `stats` subcommand
+pub fn stats(matches: &ArgMatches<'_>) -> io::Result<()> {
+    let mountpoint = path_arg(matches, "mountpoint");
+    let stat = statvfs::statvfs(mountpoint).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    println!("mount point: {:?}", mountpoint);
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(entry) = mount_entry(mountpoint)? {
+        println!("mount entry: {}", entry);
+    }
+    println!("block size: {}", stat.block_size());
+    println!("fragment size: {}", stat.fragment_size());
+    println!("blocks: {}", stat.blocks());
+    println!("free blocks: {}", stat.blocks_free());
+    println!("available blocks: {}", stat.blocks_available());
+    println!("files: {}", stat.files());
+    println!("free files: {}", stat.files_free());
+    println!("max name length: {}", stat.name_max());
+    #[cfg(feature = "abi-7-11")]
+    if let Some(info) = init_info(mountpoint) {
+        println!("{}", info);
+    }
+    #[cfg(feature = "abi-7-11")]
+    if let Some(stats) = io_stats(mountpoint) {
+        println!("{}", stats);
+    }
+    #[cfg(feature = "abi-7-11")]
+    if let Some(stats) = byte_stats(mountpoint) {
+        println!("{}", stats);
+    }
+    #[cfg(feature = "abi-7-11")]
+    if let Some(stats) = cache_stats(mountpoint) {
+        println!("{}", stats);
+    }
+    // the stats opened the mount point, which is one of the directory handles
+    #[cfg(feature = "abi-7-11")]
+    if let Some(stats) = handle_stats(mountpoint) {
+        println!("{}", stats);
+    }
+    #[cfg(feature = "abi-7-11")]
+    for file in matches.values_of_os("files").into_iter().flatten() {
+        if let Some(stats) = byte_stats(Path::new(file)) {
+            println!("file: {:?}\n{}", file, stats);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{env_name, resolve_credentials, resolve_group, resolve_user, Config};
+    use nix::unistd::{Gid, Uid};
+
+    #[test]
+    fn test_config() {
+        use std::collections::HashMap;
+
+        let content = "# mount settings\n\
+                       options = ro,allow_other\n\
+                       \n\
+                       dedup = yes # share identical chunks\n\
+                       io-timeout=30s\n\
+                       cache-limit = 1000000\n";
+        let config = Config::parse(content, HashMap::new()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(config.get("options").as_deref(), Some("ro,allow_other"));
+        assert_eq!(config.get_bool("dedup"), Ok(true));
+        assert_eq!(config.get_bool("supervise"), Ok(false));
+        assert_eq!(config.get_count("cache-limit"), Ok(Some(1_000_000)));
+        assert_eq!(config.get_count("io-timeout").ok(), None);
+        assert_eq!(
+            config.get_duration("io-timeout"),
+            Ok(Some(std::time::Duration::from_secs(30)))
+        );
+
+        assert!(Config::parse("dedup", HashMap::new()).is_err());
+        assert!(Config::parse("unknown = 1", HashMap::new()).is_err());
+        assert!(Config::parse("dedup = maybe", HashMap::new())
+            .unwrap_or_else(|e| panic!("{}", e))
+            .get_bool("dedup")
+            .is_err());
+        assert_eq!(env_name("slow-op-threshold"), "SYNC_FUSE_SLOW_OP_THRESHOLD");
+
+        // the environment overrides the config file
+        let env = vec![
+            (env_name("dedup"), "no".to_owned()),
+            (env_name("supervise"), "on".to_owned()),
+        ];
+        let config =
+            Config::parse(content, env.into_iter().collect()).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(config.get_bool("dedup"), Ok(false));
+        assert_eq!(config.get_bool("supervise"), Ok(true));
+        assert_eq!(config.get("options").as_deref(), Some("ro,allow_other"));
+    }
+
+    #[test]
+    fn test_resolve_credentials() {
+        let root = (Uid::from_raw(0), Some(Gid::from_raw(0)));
+        assert_eq!(resolve_user("root"), Ok(root));
+        assert_eq!(resolve_user("0"), Ok(root));
+        // an id missing from the user database has no primary group
+        let unknown = 4_000_000_000;
+        assert_eq!(
+            resolve_user(&unknown.to_string()),
+            Ok((Uid::from_raw(unknown), None))
+        );
+        assert!(resolve_user("no-such-user").is_err());
+        assert_eq!(resolve_group("42"), Ok(Gid::from_raw(42)));
+        assert!(resolve_group("no-such-group").is_err());
+
+        assert_eq!(resolve_credentials(None, None), Ok((None, None)));
+        assert_eq!(
+            resolve_credentials(Some("root".to_owned()), None),
+            Ok((Some(Uid::from_raw(0)), Some(Gid::from_raw(0))))
+        );
+        assert_eq!(
+            resolve_credentials(Some("root".to_owned()), Some("42".to_owned())),
+            Ok((Some(Uid::from_raw(0)), Some(Gid::from_raw(42))))
+        );
+        assert_eq!(
+            resolve_credentials(None, Some("42".to_owned())),
+            Ok((None, Some(Gid::from_raw(42))))
+        );
+        assert!(resolve_credentials(Some(unknown.to_string()), None).is_err());
+        assert_eq!(
+            resolve_credentials(Some(unknown.to_string()), Some("42".to_owned())),
+            Ok((Some(Uid::from_raw(unknown)), Some(Gid::from_raw(42))))
+        );
+    }
+}
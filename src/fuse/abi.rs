@@ -57,9 +57,21 @@ pub const FUSE_KERNEL_MINOR_VERSION: u32 = 17;
 #[cfg(all(feature = "abi-7-18", not(feature = "abi-7-19")))]
 /// fuse kernel minor version
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 18;
-#[cfg(feature = "abi-7-19")]
+#[cfg(all(feature = "abi-7-19", not(feature = "abi-7-20")))]
 /// fuse kernel minor version
 pub const FUSE_KERNEL_MINOR_VERSION: u32 = 19;
+#[cfg(all(feature = "abi-7-20", not(feature = "abi-7-21")))]
+/// fuse kernel minor version
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 20;
+#[cfg(all(feature = "abi-7-21", not(feature = "abi-7-22")))]
+/// fuse kernel minor version
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 21;
+#[cfg(all(feature = "abi-7-22", not(feature = "abi-7-23")))]
+/// fuse kernel minor version
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 22;
+#[cfg(feature = "abi-7-23")]
+/// fuse kernel minor version
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 23;
 
 /// fuse root id
 pub const FUSE_ROOT_ID: u64 = 1;
@@ -180,6 +192,9 @@ pub mod consts {
     #[cfg(feature = "abi-7-9")]
     /// Lock owner
     pub const FATTR_LOCKOWNER: u32 = 1 << 9;
+    #[cfg(feature = "abi-7-23")]
+    /// Change time, set in writeback cache mode
+    pub const FATTR_CTIME: u32 = 1 << 10;
 
     #[cfg(target_os = "macos")]
     /// Create time
@@ -202,6 +217,8 @@ pub mod consts {
     #[cfg(feature = "abi-7-10")]
     /// File open non-seekable
     pub const FOPEN_NONSEEKABLE: u32 = 1 << 2; // the file is not seekable
+    /// File open cache dir, since ABI 7.28 and ignored by older kernels
+    pub const FOPEN_CACHE_DIR: u32 = 1 << 3; // allow caching this directory
 
     #[cfg(target_os = "macos")]
     /// File open purge attr
@@ -247,6 +264,13 @@ pub mod consts {
     #[cfg(feature = "abi-7-18")]
     /// Fuse has ioctl dir
     pub const FUSE_HAS_IOCTL_DIR: u32 = 1 << 11; // kernel supports ioctl on directories
+    #[cfg(feature = "abi-7-23")]
+    /// Fuse writeback cache
+    pub const FUSE_WRITEBACK_CACHE: u32 = 1 << 16; // use writeback cache for buffered writes
+    /// Fuse no open support, offered by the kernels answering an ENOSYS open
+    pub const FUSE_NO_OPEN_SUPPORT: u32 = 1 << 17; // kernel supports zero-message opens
+    /// Fuse no opendir support, since ABI 7.29 and ignored by older kernels
+    pub const FUSE_NO_OPENDIR_SUPPORT: u32 = 1 << 24; // kernel supports zero-message opendir
 
     #[cfg(target_os = "macos")]
     /// Fuse allocate
@@ -384,6 +408,8 @@ pub enum fuse_opcode {
     FUSE_BATCH_FORGET = 42,
     #[cfg(feature = "abi-7-19")]
     FUSE_FALLOCATE = 43,
+    #[cfg(feature = "abi-7-23")]
+    FUSE_RENAME2 = 45,
 
     #[cfg(target_os = "macos")]
     FUSE_SETVOLNAME = 61,
@@ -447,6 +473,8 @@ impl TryFrom<u32> for fuse_opcode {
             42 => Ok(Self::FUSE_BATCH_FORGET),
             #[cfg(feature = "abi-7-19")]
             43 => Ok(Self::FUSE_FALLOCATE),
+            #[cfg(feature = "abi-7-23")]
+            45 => Ok(Self::FUSE_RENAME2),
 
             #[cfg(target_os = "macos")]
             61 => Ok(Self::FUSE_SETVOLNAME),
@@ -648,6 +676,19 @@ pub struct fuse_rename_in {
     pub newdir: u64,
 }
 
+#[cfg(feature = "abi-7-23")]
+#[repr(C)]
+#[derive(Debug)]
+/// Fuse rename2 in
+pub struct fuse_rename2_in {
+    /// New dir
+    pub newdir: u64,
+    /// Flags of renameat2
+    pub flags: u32,
+    /// Padding
+    pub padding: u32,
+}
+
 #[cfg(target_os = "macos")]
 #[repr(C)]
 #[derive(Debug)]
@@ -691,14 +732,22 @@ pub struct fuse_setattr_in {
     pub atime: u64,
     /// Modify time
     pub mtime: u64,
+    #[cfg(not(feature = "abi-7-23"))]
     /// Unused2
     pub unused2: u64,
+    #[cfg(feature = "abi-7-23")]
+    /// Change time
+    pub ctime: u64,
     /// Access time nsec
     pub atimensec: u32,
     /// Modify time nsec
     pub mtimensec: u32,
+    #[cfg(not(feature = "abi-7-23"))]
     /// Unused3
     pub unused3: u32,
+    #[cfg(feature = "abi-7-23")]
+    /// Change time nsec
+    pub ctimensec: u32,
     /// Mode
     pub mode: u32,
     /// Unused4
@@ -990,8 +1039,19 @@ pub struct fuse_init_out {
     pub congestion_threshold: u16,
     /// Max write
     pub max_write: u32,
+    #[cfg(feature = "abi-7-23")]
+    /// Time granularity in nanoseconds
+    pub time_gran: u32,
+    #[cfg(feature = "abi-7-23")]
+    /// Unused
+    pub unused: [u32; 9],
 }
 
+#[cfg(feature = "abi-7-23")]
+/// The size of `fuse_init_out` before ABI 7.23, the kernels older than it
+/// reject the larger reply
+pub const FUSE_COMPAT_22_INIT_OUT_SIZE: usize = 24;
+
 #[cfg(feature = "abi-7-12")]
 #[repr(C)]
 #[derive(Debug)]
@@ -1146,15 +1206,15 @@ pub struct fuse_notify_poll_wakeup_out {
 /// Fuse fallocate in
 pub struct fuse_fallocate_in {
     /// File handler
-    fh: u64,
+    pub fh: u64,
     /// Offset
-    offset: u64,
+    pub offset: u64,
     /// Length
-    length: u64,
+    pub length: u64,
     /// Mode
-    mode: u32,
+    pub mode: u32,
     /// Padding
-    padding: u32,
+    pub padding: u32,
 }
 
 #[repr(C)]
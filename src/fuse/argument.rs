@@ -41,12 +41,14 @@ impl<'a> FuseArgumentIterator<'a> {
     }
 
     /// Fetch a typed argument. Returns `None` if there's not enough data left. This function is
-    /// unsafe because there is no guarantee that the data actually contains the type T.
+    /// unsafe because there is no guarantee that the data actually contains the type T, nor
+    /// that it is aligned for T.
     #[allow(unsafe_code)]
     pub unsafe fn fetch<T>(&mut self) -> Option<&'a T> {
         let len = mem::size_of::<T>();
         let bytes = self.fetch_bytes(len)?;
-        // TODO: this might have alignment issue and fix later.
+        // the data must be aligned for T, requests are received into a page-aligned
+        // buffer and the kernel pads every argument to 8 bytes
         let ptr: *const T = bytes.as_ptr().cast();
         ptr.as_ref()
     }
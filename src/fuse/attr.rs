@@ -0,0 +1,229 @@
+//! Conversion of the file attributes to the ABI
+//!
+//! `FileAttr` is the same on every target, while `fuse_attr` is not: macOS
+//! adds the creation time and the flags, and ABI 7.9 adds the block size along
+//! with its padding. The conversion lives here alone, and the layout is pinned
+//! per target and ABI by the tests below, so enabling an ABI feature cannot
+//! silently shift the attributes the kernel reads.
+//!
+//! The seconds of the times are unsigned in the ABI but signed in the kernel,
+//! so a time before the epoch, e.g. of a file from an archive or touched to
+//! 1969, travels as the two's complement of its seconds, the nanoseconds
+//! counting forward from them as in a `timespec`.
+
+use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::abi::fuse_attr;
+use super::{Cast, FileAttr, FileType, OverflowArithmetic};
+
+/// The largest nanoseconds of a time the kernel accepts
+const MAX_NSEC: u32 = 999_999_999;
+/// The nanoseconds of a second
+const NSEC_PER_SEC: u32 = 1_000_000_000;
+
+// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
+// But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
+#[allow(trivial_numeric_casts)]
+/// Returns the mode for a given file kind and permission
+pub fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
+    (match kind {
+        FileType::NamedPipe => S_IFIFO,
+        FileType::CharDevice => S_IFCHR,
+        FileType::BlockDevice => S_IFBLK,
+        FileType::Directory => S_IFDIR,
+        FileType::RegularFile => S_IFREG,
+        FileType::Symlink => S_IFLNK,
+        FileType::Socket => S_IFSOCK,
+    })
+    .cast::<u32>()
+        | perm.cast::<u32>()
+}
+
+/// The time of the signed seconds and the nanoseconds since the epoch, as in
+/// a `timespec`. The nanoseconds are clamped to within a second, and a time
+/// out of the range of `SystemTime` falls back to the epoch.
+pub fn time_from_secs(secs: i64, nsecs: i64) -> SystemTime {
+    let nsecs = Duration::from_nanos(nsecs.clamp(0, MAX_NSEC.into()).cast());
+    let time = if secs >= 0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs(secs.cast()))
+    } else {
+        UNIX_EPOCH.checked_sub(Duration::from_secs(secs.unsigned_abs()))
+    };
+    time.and_then(|time| time.checked_add(nsecs))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+/// The time of the seconds and nanoseconds of the ABI
+pub fn time_from_abi(secs: u64, nsecs: u32) -> SystemTime {
+    time_from_secs(i64::from_ne_bytes(secs.to_ne_bytes()), nsecs.into())
+}
+
+/// The signed seconds and the nanoseconds since the epoch of the time, as in
+/// a `timespec`, a time too far from the epoch for the seconds is clamped
+pub fn time_to_secs(time: &SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => (
+            i64::try_from(after.as_secs()).unwrap_or(i64::MAX),
+            after.subsec_nanos().min(MAX_NSEC),
+        ),
+        Err(e) => {
+            let before = e.duration();
+            let secs = 0_i64.saturating_sub(i64::try_from(before.as_secs()).unwrap_or(i64::MAX));
+            match before.subsec_nanos() {
+                0 => (secs, 0),
+                // e.g. 1.25s before is 2s before and 0.75s forward
+                nsecs => (secs.saturating_sub(1), NSEC_PER_SEC.overflow_sub(nsecs)),
+            }
+        }
+    }
+}
+
+/// The seconds and nanoseconds of the time in the ABI
+pub fn time_to_abi(time: &SystemTime) -> (u64, u32) {
+    let (secs, nsecs) = time_to_secs(time);
+    (u64::from_ne_bytes(secs.to_ne_bytes()), nsecs)
+}
+
+/// Returns a `fuse_attr` from `FileAttr`, the block size is left 0 for the
+/// kernel to use the block size of the mount
+pub fn to_fuse_attr(attr: &FileAttr) -> fuse_attr {
+    let (atime, atimensec) = time_to_abi(&attr.atime);
+    let (mtime, mtimensec) = time_to_abi(&attr.mtime);
+    let (ctime, ctimensec) = time_to_abi(&attr.ctime);
+    #[cfg(target_os = "macos")]
+    let (crtime, crtimensec) = time_to_abi(&attr.crtime);
+
+    fuse_attr {
+        ino: attr.ino,
+        size: attr.size,
+        blocks: attr.blocks,
+        atime,
+        mtime,
+        ctime,
+        #[cfg(target_os = "macos")]
+        crtime,
+        atimensec,
+        mtimensec,
+        ctimensec,
+        #[cfg(target_os = "macos")]
+        crtimensec,
+        mode: mode_from_kind_and_perm(attr.kind, attr.perm),
+        nlink: attr.nlink,
+        uid: attr.uid,
+        gid: attr.gid,
+        rdev: attr.rdev,
+        #[cfg(target_os = "macos")]
+        flags: attr.flags,
+        #[cfg(feature = "abi-7-9")]
+        blksize: 0,
+        #[cfg(feature = "abi-7-9")]
+        padding: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::super::abi::fuse_attr;
+    use super::super::{FileAttr, FileType};
+    use super::{time_from_abi, time_from_secs, time_to_abi, to_fuse_attr};
+
+    /// The size of `fuse_attr` the kernel expects
+    #[cfg(all(not(target_os = "macos"), not(feature = "abi-7-9")))]
+    const FUSE_ATTR_SIZE: usize = 80;
+    /// The size of `fuse_attr` the kernel expects
+    #[cfg(all(not(target_os = "macos"), feature = "abi-7-9"))]
+    const FUSE_ATTR_SIZE: usize = 88;
+    /// The size of `fuse_attr` the kernel expects
+    #[cfg(all(target_os = "macos", not(feature = "abi-7-9")))]
+    const FUSE_ATTR_SIZE: usize = 96;
+    /// The size of `fuse_attr` the kernel expects
+    #[cfg(all(target_os = "macos", feature = "abi-7-9"))]
+    const FUSE_ATTR_SIZE: usize = 104;
+
+    #[test]
+    fn test_fuse_attr_layout() {
+        assert_eq!(mem::size_of::<fuse_attr>(), FUSE_ATTR_SIZE);
+        assert_eq!(mem::align_of::<fuse_attr>(), 8);
+    }
+
+    #[test]
+    fn test_to_fuse_attr() {
+        let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
+        let attr = FileAttr {
+            ino: 0x11,
+            size: 0x22,
+            blocks: 0x33,
+            atime: time,
+            mtime: time + Duration::from_secs(1),
+            ctime: time + Duration::from_secs(2),
+            crtime: time + Duration::from_secs(3),
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 0x55,
+            uid: 0x66,
+            gid: 0x77,
+            rdev: 0x88,
+            flags: 0x99,
+        };
+        let out = to_fuse_attr(&attr);
+        assert_eq!((out.ino, out.size, out.blocks), (0x11, 0x22, 0x33));
+        assert_eq!((out.atime, out.atimensec), (0x1234, 0x5678));
+        assert_eq!((out.mtime, out.mtimensec), (0x1235, 0x5678));
+        assert_eq!((out.ctime, out.ctimensec), (0x1236, 0x5678));
+        assert_eq!(out.mode, u32::from(libc::S_IFREG) | 0o644);
+        assert_eq!(
+            (out.nlink, out.uid, out.gid, out.rdev),
+            (0x55, 0x66, 0x77, 0x88)
+        );
+        #[cfg(target_os = "macos")]
+        {
+            assert_eq!((out.crtime, out.crtimensec), (0x1237, 0x5678));
+            assert_eq!(out.flags, 0x99);
+        }
+        #[cfg(feature = "abi-7-9")]
+        assert_eq!((out.blksize, out.padding), (0, 0));
+    }
+
+    #[test]
+    fn test_time_to_abi() {
+        assert_eq!(time_to_abi(&UNIX_EPOCH), (0, 0));
+        let time = UNIX_EPOCH + Duration::new(1, 999_999_999);
+        assert_eq!(time_to_abi(&time), (1, 999_999_999));
+        // the seconds are signed in the kernel
+        let before = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(time_to_abi(&before), (u64::MAX, 0));
+        let before = UNIX_EPOCH - Duration::from_millis(1250);
+        assert_eq!(time_to_abi(&before), (u64::MAX - 1, 750_000_000));
+        assert_eq!(time_from_abi(u64::MAX - 1, 750_000_000), before);
+        // beyond 2038
+        let after = UNIX_EPOCH + Duration::from_secs(1 << 33);
+        assert_eq!(time_to_abi(&after), (1 << 33, 0));
+        assert_eq!(time_from_abi(1 << 33, 0), after);
+    }
+
+    #[test]
+    fn test_time_from_secs() {
+        assert_eq!(
+            time_from_secs(-1, 500_000_000),
+            UNIX_EPOCH - Duration::from_millis(500)
+        );
+        // a file touched to 1969
+        let time = time_from_secs(-86_400, 0);
+        assert_eq!(time_to_abi(&time), (u64::MAX - 86_399, 0));
+        // the nanoseconds are clamped to within a second
+        assert_eq!(
+            time_from_secs(1, 2_000_000_000),
+            UNIX_EPOCH + Duration::new(1, 999_999_999)
+        );
+        assert_eq!(time_from_secs(1, -1), UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(
+            time_from_abi(0, u32::MAX),
+            UNIX_EPOCH + Duration::new(0, 999_999_999)
+        );
+    }
+}
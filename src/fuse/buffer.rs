@@ -0,0 +1,159 @@
+//! Page-aligned buffer receiving requests from the kernel
+//!
+//! The kernel copies a request into the buffer starting at its first byte,
+//! a page-aligned buffer keeps the copy and the splice of the write data on
+//! whole pages. Every argument of a request is padded to 8 bytes by the kernel,
+//! so with the buffer aligned the typed arguments fetched in place are aligned
+//! as well. The buffer may also be backed by transparent huge pages, which
+//! saves TLB misses on copying large writes.
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use log::debug;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use nix::sys::mman::{self, MmapAdvise};
+use nix::unistd::{self, SysconfVar};
+use std::alloc::{self, Layout};
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{Cast, OverflowArithmetic};
+
+/// Size of a huge page, 2M on x86-64 and aarch64 with 4k pages
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+/// Page size if the system does not tell
+const DEFAULT_PAGE_SIZE: usize = 4096;
+
+/// Buffer receiving requests, aligned to a page or a huge page
+#[derive(Debug)]
+pub struct RequestBuffer {
+    /// Start of the allocation
+    ptr: NonNull<u8>,
+    /// Layout of the allocation
+    layout: Layout,
+    /// Byte length of the request received
+    len: usize,
+}
+
+// the buffer owns its allocation the same as a Vec<u8>
+#[allow(unsafe_code)]
+unsafe impl Send for RequestBuffer {}
+
+impl RequestBuffer {
+    /// Allocate a zeroed buffer of capacity, aligned to a huge page and advised
+    /// to be backed by transparent huge pages on Linux if `huge_pages`, otherwise
+    /// to a page
+    pub fn new(capacity: usize, huge_pages: bool) -> Self {
+        let align = if huge_pages {
+            HUGE_PAGE_SIZE
+        } else {
+            page_size()
+        };
+        // round up to the alignment, so the whole buffer is made of full pages
+        let size = capacity.overflow_add(align.overflow_sub(1)) & !align.overflow_sub(1);
+        let layout = Layout::from_size_align(size, align).unwrap_or_else(|e| {
+            panic!(
+                "RequestBuffer::new() got an invalid layout of size={} and align={}, the error is: {}",
+                size, align, e
+            )
+        });
+        #[allow(unsafe_code)]
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if huge_pages {
+            // the buffer still works on normal pages if the kernel refuses
+            #[allow(unsafe_code)]
+            let res =
+                unsafe { mman::madvise(ptr.as_ptr().cast(), size, MmapAdvise::MADV_HUGEPAGE) };
+            if let Err(e) = res {
+                debug!(
+                    "RequestBuffer::new() failed to advise huge pages, the error is: {:?}",
+                    e
+                );
+            }
+        }
+        Self {
+            ptr,
+            layout,
+            len: 0,
+        }
+    }
+
+    /// Capacity of the buffer, which is at least the capacity asked for
+    pub const fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// The whole buffer to receive a request into
+    pub fn spare_mut(&mut self) -> &mut [u8] {
+        // safe because the allocation is initialized and owned by self
+        #[allow(unsafe_code)]
+        unsafe {
+            slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size())
+        }
+    }
+
+    /// Set the byte length of the request received
+    pub fn set_len(&mut self, len: usize) {
+        assert!(
+            len <= self.capacity(),
+            "RequestBuffer::set_len() got length {} beyond capacity {}",
+            len,
+            self.capacity()
+        );
+        self.len = len;
+    }
+
+    /// The request received
+    pub fn as_slice(&self) -> &[u8] {
+        // safe because the allocation is initialized and len is within it
+        #[allow(unsafe_code)]
+        unsafe {
+            slice::from_raw_parts(self.ptr.as_ptr(), self.len)
+        }
+    }
+}
+
+impl Drop for RequestBuffer {
+    fn drop(&mut self) {
+        #[allow(unsafe_code)]
+        unsafe {
+            alloc::dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
+
+/// The page size of the system
+fn page_size() -> usize {
+    match unistd::sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) if size > 0 => size.cast(),
+        Ok(_) | Err(_) => DEFAULT_PAGE_SIZE,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{page_size, RequestBuffer, HUGE_PAGE_SIZE};
+    use crate::fuse::OverflowArithmetic;
+
+    #[test]
+    fn test_request_buffer() {
+        let mut buffer = RequestBuffer::new(10000, false);
+        assert_eq!(buffer.as_slice(), b"");
+        assert_eq!(buffer.capacity() & page_size().overflow_sub(1), 0);
+        assert!(buffer.capacity() >= 10000);
+        assert_eq!(buffer.spare_mut().as_ptr().align_offset(page_size()), 0);
+        buffer
+            .spare_mut()
+            .get_mut(..5)
+            .unwrap_or_else(|| panic!())
+            .copy_from_slice(b"hello");
+        buffer.set_len(5);
+        assert_eq!(buffer.as_slice(), b"hello");
+
+        let mut buffer = RequestBuffer::new(HUGE_PAGE_SIZE.overflow_add(1), true);
+        assert_eq!(buffer.capacity(), HUGE_PAGE_SIZE.overflow_mul(2));
+        assert_eq!(buffer.spare_mut().as_ptr().align_offset(HUGE_PAGE_SIZE), 0);
+        assert!(buffer.spare_mut().iter().all(|b| *b == 0));
+    }
+}
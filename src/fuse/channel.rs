@@ -4,17 +4,27 @@
 
 // use libc::{c_void, size_t};
 use log::{debug, error};
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType};
 use nix::sys::uio::{self, IoVec};
 use nix::unistd;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
 use std::ffi::{CString, OsStr};
 use std::io;
 use std::os::raw::{c_char, c_int};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use super::buffer::RequestBuffer;
 use super::mount;
-use super::reply::ReplySender;
-use super::Cast;
+pub use super::mount::UnmountFlags;
+use super::reply::{HeldSender, ReplySender, MAX_REPLY_SEGMENTS};
+use super::{Cast, OverflowArithmetic};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -50,10 +60,14 @@ fn with_fuse_args<T, F: FnOnce(&fuse_args) -> T>(options: &[&OsStr], f: F) -> T
 #[derive(Debug)]
 /// Channel
 pub struct Channel {
-    /// Mount point
-    mountpoint: PathBuf,
+    /// Mount point, `None` if the channel was not mounted by itself
+    mountpoint: Option<PathBuf>,
     /// Fd
     fd: c_int,
+    /// The counts of the replies sent
+    stats: Arc<ReplyStats>,
+    /// True once the fd is closed
+    closed: bool,
 }
 
 impl Channel {
@@ -69,45 +83,84 @@ impl Channel {
             Err(io::Error::last_os_error())
         } else {
             Ok(Self {
-                mountpoint: mountpoint.into(),
+                mountpoint: Some(mountpoint.into()),
                 fd,
+                stats: Arc::default(),
+                closed: false,
             })
         }
     }
 
-    /// Return path of the mounted filesystem
-    pub fn mountpoint(&self) -> &Path {
-        self.mountpoint.as_ref()
+    /// Create a communication channel from an open `/dev/fuse` fd of a mount
+    /// performed by the caller, e.g. a container runtime or a mount helper. The
+    /// channel takes over the fd and closes it when dropped, but never unmounts.
+    ///
+    /// # Safety
+    ///
+    /// The fd must be open and owned by no one else, it is closed along with the
+    /// channel.
+    #[allow(unsafe_code)]
+    pub unsafe fn from_fd(fd: RawFd) -> Self {
+        Self {
+            mountpoint: None,
+            fd,
+            stats: Arc::default(),
+            closed: false,
+        }
     }
 
-    /// Receives data up to the capacity of the given buffer (can block).
-    pub fn receive(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
-        // let rc = unsafe {
-        //     libc::read(
-        //         self.fd,
-        //         buffer.as_ptr() as *mut c_void,
-        //         buffer.capacity() as size_t,
-        //     )
-        // };
-        // if rc < 0 {
-        //     Err(io::Error::last_os_error())
-        // } else {
-        //     unsafe {
-        //         buffer.set_len(rc as usize);
-        //     }
-        //     Ok(())
-        // }
+    /// Create a mock channel mounting nothing, the requests are the packets a
+    /// test harness writes to the returned fd and the replies are read from it.
+    /// The channel ends once the harness shuts down its end.
+    pub fn mock() -> io::Result<(Self, RawFd)> {
+        // the kernel driver keeps the boundaries of the requests and replies
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let sock_type = SockType::SeqPacket;
+        // the unix sockets of macOS have no seqpacket type
+        #[cfg(target_os = "macos")]
+        let sock_type = SockType::Datagram;
+        let (fd, harness_fd) =
+            socket::socketpair(AddressFamily::Unix, sock_type, None, SockFlag::empty())
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // the fd is just created, so owned by the channel only
         #[allow(unsafe_code)]
-        unsafe {
-            buffer.set_len(buffer.capacity());
+        let ch = unsafe { Self::from_fd(fd) };
+        Ok((ch, harness_fd))
+    }
+
+    /// Close the fd and unmount the mount point of the channel, the caller
+    /// unmounts a channel from fd. Only once, nothing to do if closed before.
+    pub fn close(&mut self) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on macOS before closing the fd
+        // Close the communication channel to the kernel driver
+        // (closing it before unnmount prevents sync unmount deadlock)
+        if let Err(e) = unistd::close(self.fd) {
+            error!(
+                "failed to close the channel fd={}, the error is: {}",
+                self.fd, e
+            );
         }
-        let res = unistd::read(self.fd, &mut *buffer);
+        match self.mountpoint {
+            Some(ref mountpoint) => unmount(mountpoint),
+            None => Ok(()),
+        }
+    }
+
+    /// Return path of the mounted filesystem, `None` if not mounted by the channel
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.mountpoint.as_deref()
+    }
+
+    /// Receives data up to the capacity of the given buffer (can block).
+    pub fn receive(&self, buffer: &mut RequestBuffer) -> io::Result<()> {
+        let res = unistd::read(self.fd, buffer.spare_mut());
         match res {
             Ok(s) => {
-                #[allow(unsafe_code)]
-                unsafe {
-                    buffer.set_len(s);
-                }
+                buffer.set_len(s);
                 debug!("receive successfully {} byte data", s);
                 Ok(())
             }
@@ -118,6 +171,16 @@ impl Channel {
         }
     }
 
+    /// Wait at most `timeout` for data to receive, `false` if none arrived
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fds = [PollFd::new(self.fd, PollFlags::POLLIN)];
+        let timeout = timeout.as_millis().try_into().unwrap_or(c_int::MAX);
+        match poll::poll(&mut fds, timeout) {
+            Ok(ready) => Ok(ready > 0),
+            Err(_) => Err(io::Error::last_os_error()),
+        }
+    }
+
     /// Returns a sender object for this channel. The sender object can be
     /// used to send to the channel. Multiple sender objects can be used
     /// and they can safely be sent to other threads.
@@ -128,17 +191,26 @@ impl Channel {
         // dropping the channel, it'll return an EBADF error.
         FuseChannelSender { fd: self.fd }
     }
+
+    /// Returns a sender of the replies to the requests received, which counts
+    /// them in the statistics of the channel
+    pub fn reply_sender(&self) -> FuseReplySender {
+        FuseReplySender {
+            ch: self.sender(),
+            stats: Arc::clone(&self.stats),
+        }
+    }
+
+    /// The counts of the replies sent through the channel
+    pub fn reply_stats(&self) -> &ReplyStats {
+        &self.stats
+    }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
-        // TODO: send ioctl FUSEDEVIOCSETDAEMONDEAD on macOS before closing the fd
-        // Close the communication channel to the kernel driver
-        // (closing it before unnmount prevents sync unmount deadlock)
-        // unsafe { libc::close(self.fd); }
-        unistd::close(self.fd).unwrap_or_else(|_| panic!());
-        // Unmount this channel's mount point
-        unmount(self.mountpoint.as_ref()).unwrap_or_else(|_| ());
+        // the mount point may be unmounted already, e.g. by `fusermount -u`
+        self.close().unwrap_or_else(|_| ());
     }
 }
 
@@ -152,32 +224,111 @@ pub struct FuseChannelSender {
 impl FuseChannelSender {
     /// Send all data in the slice of slice of bytes in a single write (can block).
     pub fn send(self, buffer: &[&[u8]]) -> io::Result<()> {
-        let iovecs: Vec<_> = buffer.iter().map(|d| IoVec::from_slice(d)).collect();
-        let res = uio::writev(self.fd, &iovecs);
+        let res = if buffer.len() <= MAX_REPLY_SEGMENTS {
+            let mut iovecs = [IoVec::from_slice(&[]); MAX_REPLY_SEGMENTS];
+            for (iovec, data) in iovecs.iter_mut().zip(buffer) {
+                *iovec = IoVec::from_slice(data);
+            }
+            uio::writev(self.fd, iovecs.get(..buffer.len()).unwrap_or_default())
+        } else {
+            let iovecs: Vec<_> = buffer.iter().map(|d| IoVec::from_slice(d)).collect();
+            uio::writev(self.fd, &iovecs)
+        };
         match res {
             Ok(s) => {
                 debug!("send successfully {} byte data", s);
                 Ok(())
             }
             Err(e) => {
-                error!("send failed, the error is: {:?}", e);
+                // the reply sender logs it as an error, failed notifications are expected
+                debug!("send failed, the error is: {:?}", e);
                 Err(io::Error::last_os_error())
             }
         }
     }
 }
 
-impl ReplySender for FuseChannelSender {
+/// The counts of the replies sent through a channel
+#[derive(Debug, Default)]
+pub struct ReplyStats {
+    /// The bytes of the replies sent
+    bytes_sent: AtomicU64,
+    /// The number of the error replies by errno
+    errors: Mutex<BTreeMap<i32, u64>>,
+}
+
+impl ReplyStats {
+    /// Count a reply sent, whose header is the first segment
+    fn record(&self, data: &[&[u8]]) {
+        let size = data
+            .iter()
+            .fold(0, |size, segment| size.overflow_add(segment.len()));
+        self.bytes_sent.fetch_add(size.cast(), Ordering::Relaxed);
+        let error = data
+            .first()
+            .and_then(|header| header.get(4..8))
+            .and_then(|error| error.try_into().ok())
+            .map_or(0, i32::from_ne_bytes);
+        if error < 0 {
+            let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+            let count = errors.entry(error.overflow_mul(-1)).or_insert(0);
+            *count = count.overflow_add(1);
+        }
+    }
+
+    /// The bytes of the replies sent
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// The number of the error replies by errno
+    pub fn errors(&self) -> BTreeMap<i32, u64> {
+        self.errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// Fuse reply sender, counting the replies in the statistics of the channel
+#[derive(Clone, Debug)]
+pub struct FuseReplySender {
+    /// Channel sender
+    ch: FuseChannelSender,
+    /// The counts of the replies sent
+    stats: Arc<ReplyStats>,
+}
+
+impl FuseReplySender {
+    /// The sender of the channel, e.g. for notifications
+    pub const fn channel_sender(&self) -> FuseChannelSender {
+        self.ch
+    }
+}
+
+impl ReplySender for FuseReplySender {
     fn send(&self, data: &[&[u8]]) {
-        if let Err(err) = Self::send(*self, data) {
-            error!("Failed to send FUSE reply: {}", err);
+        match self.ch.send(data) {
+            Ok(()) => self.stats.record(data),
+            Err(err) => error!("Failed to send FUSE reply: {}", err),
         }
     }
+
+    fn into_held(self) -> HeldSender {
+        HeldSender::Channel(self)
+    }
 }
 
-/// Unmount an arbitrary mount point
+/// Unmount an arbitrary mount point by force
 pub fn unmount(mountpoint: &Path) -> io::Result<()> {
-    let res = mount::umount(mountpoint);
+    unmount_options(mountpoint, UnmountFlags::FORCE)
+}
+
+/// Unmount an arbitrary mount point with the given flags, no flag for a clean
+/// unmount, `UnmountFlags::LAZY` to detach it while busy, `UnmountFlags::FORCE`
+/// to abort pending requests
+pub fn unmount_options(mountpoint: &Path, flags: UnmountFlags) -> io::Result<()> {
+    let res = mount::umount(mountpoint, flags);
     if res == 0 {
         Ok(())
     } else {
@@ -187,8 +338,10 @@ pub fn unmount(mountpoint: &Path) -> io::Result<()> {
 
 #[cfg(test)]
 mod test {
-    use super::with_fuse_args;
+    use super::{unmount_options, with_fuse_args, UnmountFlags};
     use std::ffi::{CStr, OsStr};
+    use std::fs;
+    use std::path::Path;
 
     #[test]
     fn fuse_args() {
@@ -209,4 +362,29 @@ mod test {
             );
         });
     }
+
+    #[test]
+    fn unmount_flags() {
+        let flags = UnmountFlags::LAZY | UnmountFlags::FORCE;
+        assert!(flags.contains(UnmountFlags::LAZY));
+        assert!(flags.contains(UnmountFlags::FORCE));
+        assert!(!UnmountFlags::LAZY.contains(UnmountFlags::FORCE));
+        assert!(UnmountFlags::FORCE.contains(UnmountFlags::empty()));
+        assert_eq!(UnmountFlags::default(), UnmountFlags::empty());
+    }
+
+    #[test]
+    fn unmount_not_mounted() {
+        let path = Path::new("/tmp/fuse_test_unmount_not_mounted");
+        fs::create_dir_all(path).unwrap_or_else(|_| panic!());
+        for flags in &[
+            UnmountFlags::empty(),
+            UnmountFlags::LAZY,
+            UnmountFlags::FORCE,
+            UnmountFlags::LAZY | UnmountFlags::FORCE,
+        ] {
+            assert!(unmount_options(path, *flags).is_err());
+        }
+        fs::remove_dir(path).unwrap_or_else(|_| panic!());
+    }
 }
@@ -1,4 +1,7 @@
+use nix::errno::Errno;
 use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
 
 /// A type cast trait used to replace as conversion.
 pub trait Cast {
@@ -6,7 +9,7 @@ pub trait Cast {
     fn cast<T>(self) -> T
     where
         T: TryFrom<Self>,
-        Self: Sized + std::fmt::Display + Copy,
+        Self: Sized + fmt::Display + Copy,
     {
         T::try_from(self).unwrap_or_else(|_| {
             panic!(
@@ -21,6 +24,73 @@ pub trait Cast {
 
 impl<U> Cast for U {}
 
+/// Error of a checked numeric conversion or arithmetic
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NumericError {
+    /// The value does not fit in the target type
+    OutOfRange {
+        /// The value
+        value: String,
+        /// Source type name
+        from: &'static str,
+        /// Target type name
+        to: &'static str,
+    },
+    /// The arithmetic overflows
+    Overflow {
+        /// Operation name
+        op: &'static str,
+        /// Left operand
+        lhs: String,
+        /// Right operand
+        rhs: String,
+        /// Type name of the operands
+        ty: &'static str,
+    },
+}
+
+impl fmt::Display for NumericError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfRange { value, from, to } => {
+                write!(f, "{}: {} is out of the range of {}", from, value, to)
+            }
+            Self::Overflow { op, lhs, rhs, ty } => {
+                write!(f, "{}: {} {} {} overflows", ty, lhs, op, rhs)
+            }
+        }
+    }
+}
+
+impl Error for NumericError {}
+
+/// A value out of range is an invalid argument if from the kernel or users,
+/// callers reply a more specific error, e.g. `EFBIG`, when there is one
+impl From<NumericError> for nix::Error {
+    fn from(_: NumericError) -> Self {
+        Self::Sys(Errno::EINVAL)
+    }
+}
+
+/// A checked type cast trait, for values from the kernel or users, which may
+/// be out of range, where `Cast` would panic
+pub trait TryCast {
+    /// Performs the conversion, fails if the value does not fit in `T`
+    fn try_cast<T>(self) -> Result<T, NumericError>
+    where
+        T: TryFrom<Self>,
+        Self: Sized + fmt::Display + Copy,
+    {
+        T::try_from(self).map_err(|_| NumericError::OutOfRange {
+            value: self.to_string(),
+            from: std::any::type_name::<Self>(),
+            to: std::any::type_name::<T>(),
+        })
+    }
+}
+
+impl<U> TryCast for U {}
+
 /// Cast to pointer
 pub const fn cast_to_ptr<T: ?Sized, U>(val: &T) -> *const U {
     let ptr: *const _ = val;
@@ -0,0 +1,169 @@
+//! Filesystem errors
+//!
+//! The errors a filesystem replies with. The common classes of errors have
+//! their own variant so the handlers read clearly, every other errno is kept
+//! as is. The translation to the errno sent to the kernel happens in one place,
+//! when a reply is sent with an error.
+
+use libc::{
+    EACCES, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOSPC, ENOSYS, ENOTDIR, ENOTEMPTY, EPERM, EROFS,
+};
+use std::fmt;
+use std::io;
+use std::os::raw::c_int;
+
+/// The error of a filesystem operation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FsError {
+    /// No such file or directory (ENOENT)
+    NotFound,
+    /// The file exists (EEXIST)
+    AlreadyExists,
+    /// The caller lacks the permission bits (EACCES)
+    PermissionDenied,
+    /// The caller is not allowed to do the operation (EPERM)
+    NotPermitted,
+    /// Not a directory (ENOTDIR)
+    NotDirectory,
+    /// Is a directory (EISDIR)
+    IsDirectory,
+    /// The directory is not empty (ENOTEMPTY)
+    NotEmpty,
+    /// An invalid argument (EINVAL)
+    InvalidArgument,
+    /// No space left (ENOSPC)
+    NoSpace,
+    /// The filesystem is read-only (EROFS)
+    ReadOnly,
+    /// The operation is not implemented (ENOSYS)
+    Unsupported,
+    /// An I/O error (EIO)
+    Io,
+    /// Any other errno
+    Errno(c_int),
+}
+
+impl FsError {
+    /// The errno to reply to the kernel
+    #[must_use]
+    pub const fn errno(self) -> c_int {
+        match self {
+            Self::NotFound => ENOENT,
+            Self::AlreadyExists => EEXIST,
+            Self::PermissionDenied => EACCES,
+            Self::NotPermitted => EPERM,
+            Self::NotDirectory => ENOTDIR,
+            Self::IsDirectory => EISDIR,
+            Self::NotEmpty => ENOTEMPTY,
+            Self::InvalidArgument => EINVAL,
+            Self::NoSpace => ENOSPC,
+            Self::ReadOnly => EROFS,
+            Self::Unsupported => ENOSYS,
+            Self::Io => EIO,
+            Self::Errno(errno) => errno,
+        }
+    }
+
+    /// The name of the class of the error, e.g. to tag the logs with
+    #[must_use]
+    pub const fn class(self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::AlreadyExists => "already_exists",
+            Self::PermissionDenied => "permission_denied",
+            Self::NotPermitted => "not_permitted",
+            Self::NotDirectory => "not_directory",
+            Self::IsDirectory => "is_directory",
+            Self::NotEmpty => "not_empty",
+            Self::InvalidArgument => "invalid_argument",
+            Self::NoSpace => "no_space",
+            Self::ReadOnly => "read_only",
+            Self::Unsupported => "unsupported",
+            Self::Io => "io",
+            Self::Errno(_) => "errno",
+        }
+    }
+}
+
+impl From<c_int> for FsError {
+    fn from(errno: c_int) -> Self {
+        match errno {
+            ENOENT => Self::NotFound,
+            EEXIST => Self::AlreadyExists,
+            EACCES => Self::PermissionDenied,
+            EPERM => Self::NotPermitted,
+            ENOTDIR => Self::NotDirectory,
+            EISDIR => Self::IsDirectory,
+            ENOTEMPTY => Self::NotEmpty,
+            EINVAL => Self::InvalidArgument,
+            ENOSPC => Self::NoSpace,
+            EROFS => Self::ReadOnly,
+            ENOSYS => Self::Unsupported,
+            EIO => Self::Io,
+            _ => Self::Errno(errno),
+        }
+    }
+}
+
+impl From<nix::Error> for FsError {
+    /// `EIO` if the error does not come from a syscall
+    fn from(err: nix::Error) -> Self {
+        #[allow(clippy::as_conversions)] // Errno is a fieldless enum of the errno values
+        err.as_errno()
+            .map_or(Self::Io, |errno| Self::from(errno as c_int))
+    }
+}
+
+impl From<io::Error> for FsError {
+    /// `EIO` if the error does not come from a syscall
+    fn from(err: io::Error) -> Self {
+        err.raw_os_error().map_or(Self::Io, Self::from)
+    }
+}
+
+impl From<FsError> for c_int {
+    fn from(err: FsError) -> Self {
+        err.errno()
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, errno {}", self.class(), self.errno())
+    }
+}
+
+impl std::error::Error for FsError {}
+
+#[cfg(test)]
+mod test {
+    use super::FsError;
+    use nix::errno::Errno;
+    use std::io;
+
+    #[test]
+    fn test_errno_round_trip() {
+        for errno in &[libc::ENOENT, libc::EACCES, libc::EIO, libc::EXDEV] {
+            assert_eq!(FsError::from(*errno).errno(), *errno);
+        }
+        assert_eq!(FsError::from(libc::ENOTEMPTY), FsError::NotEmpty);
+        assert_eq!(FsError::from(libc::EXDEV), FsError::Errno(libc::EXDEV));
+    }
+
+    #[test]
+    fn test_from_errors() {
+        assert_eq!(
+            FsError::from(nix::Error::Sys(Errno::ENOSPC)),
+            FsError::NoSpace
+        );
+        assert_eq!(FsError::from(nix::Error::InvalidPath), FsError::Io);
+        assert_eq!(
+            FsError::from(io::Error::from_raw_os_error(libc::EROFS)),
+            FsError::ReadOnly
+        );
+        assert_eq!(
+            FsError::from(io::Error::new(io::ErrorKind::Other, "no errno")),
+            FsError::Io
+        );
+    }
+}
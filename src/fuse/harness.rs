@@ -0,0 +1,59 @@
+//! The kernel side of a mock session in the tests, it builds the FUSE requests
+//! written to the harness fd of `Session::mock` and reads the replies
+
+use super::{Cast, OverflowArithmetic};
+use nix::unistd;
+use std::convert::TryInto;
+use std::os::unix::io::RawFd;
+
+/// Build a request of the opcode on the inode of nodeid
+pub(crate) fn request(opcode: u32, unique: u64, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+    request_of(0, opcode, unique, nodeid, arg)
+}
+
+/// Build a request of the opcode on the inode of nodeid by the user of uid
+pub(crate) fn request_of(uid: u32, opcode: u32, unique: u64, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+    let len: u32 = arg.len().overflow_add(40).cast();
+    let mut data = Vec::new();
+    data.extend_from_slice(&len.to_ne_bytes());
+    data.extend_from_slice(&opcode.to_ne_bytes());
+    data.extend_from_slice(&unique.to_ne_bytes());
+    data.extend_from_slice(&nodeid.to_ne_bytes());
+    data.extend_from_slice(&uid.to_ne_bytes());
+    data.extend_from_slice(&[0; 12]); // gid, pid, padding
+    data.extend_from_slice(arg);
+    data
+}
+
+/// Build the init request of the unique id 1 offering the minor version of
+/// ABI 7 and the flags
+pub(crate) fn init_request(minor: u32, flags: u32) -> Vec<u8> {
+    let mut init_arg = Vec::new();
+    for field in &[7_u32, minor, 4096, flags] {
+        init_arg.extend_from_slice(&field.to_ne_bytes()); // major, minor, readahead, flags
+    }
+    request(26, 1, 0, &init_arg)
+}
+
+/// Send the request to the session and read its reply
+pub(crate) fn exchange(harness_fd: RawFd, req: &[u8]) -> Vec<u8> {
+    unistd::write(harness_fd, req).unwrap_or_else(|_| panic!());
+    let mut buf = vec![0_u8; 4096];
+    let size = unistd::read(harness_fd, &mut buf).unwrap_or_else(|_| panic!());
+    buf.truncate(size);
+    buf
+}
+
+/// The error of the reply
+pub(crate) fn error_of(reply: &[u8]) -> i32 {
+    let error = reply.get(4..8).unwrap_or_else(|| panic!());
+    i32::from_ne_bytes(error.try_into().unwrap_or_else(|_| panic!()))
+}
+
+/// The u64 of the reply at offset
+pub(crate) fn u64_at(reply: &[u8], offset: usize) -> u64 {
+    let field = reply
+        .get(offset..offset.overflow_add(8))
+        .unwrap_or_else(|| panic!());
+    u64::from_ne_bytes(field.try_into().unwrap_or_else(|_| panic!()))
+}
@@ -9,6 +9,14 @@ use std::{error, fmt, mem};
 
 #[cfg(target_os = "macos")]
 use super::abi::fuse_exchange_in;
+#[cfg(feature = "abi-7-19")]
+use super::abi::fuse_fallocate_in;
+#[cfg(feature = "abi-7-9")]
+use super::abi::fuse_getattr_in;
+#[cfg(feature = "abi-7-11")]
+use super::abi::fuse_ioctl_in;
+#[cfg(feature = "abi-7-23")]
+use super::abi::fuse_rename2_in;
 use super::abi::{
     fuse_access_in, fuse_bmap_in, fuse_create_in, fuse_flush_in, fuse_forget_in, fuse_fsync_in,
     fuse_getxattr_in, fuse_in_header, fuse_init_in, fuse_interrupt_in, fuse_link_in, fuse_lk_in,
@@ -63,7 +71,12 @@ pub enum Operation<'a> {
     Forget {
         arg: &'a fuse_forget_in,
     },
+    #[cfg(not(feature = "abi-7-9"))]
     GetAttr,
+    #[cfg(feature = "abi-7-9")]
+    GetAttr {
+        arg: &'a fuse_getattr_in,
+    },
     SetAttr {
         arg: &'a fuse_setattr_in,
     },
@@ -168,11 +181,11 @@ pub enum Operation<'a> {
         arg: &'a fuse_bmap_in,
     },
     Destroy,
-    // TODO: FUSE_IOCTL since ABI 7.11
-    // IoCtl {
-    //     arg: &'a fuse_ioctl_in,
-    //     data: &'a [u8],
-    // },
+    #[cfg(feature = "abi-7-11")]
+    IoCtl {
+        arg: &'a fuse_ioctl_in,
+        data: &'a [u8],
+    },
     // TODO: FUSE_POLL since ABI 7.11
     // Poll {
     //     arg: &'a fuse_poll_in,
@@ -186,10 +199,16 @@ pub enum Operation<'a> {
     //     arg: &'a fuse_forget_in,
     //     nodes: &'a [fuse_forget_one],
     // },
-    // TODO: FUSE_FALLOCATE since ABI 7.19
-    // FAllocate {
-    //     arg: &'a fuse_fallocate_in,
-    // },
+    #[cfg(feature = "abi-7-19")]
+    FAllocate {
+        arg: &'a fuse_fallocate_in,
+    },
+    #[cfg(feature = "abi-7-23")]
+    Rename2 {
+        arg: &'a fuse_rename2_in,
+        name: &'a OsStr,
+        newname: &'a OsStr,
+    },
     #[cfg(target_os = "macos")]
     SetVolName {
         name: &'a OsStr,
@@ -215,7 +234,10 @@ impl fmt::Display for Operation<'_> {
         match self {
             Operation::Lookup { name } => write!(f, "LOOKUP name {:?}", name),
             Operation::Forget { arg } => write!(f, "FORGET nlookup {}", arg.nlookup),
+            #[cfg(not(feature = "abi-7-9"))]
             Operation::GetAttr => write!(f, "GETATTR"),
+            #[cfg(feature = "abi-7-9")]
+            Operation::GetAttr { arg } => write!(f, "GETATTR flags {:#x}, fh {}", arg.getattr_flags, arg.fh),
             Operation::SetAttr { arg } => write!(f, "SETATTR valid {:#x}", arg.valid),
             Operation::ReadLink => write!(f, "READLINK"),
             Operation::SymLink { name, link } => write!(f, "SYMLINK name {:?}, link {:?}", name, link),
@@ -249,6 +271,12 @@ impl fmt::Display for Operation<'_> {
             Operation::Interrupt { arg } => write!(f, "INTERRUPT unique {}", arg.unique),
             Operation::BMap { arg } => write!(f, "BMAP blocksize {}, ids {}", arg.blocksize, arg.block),
             Operation::Destroy => write!(f, "DESTROY"),
+            #[cfg(feature = "abi-7-11")]
+            Operation::IoCtl { arg, data } => write!(f, "IOCTL fh {}, flags {:#x}, cmd {:#x}, arg {:#x}, in size {}, out size {}, data size {}", arg.fh, arg.flags, arg.cmd, arg.arg, arg.in_size, arg.out_size, data.len()),
+            #[cfg(feature = "abi-7-19")]
+            Operation::FAllocate { arg } => write!(f, "FALLOCATE fh {}, offset {}, length {}, mode {:#x}", arg.fh, arg.offset, arg.length, arg.mode),
+            #[cfg(feature = "abi-7-23")]
+            Operation::Rename2 { arg, name, newname } => write!(f, "RENAME2 name {:?}, newdir {:#018x}, newname {:?}, flags {:#x}", name, arg.newdir, newname, arg.flags),
 
             #[cfg(target_os = "macos")]
             Operation::SetVolName { name } => write!(f, "SETVOLNAME name {:?}", name),
@@ -262,6 +290,65 @@ impl fmt::Display for Operation<'_> {
 }
 
 impl<'a> Operation<'a> {
+    /// The name of the operation, the name of its opcode without the prefix
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Operation::Lookup { .. } => "LOOKUP",
+            Operation::Forget { .. } => "FORGET",
+            #[cfg(not(feature = "abi-7-9"))]
+            Operation::GetAttr => "GETATTR",
+            #[cfg(feature = "abi-7-9")]
+            Operation::GetAttr { .. } => "GETATTR",
+            Operation::SetAttr { .. } => "SETATTR",
+            Operation::ReadLink => "READLINK",
+            Operation::SymLink { .. } => "SYMLINK",
+            Operation::MkNod { .. } => "MKNOD",
+            Operation::MkDir { .. } => "MKDIR",
+            Operation::Unlink { .. } => "UNLINK",
+            Operation::RmDir { .. } => "RMDIR",
+            Operation::Rename { .. } => "RENAME",
+            Operation::Link { .. } => "LINK",
+            Operation::Open { .. } => "OPEN",
+            Operation::Read { .. } => "READ",
+            Operation::Write { .. } => "WRITE",
+            Operation::StatFs => "STATFS",
+            Operation::Release { .. } => "RELEASE",
+            Operation::FSync { .. } => "FSYNC",
+            Operation::SetXAttr { .. } => "SETXATTR",
+            Operation::GetXAttr { .. } => "GETXATTR",
+            Operation::ListXAttr { .. } => "LISTXATTR",
+            Operation::RemoveXAttr { .. } => "REMOVEXATTR",
+            Operation::Flush { .. } => "FLUSH",
+            Operation::Init { .. } => "INIT",
+            Operation::OpenDir { .. } => "OPENDIR",
+            Operation::ReadDir { .. } => "READDIR",
+            Operation::ReleaseDir { .. } => "RELEASEDIR",
+            Operation::FSyncDir { .. } => "FSYNCDIR",
+            Operation::GetLk { .. } => "GETLK",
+            Operation::SetLk { .. } => "SETLK",
+            Operation::SetLkW { .. } => "SETLKW",
+            Operation::Access { .. } => "ACCESS",
+            Operation::Create { .. } => "CREATE",
+            Operation::Interrupt { .. } => "INTERRUPT",
+            Operation::BMap { .. } => "BMAP",
+            Operation::Destroy => "DESTROY",
+            #[cfg(feature = "abi-7-11")]
+            Operation::IoCtl { .. } => "IOCTL",
+            #[cfg(feature = "abi-7-19")]
+            Operation::FAllocate { .. } => "FALLOCATE",
+            #[cfg(feature = "abi-7-23")]
+            Operation::Rename2 { .. } => "RENAME2",
+            #[cfg(target_os = "macos")]
+            Operation::SetVolName { .. } => "SETVOLNAME",
+            #[cfg(target_os = "macos")]
+            Operation::GetXTimes => "GETXTIMES",
+            #[cfg(target_os = "macos")]
+            Operation::Exchange { .. } => "EXCHANGE",
+            Operation::NoImplementation => "No implementation",
+        }
+    }
+
     #[allow(clippy::too_many_lines)]
     /// Parse
     fn parse(opcode: &fuse_opcode, data: &mut FuseArgumentIterator<'a>) -> Option<Self> {
@@ -272,7 +359,10 @@ impl<'a> Operation<'a> {
                     name: data.fetch_str()?,
                 },
                 fuse_opcode::FUSE_FORGET => Operation::Forget { arg: data.fetch()? },
+                #[cfg(not(feature = "abi-7-9"))]
                 fuse_opcode::FUSE_GETATTR => Operation::GetAttr,
+                #[cfg(feature = "abi-7-9")]
+                fuse_opcode::FUSE_GETATTR => Operation::GetAttr { arg: data.fetch()? },
                 fuse_opcode::FUSE_SETATTR => Operation::SetAttr { arg: data.fetch()? },
                 fuse_opcode::FUSE_READLINK => Operation::ReadLink,
                 fuse_opcode::FUSE_SYMLINK => Operation::SymLink {
@@ -341,6 +431,20 @@ impl<'a> Operation<'a> {
                 fuse_opcode::FUSE_INTERRUPT => Operation::Interrupt { arg: data.fetch()? },
                 fuse_opcode::FUSE_BMAP => Operation::BMap { arg: data.fetch()? },
                 fuse_opcode::FUSE_DESTROY => Operation::Destroy,
+                #[cfg(feature = "abi-7-11")]
+                fuse_opcode::FUSE_IOCTL => Operation::IoCtl {
+                    arg: data.fetch()?,
+                    data: data.fetch_all(),
+                },
+
+                #[cfg(feature = "abi-7-19")]
+                fuse_opcode::FUSE_FALLOCATE => Operation::FAllocate { arg: data.fetch()? },
+                #[cfg(feature = "abi-7-23")]
+                fuse_opcode::FUSE_RENAME2 => Operation::Rename2 {
+                    arg: data.fetch()?,
+                    name: data.fetch_str()?,
+                    newname: data.fetch_str()?,
+                },
 
                 #[cfg(target_os = "macos")]
                 fuse_opcode::FUSE_SETVOLNAME => Operation::SetVolName {
@@ -360,11 +464,9 @@ impl<'a> Operation<'a> {
                     feature = "abi-7-15",
                     feature = "abi-7-16"
                 ))]
-                fuse_opcode::FUSE_IOCTL
-                | fuse_opcode::FUSE_POLL
+                fuse_opcode::FUSE_POLL
                 | fuse_opcode::FUSE_NOTIFY_REPLY
                 | fuse_opcode::FUSE_BATCH_FORGET
-                | fuse_opcode::FUSE_FALLOCATE
                 | fuse_opcode::CUSE_INIT => Operation::NoImplementation,
             })
         }
@@ -456,6 +558,30 @@ impl Request<'_> {
     pub const fn operation(&self) -> &Operation<'_> {
         &self.operation
     }
+
+    /// Returns the inode this request operates on, unlike `nodeid` not the parent
+    /// directory of an operation by name, whose target inode is not known until
+    /// the filesystem looks up the name. `None` if the target is not known or the
+    /// request is not on an inode.
+    #[must_use]
+    pub fn target_ino(&self) -> Option<u64> {
+        match self.operation {
+            Operation::Lookup { .. }
+            | Operation::SymLink { .. }
+            | Operation::MkNod { .. }
+            | Operation::MkDir { .. }
+            | Operation::Unlink { .. }
+            | Operation::RmDir { .. }
+            | Operation::Rename { .. }
+            | Operation::Create { .. } => None,
+            #[cfg(feature = "abi-7-23")]
+            Operation::Rename2 { .. } => None,
+            #[cfg(target_os = "macos")]
+            Operation::Exchange { .. } => None,
+            Operation::Link { arg, .. } => Some(arg.oldnodeid),
+            _ => Some(self.header.nodeid).filter(|&ino| ino != 0),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -506,6 +632,28 @@ mod tests {
         0x66, 0x6f, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x00, // name
     ];
 
+    #[cfg(all(feature = "abi-7-9", target_endian = "big"))]
+    const GETATTR_REQUEST: [u8; 56] = [
+        0x00, 0x00, 0x00, 0x38, 0x00, 0x00, 0x00, 0x03, // len, opcode
+        0xde, 0xad, 0xbe, 0xef, 0xba, 0xad, 0xd0, 0x0d, // unique
+        0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, // nodeid
+        0xc0, 0x01, 0xd0, 0x0d, 0xc0, 0x01, 0xca, 0xfe, // uid, gid
+        0xc0, 0xde, 0xba, 0x5e, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // getattr_flags, dummy
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2a, // fh
+    ];
+
+    #[cfg(all(feature = "abi-7-9", target_endian = "little"))]
+    const GETATTR_REQUEST: [u8; 56] = [
+        0x38, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // len, opcode
+        0x0d, 0xf0, 0xad, 0xba, 0xef, 0xbe, 0xad, 0xde, // unique
+        0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, // nodeid
+        0x0d, 0xd0, 0x01, 0xc0, 0xfe, 0xca, 0x01, 0xc0, // uid, gid
+        0x5e, 0xba, 0xde, 0xc0, 0x00, 0x00, 0x00, 0x00, // pid, padding
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // getattr_flags, dummy
+        0x2a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // fh
+    ];
+
     #[test]
     fn setattr() {
         fn debug_attr(req: &Request<'_>) {
@@ -768,4 +916,20 @@ mod tests {
             _ => panic!("Unexpected request operation"),
         }
     }
+
+    #[test]
+    #[cfg(feature = "abi-7-9")]
+    fn getattr() {
+        let req = Request::try_from(&GETATTR_REQUEST[..]).unwrap_or_else(|_| panic!());
+        assert_eq!(req.header.len, 56);
+        assert_eq!(req.header.opcode, 3);
+        assert_eq!(req.nodeid(), 0x1122_3344_5566_7788);
+        match req.operation() {
+            Operation::GetAttr { arg } => {
+                assert_eq!(arg.getattr_flags, 1);
+                assert_eq!(arg.fh, 42);
+            }
+            _ => panic!("Unexpected request operation"),
+        }
+    }
 }
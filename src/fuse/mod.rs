@@ -8,13 +8,22 @@ use libc::ENOSYS;
 // use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::io;
-use std::os::raw::c_int;
 use std::path::Path;
 use std::time::SystemTime;
 
 pub use abi::consts;
 pub use abi::FUSE_ROOT_ID;
-pub use channel::unmount;
+pub use attr::{time_from_secs, time_to_secs};
+pub use channel::{unmount, unmount_options, UnmountFlags};
+pub use error::FsError;
+pub use ll_request::Operation;
+pub use negotiation::InitInfo;
+#[cfg(feature = "abi-7-12")]
+pub use notify::Notifier;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use preflight::preflight;
+#[cfg(feature = "abi-7-11")]
+pub use reply::ReplyIoctl;
 #[cfg(target_os = "macos")]
 pub use reply::ReplyXTimes;
 pub use reply::ReplyXattr;
@@ -22,33 +31,61 @@ pub use reply::{
     Reply, ReplyAttr, ReplyBmap, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
     ReplyLock, ReplyOpen, ReplyStatfs, ReplyStatfsParam, ReplyWrite,
 };
-pub use request::Request;
-pub use session::Session;
+pub use seccomp::SeccompAction;
+pub use session::{Session, SessionExit, SessionSummary};
+pub use supervisor::{check_mountpoint, self_test, supervise, SupervisorConfig};
 // pub use session::{Session, BackgroundSession};
 
-pub use mount::options_validator;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use mount::escape_mount_path;
+pub use mount::{mount_options_info, options_validator, MountOptionInfo};
 /// Abi module
 mod abi;
 /// Argument module
 mod argument;
+/// Attribute conversion module
+mod attr;
+/// Buffer module
+mod buffer;
 /// Channel module
 mod channel;
 /// Conversion module
 mod conversion;
+/// Error module
+mod error;
+/// Harness module
+#[cfg(test)]
+pub(crate) mod harness;
 /// ll request module
 mod ll_request;
 /// Mount module
 mod mount;
+/// Negotiation module
+mod negotiation;
+/// Notify module
+#[cfg(feature = "abi-7-12")]
+mod notify;
+/// Preflight module
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod preflight;
+/// Privilege module
+mod privilege;
 /// Reply module
 mod reply;
 /// Request module
 mod request;
+/// Seccomp module
+mod seccomp;
 /// Session module
 mod session;
+/// Supervisor module
+mod supervisor;
+/// Syscall module
+mod syscall;
 /// Utils module
 mod utils;
-pub use conversion::Cast;
-pub use utils::OverflowArithmetic;
+pub use conversion::{Cast, NumericError, TryCast};
+pub use utils::{CheckedArithmetic, OverflowArithmetic};
 
 /// File types
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -103,6 +140,57 @@ pub struct FileAttr {
     pub flags: u32,
 }
 
+/// The caller of a request passed to the filesystem methods, detached from the
+/// request so the handlers can be called and tested without one
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Context {
+    /// The unique identifier of the request
+    pub unique: u64,
+    /// User id of the caller
+    pub uid: u32,
+    /// Group id of the caller
+    pub gid: u32,
+    /// Process id of the caller
+    pub pid: u32,
+    /// Umask of the caller, given along with mknod, mkdir and create since ABI 7.12,
+    /// otherwise 0
+    pub umask: u32,
+    /// Notifier sending notifications to the kernel through the channel of the
+    /// request, `None` without a channel
+    #[cfg(feature = "abi-7-12")]
+    pub notifier: Option<Notifier>,
+}
+
+/// Config negotiated with the kernel at init, the filesystem may adjust it in init
+#[derive(Debug)]
+pub struct FsInitConfig {
+    /// Init flags the kernel is capable of
+    pub capable_flags: u32,
+    /// Init flags to enable, only those the kernel is capable of are enabled
+    pub flags: u32,
+    /// Max readahead size, no larger than what the kernel proposes
+    pub max_readahead: u32,
+    /// The filesystem needs no open requests, if the kernel supports it the
+    /// opens are answered without a round trip and the file data is kept
+    /// cached, the fh passed to the file operations is then 0
+    pub no_open: bool,
+    /// The filesystem needs no opendir requests, if the kernel supports it
+    /// the opendirs are answered without a round trip and the entries are
+    /// kept cached, the fh passed to the directory operations is then 0
+    pub no_opendir: bool,
+    /// The kernel caches the writes and flushes them later, if it is capable
+    /// of it. The kernel then owns the size, the mtime and the ctime of the
+    /// files it caches, it reads the pages it writes partly, so the writes
+    /// may come on the handles opened write only, and it sets the times with
+    /// setattr.
+    #[cfg(feature = "abi-7-23")]
+    pub writeback_cache: bool,
+    /// Granularity of the times of the filesystem in nanoseconds, the kernel
+    /// rounds the times it sets and caches to it, 1 by default
+    #[cfg(feature = "abi-7-23")]
+    pub time_gran: u32,
+}
+
 /// Param passed to setattr
 #[derive(Debug)]
 pub struct FsSetattrParam {
@@ -120,6 +208,9 @@ pub struct FsSetattrParam {
     pub atime: Option<SystemTime>,
     /// Time of last modification
     pub mtime: Option<SystemTime>,
+    /// Time of last status change, set by the kernel in writeback cache mode
+    #[cfg(feature = "abi-7-23")]
+    pub ctime: Option<SystemTime>,
     /// File handler
     pub fh: Option<u64>,
     /// Time of creation (macOS only)
@@ -175,6 +266,8 @@ pub struct FsReleaseParam {
     pub lock_owner: u64,
     /// Flush
     pub flush: bool,
+    /// Whether to release the flock locks of `lock_owner`, since ABI 7.17
+    pub flock_release: bool,
 }
 
 /// Param passed to getlk
@@ -215,6 +308,8 @@ pub struct FsSetlkParam {
     pub pid: u32,
     /// Sleep
     pub sleep: bool,
+    /// Whether it is a flock(2) lock rather than a POSIX lock, since ABI 7.9
+    pub flock: bool,
 }
 
 /// Param passed to exchange
@@ -232,6 +327,40 @@ pub struct FsExchangeParam<'a> {
     pub options: u64,
 }
 
+/// Param passed to ioctl
+#[cfg(feature = "abi-7-11")]
+#[derive(Debug)]
+pub struct FsIoctlParam<'a> {
+    /// Inode number
+    pub ino: u64,
+    /// File handler
+    pub fh: u64,
+    /// Flags
+    pub flags: u32,
+    /// Cmd
+    pub cmd: u32,
+    /// Input data
+    pub in_data: &'a [u8],
+    /// Output size
+    pub out_size: u32,
+}
+
+/// Param passed to fallocate
+#[cfg(feature = "abi-7-19")]
+#[derive(Debug)]
+pub struct FsFallocateParam {
+    /// Inode number
+    pub ino: u64,
+    /// File handler
+    pub fh: u64,
+    /// Offset
+    pub offset: i64,
+    /// Length
+    pub length: i64,
+    /// Mode, the `FALLOC_FL_*` flags of Linux
+    pub mode: u32,
+}
+
 /// Filesystem trait.
 ///
 /// This trait must be implemented to provide a userspace filesystem via FUSE.
@@ -240,17 +369,62 @@ pub struct FsExchangeParam<'a> {
 /// nothing.
 pub trait Filesystem {
     /// Initialize filesystem.
-    /// Called before any other filesystem method.
-    fn init(&mut self, _req: &Request<'_>) -> Result<(), c_int> {
+    /// Called before any other filesystem method. The filesystem may choose which init
+    /// flags to enable among those the kernel is capable of by adjusting the config.
+    fn init(&mut self, _ctx: &Context, _config: &mut FsInitConfig) -> Result<(), FsError> {
         Ok(())
     }
 
+    /// Learn the results of the init negotiation.
+    /// Called once the kernel is replied to init, before any other request.
+    fn negotiated(&mut self, _info: &InitInfo) {}
+
+    /// Flush dirty data before the filesystem is unmounted.
+    /// Called once when the kernel sends destroy or the session ends, before `destroy`
+    /// and before the channel to the kernel is closed.
+    fn pre_unmount(&mut self) {}
+
+    /// Detach the filesystem from the kernel.
+    /// Called once the session ends without destroy from the kernel, before
+    /// `pre_unmount`, e.g. when the daemon exits and the mount may stay alive for
+    /// another daemon taking over its `/dev/fuse` fd.
+    fn detach(&mut self) {}
+
+    /// Do the background work of the filesystem.
+    /// Called by the session once no request arrived for its idle interval, so
+    /// the work delays no request, e.g. warming or maintaining the caches.
+    fn idle(&mut self) {}
+
     /// Clean up filesystem.
-    /// Called on filesystem exit.
-    fn destroy(&mut self, _req: &Request<'_>) {}
+    /// Called on filesystem exit, either when the kernel sends destroy or when the
+    /// session ends without it, e.g. after `fusermount -u`.
+    fn destroy(&mut self) {}
+
+    /// Poison an inode.
+    /// Called once handling a request on the inode panicked, unless the session is
+    /// strict. The state of the inode is unknown then, so the filesystem may fail the
+    /// further requests on it rather than risk more damage.
+    fn poison(&mut self, _ino: u64) {}
+
+    /// Whether an inode is poisoned for an operation.
+    /// The requests on a poisoned inode fail with EIO without calling the filesystem,
+    /// except forget and the operations the filesystem lets through, e.g. the one
+    /// recovering the inode.
+    fn is_poisoned(&self, _ino: u64, _op: &Operation<'_>) -> bool {
+        false
+    }
+
+    /// Authorize an operation before it is handled.
+    /// Called for every request after init but forget, interrupt, destroy,
+    /// release and releasedir, which free the resources of the kernel, the
+    /// request fails with the error returned without calling the handler, so the
+    /// access checks of an embedder, e.g. tokens or tenancy, live in one place.
+    fn authorize(&self, _ctx: &Context, _op: &Operation<'_>) -> Result<(), FsError> {
+        Ok(())
+    }
 
     /// Look up a directory entry by name and get its attributes.
-    fn lookup(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, _ctx: &Context, _parent: u64, _name: &OsStr, reply: ReplyEntry) {
         reply.error(ENOSYS);
     }
 
@@ -261,20 +435,22 @@ pub trait Filesystem {
     /// each forget. The filesystem may ignore forget calls, if the inodes don't need to
     /// have a limited lifetime. On unmount it is not guaranteed, that all referenced
     /// inodes will receive a forget message.
-    fn forget(&mut self, _req: &Request<'_>, _ino: u64, _nlookup: u64) {}
+    fn forget(&mut self, _ctx: &Context, _ino: u64, _nlookup: u64) {}
 
     /// Get file attributes.
-    fn getattr(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyAttr) {
+    /// fh is the file handle the attributes are got through, e.g. by fstat(2), it is
+    /// only set since ABI 7.9 and lets the attributes of unlinked but open files be got.
+    fn getattr(&mut self, _ctx: &Context, _ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Set file attributes.
-    fn setattr(&mut self, _req: &Request<'_>, _param: FsSetattrParam, reply: ReplyAttr) {
+    fn setattr(&mut self, _ctx: &Context, _param: FsSetattrParam, reply: ReplyAttr) {
         reply.error(ENOSYS);
     }
 
     /// Read symbolic link.
-    fn readlink(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyData) {
+    fn readlink(&mut self, _ctx: &Context, _ino: u64, reply: ReplyData) {
         reply.error(ENOSYS);
     }
 
@@ -282,7 +458,7 @@ pub trait Filesystem {
     /// Create a regular file, character device, block device, fifo or socket node.
     fn mknod(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
@@ -295,7 +471,7 @@ pub trait Filesystem {
     /// Create a directory.
     fn mkdir(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
@@ -305,19 +481,19 @@ pub trait Filesystem {
     }
 
     /// Remove a file.
-    fn unlink(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, _ctx: &Context, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Remove a directory.
-    fn rmdir(&mut self, _req: &Request<'_>, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, _ctx: &Context, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Create a symbolic link.
     fn symlink(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _parent: u64,
         _name: &OsStr,
         _link: &Path,
@@ -329,7 +505,7 @@ pub trait Filesystem {
     /// Rename a file.
     fn rename(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _parent: u64,
         _name: &OsStr,
         _newparent: u64,
@@ -342,7 +518,7 @@ pub trait Filesystem {
     /// Create a hard link.
     fn link(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _ino: u64,
         _newparent: u64,
         _newname: &OsStr,
@@ -359,7 +535,7 @@ pub trait Filesystem {
     /// anything in fh. There are also some flags (`direct_io`, `keep_cache`) which the
     /// filesystem may set, to change the way the file is opened. See `fuse_file_info`
     /// structure in `<fuse_common.h>` for more details.
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, _ctx: &Context, _ino: u64, _flags: u32, reply: ReplyOpen) {
         reply.opened(0, 0);
     }
 
@@ -372,7 +548,7 @@ pub trait Filesystem {
     /// if the open method didn't set any value.
     fn read(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -388,7 +564,7 @@ pub trait Filesystem {
     /// which case the return value of the write system call will reflect the return
     /// value of this operation. fh will contain the value set by the open method, or
     /// will be undefined if the open method didn't set any value.
-    fn write(&mut self, _req: &Request<'_>, _param: FsWriteParam<'_>, reply: ReplyWrite) {
+    fn write(&mut self, _ctx: &Context, _param: FsWriteParam<'_>, reply: ReplyWrite) {
         reply.error(ENOSYS);
     }
 
@@ -402,14 +578,7 @@ pub trait Filesystem {
     /// is not forced to flush pending writes. One reason to flush data, is if the
     /// filesystem wants to return write errors. If the filesystem supports file locking
     /// operations (setlk, getlk) it should remove all locks belonging to `lock_owner`.
-    fn flush(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        reply: ReplyEmpty,
-    ) {
+    fn flush(&mut self, _ctx: &Context, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -421,21 +590,14 @@ pub trait Filesystem {
     /// the release. fh will contain the value set by the open method, or will be undefined
     /// if the open method didn't set any value. flags will contain the same flags as for
     /// open.
-    fn release(&mut self, _req: &Request<'_>, _param: FsReleaseParam, reply: ReplyEmpty) {
+    fn release(&mut self, _ctx: &Context, _param: FsReleaseParam, reply: ReplyEmpty) {
         reply.ok();
     }
 
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    fn fsync(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _datasync: bool,
-        reply: ReplyEmpty,
-    ) {
+    fn fsync(&mut self, _ctx: &Context, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -446,7 +608,7 @@ pub trait Filesystem {
     /// anything in fh, though that makes it impossible to implement standard conforming
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
-    fn opendir(&mut self, _req: &Request<'_>, _ino: u64, _flags: u32, reply: ReplyOpen) {
+    fn opendir(&mut self, _ctx: &Context, _ino: u64, _flags: u32, reply: ReplyOpen) {
         reply.opened(0, 0);
     }
 
@@ -457,7 +619,7 @@ pub trait Filesystem {
     /// didn't set any value.
     fn readdir(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _ino: u64,
         _fh: u64,
         _offset: i64,
@@ -470,14 +632,7 @@ pub trait Filesystem {
     /// For every opendir call there will be exactly one releasedir call. fh will
     /// contain the value set by the opendir method, or will be undefined if the
     /// opendir method didn't set any value.
-    fn releasedir(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _fh: u64,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
+    fn releasedir(&mut self, _ctx: &Context, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
         reply.ok();
     }
 
@@ -487,7 +642,7 @@ pub trait Filesystem {
     /// method, or will be undefined if the opendir method didn't set any value.
     fn fsyncdir(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _ino: u64,
         _fh: u64,
         _datasync: bool,
@@ -497,7 +652,7 @@ pub trait Filesystem {
     }
 
     /// Get file system statistics.
-    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+    fn statfs(&mut self, _ctx: &Context, _ino: u64, reply: ReplyStatfs) {
         reply.statfs(&ReplyStatfsParam {
             blocks: 0,
             bfree: 0,
@@ -511,7 +666,7 @@ pub trait Filesystem {
     }
 
     /// Set an extended attribute.
-    fn setxattr(&mut self, _req: &Request<'_>, _param: FsSetxattrParam<'_>, reply: ReplyEmpty) {
+    fn setxattr(&mut self, _ctx: &Context, _param: FsSetxattrParam<'_>, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -521,7 +676,7 @@ pub trait Filesystem {
     /// `reply.error(ERANGE)` if it doesn't.
     fn getxattr(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _ino: u64,
         _name: &OsStr,
         _size: u32,
@@ -534,12 +689,12 @@ pub trait Filesystem {
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    fn listxattr(&mut self, _req: &Request<'_>, _ino: u64, _size: u32, reply: ReplyXattr) {
+    fn listxattr(&mut self, _ctx: &Context, _ino: u64, _size: u32, reply: ReplyXattr) {
         reply.error(ENOSYS);
     }
 
     /// Remove an extended attribute.
-    fn removexattr(&mut self, _req: &Request<'_>, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
+    fn removexattr(&mut self, _ctx: &Context, _ino: u64, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -547,7 +702,7 @@ pub trait Filesystem {
     /// This will be called for the access() system call. If the `default_permissions`
     /// mount option is given, this method is not called. This method is not called
     /// under Linux kernel versions 2.4.x
-    fn access(&mut self, _req: &Request<'_>, _ino: u64, _mask: u32, reply: ReplyEmpty) {
+    fn access(&mut self, _ctx: &Context, _ino: u64, _mask: u32, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
@@ -563,7 +718,7 @@ pub trait Filesystem {
     /// and open() methods will be called instead.
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        _ctx: &Context,
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
@@ -574,7 +729,7 @@ pub trait Filesystem {
     }
 
     /// Test for a POSIX file lock.
-    fn getlk(&mut self, _req: &Request<'_>, _param: FsGetlkParam, reply: ReplyLock) {
+    fn getlk(&mut self, _ctx: &Context, _param: FsGetlkParam, reply: ReplyLock) {
         reply.error(ENOSYS);
     }
 
@@ -585,45 +740,286 @@ pub trait Filesystem {
     /// used to fill in this field in getlk(). Note: if the locking methods are not
     /// implemented, the kernel will still allow file locking to work locally.
     /// Hence these are only interesting for network filesystems and similar.
-    fn setlk(&mut self, _req: &Request<'_>, _param: FsSetlkParam, reply: ReplyEmpty) {
+    fn setlk(&mut self, _ctx: &Context, _param: FsSetlkParam, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    fn bmap(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        _blocksize: u32,
-        _idx: u64,
-        reply: ReplyBmap,
-    ) {
+    fn bmap(&mut self, _ctx: &Context, _ino: u64, _blocksize: u32, _idx: u64, reply: ReplyBmap) {
+        reply.error(ENOSYS);
+    }
+
+    /// Control device.
+    /// Only restricted ioctls are forwarded by the kernel for regular files, the
+    /// size of `in_data` and `out_size` are encoded in the ioctl cmd. Note: the VFS
+    /// handles FICLONE, FIEMAP and the like itself, they never reach here.
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl(&mut self, _ctx: &Context, _param: FsIoctlParam<'_>, reply: ReplyIoctl) {
+        reply.error(ENOSYS);
+    }
+
+    /// Allocate or deallocate the space of a range of a file, as fallocate(2).
+    /// The kernel stops sending fallocate once it is replied `ENOSYS`, and fails
+    /// it with `EOPNOTSUPP` itself from then on.
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&mut self, _ctx: &Context, _param: FsFallocateParam, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// macOS only: Rename the volume. Set `fuse_init_out.flags` during init to
     /// `FUSE_VOL_RENAME` to enable
     #[cfg(target_os = "macos")]
-    fn setvolname(&mut self, _req: &Request<'_>, _name: &OsStr, reply: ReplyEmpty) {
+    fn setvolname(&mut self, _ctx: &Context, _name: &OsStr, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// macOS only (undocumented)
     #[cfg(target_os = "macos")]
-    fn exchange(&mut self, _req: &Request<'_>, _param: FsExchangeParam<'_>, reply: ReplyEmpty) {
+    fn exchange(&mut self, _ctx: &Context, _param: FsExchangeParam<'_>, reply: ReplyEmpty) {
         reply.error(ENOSYS);
     }
 
     /// macOS only: Query extended times (bkuptime and crtime). Set `fuse_init_out.flags`
     /// during init to `FUSE_XTIMES` to enable
     #[cfg(target_os = "macos")]
-    fn getxtimes(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyXTimes) {
+    fn getxtimes(&mut self, _ctx: &Context, _ino: u64, reply: ReplyXTimes) {
         reply.error(ENOSYS);
     }
 }
 
+/// Forward the operations of a boxed filesystem, so that a filesystem chosen at
+/// runtime is mounted as `Box<dyn Filesystem + Send>`
+impl<FS: Filesystem + ?Sized> Filesystem for Box<FS> {
+    fn init(&mut self, ctx: &Context, config: &mut FsInitConfig) -> Result<(), FsError> {
+        (**self).init(ctx, config)
+    }
+
+    fn negotiated(&mut self, info: &InitInfo) {
+        (**self).negotiated(info)
+    }
+
+    fn pre_unmount(&mut self) {
+        (**self).pre_unmount()
+    }
+
+    fn detach(&mut self) {
+        (**self).detach()
+    }
+
+    fn idle(&mut self) {
+        (**self).idle()
+    }
+
+    fn destroy(&mut self) {
+        (**self).destroy()
+    }
+
+    fn poison(&mut self, ino: u64) {
+        (**self).poison(ino)
+    }
+
+    fn is_poisoned(&self, ino: u64, op: &Operation<'_>) -> bool {
+        (**self).is_poisoned(ino, op)
+    }
+
+    fn authorize(&self, ctx: &Context, op: &Operation<'_>) -> Result<(), FsError> {
+        (**self).authorize(ctx, op)
+    }
+
+    fn lookup(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        (**self).lookup(ctx, parent, name, reply)
+    }
+
+    fn forget(&mut self, ctx: &Context, ino: u64, nlookup: u64) {
+        (**self).forget(ctx, ino, nlookup)
+    }
+
+    fn getattr(&mut self, ctx: &Context, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        (**self).getattr(ctx, ino, fh, reply)
+    }
+
+    fn setattr(&mut self, ctx: &Context, param: FsSetattrParam, reply: ReplyAttr) {
+        (**self).setattr(ctx, param, reply)
+    }
+
+    fn readlink(&mut self, ctx: &Context, ino: u64, reply: ReplyData) {
+        (**self).readlink(ctx, ino, reply)
+    }
+
+    fn mknod(
+        &mut self,
+        ctx: &Context,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        (**self).mknod(ctx, parent, name, mode, rdev, reply)
+    }
+
+    fn mkdir(&mut self, ctx: &Context, parent: u64, name: &OsStr, mode: u32, reply: ReplyEntry) {
+        (**self).mkdir(ctx, parent, name, mode, reply)
+    }
+
+    fn unlink(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        (**self).unlink(ctx, parent, name, reply)
+    }
+
+    fn rmdir(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        (**self).rmdir(ctx, parent, name, reply)
+    }
+
+    fn symlink(
+        &mut self,
+        ctx: &Context,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        (**self).symlink(ctx, parent, name, link, reply)
+    }
+
+    fn rename(
+        &mut self,
+        ctx: &Context,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        (**self).rename(ctx, parent, name, newparent, newname, reply)
+    }
+
+    fn link(
+        &mut self,
+        ctx: &Context,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        (**self).link(ctx, ino, newparent, newname, reply)
+    }
+
+    fn open(&mut self, ctx: &Context, ino: u64, flags: u32, reply: ReplyOpen) {
+        (**self).open(ctx, ino, flags, reply)
+    }
+
+    fn read(&mut self, ctx: &Context, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        (**self).read(ctx, ino, fh, offset, size, reply)
+    }
+
+    fn write(&mut self, ctx: &Context, param: FsWriteParam<'_>, reply: ReplyWrite) {
+        (**self).write(ctx, param, reply)
+    }
+
+    fn flush(&mut self, ctx: &Context, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        (**self).flush(ctx, ino, fh, lock_owner, reply)
+    }
+
+    fn release(&mut self, ctx: &Context, param: FsReleaseParam, reply: ReplyEmpty) {
+        (**self).release(ctx, param, reply)
+    }
+
+    fn fsync(&mut self, ctx: &Context, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        (**self).fsync(ctx, ino, fh, datasync, reply)
+    }
+
+    fn opendir(&mut self, ctx: &Context, ino: u64, flags: u32, reply: ReplyOpen) {
+        (**self).opendir(ctx, ino, flags, reply)
+    }
+
+    fn readdir(&mut self, ctx: &Context, ino: u64, fh: u64, offset: i64, reply: ReplyDirectory) {
+        (**self).readdir(ctx, ino, fh, offset, reply)
+    }
+
+    fn releasedir(&mut self, ctx: &Context, ino: u64, fh: u64, flags: u32, reply: ReplyEmpty) {
+        (**self).releasedir(ctx, ino, fh, flags, reply)
+    }
+
+    fn fsyncdir(&mut self, ctx: &Context, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        (**self).fsyncdir(ctx, ino, fh, datasync, reply)
+    }
+
+    fn statfs(&mut self, ctx: &Context, ino: u64, reply: ReplyStatfs) {
+        (**self).statfs(ctx, ino, reply)
+    }
+
+    fn setxattr(&mut self, ctx: &Context, param: FsSetxattrParam<'_>, reply: ReplyEmpty) {
+        (**self).setxattr(ctx, param, reply)
+    }
+
+    fn getxattr(&mut self, ctx: &Context, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        (**self).getxattr(ctx, ino, name, size, reply)
+    }
+
+    fn listxattr(&mut self, ctx: &Context, ino: u64, size: u32, reply: ReplyXattr) {
+        (**self).listxattr(ctx, ino, size, reply)
+    }
+
+    fn removexattr(&mut self, ctx: &Context, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        (**self).removexattr(ctx, ino, name, reply)
+    }
+
+    fn access(&mut self, ctx: &Context, ino: u64, mask: u32, reply: ReplyEmpty) {
+        (**self).access(ctx, ino, mask, reply)
+    }
+
+    fn create(
+        &mut self,
+        ctx: &Context,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        (**self).create(ctx, parent, name, mode, flags, reply)
+    }
+
+    fn getlk(&mut self, ctx: &Context, param: FsGetlkParam, reply: ReplyLock) {
+        (**self).getlk(ctx, param, reply)
+    }
+
+    fn setlk(&mut self, ctx: &Context, param: FsSetlkParam, reply: ReplyEmpty) {
+        (**self).setlk(ctx, param, reply)
+    }
+
+    fn bmap(&mut self, ctx: &Context, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        (**self).bmap(ctx, ino, blocksize, idx, reply)
+    }
+
+    #[cfg(feature = "abi-7-11")]
+    fn ioctl(&mut self, ctx: &Context, param: FsIoctlParam<'_>, reply: ReplyIoctl) {
+        (**self).ioctl(ctx, param, reply)
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&mut self, ctx: &Context, param: FsFallocateParam, reply: ReplyEmpty) {
+        (**self).fallocate(ctx, param, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn setvolname(&mut self, ctx: &Context, name: &OsStr, reply: ReplyEmpty) {
+        (**self).setvolname(ctx, name, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn exchange(&mut self, ctx: &Context, param: FsExchangeParam<'_>, reply: ReplyEmpty) {
+        (**self).exchange(ctx, param, reply)
+    }
+
+    #[cfg(target_os = "macos")]
+    fn getxtimes(&mut self, ctx: &Context, ino: u64, reply: ReplyXTimes) {
+        (**self).getxtimes(ctx, ino, reply)
+    }
+}
+
 /// Mount the given filesystem to the given mountpoint. This function will
 /// not return until the filesystem is unmounted.
 ///
@@ -1,25 +1,73 @@
+#[cfg(target_os = "linux")]
+use log::warn;
 use log::{debug, error};
-use nix::errno::{self, Errno};
+use nix::errno;
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat::{self, FileStat, Mode};
 use std::collections::HashMap;
 use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::fmt;
 use std::fs;
+#[cfg(target_os = "linux")]
+use std::io::{self, Read};
+use std::ops::BitOr;
 use std::os::unix::io::RawFd;
 use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::process::Child;
+#[cfg(target_os = "linux")]
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
 
 #[cfg(target_os = "macos")]
 use param::{
-    copy_slice, parse_mount_flag, FUSE_IOC_MAGIC, FUSE_IOC_TYPE_MODE, MAXPATHLEN, MNT_NOATIME,
+    copy_name, parse_mount_flag, FUSE_IOC_MAGIC, FUSE_IOC_TYPE_MODE, MAXPATHLEN, MNT_NOATIME,
     MNT_NODEV, MNT_NOSUID, MNT_NOUSERXATTR,
 };
 use param::{get_mount_options, FuseMountArgs, MNT_FORCE};
-#[cfg(target_os = "linux")]
-use param::{MS_NODEV, MS_NOSUID};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use param::{MNT_DETACH, MS_NODEV, MS_NOSUID};
 
+#[cfg(target_os = "macos")]
 use super::conversion;
+use super::syscall;
 #[cfg(target_os = "macos")]
 use super::Cast;
+#[cfg(target_os = "linux")]
+use super::OverflowArithmetic;
+
+/// Flags of unmount, no flag asks for a clean unmount, which fails if the
+/// mount point is busy
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct UnmountFlags(u8);
+
+impl UnmountFlags {
+    /// Detach the mount point at once and clean up once it is no longer busy,
+    /// not supported on macOS
+    pub const LAZY: Self = Self(1);
+    /// Abort pending requests and unmount even if the mount point is busy
+    pub const FORCE: Self = Self(1 << 1);
+
+    /// No flag, for a clean unmount
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Whether all the given flags are set
+    pub const fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for UnmountFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
 
 /// Fuse mount option
 pub struct FuseMountOption {
@@ -29,7 +77,7 @@ pub struct FuseMountOption {
     pub parser: fn(&mut FuseMountArgs, &FuseMountOption, &str),
     /// validator
     pub validator: fn(&FuseMountOption, &str) -> bool,
-    #[cfg(target_os = "linux")]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     /// Flag
     pub flag: Option<u64>,
     #[cfg(target_os = "macos")]
@@ -40,6 +88,105 @@ pub struct FuseMountOption {
     pub fuse_flag: Option<u64>,
 }
 
+/// Prefix of the options passed to the kernel as is, e.g. `kernel:max_read=65536`,
+/// for the kernel options not modeled yet
+pub const KERNEL_OPTION_PREFIX: &str = "kernel:";
+
+/// The description of a mount option and the platforms supporting it
+struct MountOptionDoc {
+    /// Name, the same as in the table of the platform
+    name: &'static str,
+    /// Description
+    description: &'static str,
+    /// Platforms supporting the option
+    platforms: &'static [&'static str],
+}
+
+/// The descriptions of the mount options of all the platforms, shared by the
+/// tables of the platforms, which only tell how to check and parse them
+const MOUNT_OPTION_DOCS: &[MountOptionDoc] = &[
+    MountOptionDoc {
+        name: "ro",
+        description: "Mount the filesystem read-only",
+        platforms: &["linux", "android", "macos"],
+    },
+    MountOptionDoc {
+        name: "noexec",
+        description: "Disallow executing the files of the filesystem",
+        platforms: &["linux", "android"],
+    },
+    MountOptionDoc {
+        name: "noatime",
+        description: "Do not update the access times of the files",
+        platforms: &["linux", "android"],
+    },
+    MountOptionDoc {
+        name: "allow_other",
+        description: "Allow users other than the mounting user to access the filesystem",
+        platforms: &["linux", "android", "macos"],
+    },
+    MountOptionDoc {
+        name: "fsname=<name>",
+        description: "Name of the filesystem shown in the mount table, less than 1024 bytes on macOS",
+        platforms: &["linux", "android", "macos"],
+    },
+    MountOptionDoc {
+        name: "subtype=<type>",
+        description: "Subtype of the filesystem, shown as the type fuse.<type> in the mount table",
+        platforms: &["linux", "android"],
+    },
+    MountOptionDoc {
+        name: "kernel:<option>",
+        description: "Pass the option to the kernel as is without checking it, ignored on macOS",
+        platforms: &["linux", "android"],
+    },
+    MountOptionDoc {
+        name: "noappledouble",
+        description: "Deny the AppleDouble files, named ._* by Finder, and hide them",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "noapplexattr",
+        description: "Deny the extended attributes prefixed with com.apple.",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "volname=<name>",
+        description: "Name of the volume shown by Finder, less than 1024 bytes",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "blocksize=<n>",
+        description: "Block size in bytes reported to the kernel, a power of two from 512 to 131072, 4096 by default",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "daemon_timeout=<n>",
+        description: "Seconds the kernel waits for a reply before giving up the mount, at most 600, 60 by default, 0 waits forever",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "iosize=<n>",
+        description: "Max bytes of a read or write, a power of two from 512 to 33554432, 65536 by default",
+        platforms: &["macos"],
+    },
+    MountOptionDoc {
+        name: "fssubtype=<n>",
+        description: "Sub type id of the filesystem reported by statfs",
+        platforms: &["macos"],
+    },
+];
+
+/// The key of an option in the options map, the name before `=`, or the prefix of
+/// an option passed to the kernel as is
+fn option_key(option: &str) -> &str {
+    if option.starts_with(KERNEL_OPTION_PREFIX) {
+        KERNEL_OPTION_PREFIX
+    } else {
+        option.split('=').next().unwrap_or(option)
+    }
+}
+
 /// Get all options
 fn get_all_options() -> String {
     get_mount_options()
@@ -49,6 +196,35 @@ fn get_all_options() -> String {
         .join(",")
 }
 
+/// A mount option for listing, with its description and the platforms supporting it
+#[derive(Clone, Debug)]
+pub struct MountOptionInfo {
+    /// Name, with a placeholder for the value if any, e.g. `fsname=<name>`
+    pub name: String,
+    /// Description
+    pub description: &'static str,
+    /// Platforms supporting the option
+    pub platforms: &'static [&'static str],
+}
+
+/// List the mount options supported on this platform
+pub fn mount_options_info() -> Vec<MountOptionInfo> {
+    get_mount_options()
+        .into_iter()
+        .map(|op| {
+            let doc = MOUNT_OPTION_DOCS
+                .iter()
+                .find(|doc| doc.name == op.name)
+                .unwrap_or_else(|| panic!("mount option {:?} is not described", op.name));
+            MountOptionInfo {
+                name: op.name,
+                description: doc.description,
+                platforms: doc.platforms,
+            }
+        })
+        .collect()
+}
+
 /// Check if an option is valid.
 pub fn options_validator(option: &str) -> Result<(), String> {
     let ret = option
@@ -71,15 +247,7 @@ pub fn options_validator(option: &str) -> Result<(), String> {
 pub fn get_mount_options_map() -> HashMap<String, FuseMountOption> {
     let mut map: HashMap<String, FuseMountOption> = HashMap::new();
     for op in get_mount_options() {
-        let key = op
-            .name
-            .clone()
-            .split('=')
-            .collect::<Vec<_>>()
-            .get(0)
-            .unwrap_or_else(|| panic!("Indexing is out of bounds"))
-            .to_owned()
-            .to_string();
+        let key = option_key(&op.name).to_owned();
         let val = op;
 
         map.insert(key, val);
@@ -87,7 +255,7 @@ pub fn get_mount_options_map() -> HashMap<String, FuseMountOption> {
     map
 }
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 /// Param
 mod param {
     // https://github.com/torvalds/linux/blob/master/include/uapi/linux/mount.h#L11
@@ -98,10 +266,17 @@ mod param {
     pub const MS_NOSUID: u64 = 2; // Ignore suid and sgid bits
     /// NODEV
     pub const MS_NODEV: u64 = 4; // Disallow access to device special files
+    /// NOEXEC
+    pub const MS_NOEXEC: u64 = 8; // Disallow program execution
+    /// NOATIME
+    pub const MS_NOATIME: u64 = 1024; // Do not update access times
     /// Force un-mount
     pub const MNT_FORCE: i32 = 1; // Force un-mount
+    /// Lazy un-mount
+    pub const MNT_DETACH: i32 = 2; // Just detach from the tree
 
-    use super::FuseMountOption;
+    use super::{FuseMountOption, KERNEL_OPTION_PREFIX};
+    use log::warn;
     use regex::Regex;
     /// Add option
     fn add_option(options: &Option<String>, option: &str) -> Option<String> {
@@ -116,7 +291,25 @@ mod param {
         }
     }
 
+    /// The value of a key value option, everything after the first `=`
+    fn option_value(option: &str) -> &str {
+        option.split_once('=').map_or("", |(_, value)| value)
+    }
+
+    /// Escape the `,` and `\` of an option value, which fusermount unescapes
+    fn escape(value: &str) -> String {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if c == ',' || c == '\\' {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
     /// Get mount options
+    #[allow(clippy::too_many_lines)]
     pub fn get_mount_options() -> Vec<FuseMountOption> {
         /// Parse flag
         fn parse_flag(args: &mut FuseMountArgs, mount_option: &FuseMountOption, option: &str) {
@@ -136,11 +329,27 @@ mod param {
             args.kernel_opts = add_option(&args.kernel_opts, option);
         }
 
-        /// Parse fsname
+        /// Parse fsname, the source of the mount in the mount table
         fn parse_fsname(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
-            let name = String::from(option.split('=').last().unwrap_or_else(|| panic!())); //Safe to use unwrap here, becuase option is always valid.
-            args.fsname = Some(name);
-            args.fusermount_opts = add_option(&args.fusermount_opts, option);
+            let name = option_value(option);
+            args.fusermount_opts =
+                add_option(&args.fusermount_opts, &format!("fsname={}", escape(name)));
+            args.fsname = Some(String::from(name));
+        }
+        /// Parse subtype, the type of the mount is `fuse.<subtype>`
+        fn parse_subtype(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
+            let subtype = option_value(option);
+            args.subtype_opt = Some(format!("subtype={}", escape(subtype)));
+            args.subtype = Some(String::from(subtype));
+        }
+        /// Parse an option passed to the kernel as is
+        fn parse_kernel(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
+            let kernel_option = option.get(KERNEL_OPTION_PREFIX.len()..).unwrap_or_default();
+            warn!(
+                "passing mount option {:?} to the kernel as is, it is not checked",
+                kernel_option
+            );
+            args.kernel_opts = add_option(&args.kernel_opts, kernel_option);
         }
         /// Match name
         fn name_match(mount_option: &FuseMountOption, option: &str) -> bool {
@@ -159,6 +368,14 @@ mod param {
             let option_regex = Regex::new(regex_str.as_str()).unwrap_or_else(|_| panic!()); //Safe to use unwrap here, becuase regex_str is always valid.
             option_regex.is_match(option)
         }
+        /// Match an option passed to the kernel as is
+        fn kernel_match(_mount_option: &FuseMountOption, option: &str) -> bool {
+            option
+                .strip_prefix(KERNEL_OPTION_PREFIX)
+                .map_or(false, |kernel_option| {
+                    !kernel_option.is_empty() && !kernel_option.contains(char::is_whitespace)
+                })
+        }
         vec![
             FuseMountOption {
                 name: String::from("ro"),
@@ -166,6 +383,18 @@ mod param {
                 validator: name_match,
                 flag: Some(MS_RDONLY),
             },
+            FuseMountOption {
+                name: String::from("noexec"),
+                parser: parse_flag,
+                validator: name_match,
+                flag: Some(MS_NOEXEC),
+            },
+            FuseMountOption {
+                name: String::from("noatime"),
+                parser: parse_flag,
+                validator: name_match,
+                flag: Some(MS_NOATIME),
+            },
             FuseMountOption {
                 name: String::from("allow_other"),
                 parser: parse_allow_other,
@@ -178,6 +407,18 @@ mod param {
                 validator: key_value_match,
                 flag: None,
             },
+            FuseMountOption {
+                name: String::from("subtype=<type>"),
+                parser: parse_subtype,
+                validator: key_value_match,
+                flag: None,
+            },
+            FuseMountOption {
+                name: format!("{}<option>", KERNEL_OPTION_PREFIX),
+                parser: parse_kernel,
+                validator: kernel_match,
+                flag: None,
+            },
         ]
     }
 
@@ -228,14 +469,8 @@ mod param {
             };
             let mount_options_map = super::get_mount_options_map();
             options.iter().for_each(|op| {
-                let key = op
-                    .split('=')
-                    .collect::<Vec<_>>()
-                    .get(0)
-                    .unwrap_or_else(|| panic!("Indexing is out of bounds"))
-                    .to_owned()
-                    .to_string();
-                let option = mount_options_map.get(&key).unwrap_or_else(|| panic!()); // Safe to use unwrap here, because key always exists
+                let key = super::option_key(op);
+                let option = mount_options_map.get(key).unwrap_or_else(|| panic!()); // Safe to use unwrap here, because key always exists
                 (option.parser)(&mut args, option, op)
             });
             args
@@ -308,6 +543,19 @@ mod param {
         pub const FUSE_DEFAULT_DAEMON_TIMEOUT: u32 = 60; // seconds
         /// Fuse default iosize
         pub const FUSE_DEFAULT_IOSIZE: u32 = 16 * PAGE_SIZE;
+
+        /// Fuse min blocksize
+        pub const FUSE_MIN_BLOCKSIZE: u32 = 512;
+        /// Fuse max blocksize, MAXPHYS
+        pub const FUSE_MAX_BLOCKSIZE: u32 = 128 * 1024;
+        /// Fuse min daemon timeout
+        pub const FUSE_MIN_DAEMON_TIMEOUT: u32 = 0; // seconds, no timeout
+        /// Fuse max daemon timeout
+        pub const FUSE_MAX_DAEMON_TIMEOUT: u32 = 600; // seconds
+        /// Fuse min iosize
+        pub const FUSE_MIN_IOSIZE: u32 = 512;
+        /// Fuse max iosize
+        pub const FUSE_MAX_IOSIZE: u32 = 32 * 1024 * 1024;
     }
     pub use fuse_default_configs::*;
 
@@ -325,6 +573,8 @@ mod param {
         pub const FUSE_MOPT_DEBUG: u64 = 0x0000_0000_0000_0040;
         /// Fuse mopt fsname
         pub const FUSE_MOPT_FSNAME: u64 = 0x0000_0000_0000_1000;
+        /// Fuse mopt no appledouble
+        pub const FUSE_MOPT_NO_APPLEDOUBLE: u64 = 0x0000_0000_0040_0000;
         /// Fuse mopt no applexattr
         pub const FUSE_MOPT_NO_APPLEXATTR: u64 = 0x0000_0000_0080_0000;
     }
@@ -365,9 +615,11 @@ mod param {
         rdev: u32, // dev_t for the /dev/osxfuse{n} in question
     }
 
-    use super::FuseMountOption;
+    use super::{FuseMountOption, KERNEL_OPTION_PREFIX};
+    use log::warn;
     use regex::Regex;
     /// Get mount options
+    #[allow(clippy::too_many_lines)]
     pub fn get_mount_options() -> Vec<FuseMountOption> {
         /// Empty parser
         fn empty_parser(_args: &mut FuseMountArgs, _mount_option: &FuseMountOption, _option: &str) {
@@ -383,15 +635,54 @@ mod param {
             }
         }
 
-        /// Parse fsname
+        /// The value of a key value option, everything after the first `=`
+        fn option_value(option: &str) -> &str {
+            option.split_once('=').map_or("", |(_, value)| value)
+        }
+        /// Parse fsname, the source of the mount in the mount table
         fn parse_fsname(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
-            let name = String::from(option.split('=').last().unwrap_or_else(|| panic!())); //Safe to use unwrap here, becuase option is always valid.
-            copy_slice(
-                CString::new(name)
-                    .unwrap_or_else(|_| panic!("CString::new failed!"))
-                    .as_bytes(),
-                &mut args.fsname,
-            );
+            // the length is checked by the validator
+            copy_name(option_value(option).as_bytes(), &mut args.fsname);
+        }
+        /// Parse volname, the name of the volume shown by Finder
+        fn parse_volname(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
+            copy_name(option_value(option).as_bytes(), &mut args.volname);
+        }
+        /// Parse fssubtype, the sub type id of the filesystem
+        fn parse_fssubtype(
+            args: &mut FuseMountArgs,
+            _mount_option: &FuseMountOption,
+            option: &str,
+        ) {
+            if let Ok(fssubtype) = option_value(option).parse() {
+                args.fssubtype = fssubtype;
+            }
+        }
+        /// Parse blocksize, the block size reported to the kernel
+        fn parse_blocksize(
+            args: &mut FuseMountArgs,
+            _mount_option: &FuseMountOption,
+            option: &str,
+        ) {
+            if let Ok(blocksize) = option_value(option).parse() {
+                args.blocksize = blocksize;
+            }
+        }
+        /// Parse daemon_timeout, the seconds the kernel waits for a reply
+        fn parse_daemon_timeout(
+            args: &mut FuseMountArgs,
+            _mount_option: &FuseMountOption,
+            option: &str,
+        ) {
+            if let Ok(daemon_timeout) = option_value(option).parse() {
+                args.daemon_timeout = daemon_timeout;
+            }
+        }
+        /// Parse iosize, the max size of a read or write
+        fn parse_iosize(args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
+            if let Ok(iosize) = option_value(option).parse() {
+                args.iosize = iosize;
+            }
         }
         /// Match name
         fn name_match(mount_option: &FuseMountOption, option: &str) -> bool {
@@ -410,6 +701,77 @@ mod param {
             let option_regex = Regex::new(regex_str.as_str()).unwrap_or_else(|_| panic!()); //Safe to use unwrap here, becuase regex_str is always valid.
             option_regex.is_match(option)
         }
+        /// Match a key with an id, a 32 bits number
+        fn key_id_match(mount_option: &FuseMountOption, option: &str) -> bool {
+            option.split_once('=').map_or(false, |(key, value)| {
+                mount_option.name.split('=').next() == Some(key) && value.parse::<u32>().is_ok()
+            })
+        }
+        /// Match a key with a 32 bits number within `min..=max`, and a power of
+        /// two if `power_of_two`
+        fn key_number_match(
+            mount_option: &FuseMountOption,
+            option: &str,
+            min: u32,
+            max: u32,
+            power_of_two: bool,
+        ) -> bool {
+            option.split_once('=').map_or(false, |(key, value)| {
+                mount_option.name.split('=').next() == Some(key)
+                    && value.parse::<u32>().map_or(false, |number| {
+                        min <= number
+                            && number <= max
+                            && (!power_of_two || number.is_power_of_two())
+                    })
+            })
+        }
+        /// Match blocksize, a power of two within the limits of osxfuse
+        fn blocksize_match(mount_option: &FuseMountOption, option: &str) -> bool {
+            key_number_match(
+                mount_option,
+                option,
+                FUSE_MIN_BLOCKSIZE,
+                FUSE_MAX_BLOCKSIZE,
+                true,
+            )
+        }
+        /// Match daemon_timeout, the seconds within the limits of osxfuse
+        fn daemon_timeout_match(mount_option: &FuseMountOption, option: &str) -> bool {
+            key_number_match(
+                mount_option,
+                option,
+                FUSE_MIN_DAEMON_TIMEOUT,
+                FUSE_MAX_DAEMON_TIMEOUT,
+                false,
+            )
+        }
+        /// Match iosize, a power of two within the limits of osxfuse
+        fn iosize_match(mount_option: &FuseMountOption, option: &str) -> bool {
+            key_number_match(mount_option, option, FUSE_MIN_IOSIZE, FUSE_MAX_IOSIZE, true)
+        }
+        /// Match a key with a name fitting the mount args along with its nul,
+        /// the name may hold any character but nul, e.g. spaces or non-ASCII
+        /// characters, its length is counted in bytes
+        fn key_name_match(mount_option: &FuseMountOption, option: &str) -> bool {
+            option.split_once('=').map_or(false, |(key, value)| {
+                mount_option.name.split('=').next() == Some(key)
+                    && !value.is_empty()
+                    && value.len() < MAXPATHLEN
+                    && !value.contains('\0')
+            })
+        }
+        /// Match an option passed to the kernel as is
+        fn kernel_match(_mount_option: &FuseMountOption, option: &str) -> bool {
+            option
+                .strip_prefix(KERNEL_OPTION_PREFIX)
+                .map_or(false, |kernel_option| {
+                    !kernel_option.is_empty() && !kernel_option.contains(char::is_whitespace)
+                })
+        }
+        /// Ignore an option passed to the kernel as is, the macOS mount args have no room for it
+        fn parse_kernel(_args: &mut FuseMountArgs, _mount_option: &FuseMountOption, option: &str) {
+            warn!("ignoring mount option {:?}, not supported on macOS", option);
+        }
 
         vec![
             FuseMountOption {
@@ -426,10 +788,66 @@ mod param {
                 flag: None,
                 fuse_flag: Some(FUSE_MOPT_ALLOW_OTHER),
             },
+            FuseMountOption {
+                name: String::from("noappledouble"),
+                parser: parse_fuse_flag,
+                validator: name_match,
+                flag: None,
+                fuse_flag: Some(FUSE_MOPT_NO_APPLEDOUBLE),
+            },
+            FuseMountOption {
+                name: String::from("noapplexattr"),
+                parser: parse_fuse_flag,
+                validator: name_match,
+                flag: None,
+                fuse_flag: Some(FUSE_MOPT_NO_APPLEXATTR),
+            },
             FuseMountOption {
                 name: String::from("fsname=<name>"),
                 parser: parse_fsname,
-                validator: key_value_match,
+                validator: key_name_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: String::from("volname=<name>"),
+                parser: parse_volname,
+                validator: key_name_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: String::from("blocksize=<n>"),
+                parser: parse_blocksize,
+                validator: blocksize_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: String::from("daemon_timeout=<n>"),
+                parser: parse_daemon_timeout,
+                validator: daemon_timeout_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: String::from("iosize=<n>"),
+                parser: parse_iosize,
+                validator: iosize_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: String::from("fssubtype=<n>"),
+                parser: parse_fssubtype,
+                validator: key_id_match,
+                flag: None,
+                fuse_flag: None,
+            },
+            FuseMountOption {
+                name: format!("{}<option>", KERNEL_OPTION_PREFIX),
+                parser: parse_kernel,
+                validator: kernel_match,
                 flag: None,
                 fuse_flag: None,
             },
@@ -454,29 +872,23 @@ mod param {
 
             let mut args = Self {
                 mntpath: [0_u8; MAXPATHLEN],
-                fsname: [0_u8; MAXPATHLEN],
-                fstypename: [0_u8; MFSTYPENAMELEN],
-                volname: [0_u8; MAXPATHLEN],
+                fsname: fsname_slice,
+                fstypename: fstypename_slice,
+                volname: volname_slice,
                 altflags: 0_u64,
-                blocksize: 0_u32,
-                daemon_timeout: 0_u32,
+                blocksize: FUSE_DEFAULT_BLOCKSIZE,
+                daemon_timeout: FUSE_DEFAULT_DAEMON_TIMEOUT,
                 fsid: 0_u32,
-                fssubtype: 0_u32,
-                iosize: 0_u32,
+                fssubtype: FUSE_FSSUBTYPE_UNKNOWN,
+                iosize: FUSE_DEFAULT_IOSIZE,
                 random: 0_u32,
                 rdev: 0_u32,
             };
 
             let mount_options_map = super::get_mount_options_map();
             options.iter().for_each(|op| {
-                let key = op
-                    .split('=')
-                    .collect::<Vec<_>>()
-                    .get(0)
-                    .unwrap_or_else(|| panic!("Indexing is out of bounds"))
-                    .to_owned()
-                    .to_string();
-                let option = mount_options_map.get(&key).unwrap_or_else(|| panic!()); // Safe to use unwrap here, because key always exists
+                let key = super::option_key(op);
+                let option = mount_options_map.get(key).unwrap_or_else(|| panic!()); // Safe to use unwrap here, because key always exists
                 (option.parser)(&mut args, option, op)
             });
             args
@@ -506,6 +918,18 @@ mod param {
             .copy_from_slice(from);
     }
 
+    /// Copy a name into a nul terminated field of the mount args, replacing
+    /// the name there, `false` if it does not fit along with its nul or holds
+    /// a nul itself
+    pub fn copy_name(name: &[u8], to: &mut [u8]) -> bool {
+        if name.len() >= to.len() || name.contains(&0) {
+            return false;
+        }
+        to.iter_mut().for_each(|byte| *byte = 0);
+        copy_slice(name, to);
+        true
+    }
+
     /// Parse mount flag
     pub fn parse_mount_flag(options: &[&str]) -> i32 {
         let mut flag: i32 = 0;
@@ -523,31 +947,52 @@ mod param {
     }
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+/// Direct umount
+fn direct_umount(mount_point: &Path, flags: UnmountFlags) -> i32 {
+    // the path must be nul terminated
+    let mntpath = syscall::c_path(mount_point).unwrap_or_else(|_| panic!("CString::new failed"));
+    let mut umount_flags = 0;
+    if flags.contains(UnmountFlags::FORCE) {
+        umount_flags |= MNT_FORCE;
+    }
+    if flags.contains(UnmountFlags::LAZY) {
+        umount_flags |= MNT_DETACH;
+    }
+    syscall::umount(&mntpath, umount_flags).map_or(-1, |()| 0)
+}
+
 #[cfg(target_os = "linux")]
-/// Umount
-pub fn umount(short_path: &Path) -> i32 {
+/// Umount, fusermount cannot force unmount, so non-root users get a lazy
+/// unmount instead when asking for a forced one
+pub fn umount(short_path: &Path, flags: UnmountFlags) -> i32 {
     use nix::unistd;
     use std::process::Command;
 
     let mntpnt = short_path.as_os_str();
 
     if unistd::geteuid().is_root() {
-        // direct umount
-        #[allow(unsafe_code)]
-        #[cfg(target_arch = "aarch64")]
-        let result = unsafe { libc::umount2(conversion::cast_to_ptr(mntpnt), MNT_FORCE) };
-        #[allow(unsafe_code)]
-        #[cfg(target_arch = "x86_64")]
-        let result = unsafe { libc::umount2(conversion::cast_to_ptr(mntpnt), MNT_FORCE) };
-
-        result
+        direct_umount(short_path, flags)
     } else {
         // use fusermount to umount
-        let umount_handle = Command::new("fusermount")
-            .arg("-uz") // lazy umount
+        let umount_arg = if flags == UnmountFlags::empty() {
+            "-u"
+        } else {
+            "-uz" // lazy umount
+        };
+        // fails rather than panics, the daemon may be sandboxed after
+        // dropping the privileges
+        let umount_handle = match Command::new("fusermount")
+            .arg(umount_arg)
             .arg(mntpnt)
             .output()
-            .unwrap_or_else(|_| panic!("fusermount command failed to start"));
+        {
+            Ok(handle) => handle,
+            Err(e) => {
+                error!("fusermount command failed to start, the error is: {}", e);
+                return -1;
+            }
+        };
         if umount_handle.status.success() {
             0
         } else {
@@ -569,29 +1014,173 @@ pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
         direct_mount(mount_point, options)
     } else {
         // use fusermount to mount
-        fuser_mount(mount_point, options)
+        fuser_mount(mount_point, options).unwrap_or_else(|e| {
+            error!("failed to mount {:?}, {}", mount_point, e);
+            // the caller reports the failure by errno
+            syscall::set_errno(e.errno());
+            -1
+        })
+    }
+}
+
+#[cfg(target_os = "android")]
+/// Umount, there is no fusermount on Android
+pub fn umount(mount_point: &Path, flags: UnmountFlags) -> i32 {
+    direct_umount(mount_point, flags)
+}
+
+#[cfg(target_os = "android")]
+/// Mount, there is no fusermount on Android, so the mount is always direct
+/// and needs the privilege to mount, e.g. root or `CAP_SYS_ADMIN`
+pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
+    direct_mount(mount_point, options)
+}
+
+/// Time fusermount may take to mount and send the fd of the device
+#[cfg(target_os = "linux")]
+const FUSERMOUNT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of the retries of a fusermount handshake failing for a transient reason
+#[cfg(target_os = "linux")]
+const FUSERMOUNT_RETRIES: u32 = 2;
+/// Time to wait before retrying a fusermount handshake
+#[cfg(target_os = "linux")]
+const FUSERMOUNT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The failure of mounting by fusermount
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+enum FusermountError {
+    /// fusermount is not installed or not in `PATH`
+    NotFound,
+    /// fusermount may not be run or may not mount, along with its message
+    PermissionDenied(String),
+    /// fusermount failed with its exit code and message
+    Failed(Option<i32>, String),
+    /// fusermount did not end in time
+    TimedOut,
+    /// fusermount ended without sending the fd of the device
+    NoFd,
+    /// The socket of the handshake failed
+    Socket(nix::Error),
+    /// fusermount could not be run or waited for
+    Io(io::Error),
+}
+
+#[cfg(target_os = "linux")]
+impl FusermountError {
+    /// The error of fusermount exiting with `code` and printing `message`
+    fn from_exit(code: Option<i32>, message: &str) -> Self {
+        let message = message.trim().to_owned();
+        // fusermount prints the strerror of the failed syscalls
+        if message.contains("Permission denied")
+            || message.contains("Operation not permitted")
+            || message.contains("user_allow_other")
+        {
+            Self::PermissionDenied(message)
+        } else {
+            Self::Failed(code, message)
+        }
+    }
+
+    /// The error of fusermount failing to start
+    fn from_spawn(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            io::ErrorKind::PermissionDenied => Self::PermissionDenied(err.to_string()),
+            _ => Self::Io(err),
+        }
+    }
+
+    /// The errno reporting the error
+    fn errno(&self) -> errno::Errno {
+        match *self {
+            Self::NotFound => errno::Errno::ENOENT,
+            Self::PermissionDenied(_) => errno::Errno::EPERM,
+            Self::TimedOut => errno::Errno::ETIMEDOUT,
+            Self::Failed(..) | Self::NoFd => errno::Errno::EIO,
+            Self::Socket(ref err) => err.as_errno().unwrap_or(errno::Errno::EIO),
+            Self::Io(ref err) => err
+                .raw_os_error()
+                .map_or(errno::Errno::EIO, errno::Errno::from_i32),
+        }
+    }
+
+    /// Whether the handshake may succeed if retried
+    const fn is_transient(&self) -> bool {
+        matches!(*self, Self::TimedOut | Self::NoFd)
     }
 }
 
 #[cfg(target_os = "linux")]
-/// Fusermount
-fn fuser_mount(mount_point: &Path, options: &[&str]) -> RawFd {
-    use nix::cmsg_space;
-    use nix::sys::socket::{
-        self, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType,
-    };
-    use nix::sys::uio::IoVec;
-    use std::process::Command;
+impl fmt::Display for FusermountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::NotFound => write!(
+                f,
+                "fusermount is not found, install the package of FUSE providing it or mount as root"
+            ),
+            Self::PermissionDenied(ref message) => write!(
+                f,
+                "fusermount is not permitted to mount, check it is setuid root and the user may \
+                 mount there: {}",
+                message
+            ),
+            Self::Failed(code, ref message) => {
+                write!(
+                    f,
+                    "fusermount failed with exit code {:?}: {}",
+                    code, message
+                )
+            }
+            Self::TimedOut => write!(f, "fusermount did not end within {:?}", FUSERMOUNT_TIMEOUT),
+            Self::NoFd => write!(f, "fusermount did not send the fd of the FUSE device"),
+            Self::Socket(ref err) => {
+                write!(
+                    f,
+                    "the socket to receive the fd from fusermount failed: {}",
+                    err
+                )
+            }
+            Self::Io(ref err) => write!(f, "failed to run fusermount: {}", err),
+        }
+    }
+}
 
-    let args = FuseMountArgs::parse(options);
+/// Escape the separators of the fields of fstab and mtab in a path as they are
+/// in `/proc/mounts`, in octal
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn escape_mount_path(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\\' => escaped.push_str(&format!("\\{:03o}", u32::from(c))),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
 
-    let (local, remote) = socket::socketpair(
-        AddressFamily::Unix,
-        SockType::Stream,
-        None,
-        SockFlag::empty(),
-    )
-    .unwrap_or_else(|_| panic!("failed to create socket pair"));
+/// The ids of the mounts at the mount point in `/proc/self/mountinfo`, the
+/// topmost last
+#[cfg(target_os = "linux")]
+fn mount_ids(mount_point: &Path) -> io::Result<Vec<u64>> {
+    let path = fs::canonicalize(mount_point).unwrap_or_else(|_| mount_point.to_path_buf());
+    let escaped = escape_mount_path(&path.to_string_lossy());
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(mountinfo
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let id = fields.next()?.parse().ok()?;
+            (fields.nth(3)? == escaped).then_some(id)
+        })
+        .collect())
+}
+
+#[cfg(target_os = "linux")]
+/// Fusermount, the handshake is retried if it fails for a transient reason
+fn fuser_mount(mount_point: &Path, options: &[&str]) -> Result<RawFd, FusermountError> {
+    let args = FuseMountArgs::parse(options);
 
     // Default options
     let mut opts = String::from("nosuid,nodev,noexec,nonempty");
@@ -612,37 +1201,135 @@ fn fuser_mount(mount_point: &Path, options: &[&str]) -> RawFd {
         opts.push_str(s);
     }
 
-    let mount_handle = Command::new("fusermount")
+    let mut retries = 0_u32;
+    loop {
+        let mounted_before = mount_ids(mount_point).unwrap_or_default();
+        match fuser_mount_once(mount_point, &opts) {
+            Err(e) if e.is_transient() && retries < FUSERMOUNT_RETRIES => {
+                retries = retries.overflow_add(1);
+                warn!(
+                    "fusermount handshake of {:?} failed, retry {}: {}",
+                    mount_point, retries, e
+                );
+                // fusermount may have mounted before failing to hand over the fd,
+                // only a mount on top added by this attempt is undone
+                let mounted = mount_ids(mount_point).unwrap_or_default();
+                if mounted
+                    .last()
+                    .map_or(false, |id| !mounted_before.contains(id))
+                {
+                    let _res = umount(mount_point, UnmountFlags::LAZY);
+                }
+                thread::sleep(FUSERMOUNT_RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Run fusermount with `opts` and receive the fd of the device from it
+fn fuser_mount_once(mount_point: &Path, opts: &str) -> Result<RawFd, FusermountError> {
+    use nix::fcntl::{FcntlArg, FdFlag};
+    use nix::sys::socket::{self, AddressFamily, SockFlag, SockType};
+    use nix::unistd;
+    use std::process::{Command, Stdio};
+
+    let (local, remote) = socket::socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::empty(),
+    )
+    .map_err(FusermountError::Socket)?;
+    // only the remote end is for fusermount
+    if let Err(e) = fcntl::fcntl(local, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        unistd::close(local).unwrap_or(());
+        unistd::close(remote).unwrap_or(());
+        return Err(FusermountError::Socket(e));
+    }
+
+    let child = Command::new("fusermount")
         .arg("-o")
-        .arg(&opts[..])
+        .arg(opts)
         .arg(mount_point.as_os_str())
         .env("_FUSE_COMMFD", remote.to_string())
-        .output()
-        .unwrap_or_else(|_| panic!("fusermount command failed to start"));
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    unistd::close(remote).unwrap_or(());
+
+    // once fusermount ends the socket has the fd if it sent any, and never blocks
+    let res = child
+        .map_err(FusermountError::from_spawn)
+        .and_then(wait_fusermount)
+        .and_then(|()| receive_fd(local));
+    unistd::close(local).unwrap_or(());
+    res
+}
 
-    assert!(mount_handle.status.success());
+#[cfg(target_os = "linux")]
+/// Wait for fusermount to end within the timeout, killing it if not
+fn wait_fusermount(mut child: Child) -> Result<(), FusermountError> {
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait().map_err(FusermountError::Io)? {
+            Some(status) => break status,
+            None if start.elapsed() < FUSERMOUNT_TIMEOUT => {
+                thread::sleep(Duration::from_millis(10));
+            }
+            None => {
+                child.kill().unwrap_or(());
+                let _status = child.wait();
+                return Err(FusermountError::TimedOut);
+            }
+        }
+    };
+    if status.success() {
+        return Ok(());
+    }
+    let mut message = String::new();
+    if let Some(mut stderr) = child.stderr.take() {
+        let _size = stderr.read_to_string(&mut message);
+    }
+    debug!("fusermount failed to mount: {}", message);
+    Err(FusermountError::from_exit(status.code(), &message))
+}
+
+#[cfg(target_os = "linux")]
+/// Receive the fd of the device sent by fusermount over the socket
+fn receive_fd(local: RawFd) -> Result<RawFd, FusermountError> {
+    use nix::cmsg_space;
+    use nix::sys::socket::{self, ControlMessageOwned, MsgFlags};
+    use nix::sys::uio::IoVec;
+    use nix::unistd;
 
     let mut buf = [0_u8; 5];
     let iov = [IoVec::from_mut_slice(&mut buf[..])];
     #[allow(clippy::integer_arithmetic)]
     let mut cmsgspace = cmsg_space!([RawFd; 1]);
-    let msg = socket::recvmsg(local, &iov, Some(&mut cmsgspace), MsgFlags::empty())
-        .unwrap_or_else(|_| panic!("failed to receive from fusermount"));
-
-    let mut mount_fd = -1;
-    for cmsg in msg.cmsgs() {
-        if let ControlMessageOwned::ScmRights(fd) = cmsg {
-            assert_eq!(fd.len(), 1);
-            mount_fd = *fd.get(0).unwrap_or_else(|| panic!());
-        } else {
-            panic!("unexpected cmsg");
-        }
+    let msg = socket::recvmsg(
+        local,
+        &iov,
+        Some(&mut cmsgspace),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )
+    .map_err(FusermountError::Socket)?;
+
+    let mut fds = msg.cmsgs().flat_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmRights(fds) => fds,
+        _ => Vec::new(),
+    });
+    let mount_fd = fds.next().ok_or(FusermountError::NoFd)?;
+    // only one fd is expected, close the others if any
+    for fd in fds {
+        unistd::close(fd).unwrap_or(());
     }
-
-    mount_fd
+    Ok(mount_fd)
 }
 
-#[cfg(target_os = "linux")]
+#[cfg(any(target_os = "linux", target_os = "android"))]
 /// Direct mount
 fn direct_mount(mount_point: &Path, options: &[&str]) -> RawFd {
     use nix::sys::stat::SFlag;
@@ -652,7 +1339,7 @@ fn direct_mount(mount_point: &Path, options: &[&str]) -> RawFd {
     let devpath = Path::new("/dev/fuse");
 
     let dev_fd: RawFd;
-    let result = fcntl::open(devpath, OFlag::O_RDWR, Mode::empty());
+    let result = fcntl::open(devpath, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty());
     match result {
         Ok(fd) => {
             debug!("open fuse device successfully");
@@ -715,40 +1402,27 @@ fn direct_mount(mount_point: &Path, options: &[&str]) -> RawFd {
     let opts = CString::new(&*opts).unwrap_or_else(|_| panic!("CString::new failed"));
     let flag = MS_NOSUID | MS_NODEV | args.get_flags();
     debug!("direct mount opts: {:?}", &opts);
-    #[allow(unsafe_code)]
-    unsafe {
-        let result = libc::mount(
-            fsname.as_ptr(),
-            mntpath.as_ptr(),
-            fstype.as_ptr(),
-            flag,
-            opts.as_ptr().cast(),
-        );
-        if result == 0 {
-            debug!("mount {:?} to {:?} successfully!", mntpath, devpath);
-            dev_fd
-        } else {
-            let e = Errno::from_i32(errno::errno());
-            debug!("errno={}, {:?}", errno::errno(), e);
-            let mount_fail_str = "mount failed!";
-            #[cfg(target_arch = "aarch64")]
-            libc::perror(mount_fail_str.as_ptr());
-            #[cfg(target_arch = "x86_64")]
-            libc::perror(mount_fail_str.as_ptr().cast());
-
-            -1
-        }
+    if let Err(e) = syscall::mount(&fsname, &mntpath, &fstype, flag, &opts) {
+        debug!("errno={}, {:?}", errno::errno(), e);
+        syscall::perror("mount failed!");
+        -1
+    } else {
+        debug!("mount {:?} to {:?} successfully!", mntpath, devpath);
+        dev_fd
     }
 }
 
 #[cfg(any(target_os = "macos"))]
-/// Umount
-pub fn umount(mount_point: &Path) -> i32 {
-    let mntpnt = mount_point.as_os_str();
-    #[allow(unsafe_code)]
-    unsafe {
-        libc::unmount(conversion::cast_to_ptr(mntpnt), MNT_FORCE)
-    }
+/// Umount, lazy unmount is not supported
+pub fn umount(mount_point: &Path, flags: UnmountFlags) -> i32 {
+    // the path must be nul terminated
+    let mntpath = syscall::c_path(mount_point).unwrap_or_else(|_| panic!("CString::new failed"));
+    let umount_flags = if flags.contains(UnmountFlags::FORCE) {
+        MNT_FORCE
+    } else {
+        0
+    };
+    syscall::umount(&mntpath, umount_flags).map_or(-1, |()| 0)
 }
 
 #[cfg(any(target_os = "macos"))]
@@ -757,7 +1431,7 @@ pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
     let mut args = FuseMountArgs::parse(options);
     let devpath = Path::new("/dev/osxfuse1");
     let fd: RawFd;
-    let res = fcntl::open(devpath, OFlag::O_RDWR, Mode::empty());
+    let res = fcntl::open(devpath, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty());
     match res {
         Ok(f) => {
             fd = f;
@@ -793,25 +1467,39 @@ pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
     if result == 0 {
         debug!("successfully read drandom={}", drandom);
     } else {
-        let ioctl_fail_str = "ioctl read random secret failed!";
-        #[allow(unsafe_code)]
-        unsafe {
-            libc::perror(ioctl_fail_str.as_ptr().cast());
-        }
+        syscall::perror("ioctl read random secret failed!");
         return -1;
     }
 
-    let full_path = fs::canonicalize(mount_point)
-        .unwrap_or_else(|_| panic!("fail to get full path of mount point"));
-    let cstr_path = full_path
-        .to_str()
-        .unwrap_or_else(|| panic!("full mount path to string failed"));
-
-    let mntpath = CString::new(cstr_path).unwrap_or_else(|_| panic!("CString::new failed"));
+    let full_path = match fs::canonicalize(mount_point) {
+        Ok(full_path) => full_path,
+        Err(e) => {
+            error!(
+                "failed to get the full path of mount point {:?}, {}",
+                mount_point, e
+            );
+            return -1;
+        }
+    };
+    let mntpath = match syscall::c_path(&full_path) {
+        Ok(mntpath) => mntpath,
+        Err(e) => {
+            error!("invalid mount point {:?}, {}", full_path, e);
+            return -1;
+        }
+    };
     let fstype = CString::new("osxfuse").unwrap_or_else(|_| panic!("CString::new failed"));
 
     let mut mntpath_slice = [0_u8; MAXPATHLEN];
-    copy_slice(mntpath.as_bytes(), &mut mntpath_slice);
+    if !copy_name(mntpath.as_bytes(), &mut mntpath_slice) {
+        error!(
+            "the full path of mount point {:?} takes {} bytes, it must be less than {}",
+            full_path,
+            mntpath.as_bytes().len(),
+            MAXPATHLEN,
+        );
+        return -1;
+    }
 
     args.set_mntpath(mntpath_slice);
     args.set_random(drandom);
@@ -822,24 +1510,126 @@ pub fn mount(mount_point: &Path, options: &[&str]) -> RawFd {
     let parsed_flag = parse_mount_flag(options);
     flag |= parsed_flag;
 
-    #[allow(unsafe_code)]
-    unsafe {
-        let mount_result = libc::mount(
-            fstype.as_ptr(),
-            mntpath.as_ptr(),
-            flag,
-            conversion::cast_to_mut_ptr(&mut args),
+    if let Err(e) = syscall::mount(&fstype, &mntpath, flag, &mut args) {
+        debug!("errno={}, {:?}", errno::errno(), e);
+        syscall::perror("mount failed!");
+        -1
+    } else {
+        debug!("mount {:?} to {:?} successfully!", mntpath, devpath);
+        fd
+    }
+}
+
+#[cfg(test)]
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod test {
+    #[cfg(target_os = "linux")]
+    use super::FusermountError;
+    use super::{mount_options_info, options_validator, FuseMountArgs, MOUNT_OPTION_DOCS};
+
+    #[test]
+    fn test_mount_options_info() {
+        let options = mount_options_info();
+        assert!(options
+            .iter()
+            .all(|op| !op.description.is_empty() && op.platforms.contains(&"linux")));
+        assert!(options.iter().any(|op| op.name == "fsname=<name>"));
+        // the table of this platform has every option described for it
+        let supported = MOUNT_OPTION_DOCS
+            .iter()
+            .filter(|doc| doc.platforms.contains(&std::env::consts::OS))
+            .count();
+        assert_eq!(options.len(), supported);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_escape_mount_path() {
+        use super::escape_mount_path;
+
+        assert_eq!(escape_mount_path("/mnt/plain"), "/mnt/plain");
+        assert_eq!(
+            escape_mount_path("/mnt/a b\tc\nd\\e"),
+            "/mnt/a\\040b\\011c\\012d\\134e"
         );
-        if mount_result == 0 {
-            debug!("mount {:?} to {:?} successfully!", mntpath, devpath);
-            fd
-        } else {
-            let e = Errno::from_i32(errno::errno());
-            debug!("errno={}, {:?}", errno::errno(), e);
-            let mount_fail_str = "mount failed!";
-            libc::perror(mount_fail_str.as_ptr().cast());
+    }
 
-            -1
-        }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_mount_ids() {
+        use super::mount_ids;
+        use std::path::Path;
+
+        let dir = Path::new("/tmp/sync_fuse_test_mount_ids");
+        std::fs::create_dir_all(dir).unwrap_or_else(|_| panic!());
+        assert!(!mount_ids(Path::new("/"))
+            .unwrap_or_else(|_| panic!())
+            .is_empty());
+        assert!(mount_ids(dir).unwrap_or_else(|_| panic!()).is_empty());
+        std::fs::remove_dir(dir).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_kernel_options() {
+        assert!(options_validator("ro,kernel:max_read=65536").is_ok());
+        assert!(options_validator("kernel:").is_err());
+        assert!(options_validator("max_read=65536").is_err());
+
+        let args = FuseMountArgs::parse(&["allow_other", "kernel:max_read=65536", "ro"]);
+        assert_eq!(
+            args.get_kernel_opts().map(String::as_str),
+            Some("allow_other,max_read=65536")
+        );
+        assert_eq!(args.get_fusermount_opts().map(String::as_str), Some("ro"));
+    }
+
+    #[test]
+    fn test_name_options() {
+        assert!(options_validator("fsname=/srv/data,subtype=memfs").is_ok());
+        assert!(options_validator("subtype=").is_err());
+
+        let args = FuseMountArgs::parse(&["fsname=/srv/a=b,c\\d", "subtype=memfs"]);
+        assert_eq!(args.get_fsname().map(String::as_str), Some("/srv/a=b,c\\d"));
+        assert_eq!(args.get_subtype().map(String::as_str), Some("memfs"));
+        // escaped for fusermount, which splits the options at the commas
+        assert_eq!(
+            args.get_fusermount_opts().map(String::as_str),
+            Some("fsname=/srv/a=b\\,c\\\\d")
+        );
+        assert_eq!(
+            args.get_subtype_opt().map(String::as_str),
+            Some("subtype=memfs")
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_fusermount_error() {
+        use nix::errno::Errno;
+        use std::io;
+
+        let err = FusermountError::from_exit(
+            Some(1),
+            "fusermount: failed to open /etc/fuse.conf: Permission denied\n",
+        );
+        assert_eq!(err.errno(), Errno::EPERM);
+        assert!(!err.is_transient());
+        assert!(err
+            .to_string()
+            .ends_with("/etc/fuse.conf: Permission denied"));
+        let err = FusermountError::from_exit(Some(1), "fusermount: bad mount point /x\n");
+        assert_eq!(err.errno(), Errno::EIO);
+        assert_eq!(
+            err.to_string(),
+            "fusermount failed with exit code Some(1): fusermount: bad mount point /x"
+        );
+
+        let err = FusermountError::from_spawn(io::Error::from(io::ErrorKind::NotFound));
+        assert_eq!(err.errno(), Errno::ENOENT);
+        assert!(!err.is_transient());
+        assert!(FusermountError::NoFd.is_transient());
+        assert_eq!(FusermountError::TimedOut.errno(), Errno::ETIMEDOUT);
+        let err = FusermountError::Socket(nix::Error::Sys(Errno::EMFILE));
+        assert_eq!(err.errno(), Errno::EMFILE);
     }
 }
@@ -0,0 +1,260 @@
+//! Results of the init negotiation with the kernel
+//!
+//! The kernel offers the init flags it is capable of, and the filesystem
+//! accepts those it wants among them. A flag wanted but not offered is
+//! silently dropped, so the results are recorded to let users confirm
+//! which capabilities, e.g. big writes, actually got enabled.
+
+use std::convert::TryInto;
+use std::fmt;
+#[cfg(feature = "abi-7-11")]
+use std::mem;
+
+/// The names of the init flags by bit shared by the platforms, as in
+/// `include/uapi/linux/fuse.h`
+const COMMON_INIT_FLAG_NAMES: [&str; 27] = [
+    "ASYNC_READ",
+    "POSIX_LOCKS",
+    "FILE_OPS",
+    "ATOMIC_O_TRUNC",
+    "EXPORT_SUPPORT",
+    "BIG_WRITES",
+    "DONT_MASK",
+    "SPLICE_WRITE",
+    "SPLICE_MOVE",
+    "SPLICE_READ",
+    "FLOCK_LOCKS",
+    "HAS_IOCTL_DIR",
+    "AUTO_INVAL_DATA",
+    "DO_READDIRPLUS",
+    "READDIRPLUS_AUTO",
+    "ASYNC_DIO",
+    "WRITEBACK_CACHE",
+    "NO_OPEN_SUPPORT",
+    "PARALLEL_DIROPS",
+    "HANDLE_KILLPRIV",
+    "POSIX_ACL",
+    "ABORT_ERROR",
+    "MAX_PAGES",
+    "CACHE_SYMLINKS",
+    "NO_OPENDIR_SUPPORT",
+    "EXPLICIT_INVAL_DATA",
+    "MAP_ALIGNMENT",
+];
+
+/// The names of the init flags by bit following the shared ones
+#[cfg(not(target_os = "macos"))]
+const PLATFORM_INIT_FLAG_NAMES: [&str; 4] = [
+    "SUBMOUNTS",
+    "HANDLE_KILLPRIV_V2",
+    "SETXATTR_EXT",
+    "INIT_EXT",
+];
+
+/// The names of the init flags by bit following the shared ones, the high
+/// bits are specific to macOS
+#[cfg(target_os = "macos")]
+const PLATFORM_INIT_FLAG_NAMES: [&str; 5] = [
+    "ALLOCATE",
+    "EXCHANGE_DATA",
+    "CASE_INSENSITIVE",
+    "VOL_RENAME",
+    "XTIMES",
+];
+
+/// The bit of `FUSE_BIG_WRITES`, without it the kernel writes a page at a time
+const BIG_WRITES_FLAG: u32 = 1 << 5;
+
+/// The bit of `FUSE_MAX_PAGES`, without it a request carries 32 pages at most
+const MAX_PAGES_FLAG: u32 = 1 << 22;
+
+/// The size of the writes without `FUSE_BIG_WRITES`
+const PAGE_WRITE_SIZE: u32 = 4096;
+
+/// The size of the writes of 32 pages, the default limit of the kernel
+const DEFAULT_MAX_PAGES_WRITE_SIZE: u32 = 32 * PAGE_WRITE_SIZE;
+
+/// The results of the init negotiation, the same layout is returned by the
+/// ioctl of the filesystems exposing them
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InitInfo {
+    /// ABI major version of the kernel
+    pub kernel_major: u32,
+    /// ABI minor version of the kernel
+    pub kernel_minor: u32,
+    /// Effective ABI major version
+    pub major: u32,
+    /// Effective ABI minor version, the lower of the kernel and this library
+    pub minor: u32,
+    /// Init flags offered by the kernel
+    pub offered_flags: u32,
+    /// Init flags wanted by the filesystem
+    pub wanted_flags: u32,
+    /// Init flags accepted, i.e. both offered and wanted
+    pub flags: u32,
+    /// Max readahead size
+    pub max_readahead: u32,
+    /// Max write size replied to the kernel
+    pub max_write: u32,
+}
+
+impl InitInfo {
+    /// Parse from the bytes returned by `to_bytes`
+    #[cfg(feature = "abi-7-11")]
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        let mut fields = data
+            .chunks_exact(mem::size_of::<u32>())
+            .filter_map(|bytes| bytes.try_into().ok().map(u32::from_ne_bytes));
+        Some(Self {
+            kernel_major: fields.next()?,
+            kernel_minor: fields.next()?,
+            major: fields.next()?,
+            minor: fields.next()?,
+            offered_flags: fields.next()?,
+            wanted_flags: fields.next()?,
+            flags: fields.next()?,
+            max_readahead: fields.next()?,
+            max_write: fields.next()?,
+        })
+    }
+
+    /// Serialize the fields in order in native endian, as returned by the
+    /// ioctl
+    #[cfg(feature = "abi-7-11")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.kernel_major,
+            self.kernel_minor,
+            self.major,
+            self.minor,
+            self.offered_flags,
+            self.wanted_flags,
+            self.flags,
+            self.max_readahead,
+            self.max_write,
+        ]
+        .iter()
+        .flat_map(|field| field.to_ne_bytes().to_vec())
+        .collect()
+    }
+
+    /// The largest write the kernel sends with 4k pages, a page without
+    /// `FUSE_BIG_WRITES` and 32 pages at most without `FUSE_MAX_PAGES`
+    pub fn effective_max_write(&self) -> u32 {
+        if self.flags & BIG_WRITES_FLAG == 0 {
+            PAGE_WRITE_SIZE
+        } else if self.flags & MAX_PAGES_FLAG == 0 {
+            self.max_write.min(DEFAULT_MAX_PAGES_WRITE_SIZE)
+        } else {
+            self.max_write
+        }
+    }
+}
+
+/// The names of the init flags set in `flags` separated by `|`, the unknown
+/// bits are given in hex, `-` if none is set
+pub fn init_flag_names(flags: u32) -> String {
+    let mut names = Vec::new();
+    let mut unknown = 0_u32;
+    for bit in 0..32_u32 {
+        let flag = 1_u32.checked_shl(bit).unwrap_or(0);
+        if flags & flag == 0 {
+            continue;
+        }
+        if let Some(name) = bit.try_into().ok().and_then(|index: usize| {
+            COMMON_INIT_FLAG_NAMES.get(index).or_else(|| {
+                PLATFORM_INIT_FLAG_NAMES.get(index.checked_sub(COMMON_INIT_FLAG_NAMES.len())?)
+            })
+        }) {
+            names.push((*name).to_owned());
+        } else {
+            unknown |= flag;
+        }
+    }
+    if unknown != 0 {
+        names.push(format!("{:#x}", unknown));
+    }
+    if names.is_empty() {
+        "-".to_owned()
+    } else {
+        names.join("|")
+    }
+}
+
+impl fmt::Display for InitInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "ABI {}.{} (kernel {}.{})",
+            self.major, self.minor, self.kernel_major, self.kernel_minor
+        )?;
+        writeln!(f, "offered flags: {}", init_flag_names(self.offered_flags))?;
+        writeln!(f, "accepted flags: {}", init_flag_names(self.flags))?;
+        writeln!(
+            f,
+            "wanted but not offered flags: {}",
+            init_flag_names(self.wanted_flags & !self.offered_flags)
+        )?;
+        writeln!(f, "max readahead: {}", self.max_readahead)?;
+        write!(
+            f,
+            "max write: {} (effective {})",
+            self.max_write,
+            self.effective_max_write()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{init_flag_names, InitInfo};
+
+    #[test]
+    fn test_init_info() {
+        assert_eq!(init_flag_names(0), "-");
+        assert_eq!(init_flag_names(0b10_0001), "ASYNC_READ|BIG_WRITES");
+        assert_eq!(init_flag_names(1 << 26), "MAP_ALIGNMENT");
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(init_flag_names(1 << 27 | 1 << 30), "SUBMOUNTS|INIT_EXT");
+        #[cfg(target_os = "macos")]
+        assert_eq!(init_flag_names(1 << 27 | 1 << 31), "ALLOCATE|XTIMES");
+        #[cfg(not(target_os = "macos"))]
+        assert_eq!(init_flag_names(1 << 31 | 1), "ASYNC_READ|0x80000000");
+
+        let info = InitInfo {
+            kernel_major: 7,
+            kernel_minor: 31,
+            major: 7,
+            minor: 12,
+            offered_flags: 0b11,
+            wanted_flags: 0b10_0011,
+            flags: 0b11,
+            max_readahead: 0x2_0000,
+            max_write: 0x100_0000,
+        };
+        #[cfg(feature = "abi-7-11")]
+        {
+            assert_eq!(InitInfo::from_bytes(&info.to_bytes()), Some(info));
+            assert_eq!(InitInfo::from_bytes(&[0; 4]), None);
+        }
+        assert_eq!(info.effective_max_write(), 4096);
+        let big_writes = InitInfo {
+            flags: 0b10_0011,
+            ..info
+        };
+        assert_eq!(big_writes.effective_max_write(), 0x2_0000);
+        assert_eq!(
+            info.to_string(),
+            "ABI 7.12 (kernel 7.31)\n\
+             offered flags: ASYNC_READ|POSIX_LOCKS\n\
+             accepted flags: ASYNC_READ|POSIX_LOCKS\n\
+             wanted but not offered flags: BIG_WRITES\n\
+             max readahead: 131072\n\
+             max write: 16777216 (effective 4096)"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Kernel notifications
+//!
+//! Unsolicited messages sent to the FUSE kernel driver to invalidate its caches
+//! of nodes changed behind its back. A notification must not be sent while the
+//! kernel waits for the reply of a request on the same node, it may deadlock,
+//! so filesystems send them after replying.
+
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+
+use super::abi::{
+    fuse_notify_code, fuse_notify_inval_entry_out, fuse_notify_inval_inode_out, fuse_out_header,
+};
+use super::channel::FuseChannelSender;
+use super::reply::as_bytes;
+use super::{Cast, OverflowArithmetic};
+
+/// Notifier sending notifications to the kernel through the channel
+#[derive(Clone, Copy, Debug)]
+pub struct Notifier {
+    /// Channel sender
+    ch: FuseChannelSender,
+}
+
+impl Notifier {
+    /// Create a notifier sending through the given channel
+    pub(crate) const fn new(ch: FuseChannelSender) -> Self {
+        Self { ch }
+    }
+
+    /// Invalidate the cached attributes of the i-node and its data in the
+    /// range from `offset` of `len` byte, a zero `len` means to the end of
+    /// the data and a negative `offset` only invalidates the attributes.
+    /// Fails with `ENOENT` if the kernel does not cache the i-node.
+    pub fn inval_inode(self, ino: u64, offset: i64, len: i64) -> io::Result<()> {
+        let arg = fuse_notify_inval_inode_out {
+            ino,
+            off: offset,
+            len,
+        };
+        as_bytes(&arg, |bytes| {
+            self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_INODE, bytes)
+        })
+    }
+
+    /// Invalidate the entry of `name` under the directory of `parent` and
+    /// the attributes of the directory. Fails with `ENOENT` if the kernel
+    /// does not cache the entry.
+    pub fn inval_entry(self, parent: u64, name: &OsStr) -> io::Result<()> {
+        let arg = fuse_notify_inval_entry_out {
+            parent,
+            namelen: name.len().cast(),
+            padding: 0,
+        };
+        as_bytes(&arg, |bytes| {
+            let mut segments = bytes.to_vec();
+            // the name is null terminated
+            segments.extend_from_slice(&[name.as_bytes(), &[0]]);
+            self.send(fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY, &segments)
+        })
+    }
+
+    /// Send the notification of `code`, notifications are distinguished from
+    /// replies by a zero unique id and carry their code in the error field
+    fn send(self, code: fuse_notify_code, bytes: &[&[u8]]) -> io::Result<()> {
+        let len = bytes.iter().fold(0, |l, b| l.overflow_add(b.len()));
+        let header = fuse_out_header {
+            len: (mem::size_of::<fuse_out_header>().overflow_add(len)).cast(),
+            #[allow(clippy::as_conversions)] // the codes are a fieldless enum
+            error: code as i32,
+            unique: 0,
+        };
+        as_bytes(&header, |headerbytes| {
+            let mut segments = headerbytes.to_vec();
+            segments.extend_from_slice(bytes);
+            self.ch.send(&segments)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nix::unistd;
+    use std::ffi::OsStr;
+
+    use super::super::channel::Channel;
+    use super::Notifier;
+
+    #[test]
+    fn notify_inval() {
+        let (read_fd, write_fd) = unistd::pipe().unwrap_or_else(|_| panic!());
+        #[allow(unsafe_code)]
+        let channel = unsafe { Channel::from_fd(write_fd) };
+        let notifier = Notifier::new(channel.sender());
+        notifier
+            .inval_inode(0x1234, -1, 0)
+            .unwrap_or_else(|_| panic!());
+        let mut buf = [0_u8; 64];
+        let size = unistd::read(read_fd, &mut buf).unwrap_or_else(|_| panic!());
+        assert_eq!(
+            buf.get(..size).unwrap_or_else(|| panic!()),
+            [
+                0x28, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+            .as_ref()
+        );
+
+        notifier
+            .inval_entry(0x1234, OsStr::new("ab"))
+            .unwrap_or_else(|_| panic!());
+        let size = unistd::read(read_fd, &mut buf).unwrap_or_else(|_| panic!());
+        assert_eq!(
+            buf.get(..size).unwrap_or_else(|| panic!()),
+            [
+                0x23, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, b'a', b'b', 0x00,
+            ]
+            .as_ref()
+        );
+
+        unistd::close(read_fd).unwrap_or_else(|_| panic!());
+    }
+}
@@ -0,0 +1,394 @@
+//! Preflight checks of mounting
+//!
+//! A mount failing for want of the FUSE module, of the access to `/dev/fuse`,
+//! of `fusermount` or of `user_allow_other` only reports a bare errno. The
+//! preflight checks these prerequisites one by one before the real mount and
+//! tells which of them is missing. The ABI of the kernel is probed by mounting
+//! a scratch directory and reading its INIT request, which is never answered:
+//! the scratch mount is aborted and unmounted right after.
+
+use nix::fcntl::{self, OFlag};
+use nix::sys::stat::Mode;
+use nix::sys::utsname;
+use nix::unistd;
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+use std::process;
+
+use super::abi::{FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION};
+use super::buffer::RequestBuffer;
+use super::channel::Channel;
+use super::ll_request::{Operation, Request};
+
+/// The device of the FUSE driver
+const FUSE_DEVICE: &str = "/dev/fuse";
+/// The config of fusermount
+const FUSE_CONF: &str = "/etc/fuse.conf";
+/// The size of the buffer reading the INIT request, the smallest buffer the
+/// kernel accepts
+const PROBE_BUFFER_SIZE: usize = 8192;
+
+/// The outcome of a check
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CheckStatus {
+    /// The prerequisite is met
+    Pass,
+    /// The mount may work, with limitations
+    Warn,
+    /// The mount is bound to fail
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match *self {
+            Self::Pass => "ok",
+            Self::Warn => "warn",
+            Self::Fail => "FAIL",
+        };
+        // padded along with the other columns of the report
+        f.pad(status)
+    }
+}
+
+/// A check of a prerequisite of mounting
+#[derive(Clone, Debug)]
+pub struct PreflightCheck {
+    /// The prerequisite checked
+    pub name: &'static str,
+    /// The outcome
+    pub status: CheckStatus,
+    /// What was found, or what to do about it
+    pub detail: String,
+}
+
+impl PreflightCheck {
+    /// New check of `name`
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// The checks of the prerequisites of mounting, in the order they are run
+#[derive(Clone, Debug, Default)]
+pub struct PreflightReport {
+    /// The checks
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != CheckStatus::Fail)
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .checks
+            .iter()
+            .map(|check| check.name.len())
+            .max()
+            .unwrap_or(0);
+        for (idx, check) in self.checks.iter().enumerate() {
+            if idx > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{:<width$}  {:<4}  {}",
+                check.name,
+                check.status,
+                check.detail,
+                width = width
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Check the kernel release and whether the FUSE driver is registered
+fn check_kernel() -> PreflightCheck {
+    let uname = utsname::uname();
+    let release = format!("{} {}", uname.sysname(), uname.release());
+    match fs::read_to_string("/proc/filesystems") {
+        Ok(filesystems) if filesystems.lines().any(|line| line.ends_with("\tfuse")) => {
+            PreflightCheck::new("kernel", CheckStatus::Pass, release)
+        }
+        Ok(_) => PreflightCheck::new(
+            "kernel",
+            CheckStatus::Warn,
+            format!(
+                "{}, the fuse module is not loaded yet, it is loaded on the first open of {} or by `modprobe fuse`",
+                release, FUSE_DEVICE
+            ),
+        ),
+        Err(e) => PreflightCheck::new(
+            "kernel",
+            CheckStatus::Warn,
+            format!("{}, failed to read /proc/filesystems: {}", release, e),
+        ),
+    }
+}
+
+/// Check the device of the FUSE driver opens for reading and writing
+fn check_device() -> PreflightCheck {
+    match fcntl::open(FUSE_DEVICE, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty()) {
+        Ok(fd) => {
+            unistd::close(fd).unwrap_or(());
+            PreflightCheck::new("device", CheckStatus::Pass, FUSE_DEVICE)
+        }
+        Err(e) => PreflightCheck::new(
+            "device",
+            CheckStatus::Fail,
+            format!(
+                "cannot open {}: {}, load the fuse module or grant the access to the device",
+                FUSE_DEVICE, e
+            ),
+        ),
+    }
+}
+
+/// The path of the program `name` in `PATH`
+#[cfg(target_os = "linux")]
+fn find_program(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Check the user mounts as root, Android has no fusermount to mount for the
+/// other users
+#[cfg(target_os = "android")]
+fn check_fusermount(root: bool) -> PreflightCheck {
+    if root {
+        PreflightCheck::new(
+            "fusermount",
+            CheckStatus::Pass,
+            "not needed, root mounts directly",
+        )
+    } else {
+        PreflightCheck::new(
+            "fusermount",
+            CheckStatus::Fail,
+            "not available on Android, mount as root",
+        )
+    }
+}
+
+/// Check fusermount is there to mount for a user other than root
+#[cfg(target_os = "linux")]
+fn check_fusermount(root: bool) -> PreflightCheck {
+    if root {
+        return PreflightCheck::new(
+            "fusermount",
+            CheckStatus::Pass,
+            "not needed, root mounts directly",
+        );
+    }
+    let path = match find_program("fusermount") {
+        Some(path) => path,
+        None => {
+            return PreflightCheck::new(
+                "fusermount",
+                CheckStatus::Fail,
+                "not found in PATH, install the package of FUSE providing it or mount as root",
+            )
+        }
+    };
+    match fs::metadata(&path) {
+        Ok(meta) if meta.uid() == 0 && meta.mode() & Mode::S_ISUID.bits() != 0 => {
+            PreflightCheck::new(
+                "fusermount",
+                CheckStatus::Pass,
+                format!("{}, setuid root", path.display()),
+            )
+        }
+        Ok(_) => PreflightCheck::new(
+            "fusermount",
+            CheckStatus::Fail,
+            format!(
+                "{} is not setuid root, it cannot mount for other users",
+                path.display()
+            ),
+        ),
+        Err(e) => PreflightCheck::new(
+            "fusermount",
+            CheckStatus::Fail,
+            format!("cannot stat {}: {}", path.display(), e),
+        ),
+    }
+}
+
+/// Whether the config of fusermount lets the users mount with `allow_other`
+fn allows_other(conf: &str) -> bool {
+    conf.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .any(|line| line == "user_allow_other")
+}
+
+/// Check `allow_other` is permitted if requested
+fn check_allow_other(options: &[&str], root: bool) -> PreflightCheck {
+    if !options.contains(&"allow_other") {
+        return PreflightCheck::new("allow_other", CheckStatus::Pass, "not requested");
+    }
+    if root {
+        return PreflightCheck::new("allow_other", CheckStatus::Pass, "permitted to root");
+    }
+    match fs::read_to_string(FUSE_CONF) {
+        Ok(conf) if allows_other(&conf) => PreflightCheck::new(
+            "allow_other",
+            CheckStatus::Pass,
+            format!("user_allow_other is set in {}", FUSE_CONF),
+        ),
+        Ok(_) => PreflightCheck::new(
+            "allow_other",
+            CheckStatus::Fail,
+            format!("add user_allow_other to {} or mount as root", FUSE_CONF),
+        ),
+        Err(e) => PreflightCheck::new(
+            "allow_other",
+            CheckStatus::Fail,
+            format!(
+                "cannot read {}: {}, it needs user_allow_other for users other than root",
+                FUSE_CONF, e
+            ),
+        ),
+    }
+}
+
+/// Mount the scratch directory and read the ABI version of the INIT request
+fn probe_abi(scratch: &Path) -> Result<(u32, u32), String> {
+    // the channel aborts the connection and unmounts once dropped
+    let ch = Channel::new(scratch, &["fsname=preflight", "subtype=preflight"])
+        .map_err(|e| format!("scratch mount failed: {}", e))?;
+    let mut buffer = RequestBuffer::new(PROBE_BUFFER_SIZE, false);
+    ch.receive(&mut buffer)
+        .map_err(|e| format!("failed to read the INIT request: {}", e))?;
+    let request = Request::try_from(buffer.as_slice())
+        .map_err(|e| format!("failed to parse the INIT request: {}", e))?;
+    match *request.operation() {
+        Operation::Init { arg } => Ok((arg.major, arg.minor)),
+        ref op => Err(format!(
+            "the first request is {} instead of INIT",
+            op.name()
+        )),
+    }
+}
+
+/// Check the ABI of the kernel against the ABI the library is built for
+fn check_abi() -> PreflightCheck {
+    let scratch = env::temp_dir().join(format!("fuse_preflight_{}", process::id()));
+    if let Err(e) = fs::create_dir_all(&scratch) {
+        return PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Warn,
+            format!("cannot create the scratch mount point {:?}: {}", scratch, e),
+        );
+    }
+    let probed = probe_abi(&scratch);
+    fs::remove_dir(&scratch).unwrap_or(());
+    let library = format!("{}.{}", FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION);
+    match probed {
+        Ok((major, minor)) if major != FUSE_KERNEL_VERSION => PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Fail,
+            format!(
+                "kernel {}.{} is incompatible with the library {}",
+                major, minor, library
+            ),
+        ),
+        Ok((major, minor)) if minor < FUSE_KERNEL_MINOR_VERSION => PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Warn,
+            format!(
+                "kernel {}.{} is older than the library {}, the newer features are off",
+                major, minor, library
+            ),
+        ),
+        Ok((major, minor)) => PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Pass,
+            format!("kernel {}.{}, library {}", major, minor, library),
+        ),
+        Err(e) => PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Warn,
+            format!("not probed, {}", e),
+        ),
+    }
+}
+
+/// Check the prerequisites of mounting with `options`: the FUSE driver, the
+/// access to its device, fusermount for the users other than root, the
+/// permission of `allow_other` if requested, and the ABI of the kernel, which
+/// is only probed once the other checks pass
+pub fn preflight(options: &[&str]) -> PreflightReport {
+    let root = unistd::geteuid().is_root();
+    let mut report = PreflightReport {
+        checks: vec![
+            check_kernel(),
+            check_device(),
+            check_fusermount(root),
+            check_allow_other(options, root),
+        ],
+    };
+    if report.passed() {
+        report.checks.push(check_abi());
+    } else {
+        report.checks.push(PreflightCheck::new(
+            "kernel ABI",
+            CheckStatus::Warn,
+            "not probed, a check above failed",
+        ));
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::{allows_other, CheckStatus, PreflightCheck, PreflightReport};
+
+    #[test]
+    fn test_allows_other() {
+        assert!(allows_other("# mount_max = 1000\nuser_allow_other\n"));
+        assert!(allows_other("  user_allow_other # for the shares\n"));
+        assert!(!allows_other("#user_allow_other\n"));
+        assert!(!allows_other(""));
+    }
+
+    #[test]
+    fn test_preflight_report() {
+        let mut report = PreflightReport {
+            checks: vec![
+                PreflightCheck::new("device", CheckStatus::Pass, "/dev/fuse"),
+                PreflightCheck::new("kernel ABI", CheckStatus::Warn, "not probed"),
+            ],
+        };
+        assert!(report.passed());
+        assert_eq!(
+            report.to_string(),
+            "device      ok    /dev/fuse\nkernel ABI  warn  not probed"
+        );
+        report.checks.push(PreflightCheck::new(
+            "allow_other",
+            CheckStatus::Fail,
+            "add user_allow_other",
+        ));
+        assert!(!report.passed());
+    }
+}
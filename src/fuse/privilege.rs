@@ -0,0 +1,111 @@
+//! Dropping the privileges of the daemon
+//!
+//! A daemon started as root to mount directly only needs the privileges for
+//! the mount itself. Once the `/dev/fuse` fd is acquired and the mount is done,
+//! it switches to an unprivileged user and group before serving any request,
+//! so a bug handling a request cannot be turned into root access. The switch
+//! cannot be undone, the unmount is left to `fusermount` or the caller.
+
+use nix::unistd::{self, Gid, Uid};
+use std::io;
+
+/// Set the supplementary groups to the single group `gid`
+#[cfg(not(target_os = "macos"))]
+fn set_groups(gid: Gid) -> nix::Result<()> {
+    unistd::setgroups(&[gid])
+}
+
+/// Set the supplementary groups to the single group `gid`
+#[cfg(target_os = "macos")]
+fn set_groups(gid: Gid) -> nix::Result<()> {
+    let groups = [gid.as_raw()];
+    #[allow(unsafe_code)]
+    let res = unsafe { libc::setgroups(1, groups.as_ptr()) };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+/// Convert the error of a syscall
+fn io_error(err: nix::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// Switch the process to the group `gid` and the user `uid`, the group first
+/// as switching the group takes the privilege given up by switching the user.
+/// The supplementary groups of root are dropped as well. Fails if the switch
+/// could be undone afterwards, or if a user is given without a group, which
+/// would keep the group and the supplementary groups of root.
+pub fn drop_privileges(uid: Option<Uid>, gid: Option<Gid>) -> io::Result<()> {
+    if uid.is_some() && gid.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the user is switched without a group",
+        ));
+    }
+    if let Some(gid) = gid {
+        set_groups(gid).map_err(io_error)?;
+        unistd::setgid(gid).map_err(io_error)?;
+    }
+    if let Some(uid) = uid {
+        unistd::setuid(uid).map_err(io_error)?;
+        if !uid.is_root() && unistd::setuid(Uid::from_raw(0)).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the root privilege is regained after switching the user",
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[cfg(not(target_os = "macos"))]
+mod test {
+    use nix::sys::wait::{self, WaitStatus};
+    use nix::unistd::{self, ForkResult, Gid, Uid};
+
+    use super::drop_privileges;
+
+    #[test]
+    fn test_drop_privileges() {
+        // nothing to switch
+        drop_privileges(None, None).unwrap_or_else(|_| panic!());
+        // a user without a group would keep the groups
+        let (uid, gid) = (unistd::getuid(), unistd::getgid());
+        assert!(drop_privileges(Some(Uid::from_raw(0xFFFE)), None).is_err());
+        assert_eq!((unistd::getuid(), unistd::getgid()), (uid, gid));
+        if !unistd::geteuid().is_root() {
+            // the groups are only switched with the privilege
+            assert!(drop_privileges(None, Some(Gid::from_raw(0))).is_err());
+            return;
+        }
+        // switch in a child not to drop the privileges of the other tests
+        match unistd::fork().unwrap_or_else(|_| panic!()) {
+            ForkResult::Child => {
+                let nobody = 0xFFFE;
+                let code =
+                    match drop_privileges(Some(Uid::from_raw(nobody)), Some(Gid::from_raw(nobody)))
+                    {
+                        Ok(())
+                            if unistd::getuid().as_raw() == nobody
+                                && unistd::getgid().as_raw() == nobody
+                                && unistd::getgroups().ok()
+                                    == Some(vec![Gid::from_raw(nobody)]) =>
+                        {
+                            0
+                        }
+                        _ => 1,
+                    };
+                #[allow(unsafe_code)]
+                unsafe {
+                    libc::_exit(code)
+                }
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(
+                    wait::waitpid(child, None).ok(),
+                    Some(WaitStatus::Exited(child, 0))
+                );
+            }
+        }
+    }
+}
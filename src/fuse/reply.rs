@@ -7,31 +7,99 @@
 //! error() exactly once).
 
 use super::OverflowArithmetic;
-use libc::{EIO, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK};
-use log::warn;
+use libc::{E2BIG, EIO, ENOMEM, ERANGE};
+use log::{debug, warn};
+use std::cell::RefCell;
 use std::convert::AsRef;
 use std::ffi::OsStr;
 use std::fmt;
 use std::marker::PhantomData;
 use std::os::raw::c_int;
 use std::os::unix::ffi::OsStrExt;
-use std::time::{Duration, SystemTime, SystemTimeError, UNIX_EPOCH};
+use std::time::Duration;
+#[cfg(target_os = "macos")]
+use std::time::SystemTime;
 use std::{mem, ptr, slice};
 
+use super::abi::consts::{FOPEN_CACHE_DIR, FOPEN_KEEP_CACHE};
 #[cfg(target_os = "macos")]
 use super::abi::fuse_getxtimes_out;
+#[cfg(feature = "abi-7-11")]
+use super::abi::fuse_ioctl_out;
 use super::abi::{
-    fuse_attr, fuse_attr_out, fuse_bmap_out, fuse_dirent, fuse_entry_out, fuse_file_lock,
-    fuse_getxattr_out, fuse_kstatfs, fuse_lk_out, fuse_open_out, fuse_out_header, fuse_statfs_out,
-    fuse_write_out,
+    fuse_attr_out, fuse_bmap_out, fuse_dirent, fuse_entry_out, fuse_file_lock, fuse_getxattr_out,
+    fuse_kstatfs, fuse_lk_out, fuse_open_out, fuse_out_header, fuse_statfs_out, fuse_write_out,
 };
+#[cfg(target_os = "macos")]
+use super::attr::time_to_abi;
+use super::attr::{mode_from_kind_and_perm, to_fuse_attr};
+
+use super::channel::FuseReplySender;
+use super::{conversion, Cast, FileAttr, FileType, FsError, TryCast};
+
+/// Maximum number of data segments a reply sends without allocating
+pub const MAX_REPLY_SEGMENTS: usize = 16;
+/// Maximum number of reply buffers kept for reuse per worker thread
+const MAX_POOLED_REPLY_BUFFERS: usize = 4;
 
-use super::{conversion, Cast, FileAttr, FileType};
+thread_local! {
+    /// Reply buffers of the worker thread, reused across replies
+    static REPLY_BUFFER_POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Take an empty buffer of at least `size` byte capacity from the pool of the worker thread,
+/// `None` if the memory cannot be allocated
+fn take_reply_buffer(size: usize) -> Option<Vec<u8>> {
+    let mut buffer = REPLY_BUFFER_POOL
+        .with(|pool| pool.borrow_mut().pop())
+        .unwrap_or_default();
+    buffer.clear();
+    buffer.try_reserve(size).ok()?;
+    Some(buffer)
+}
+
+/// Return a buffer to the pool of the worker thread
+fn put_reply_buffer(buffer: Vec<u8>) {
+    REPLY_BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED_REPLY_BUFFERS {
+            pool.push(buffer);
+        }
+    });
+}
 
 /// Generic reply callback to send data
 pub trait ReplySender: Send + 'static {
     /// Send data.
     fn send(&self, data: &[&[u8]]);
+
+    /// Convert into the sender held by a reply, boxed by default
+    fn into_held(self) -> HeldSender
+    where
+        Self: Sized,
+    {
+        HeldSender::Boxed(Box::new(self))
+    }
+}
+
+/// The sender held by a reply, the channel sender is held inline
+/// so that creating a reply does not allocate
+#[derive(Debug)]
+pub enum HeldSender {
+    /// Channel sender
+    Channel(FuseReplySender),
+    /// Any other sender
+    Boxed(Box<dyn ReplySender>),
+}
+
+impl HeldSender {
+    /// Send data
+    fn send(&self, data: &[&[u8]]) {
+        match self {
+            Self::Channel(sender) => ReplySender::send(sender, data),
+            Self::Boxed(sender) => sender.send(data),
+        }
+    }
 }
 
 impl fmt::Debug for Box<dyn ReplySender> {
@@ -47,7 +115,7 @@ pub trait Reply {
 }
 
 /// Serialize an arbitrary type to bytes (memory copy, useful for `fuse_*_out` types)
-fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
+pub(super) fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     let length = mem::size_of::<T>();
     match length {
         0 => f(&[]),
@@ -60,96 +128,6 @@ fn as_bytes<T, U, F: FnOnce(&[&[u8]]) -> U>(data: &T, f: F) -> U {
     }
 }
 
-/// Time from system time
-fn time_from_system_time(system_time: &SystemTime) -> Result<(u64, u32), SystemTimeError> {
-    let duration = system_time.duration_since(UNIX_EPOCH)?;
-    Ok((duration.as_secs(), duration.subsec_nanos()))
-}
-
-// Some platforms like Linux x86_64 have mode_t = u32, and lint warns of a trivial_numeric_casts.
-// But others like macOS x86_64 have mode_t = u16, requiring a typecast.  So, just silence lint.
-#[allow(trivial_numeric_casts)]
-/// Returns the mode for a given file kind and permission
-fn mode_from_kind_and_perm(kind: FileType, perm: u16) -> u32 {
-    (match kind {
-        FileType::NamedPipe => S_IFIFO,
-        FileType::CharDevice => S_IFCHR,
-        FileType::BlockDevice => S_IFBLK,
-        FileType::Directory => S_IFDIR,
-        FileType::RegularFile => S_IFREG,
-        FileType::Symlink => S_IFLNK,
-        FileType::Socket => S_IFSOCK,
-    })
-    .cast::<u32>()
-        | perm.cast::<u32>()
-}
-
-/// Returns a `fuse_attr` from `FileAttr`
-#[cfg(target_os = "macos")]
-fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
-    // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-    let (a_time_secs, a_time_nanos) =
-        time_from_system_time(&attr.atime).unwrap_or_else(|_| panic!());
-    let (m_time_secs, m_time_nanos) =
-        time_from_system_time(&attr.mtime).unwrap_or_else(|_| panic!());
-    let (c_time_secs, c_time_nanos) =
-        time_from_system_time(&attr.ctime).unwrap_or_else(|_| panic!());
-    let (create_time_secs, create_time_nanos) =
-        time_from_system_time(&attr.crtime).unwrap_or_else(|_| panic!());
-
-    fuse_attr {
-        ino: attr.ino,
-        size: attr.size,
-        blocks: attr.blocks,
-        atime: a_time_secs,
-        mtime: m_time_secs,
-        ctime: c_time_secs,
-        crtime: create_time_secs,
-        atimensec: a_time_nanos,
-        mtimensec: m_time_nanos,
-        ctimensec: c_time_nanos,
-        crtimensec: create_time_nanos,
-        mode: mode_from_kind_and_perm(attr.kind, attr.perm),
-        nlink: attr.nlink,
-        uid: attr.uid,
-        gid: attr.gid,
-        rdev: attr.rdev,
-        flags: attr.flags,
-    }
-}
-
-/// Returns a `fuse_attr` from `FileAttr`
-#[cfg(not(target_os = "macos"))]
-fn fuse_attr_from_attr(attr: &FileAttr) -> fuse_attr {
-    // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-    let (atime_secs, atime_nanos) = time_from_system_time(&attr.atime).unwrap_or_else(|_| panic!());
-    let (m_time_secs, m_time_nanos) =
-        time_from_system_time(&attr.mtime).unwrap_or_else(|_| panic!());
-    let (c_time_secs, c_time_nanos) =
-        time_from_system_time(&attr.ctime).unwrap_or_else(|_| panic!());
-
-    fuse_attr {
-        ino: attr.ino,
-        size: attr.size,
-        blocks: attr.blocks,
-        atime: atime_secs,
-        mtime: m_time_secs,
-        ctime: c_time_secs,
-        atimensec: atime_nanos,
-        mtimensec: m_time_nanos,
-        ctimensec: c_time_nanos,
-        mode: mode_from_kind_and_perm(attr.kind, attr.perm),
-        nlink: attr.nlink,
-        uid: attr.uid,
-        gid: attr.gid,
-        rdev: attr.rdev,
-        #[cfg(feature = "abi-7-9")]
-        blksize: 0_u32,
-        #[cfg(feature = "abi-7-9")]
-        padding: 0_u32,
-    }
-}
-
 ///
 /// Raw reply
 ///
@@ -158,7 +136,7 @@ pub struct ReplyRaw<T> {
     /// Unique id of the request to reply to
     unique: u64,
     /// Closure to call for sending the reply
-    sender: Option<Box<dyn ReplySender>>,
+    sender: Option<HeldSender>,
     /// Marker for being able to have T on this struct (which enforces
     /// reply types to send the correct type of data)
     marker: PhantomData<T>,
@@ -166,10 +144,9 @@ pub struct ReplyRaw<T> {
 
 impl<T> Reply for ReplyRaw<T> {
     fn new<S: ReplySender>(unique: u64, sender: S) -> Self {
-        let sender = Box::new(sender);
         Self {
             unique,
-            sender: Some(sender),
+            sender: Some(sender.into_held()),
             marker: PhantomData,
         }
     }
@@ -188,9 +165,18 @@ impl<T> ReplyRaw<T> {
         };
         as_bytes(&header, |headerbytes| {
             let sender = self.sender.take().unwrap_or_else(|| panic!());
-            let mut sendbytes = headerbytes.to_vec();
-            sendbytes.extend(bytes);
-            sender.send(&sendbytes);
+            let segment_count = headerbytes.len().overflow_add(bytes.len());
+            if segment_count <= MAX_REPLY_SEGMENTS {
+                let mut sendbytes: [&[u8]; MAX_REPLY_SEGMENTS] = [&[]; MAX_REPLY_SEGMENTS];
+                for (segment, data) in sendbytes.iter_mut().zip(headerbytes.iter().chain(bytes)) {
+                    *segment = data;
+                }
+                sender.send(sendbytes.get(..segment_count).unwrap_or_else(|| panic!()));
+            } else {
+                let mut sendbytes = headerbytes.to_vec();
+                sendbytes.extend(bytes);
+                sender.send(&sendbytes);
+            }
         });
     }
 
@@ -201,9 +187,29 @@ impl<T> ReplyRaw<T> {
         })
     }
 
+    /// Reply to a request with the leading `size` bytes of the given type, for
+    /// the replies growing with the ABI that the older kernels take shorter
+    #[cfg(feature = "abi-7-23")]
+    pub fn ok_truncated(mut self, data: &T, size: usize) {
+        as_bytes(data, |bytes| {
+            let leading: Vec<&[u8]> = bytes
+                .iter()
+                .map(|segment| segment.get(..size).unwrap_or(segment))
+                .collect();
+            self.send(0, &leading);
+        });
+    }
+
     /// Reply to a request with the given error code
-    pub fn error(mut self, err: c_int) {
-        self.send(err, &[]);
+    pub fn error<E: Into<FsError>>(mut self, err: E) {
+        self.send_error(err.into());
+    }
+
+    /// Reply to a request with the given error, the single place translating
+    /// the errors of the filesystem to the errno sent to the kernel
+    fn send_error(&mut self, err: FsError) {
+        debug!("Replying to operation {} with error {}", self.unique, err);
+        self.send(err.errno(), &[]);
     }
 }
 
@@ -243,7 +249,7 @@ impl ReplyEmpty {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -271,8 +277,14 @@ impl ReplyData {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request with the given data segments, which are sent
+    /// as they are without copying into a single buffer
+    pub fn data_vectored(mut self, data: &[&[u8]]) {
+        self.reply.send(0, data);
+    }
+
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -304,12 +316,12 @@ impl ReplyEntry {
             attr_valid: ttl.as_secs(),
             entry_valid_nsec: ttl.subsec_nanos(),
             attr_valid_nsec: ttl.subsec_nanos(),
-            attr: fuse_attr_from_attr(attr),
+            attr: to_fuse_attr(attr),
         });
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -338,12 +350,12 @@ impl ReplyAttr {
             attr_valid: ttl.as_secs(),
             attr_valid_nsec: ttl.subsec_nanos(),
             dummy: 0,
-            attr: fuse_attr_from_attr(attr),
+            attr: to_fuse_attr(attr),
         });
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -373,11 +385,8 @@ impl ReplyXTimes {
     /// Reply to a request with the given xtimes
     #[allow(dead_code)]
     pub fn xtimes(self, bkuptime: SystemTime, crtime: SystemTime) {
-        // FIXME: unwrap may panic, use unwrap_or((0, 0)) or return a result instead?
-        let (bkuptime_secs, bkuptime_nanos) =
-            time_from_system_time(&bkuptime).unwrap_or_else(|_| panic!());
-        let (crtime_secs, crtime_nanos) =
-            time_from_system_time(&crtime).unwrap_or_else(|_| panic!());
+        let (bkuptime_secs, bkuptime_nanos) = time_to_abi(&bkuptime);
+        let (crtime_secs, crtime_nanos) = time_to_abi(&crtime);
         self.reply.ok(&fuse_getxtimes_out {
             bkuptime: bkuptime_secs,
             crtime: crtime_secs,
@@ -387,7 +396,7 @@ impl ReplyXTimes {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -399,29 +408,54 @@ impl ReplyXTimes {
 pub struct ReplyOpen {
     /// Reply
     reply: ReplyRaw<fuse_open_out>,
+    /// Whether to keep the kernel cache of the opened file or directory
+    keep_cache: bool,
+    /// Whether to allow the kernel to cache the entries of the opened directory
+    cache_dir: bool,
 }
 
 impl Reply for ReplyOpen {
     fn new<S: ReplySender>(unique: u64, sender: S) -> Self {
         Self {
             reply: Reply::new(unique, sender),
+            keep_cache: false,
+            cache_dir: false,
         }
     }
 }
 
 impl ReplyOpen {
+    /// Keep the kernel cache of the opened file or directory instead of
+    /// invalidating it, sets `FOPEN_KEEP_CACHE` in the open result
+    pub fn keep_cache(&mut self, keep_cache: bool) {
+        self.keep_cache = keep_cache;
+    }
+
+    /// Allow the kernel to cache the entries of the opened directory,
+    /// sets `FOPEN_CACHE_DIR` in the open result
+    pub fn cache_dir(&mut self, cache_dir: bool) {
+        self.cache_dir = cache_dir;
+    }
+
     /// Reply to a request with the given open result
     pub fn opened(self, fh: u64, flags: u32) {
+        let mut open_flags = flags;
+        if self.keep_cache {
+            open_flags |= FOPEN_KEEP_CACHE;
+        }
+        if self.cache_dir {
+            open_flags |= FOPEN_CACHE_DIR;
+        }
         self.reply.ok(&fuse_open_out {
             fh,
-            open_flags: flags,
+            open_flags,
             padding: 0,
         });
     }
 
     /// Reply to a request with the given error code
     #[allow(dead_code)]
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -450,7 +484,7 @@ impl ReplyWrite {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -514,7 +548,7 @@ impl ReplyStatfs {
 
     /// Reply to a request with the given error code
     #[allow(dead_code)]
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -548,7 +582,7 @@ impl ReplyCreate {
                 attr_valid: ttl.as_secs(),
                 entry_valid_nsec: ttl.subsec_nanos(),
                 attr_valid_nsec: ttl.subsec_nanos(),
-                attr: fuse_attr_from_attr(attr),
+                attr: to_fuse_attr(attr),
             },
             fuse_open_out {
                 fh,
@@ -559,7 +593,7 @@ impl ReplyCreate {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -596,7 +630,7 @@ impl ReplyLock {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -626,7 +660,48 @@ impl ReplyBmap {
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
+        self.reply.error(err);
+    }
+}
+
+///
+/// Ioctl Reply
+///
+#[cfg(feature = "abi-7-11")]
+#[derive(Debug)]
+pub struct ReplyIoctl {
+    /// Reply
+    reply: ReplyRaw<fuse_ioctl_out>,
+}
+
+#[cfg(feature = "abi-7-11")]
+impl Reply for ReplyIoctl {
+    fn new<S: ReplySender>(unique: u64, sender: S) -> Self {
+        Self {
+            reply: Reply::new(unique, sender),
+        }
+    }
+}
+
+#[cfg(feature = "abi-7-11")]
+impl ReplyIoctl {
+    /// Reply to a request with the given ioctl result and output data
+    pub fn ioctl(mut self, result: i32, data: &[u8]) {
+        let ioctl_out = fuse_ioctl_out {
+            result,
+            flags: 0,
+            in_iovs: 0,
+            out_iovs: 0,
+        };
+        as_bytes(&ioctl_out, |bytes| {
+            let out_bytes = bytes.first().copied().unwrap_or_default();
+            self.reply.send(0, &[out_bytes, data]);
+        });
+    }
+
+    /// Reply to a request with the given error code
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -638,16 +713,37 @@ impl ReplyBmap {
 pub struct ReplyDirectory {
     /// Reply
     reply: ReplyRaw<()>,
-    /// Data
+    /// Data, taken from and returned to the reply buffer pool of the worker thread
     data: Vec<u8>,
+    /// Maximum size of the data
+    size: usize,
+    /// Whether the buffer failed to allocate, the reply is `ENOMEM` then
+    out_of_memory: bool,
 }
 
 impl ReplyDirectory {
     /// Creates a new `ReplyDirectory` with a specified buffer size.
     pub fn new<S: ReplySender>(unique: u64, sender: S, size: usize) -> Self {
-        Self {
-            reply: Reply::new(unique, sender),
-            data: Vec::with_capacity(size),
+        let reply = Reply::new(unique, sender);
+        if let Some(data) = take_reply_buffer(size) {
+            Self {
+                reply,
+                data,
+                size,
+                out_of_memory: false,
+            }
+        } else {
+            warn!(
+                "ReplyDirectory::new() cannot allocate the buffer of {} byte",
+                size
+            );
+            // the buffer is full from the start, so nothing is added
+            Self {
+                reply,
+                data: Vec::new(),
+                size: 0,
+                out_of_memory: true,
+            }
         }
     }
 
@@ -660,7 +756,7 @@ impl ReplyDirectory {
         let entsize = (entlen.overflow_add(mem::size_of::<u64>()).overflow_sub(1))
             & !(mem::size_of::<u64>().overflow_sub(1)); // 64bit align
         let padlen = entsize.overflow_sub(entlen);
-        if self.data.len().overflow_add(entsize) > self.data.capacity() {
+        if self.data.len().overflow_add(entsize) > self.size {
             return true;
         }
         #[allow(unsafe_code)]
@@ -693,14 +789,25 @@ impl ReplyDirectory {
         false
     }
 
-    /// Reply to a request with the filled directory buffer
+    /// Reply to a request with the filled directory buffer, or `ENOMEM` if the
+    /// buffer failed to allocate
     pub fn ok(mut self) {
-        self.reply.send(0, &[&self.data]);
+        if self.out_of_memory {
+            self.reply.send(ENOMEM, &[]);
+        } else {
+            self.reply.send(0, &[&self.data]);
+        }
     }
 
     /// Reply to a request with the given error code
-    pub fn error(self, err: c_int) {
-        self.reply.error(err);
+    pub fn error<E: Into<FsError>>(mut self, err: E) {
+        self.reply.send_error(err.into());
+    }
+}
+
+impl Drop for ReplyDirectory {
+    fn drop(&mut self) {
+        put_reply_buffer(mem::take(&mut self.data));
     }
 }
 
@@ -723,19 +830,29 @@ impl Reply for ReplyXattr {
 
 impl ReplyXattr {
     /// Reply to a request with the size of the xattr.
-    #[allow(dead_code)]
     pub fn size(self, size: u32) {
         self.reply.ok(&fuse_getxattr_out { size, padding: 0 });
     }
 
     /// Reply to a request with the data in the xattr.
-    #[allow(dead_code)]
     pub fn data(mut self, data: &[u8]) {
         self.reply.send(0, &[data]);
     }
 
+    /// Reply to a request of `size` following the two-phase protocol, with the
+    /// size of the value if `size` is 0, the value if it fits in `size`, or
+    /// `ERANGE` if it does not, so that callers can query the size first.
+    pub fn value(self, size: u32, value: &[u8]) {
+        match value.len().try_cast::<u32>() {
+            Ok(len) if size == 0 => self.size(len),
+            Ok(len) if len <= size => self.data(value),
+            Ok(_) => self.error(ERANGE),
+            Err(_) => self.error(E2BIG),
+        }
+    }
+
     /// Reply to a request with the given error code.
-    pub fn error(self, err: c_int) {
+    pub fn error<E: Into<FsError>>(self, err: E) {
         self.reply.error(err);
     }
 }
@@ -743,6 +860,8 @@ impl ReplyXattr {
 #[cfg(test)]
 mod test {
     use super::as_bytes;
+    #[cfg(feature = "abi-7-11")]
+    use super::ReplyIoctl;
     #[cfg(target_os = "macos")]
     use super::ReplyXTimes;
     use super::ReplyXattr;
@@ -809,6 +928,23 @@ mod test {
         });
     }
 
+    /// Add the zero block size and padding of ABI 7.9 to the expected reply of
+    /// an attribute, followed by `tail` byte of the reply
+    #[cfg_attr(not(feature = "abi-7-9"), allow(unused_mut, unused_variables))]
+    fn with_blksize(mut expected: Vec<Vec<u8>>, tail: usize) -> Vec<Vec<u8>> {
+        #[cfg(feature = "abi-7-9")]
+        if let [header, data] = expected.as_mut_slice() {
+            use super::OverflowArithmetic;
+            let at = data.len().overflow_sub(tail);
+            data.splice(at..at, vec![0; 8]).for_each(drop);
+            // the length of the reply leads the header
+            if let Some(len) = header.first_mut() {
+                *len = len.overflow_add(8);
+            }
+        }
+        expected
+    }
+
     struct AssertSender {
         expected: Vec<Vec<u8>>,
     }
@@ -879,49 +1015,68 @@ mod test {
     }
 
     #[test]
-    fn reply_entry() {
+    fn reply_data_vectored() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![
-                        0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
+            expected: vec![
                 vec![
+                    0x15, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0xde, 0xad],
+                vec![0xbe, 0xef, 0x00],
+            ],
+        };
+        let reply: ReplyData = Reply::new(0xdead_beef, sender);
+        reply.data_vectored(&[&[0xde, 0xad], &[0xbe, 0xef, 0x00]]);
+    }
+
+    #[test]
+    fn reply_entry() {
+        let sender = AssertSender {
+            expected: with_blksize(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x88, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                0,
+            ),
         };
         let reply: ReplyEntry = Reply::new(0xdead_beef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -948,43 +1103,46 @@ mod test {
     #[test]
     fn reply_attr() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
+            expected: with_blksize(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
-                vec![
-                    vec![
-                        0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x70, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                0,
+            ),
         };
         let reply: ReplyAttr = Reply::new(0xdead_beef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -1046,6 +1204,26 @@ mod test {
         reply.opened(0x1122, 0x33);
     }
 
+    #[test]
+    fn reply_open_cache() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![
+                    0x22, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+            ],
+        };
+        let mut reply: ReplyOpen = Reply::new(0xdead_beef, sender);
+        reply.keep_cache(true);
+        reply.cache_dir(true);
+        reply.opened(0x1122, 0x01);
+    }
+
     #[test]
     fn reply_write() {
         let sender = AssertSender {
@@ -1096,50 +1274,53 @@ mod test {
     #[test]
     fn reply_create() {
         let sender = AssertSender {
-            expected: if cfg!(target_os = "macos") {
-                vec![
-                    vec![
-                        0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                    vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0x99, 0x00, 0x00, 0x00, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            } else {
-                vec![
+            expected: with_blksize(
+                if cfg!(target_os = "macos") {
                     vec![
-                        0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
+                        vec![
+                            0xa8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0x99, 0x00, 0x00, 0x00, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0xcc, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                } else {
                     vec![
-                        0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
-                        0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-                        0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
-                        0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
-                        0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
-                        0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
-                        0x00, 0x00, 0x00, 0x00,
-                    ],
-                ]
-            },
+                        vec![
+                            0x98, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                        vec![
+                            0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xaa, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x65, 0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x21, 0x43, 0x00, 0x00,
+                            0x21, 0x43, 0x00, 0x00, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x22, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                            0x34, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x34, 0x12, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00, 0x78, 0x56, 0x00, 0x00,
+                            0x78, 0x56, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x55, 0x00, 0x00, 0x00,
+                            0x66, 0x00, 0x00, 0x00, 0x77, 0x00, 0x00, 0x00, 0x88, 0x00, 0x00, 0x00,
+                            0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xcc, 0x00, 0x00, 0x00,
+                            0x00, 0x00, 0x00, 0x00,
+                        ],
+                    ]
+                },
+                16,
+            ),
         };
         let reply: ReplyCreate = Reply::new(0xdead_beef, sender);
         let time = UNIX_EPOCH + Duration::new(0x1234, 0x5678);
@@ -1196,6 +1377,26 @@ mod test {
         reply.bmap(0x1234);
     }
 
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn reply_ioctl() {
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x24, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![
+                    0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0x11, 0x22, 0x33, 0x44],
+            ],
+        };
+        let reply: ReplyIoctl = Reply::new(0xdead_beef, sender);
+        reply.ioctl(7, &[0x11, 0x22, 0x33, 0x44]);
+    }
+
     #[test]
     fn reply_directory() {
         let sender = AssertSender {
@@ -1219,6 +1420,64 @@ mod test {
         reply.ok();
     }
 
+    #[test]
+    fn reply_directory_non_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x30, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![
+                    0xbb, 0xaa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x63, 0x61,
+                    0x66, 0xe9, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ],
+        };
+        // the name is passed as is
+        let mut reply = ReplyDirectory::new(0xdead_beef, sender, 4096);
+        reply.add(
+            0xaabb,
+            1,
+            FileType::RegularFile,
+            OsStr::from_bytes(b"caf\xe9"),
+        );
+        reply.ok();
+    }
+
+    #[test]
+    fn reply_directory_reuse_buffer() {
+        let (tx, rx) = channel::<()>();
+        let mut reply = ReplyDirectory::new(0xdead_beef, tx.clone(), 4096);
+        assert!(!reply.add(0xaabb, 1, FileType::Directory, "hello"));
+        reply.ok();
+        rx.recv().unwrap_or_else(|_| panic!());
+
+        // the reused buffer is larger than the requested size
+        let mut reply = ReplyDirectory::new(0xdead_beef, tx, 40);
+        assert!(!reply.add(0xaabb, 1, FileType::Directory, "hello"));
+        assert!(reply.add(0xccdd, 2, FileType::RegularFile, "world.rs"));
+        reply.ok();
+        rx.recv().unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn reply_directory_out_of_memory() {
+        let sender = AssertSender {
+            expected: vec![vec![
+                0x10, 0x00, 0x00, 0x00, 0xf4, 0xff, 0xff, 0xff, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00,
+                0x00, 0x00,
+            ]],
+        };
+        let mut reply = ReplyDirectory::new(0xdead_beef, sender, usize::MAX);
+        assert!(reply.add(0xaabb, 1, FileType::Directory, "hello"));
+        reply.ok();
+    }
+
     impl super::ReplySender for Sender<()> {
         fn send(&self, _: &[&[u8]]) {
             Self::send(self, ()).unwrap_or_else(|_| panic!())
@@ -1255,6 +1514,42 @@ mod test {
         reply.data(&[0x11, 0x22, 0x33, 0x44]);
     }
 
+    #[test]
+    fn reply_xattr_value() {
+        // too small for the value
+        let sender = AssertSender {
+            expected: vec![vec![
+                0x10, 0x00, 0x00, 0x00, 0xDE, 0xFF, 0xFF, 0xFF, 0xEF, 0xBE, 0xAD, 0xDE, 0x00, 0x00,
+                0x00, 0x00,
+            ]],
+        };
+        let reply = ReplyXattr::new(0xdead_beef, sender);
+        reply.value(3, &[0x11, 0x22, 0x33, 0x44]);
+        // the size is queried first
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x18, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ],
+        };
+        let reply = ReplyXattr::new(0xdead_beef, sender);
+        reply.value(0, &[0x11, 0x22, 0x33, 0x44]);
+        let sender = AssertSender {
+            expected: vec![
+                vec![
+                    0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xEF, 0xBE, 0xAD, 0xDE, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+                vec![0x11, 0x22, 0x33, 0x44],
+            ],
+        };
+        let reply = ReplyXattr::new(0xdead_beef, sender);
+        reply.value(4, &[0x11, 0x22, 0x33, 0x44]);
+    }
+
     #[test]
     fn async_reply() {
         let (tx, rx) = channel::<()>();
@@ -5,53 +5,75 @@
 //!
 //! TODO: This module is meant to go away soon in favor of `ll::Request`.
 
-use libc::{EIO, ENOSYS, EPROTO};
-use log::{debug, error, warn};
+use libc::{EINVAL, EIO, ENOSYS, EPROTO};
+use log::{debug, error, info, warn};
 use std::convert::TryFrom;
 use std::path::Path;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
 
+#[cfg(feature = "abi-7-17")]
+use super::abi::consts::FUSE_RELEASE_FLOCK_UNLOCK;
 use super::abi::consts::{
     FATTR_ATIME, FATTR_FH, FATTR_GID, FATTR_MODE, FATTR_MTIME, FATTR_SIZE, FATTR_UID,
-    FUSE_ASYNC_READ, FUSE_RELEASE_FLUSH,
+    FUSE_ASYNC_READ, FUSE_NO_OPENDIR_SUPPORT, FUSE_NO_OPEN_SUPPORT, FUSE_RELEASE_FLUSH,
 };
 #[cfg(target_os = "macos")]
 use super::abi::consts::{
     FATTR_BKUPTIME, FATTR_CHGTIME, FATTR_CRTIME, FATTR_FLAGS, FUSE_CASE_INSENSITIVE,
     FUSE_VOL_RENAME, FUSE_XTIMES,
 };
+#[cfg(feature = "abi-7-9")]
+use super::abi::consts::{FUSE_GETATTR_FH, FUSE_LK_FLOCK};
 
+#[cfg(feature = "abi-7-23")]
+use super::abi::consts::{FATTR_CTIME, FUSE_WRITEBACK_CACHE};
+#[cfg(feature = "abi-7-23")]
+use super::abi::FUSE_COMPAT_22_INIT_OUT_SIZE;
 use super::abi::{
-    fuse_init_out, fuse_setattr_in, fuse_setxattr_in, FUSE_KERNEL_MINOR_VERSION,
+    fuse_init_in, fuse_init_out, fuse_setattr_in, fuse_setxattr_in, FUSE_KERNEL_MINOR_VERSION,
     FUSE_KERNEL_VERSION,
 };
-use super::channel::FuseChannelSender;
+use super::attr::time_from_abi;
+use super::channel::FuseReplySender;
 use super::ll_request;
-use super::reply::{Reply, ReplyDirectory, ReplyEmpty, ReplyRaw};
+#[cfg(feature = "abi-7-12")]
+use super::notify::Notifier;
+use super::reply::{Reply, ReplyDirectory, ReplyEmpty, ReplyOpen, ReplyRaw};
 use super::session::{Session, BUFFER_SIZE, MAX_WRITE_SIZE};
 #[cfg(target_os = "macos")]
 use super::FsExchangeParam;
+#[cfg(feature = "abi-7-19")]
+use super::FsFallocateParam;
+#[cfg(feature = "abi-7-11")]
+use super::FsIoctlParam;
 use super::{
-    Cast, Filesystem, FsGetlkParam, FsReleaseParam, FsSetattrParam, FsSetlkParam, FsSetxattrParam,
-    FsWriteParam,
+    Cast, Context, Filesystem, FsGetlkParam, FsInitConfig, FsReleaseParam, FsSetattrParam,
+    FsSetlkParam, FsSetxattrParam, FsWriteParam, InitInfo, TryCast,
 };
 
-/// We generally support async reads
+/// We generally support async reads, filesystems may change the flags in init
 #[cfg(not(target_os = "macos"))]
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
 /// On macOS, we additionally support case insensitiveness, volume renames and xtimes
-/// TODO: we should eventually let the filesystem implementation decide which flags to set
 #[cfg(target_os = "macos")]
 const INIT_FLAGS: u32 = FUSE_ASYNC_READ | FUSE_CASE_INSENSITIVE | FUSE_VOL_RENAME | FUSE_XTIMES;
 // TODO: Add FUSE_EXPORT_SUPPORT and FUSE_BIG_WRITES (requires ABI 7.10)
 
+/// A stage of the dispatch before the handler of the operation, `true` if it
+/// replied to the request, which ends the dispatch
+type Gate<'a, FS> = fn(&Request<'a>, &mut Session<FS>, &Context) -> bool;
+
+/// The handler of an operation, calling the filesystem method of it, which
+/// sends back the reply
+type Handler<'a, FS> = fn(&Request<'a>, &mut Session<FS>, &Context);
+
 /// Request data structure
 #[derive(Debug)]
 pub struct Request<'a> {
     /// Channel sender for sending the reply
-    ch: FuseChannelSender,
+    ch: FuseReplySender,
     /// Request raw data
     data: &'a [u8],
     /// Parsed request
@@ -60,7 +82,7 @@ pub struct Request<'a> {
 
 impl<'a> Request<'a> {
     /// Create a new request from the given data
-    pub fn new(ch: FuseChannelSender, data: &'a [u8]) -> Option<Request<'a>> {
+    pub fn new(ch: FuseReplySender, data: &'a [u8]) -> Option<Request<'a>> {
         let request = match ll_request::Request::try_from(data) {
             Ok(request) => request,
             Err(err) => {
@@ -74,10 +96,330 @@ impl<'a> Request<'a> {
     }
 
     /// Dispatch request to the given filesystem.
-    /// This calls the appropriate filesystem operation method for the
-    /// request and sends back the returned reply to the kernel
-    #[allow(clippy::too_many_lines)]
+    /// The request passes the gates in order, the first gate replying to it ends the
+    /// dispatch, e.g. the operations before init or on a poisoned inode. A request
+    /// passing every gate goes to the handler of its operation in the table of
+    /// `handler()`, which calls the filesystem method and sends back the returned
+    /// reply to the kernel
     pub fn dispatch<FS: Filesystem>(&self, se: &mut Session<FS>) {
+        debug!("{}", self.request);
+        let ctx = self.context();
+        let gates: [Gate<'a, FS>; 8] = [
+            Self::gate_init,
+            Self::gate_uninitialized,
+            Self::gate_destroy,
+            Self::gate_forget,
+            Self::gate_poisoned,
+            Self::gate_interrupt,
+            Self::gate_hooks,
+            Self::gate_authorize,
+        ];
+        if gates.iter().any(|gate| gate(self, se, &ctx)) {
+            return;
+        }
+        if let Some(handler) = self.handler() {
+            handler(self, se, &ctx);
+        }
+    }
+
+    /// Initialize the filesystem, the first request of a session
+    fn gate_init<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) -> bool {
+        match self.request.operation() {
+            ll_request::Operation::Init { arg } => {
+                self.init(se, ctx, arg);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail any operation before initialization
+    fn gate_uninitialized<FS: Filesystem>(&self, se: &mut Session<FS>, _ctx: &Context) -> bool {
+        if se.initialized {
+            return false;
+        }
+        warn!("Ignoring FUSE operation before init: {}", self.request);
+        self.reply::<ReplyEmpty>().error(EIO);
+        true
+    }
+
+    /// Destroy the filesystem, and fail any operation after destroy
+    fn gate_destroy<FS: Filesystem>(&self, se: &mut Session<FS>, _ctx: &Context) -> bool {
+        match self.request.operation() {
+            ll_request::Operation::Destroy => {
+                se.destroy_requested();
+                self.reply::<ReplyEmpty>().ok();
+                true
+            }
+            _ if se.destroyed => {
+                warn!("Ignoring FUSE operation after destroy: {}", self.request);
+                self.reply::<ReplyEmpty>().error(EIO);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget, even of a poisoned inode and without authorization, no reply
+    fn gate_forget<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) -> bool {
+        match self.request.operation() {
+            ll_request::Operation::Forget { arg } => {
+                se.filesystem
+                    .forget(ctx, self.request.nodeid(), arg.nlookup);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail any operation but forget on a poisoned inode
+    fn gate_poisoned<FS: Filesystem>(&self, se: &mut Session<FS>, _ctx: &Context) -> bool {
+        if !se
+            .filesystem
+            .is_poisoned(self.request.nodeid(), self.request.operation())
+        {
+            return false;
+        }
+        warn!("Failing FUSE operation on poisoned inode: {}", self.request);
+        self.reply::<ReplyEmpty>().error(EIO);
+        true
+    }
+
+    /// Interrupt
+    fn gate_interrupt<FS: Filesystem>(&self, _se: &mut Session<FS>, _ctx: &Context) -> bool {
+        match self.request.operation() {
+            ll_request::Operation::Interrupt { .. } => {
+                // TODO: handle FUSE_INTERRUPT
+                self.reply::<ReplyEmpty>().error(ENOSYS);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail the operation a hook of the session fails
+    fn gate_hooks<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) -> bool {
+        match se.before_op_hooks(ctx, self.request.operation()) {
+            Ok(()) => false,
+            Err(err) => {
+                debug!("Failed FUSE operation by hook, {}: {}", err, self.request);
+                self.reply::<ReplyEmpty>().error(err);
+                true
+            }
+        }
+    }
+
+    /// Fail the operation unless the filesystem authorizes it, the release of
+    /// a handle is never denied, or the handle would leak
+    fn gate_authorize<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) -> bool {
+        let op = self.request.operation();
+        if let ll_request::Operation::Release { .. } | ll_request::Operation::ReleaseDir { .. } =
+            *op
+        {
+            return false;
+        }
+        match se.filesystem.authorize(ctx, op) {
+            Ok(()) => false,
+            Err(errno) => {
+                debug!("Denied FUSE operation, {}: {}", errno, self.request);
+                self.reply::<ReplyEmpty>().error(errno);
+                true
+            }
+        }
+    }
+
+    /// Filesystem initialization
+    fn init<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context, arg: &fuse_init_in) {
+        debug!("Init args: {:?}", arg);
+        let reply: ReplyRaw<fuse_init_out> = self.reply();
+        // We don't support ABI versions before 7.6
+        if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
+            error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
+            reply.error(EPROTO);
+            return;
+        }
+        // Remember ABI version supported by kernel
+        se.proto_major = arg.major;
+        se.proto_minor = arg.minor;
+        let mut config = FsInitConfig {
+            capable_flags: arg.flags,
+            flags: INIT_FLAGS,
+            max_readahead: if BUFFER_SIZE.cast::<u32>() < arg.max_readahead {
+                BUFFER_SIZE.cast()
+            } else {
+                arg.max_readahead
+            }, // TODO: adjust BUFFER_SIZE according to max_readahead
+            no_open: false,
+            no_opendir: false,
+            #[cfg(feature = "abi-7-23")]
+            writeback_cache: false,
+            #[cfg(feature = "abi-7-23")]
+            time_gran: 1,
+        };
+        // Call filesystem init method and give it a chance to return an error
+        // or to adjust the config
+        let res = se.filesystem.init(ctx, &mut config);
+        if let Err(err) = res {
+            reply.error(err);
+            return;
+        }
+        #[cfg(feature = "abi-7-23")]
+        if config.writeback_cache {
+            config.flags |= FUSE_WRITEBACK_CACHE;
+        }
+        // Reply with our desired version and settings. If the kernel supports a
+        // larger major version, it'll re-send a matching init message. If it
+        // supports only lower major versions, we replied with an error above.
+        let init = fuse_init_out {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            // max_readahead: arg.max_readahead, // accept any readahead size
+            max_readahead: config.max_readahead.min(arg.max_readahead),
+            flags: arg.flags & config.flags, // use features given in config and reported as capable
+            #[cfg(not(feature = "abi-7-13"))]
+            unused: 0,
+            #[cfg(feature = "abi-7-13")]
+            max_background: 0_u16,
+            #[cfg(feature = "abi-7-13")]
+            congestion_threshold: 0_u16,
+            max_write: MAX_WRITE_SIZE.cast(), // TODO: use a max write size that fits into the session's buffer
+            #[cfg(feature = "abi-7-23")]
+            time_gran: config.time_gran,
+            #[cfg(feature = "abi-7-23")]
+            unused: [0; 9],
+        };
+        debug!(
+            "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
+            init.major, init.minor, init.flags, init.max_readahead, init.max_write
+        );
+        let info = InitInfo {
+            kernel_major: arg.major,
+            kernel_minor: arg.minor,
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION.min(arg.minor),
+            offered_flags: arg.flags,
+            wanted_flags: config.flags,
+            flags: init.flags,
+            max_readahead: init.max_readahead,
+            max_write: init.max_write,
+        };
+        info!(
+            "FUSE init negotiated: {}",
+            info.to_string().replace('\n', ", ")
+        );
+        // An ENOSYS open fails the open on the kernels not offering
+        // the support, so the opens are only skipped if offered
+        se.no_open = config.no_open && arg.flags & FUSE_NO_OPEN_SUPPORT != 0;
+        se.no_opendir = config.no_opendir && arg.flags & FUSE_NO_OPENDIR_SUPPORT != 0;
+        se.initialized = true;
+        se.init_info = Some(info);
+        // The kernels before ABI 7.23 know no time granularity and reject
+        // the larger reply
+        #[cfg(feature = "abi-7-23")]
+        if arg.minor < 23 {
+            reply.ok_truncated(&init, FUSE_COMPAT_22_INIT_OUT_SIZE);
+        } else {
+            reply.ok(&init);
+        }
+        #[cfg(not(feature = "abi-7-23"))]
+        reply.ok(&init);
+        se.filesystem.negotiated(&info);
+        se.notify_mounted();
+    }
+
+    /// The handler of the operation of a request past the gates, `None` for
+    /// the operations the gates answer. Each operation has a handler of its
+    /// own, so the compiler asks for one along with a new operation.
+    fn handler<FS: Filesystem>(&self) -> Option<Handler<'a, FS>> {
+        Some(match *self.request.operation() {
+            // Handled by the gates
+            ll_request::Operation::Init { .. }
+            | ll_request::Operation::Destroy
+            | ll_request::Operation::Forget { .. }
+            | ll_request::Operation::Interrupt { .. } => return None,
+
+            ll_request::Operation::Lookup { .. } => Self::handle_lookup,
+            #[cfg(not(feature = "abi-7-9"))]
+            ll_request::Operation::GetAttr => Self::handle_getattr,
+            #[cfg(feature = "abi-7-9")]
+            ll_request::Operation::GetAttr { .. } => Self::handle_getattr,
+            ll_request::Operation::SetAttr { .. } => Self::handle_setattr,
+            ll_request::Operation::ReadLink => Self::handle_readlink,
+            ll_request::Operation::MkNod { .. } => Self::handle_mknod,
+            ll_request::Operation::MkDir { .. } => Self::handle_mkdir,
+            ll_request::Operation::Unlink { .. } => Self::handle_unlink,
+            ll_request::Operation::RmDir { .. } => Self::handle_rmdir,
+            ll_request::Operation::SymLink { .. } => Self::handle_symlink,
+            ll_request::Operation::Rename { .. } => Self::handle_rename,
+            ll_request::Operation::Link { .. } => Self::handle_link,
+            ll_request::Operation::Open { .. } => Self::handle_open,
+            ll_request::Operation::Read { .. } => Self::handle_read,
+            ll_request::Operation::Write { .. } => Self::handle_write,
+            ll_request::Operation::Flush { .. } => Self::handle_flush,
+            ll_request::Operation::Release { .. } => Self::handle_release,
+            ll_request::Operation::FSync { .. } => Self::handle_fsync,
+            ll_request::Operation::OpenDir { .. } => Self::handle_opendir,
+            ll_request::Operation::ReadDir { .. } => Self::handle_readdir,
+            ll_request::Operation::ReleaseDir { .. } => Self::handle_releasedir,
+            ll_request::Operation::FSyncDir { .. } => Self::handle_fsyncdir,
+            ll_request::Operation::StatFs => Self::handle_statfs,
+            ll_request::Operation::SetXAttr { .. } => Self::handle_setxattr,
+            ll_request::Operation::GetXAttr { .. } => Self::handle_getxattr,
+            ll_request::Operation::ListXAttr { .. } => Self::handle_listxattr,
+            ll_request::Operation::RemoveXAttr { .. } => Self::handle_removexattr,
+            ll_request::Operation::Access { .. } => Self::handle_access,
+            ll_request::Operation::Create { .. } => Self::handle_create,
+            ll_request::Operation::GetLk { .. } => Self::handle_getlk,
+            ll_request::Operation::SetLk { .. } | ll_request::Operation::SetLkW { .. } => {
+                Self::handle_setlk
+            }
+            ll_request::Operation::BMap { .. } => Self::handle_bmap,
+            #[cfg(feature = "abi-7-11")]
+            ll_request::Operation::IoCtl { .. } => Self::handle_ioctl,
+            #[cfg(feature = "abi-7-19")]
+            ll_request::Operation::FAllocate { .. } => Self::handle_fallocate,
+            #[cfg(feature = "abi-7-23")]
+            ll_request::Operation::Rename2 { .. } => Self::handle_rename2,
+            #[cfg(target_os = "macos")]
+            ll_request::Operation::SetVolName { .. } => Self::handle_setvolname,
+            #[cfg(target_os = "macos")]
+            ll_request::Operation::GetXTimes => Self::handle_getxtimes,
+            #[cfg(target_os = "macos")]
+            ll_request::Operation::Exchange { .. } => Self::handle_exchange,
+            ll_request::Operation::NoImplementation => Self::handle_no_implementation,
+        })
+    }
+
+    /// Lookup
+    fn handle_lookup<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Lookup { name } = *self.request.operation() {
+            se.filesystem
+                .lookup(ctx, self.request.nodeid(), name, self.reply());
+        }
+    }
+
+    /// Getattr
+    #[cfg(not(feature = "abi-7-9"))]
+    fn handle_getattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        se.filesystem
+            .getattr(ctx, self.request.nodeid(), None, self.reply());
+    }
+
+    /// Getattr, of the handle if the kernel passes one
+    #[cfg(feature = "abi-7-9")]
+    fn handle_getattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::GetAttr { arg } = *self.request.operation() {
+            let fh = match arg.getattr_flags & FUSE_GETATTR_FH {
+                0 => None,
+                _ => Some(arg.fh),
+            };
+            se.filesystem
+                .getattr(ctx, self.request.nodeid(), fh, self.reply());
+        }
+    }
+
+    /// Setattr, of the attributes the kernel marks valid
+    fn handle_setattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
         #[cfg(target_os = "macos")]
         #[inline]
         /// Get macos setattr
@@ -91,30 +433,15 @@ impl<'a> Request<'a> {
         ) {
             let crtime = match arg.valid & FATTR_CRTIME {
                 0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.crtime, arg.crtimensec)) {
-                        Some(crt) => crt,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.crtime, arg.crtimensec)),
+                _ => Some(time_from_abi(arg.crtime, arg.crtimensec)),
             };
             let chgtime = match arg.valid & FATTR_CHGTIME {
                 0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.chgtime, arg.chgtimensec)) {
-                        Some(cht) => cht,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.chgtime, arg.chgtimensec)),
+                _ => Some(time_from_abi(arg.chgtime, arg.chgtimensec)),
             };
             let bkuptime = match arg.valid & FATTR_BKUPTIME {
                 0 => None,
-                _ => Some(
-                    match UNIX_EPOCH.checked_add(Duration::new(arg.bkuptime, arg.bkuptimensec)) {
-                        Some(bkt) => bkt,
-                        None => SystemTime::now(),
-                    },
-                ), // _ => Some(UNIX_EPOCH + Duration::new(arg.bkuptime, arg.bkuptimensec)),
+                _ => Some(time_from_abi(arg.bkuptime, arg.bkuptimensec)),
             };
             let flags = match arg.valid & FATTR_FLAGS {
                 0 => None,
@@ -122,18 +449,6 @@ impl<'a> Request<'a> {
             };
             (crtime, chgtime, bkuptime, flags)
         }
-        #[cfg(target_os = "macos")]
-        #[inline]
-        /// Get position
-        const fn get_position(arg: &fuse_setxattr_in) -> u32 {
-            arg.position
-        }
-        #[cfg(not(target_os = "macos"))]
-        #[inline]
-        /// Get position
-        const fn get_position(_arg: &fuse_setxattr_in) -> u32 {
-            0
-        }
         #[cfg(not(target_os = "macos"))]
         #[inline]
         /// Get macos setattr
@@ -147,415 +462,576 @@ impl<'a> Request<'a> {
         ) {
             (None, None, None, None)
         }
-        debug!("{}", self.request);
+        let arg = match *self.request.operation() {
+            ll_request::Operation::SetAttr { arg } => arg,
+            _ => return,
+        };
+        let mode = match arg.valid & FATTR_MODE {
+            0 => None,
+            _ => Some(arg.mode),
+        };
+        let user_id = match arg.valid & FATTR_UID {
+            0 => None,
+            _ => Some(arg.uid),
+        };
+        let group_id = match arg.valid & FATTR_GID {
+            0 => None,
+            _ => Some(arg.gid),
+        };
+        let size = match arg.valid & FATTR_SIZE {
+            0 => None,
+            _ => Some(arg.size),
+        };
+        let atime = match arg.valid & FATTR_ATIME {
+            0 => None,
+            _ => Some(time_from_abi(arg.atime, arg.atimensec)),
+        };
+        let m_time = match arg.valid & FATTR_MTIME {
+            0 => None,
+            _ => Some(time_from_abi(arg.mtime, arg.mtimensec)),
+        };
+        let fh = match arg.valid & FATTR_FH {
+            0 => None,
+            _ => Some(arg.fh),
+        };
+        #[cfg(feature = "abi-7-23")]
+        let ctime = match arg.valid & FATTR_CTIME {
+            0 => None,
+            _ => Some(time_from_abi(arg.ctime, arg.ctimensec)),
+        };
+        let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
+        se.filesystem.setattr(
+            ctx,
+            FsSetattrParam {
+                ino: self.request.nodeid(),
+                mode,
+                uid: user_id,
+                gid: group_id,
+                size,
+                atime,
+                mtime: m_time,
+                #[cfg(feature = "abi-7-23")]
+                ctime,
+                fh,
+                crtime,
+                chgtime,
+                bkuptime,
+                flags,
+            },
+            self.reply(),
+        );
+    }
 
-        match self.request.operation() {
-            // Filesystem initialization
-            ll_request::Operation::Init { arg } => {
-                debug!("Init args: {:?}", arg);
-                let reply: ReplyRaw<fuse_init_out> = self.reply();
-                // We don't support ABI versions before 7.6
-                if arg.major < 7 || (arg.major == 7 && arg.minor < 6) {
-                    error!("Unsupported FUSE ABI version {}.{}", arg.major, arg.minor);
-                    reply.error(EPROTO);
-                    return;
-                }
-                // Remember ABI version supported by kernel
-                se.proto_major = arg.major;
-                se.proto_minor = arg.minor;
-                // Call filesystem init method and give it a chance to return an error
-                let res = se.filesystem.init(self);
-                if let Err(err) = res {
-                    reply.error(err);
-                    return;
-                }
-                // Reply with our desired version and settings. If the kernel supports a
-                // larger major version, it'll re-send a matching init message. If it
-                // supports only lower major versions, we replied with an error above.
-                let init = fuse_init_out {
-                    major: FUSE_KERNEL_VERSION,
-                    minor: FUSE_KERNEL_MINOR_VERSION,
-                    // max_readahead: arg.max_readahead, // accept any readahead size
-                    max_readahead: if BUFFER_SIZE.cast::<u32>() < arg.max_readahead {
-                        BUFFER_SIZE.cast()
-                    } else {
-                        arg.max_readahead
-                    }, // TODO: adjust BUFFER_SIZE according to max_readahead
-                    flags: arg.flags & INIT_FLAGS, // use features given in INIT_FLAGS and reported as capable
-                    #[cfg(not(feature = "abi-7-13"))]
-                    unused: 0,
-                    #[cfg(feature = "abi-7-13")]
-                    max_background: 0_u16,
-                    #[cfg(feature = "abi-7-13")]
-                    congestion_threshold: 0_u16,
-                    max_write: MAX_WRITE_SIZE.cast(), // TODO: use a max write size that fits into the session's buffer
-                };
-                debug!(
-                    "INIT response: ABI {}.{}, flags {:#x}, max readahead {}, max write {}",
-                    init.major, init.minor, init.flags, init.max_readahead, init.max_write
-                );
-                se.initialized = true;
-                reply.ok(&init);
-            }
-            // Any operation is invalid before initialization
-            _ if !se.initialized => {
-                warn!("Ignoring FUSE operation before init: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
-            }
-            // Filesystem destroyed
-            ll_request::Operation::Destroy => {
-                se.filesystem.destroy(self);
-                se.destroyed = true;
-                self.reply::<ReplyEmpty>().ok();
-            }
-            // Any operation is invalid after destroy
-            _ if se.destroyed => {
-                warn!("Ignoring FUSE operation after destroy: {}", self.request);
-                self.reply::<ReplyEmpty>().error(EIO);
-            }
+    /// Readlink
+    fn handle_readlink<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        se.filesystem
+            .readlink(ctx, self.request.nodeid(), self.reply());
+    }
 
-            ll_request::Operation::Interrupt { .. } => {
-                // TODO: handle FUSE_INTERRUPT
-                self.reply::<ReplyEmpty>().error(ENOSYS);
-            }
+    /// Mknod
+    fn handle_mknod<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::MkNod { arg, name } = *self.request.operation() {
+            se.filesystem.mknod(
+                ctx,
+                self.request.nodeid(),
+                name,
+                arg.mode,
+                arg.rdev,
+                self.reply(),
+            );
+        }
+    }
 
-            ll_request::Operation::Lookup { name } => {
-                se.filesystem
-                    .lookup(self, self.request.nodeid(), name, self.reply());
-            }
-            ll_request::Operation::Forget { arg } => {
-                se.filesystem
-                    .forget(self, self.request.nodeid(), arg.nlookup); // no reply
-            }
-            ll_request::Operation::GetAttr => {
-                se.filesystem
-                    .getattr(self, self.request.nodeid(), self.reply());
-            }
-            ll_request::Operation::SetAttr { arg } => {
-                let mode = match arg.valid & FATTR_MODE {
-                    0 => None,
-                    _ => Some(arg.mode),
-                };
-                let user_id = match arg.valid & FATTR_UID {
-                    0 => None,
-                    _ => Some(arg.uid),
-                };
-                let group_id = match arg.valid & FATTR_GID {
-                    0 => None,
-                    _ => Some(arg.gid),
-                };
-                let size = match arg.valid & FATTR_SIZE {
-                    0 => None,
-                    _ => Some(arg.size),
-                };
-                let atime = match arg.valid & FATTR_ATIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.atime, arg.atimensec)),
-                };
-                let m_time = match arg.valid & FATTR_MTIME {
-                    0 => None,
-                    _ => Some(UNIX_EPOCH + Duration::new(arg.mtime, arg.mtimensec)),
-                };
-                let fh = match arg.valid & FATTR_FH {
-                    0 => None,
-                    _ => Some(arg.fh),
-                };
-                let (crtime, chgtime, bkuptime, flags) = get_macos_setattr(arg);
-                se.filesystem.setattr(
-                    self,
-                    FsSetattrParam {
-                        ino: self.request.nodeid(),
-                        mode,
-                        uid: user_id,
-                        gid: group_id,
-                        size,
-                        atime,
-                        mtime: m_time,
-                        fh,
-                        crtime,
-                        chgtime,
-                        bkuptime,
-                        flags,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::ReadLink => {
-                se.filesystem
-                    .readlink(self, self.request.nodeid(), self.reply());
-            }
-            ll_request::Operation::MkNod { arg, name } => {
-                se.filesystem.mknod(
-                    self,
-                    self.request.nodeid(),
-                    name,
-                    arg.mode,
-                    arg.rdev,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::MkDir { arg, name } => {
-                se.filesystem
-                    .mkdir(self, self.request.nodeid(), name, arg.mode, self.reply());
-            }
-            ll_request::Operation::Unlink { name } => {
-                se.filesystem
-                    .unlink(self, self.request.nodeid(), name, self.reply());
-            }
-            ll_request::Operation::RmDir { name } => {
-                se.filesystem
-                    .rmdir(self, self.request.nodeid(), name, self.reply());
-            }
-            ll_request::Operation::SymLink { name, link } => {
-                se.filesystem.symlink(
-                    self,
-                    self.request.nodeid(),
-                    name,
-                    Path::new(link),
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::Rename { arg, name, newname } => {
-                se.filesystem.rename(
-                    self,
-                    self.request.nodeid(),
-                    name,
-                    arg.newdir,
-                    newname,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::Link { arg, name } => {
-                se.filesystem.link(
-                    self,
-                    arg.oldnodeid,
-                    self.request.nodeid(),
-                    name,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::Open { arg } => {
+    /// Mkdir
+    fn handle_mkdir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::MkDir { arg, name } = *self.request.operation() {
+            se.filesystem
+                .mkdir(ctx, self.request.nodeid(), name, arg.mode, self.reply());
+        }
+    }
+
+    /// Unlink
+    fn handle_unlink<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Unlink { name } = *self.request.operation() {
+            se.filesystem
+                .unlink(ctx, self.request.nodeid(), name, self.reply());
+        }
+    }
+
+    /// Rmdir
+    fn handle_rmdir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::RmDir { name } = *self.request.operation() {
+            se.filesystem
+                .rmdir(ctx, self.request.nodeid(), name, self.reply());
+        }
+    }
+
+    /// Symlink
+    fn handle_symlink<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::SymLink { name, link } = *self.request.operation() {
+            se.filesystem.symlink(
+                ctx,
+                self.request.nodeid(),
+                name,
+                Path::new(link),
+                self.reply(),
+            );
+        }
+    }
+
+    /// Rename
+    fn handle_rename<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Rename { arg, name, newname } = *self.request.operation() {
+            se.filesystem.rename(
+                ctx,
+                self.request.nodeid(),
+                name,
+                arg.newdir,
+                newname,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Link
+    fn handle_link<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Link { arg, name } = *self.request.operation() {
+            se.filesystem.link(
+                ctx,
+                arg.oldnodeid,
+                self.request.nodeid(),
+                name,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Open, unless the filesystem needs no opens. The kernel takes ENOSYS as
+    /// such and sends none of them from then on.
+    fn handle_open<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Open { arg } = *self.request.operation() {
+            if se.no_open {
+                self.reply::<ReplyOpen>().error(ENOSYS);
+            } else {
                 se.filesystem
-                    .open(self, self.request.nodeid(), arg.flags, self.reply());
+                    .open(ctx, self.request.nodeid(), arg.flags, self.reply());
             }
-            ll_request::Operation::Read { arg } => {
-                se.filesystem.read(
-                    self,
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.offset.cast(),
+        }
+    }
+
+    /// Read
+    fn handle_read<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Read { arg } = *self.request.operation() {
+            let offset = match self.checked_offset(arg.offset) {
+                Some(offset) => offset,
+                None => return,
+            };
+            se.filesystem.read(
+                ctx,
+                self.request.nodeid(),
+                arg.fh,
+                offset,
+                arg.size,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Write, of the data of the size the kernel tells
+    fn handle_write<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Write { arg, data } = *self.request.operation() {
+            if data.len().try_cast::<u32>() != Ok(arg.size) {
+                warn!(
+                    "Invalid write size {}, the data size is {}: {}",
                     arg.size,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::Write { arg, data } => {
-                assert_eq!(data.len(), arg.size.cast());
-                se.filesystem.write(
-                    self,
-                    FsWriteParam {
-                        ino: self.request.nodeid(),
-                        fh: arg.fh,
-                        offset: arg.offset.cast(),
-                        data,
-                        flags: arg.write_flags,
-                    },
-                    self.reply(),
+                    data.len(),
+                    self.request
                 );
+                self.reply::<ReplyEmpty>().error(EINVAL);
+                return;
             }
-            ll_request::Operation::Flush { arg } => {
-                se.filesystem.flush(
-                    self,
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.lock_owner,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::Release { arg } => {
-                let flush_parameter = !matches!(arg.release_flags & FUSE_RELEASE_FLUSH, 0);
-
-                se.filesystem.release(
-                    self,
-                    FsReleaseParam {
-                        ino: self.request.nodeid(),
-                        fh: arg.fh,
-                        flags: arg.flags,
-                        lock_owner: arg.lock_owner,
-                        flush: flush_parameter,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::FSync { arg } => {
-                let datasync = !matches!(arg.fsync_flags & 1, 0);
-                se.filesystem
-                    .fsync(self, self.request.nodeid(), arg.fh, datasync, self.reply());
-            }
-            ll_request::Operation::OpenDir { arg } => {
-                se.filesystem
-                    .opendir(self, self.request.nodeid(), arg.flags, self.reply());
-            }
-            ll_request::Operation::ReadDir { arg } => {
-                se.filesystem.readdir(
-                    self,
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.offset.cast(),
-                    ReplyDirectory::new(self.request.unique(), self.ch, arg.size.cast()),
-                );
-            }
-            ll_request::Operation::ReleaseDir { arg } => {
-                se.filesystem.releasedir(
-                    self,
-                    self.request.nodeid(),
-                    arg.fh,
-                    arg.flags,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::FSyncDir { arg } => {
-                let datasync = !matches!(arg.fsync_flags & 1, 0);
-                se.filesystem
-                    .fsyncdir(self, self.request.nodeid(), arg.fh, datasync, self.reply());
-            }
-            ll_request::Operation::StatFs => {
+            let offset = match self.checked_offset(arg.offset) {
+                Some(offset) => offset,
+                None => return,
+            };
+            se.filesystem.write(
+                ctx,
+                FsWriteParam {
+                    ino: self.request.nodeid(),
+                    fh: arg.fh,
+                    offset,
+                    data,
+                    flags: arg.write_flags,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// Flush
+    fn handle_flush<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Flush { arg } = *self.request.operation() {
+            se.filesystem.flush(
+                ctx,
+                self.request.nodeid(),
+                arg.fh,
+                arg.lock_owner,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Release
+    fn handle_release<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Release { arg } = *self.request.operation() {
+            let flush_parameter = !matches!(arg.release_flags & FUSE_RELEASE_FLUSH, 0);
+            #[cfg(feature = "abi-7-17")]
+            let flock_release = !matches!(arg.release_flags & FUSE_RELEASE_FLOCK_UNLOCK, 0);
+            #[cfg(not(feature = "abi-7-17"))]
+            let flock_release = false;
+
+            se.filesystem.release(
+                ctx,
+                FsReleaseParam {
+                    ino: self.request.nodeid(),
+                    fh: arg.fh,
+                    flags: arg.flags,
+                    lock_owner: arg.lock_owner,
+                    flush: flush_parameter,
+                    flock_release,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// Fsync
+    fn handle_fsync<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::FSync { arg } = *self.request.operation() {
+            let datasync = !matches!(arg.fsync_flags & 1, 0);
+            se.filesystem
+                .fsync(ctx, self.request.nodeid(), arg.fh, datasync, self.reply());
+        }
+    }
+
+    /// Opendir, unless the filesystem needs no opendirs
+    fn handle_opendir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::OpenDir { arg } = *self.request.operation() {
+            if se.no_opendir {
+                self.reply::<ReplyOpen>().error(ENOSYS);
+            } else {
                 se.filesystem
-                    .statfs(self, self.request.nodeid(), self.reply());
+                    .opendir(ctx, self.request.nodeid(), arg.flags, self.reply());
             }
-            ll_request::Operation::SetXAttr { arg, name, value } => {
-                assert!(value.len() == arg.size.cast());
-                se.filesystem.setxattr(
-                    self,
-                    FsSetxattrParam {
-                        ino: self.request.nodeid(),
-                        name,
-                        value,
-                        flags: arg.flags,
-                        position: get_position(arg),
-                    },
-                    self.reply(),
+        }
+    }
+
+    /// Readdir
+    fn handle_readdir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::ReadDir { arg } = *self.request.operation() {
+            let offset = match self.checked_offset(arg.offset) {
+                Some(offset) => offset,
+                None => return,
+            };
+            se.filesystem.readdir(
+                ctx,
+                self.request.nodeid(),
+                arg.fh,
+                offset,
+                ReplyDirectory::new(self.request.unique(), self.ch.clone(), arg.size.cast()),
+            );
+        }
+    }
+
+    /// Releasedir
+    fn handle_releasedir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::ReleaseDir { arg } = *self.request.operation() {
+            se.filesystem
+                .releasedir(ctx, self.request.nodeid(), arg.fh, arg.flags, self.reply());
+        }
+    }
+
+    /// Fsyncdir
+    fn handle_fsyncdir<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::FSyncDir { arg } = *self.request.operation() {
+            let datasync = !matches!(arg.fsync_flags & 1, 0);
+            se.filesystem
+                .fsyncdir(ctx, self.request.nodeid(), arg.fh, datasync, self.reply());
+        }
+    }
+
+    /// Statfs
+    fn handle_statfs<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        se.filesystem
+            .statfs(ctx, self.request.nodeid(), self.reply());
+    }
+
+    /// Setxattr, of the value of the size the kernel tells
+    fn handle_setxattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        #[cfg(target_os = "macos")]
+        #[inline]
+        /// Get position
+        const fn get_position(arg: &fuse_setxattr_in) -> u32 {
+            arg.position
+        }
+        #[cfg(not(target_os = "macos"))]
+        #[inline]
+        /// Get position
+        const fn get_position(_arg: &fuse_setxattr_in) -> u32 {
+            0
+        }
+        if let ll_request::Operation::SetXAttr { arg, name, value } = *self.request.operation() {
+            if value.len().try_cast::<u32>() != Ok(arg.size) {
+                warn!(
+                    "Invalid xattr size {}, the value size is {}: {}",
+                    arg.size,
+                    value.len(),
+                    self.request
                 );
+                self.reply::<ReplyEmpty>().error(EINVAL);
+                return;
             }
-            ll_request::Operation::GetXAttr { arg, name } => {
-                se.filesystem
-                    .getxattr(self, self.request.nodeid(), name, arg.size, self.reply());
-            }
-            ll_request::Operation::ListXAttr { arg } => {
-                se.filesystem
-                    .listxattr(self, self.request.nodeid(), arg.size, self.reply());
-            }
-            ll_request::Operation::RemoveXAttr { name } => {
-                se.filesystem
-                    .removexattr(self, self.request.nodeid(), name, self.reply());
-            }
-            ll_request::Operation::Access { arg } => {
-                se.filesystem
-                    .access(self, self.request.nodeid(), arg.mask, self.reply());
-            }
-            ll_request::Operation::Create { arg, name } => {
-                se.filesystem.create(
-                    self,
-                    self.request.nodeid(),
+            se.filesystem.setxattr(
+                ctx,
+                FsSetxattrParam {
+                    ino: self.request.nodeid(),
                     name,
-                    arg.mode,
-                    arg.flags,
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::GetLk { arg } => {
-                se.filesystem.getlk(
-                    self,
-                    FsGetlkParam {
-                        ino: self.request.nodeid(),
-                        fh: arg.fh,
-                        lock_owner: arg.owner,
-                        start: arg.lk.start,
-                        end: arg.lk.end,
-                        typ: arg.lk.typ,
-                        pid: arg.lk.pid,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::SetLk { arg } => {
-                se.filesystem.setlk(
-                    self,
-                    FsSetlkParam {
-                        ino: self.request.nodeid(),
-                        fh: arg.fh,
-                        lock_owner: arg.owner,
-                        start: arg.lk.start,
-                        end: arg.lk.end,
-                        typ: arg.lk.typ,
-                        pid: arg.lk.pid,
-                        sleep: false,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::SetLkW { arg } => {
-                se.filesystem.setlk(
-                    self,
-                    FsSetlkParam {
-                        ino: self.request.nodeid(),
-                        fh: arg.fh,
-                        lock_owner: arg.owner,
-                        start: arg.lk.start,
-                        end: arg.lk.end,
-                        typ: arg.lk.typ,
-                        pid: arg.lk.pid,
-                        sleep: true,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::BMap { arg } => {
-                se.filesystem.bmap(
-                    self,
-                    self.request.nodeid(),
-                    arg.blocksize,
-                    arg.block,
-                    self.reply(),
-                );
-            }
+                    value,
+                    flags: arg.flags,
+                    position: get_position(arg),
+                },
+                self.reply(),
+            );
+        }
+    }
 
-            #[cfg(target_os = "macos")]
-            ll_request::Operation::SetVolName { name } => {
-                se.filesystem.setvolname(self, name, self.reply());
-            }
-            #[cfg(target_os = "macos")]
-            ll_request::Operation::GetXTimes => {
-                se.filesystem
-                    .getxtimes(self, self.request.nodeid(), self.reply());
-            }
-            #[cfg(target_os = "macos")]
-            ll_request::Operation::Exchange {
-                arg,
-                oldname,
-                newname,
-            } => {
-                se.filesystem.exchange(
-                    self,
-                    FsExchangeParam {
-                        parent: arg.olddir,
-                        name: oldname,
-                        newparent: arg.newdir,
-                        newname,
-                        options: arg.options,
-                    },
-                    self.reply(),
-                );
-            }
-            ll_request::Operation::NoImplementation => {
-                error!("Operation is not implemented!");
-            }
+    /// Getxattr
+    fn handle_getxattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::GetXAttr { arg, name } = *self.request.operation() {
+            se.filesystem
+                .getxattr(ctx, self.request.nodeid(), name, arg.size, self.reply());
+        }
+    }
+
+    /// Listxattr
+    fn handle_listxattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::ListXAttr { arg } = *self.request.operation() {
+            se.filesystem
+                .listxattr(ctx, self.request.nodeid(), arg.size, self.reply());
+        }
+    }
+
+    /// Removexattr
+    fn handle_removexattr<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::RemoveXAttr { name } = *self.request.operation() {
+            se.filesystem
+                .removexattr(ctx, self.request.nodeid(), name, self.reply());
+        }
+    }
+
+    /// Access
+    fn handle_access<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Access { arg } = *self.request.operation() {
+            se.filesystem
+                .access(ctx, self.request.nodeid(), arg.mask, self.reply());
+        }
+    }
+
+    /// Create
+    fn handle_create<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Create { arg, name } = *self.request.operation() {
+            se.filesystem.create(
+                ctx,
+                self.request.nodeid(),
+                name,
+                arg.mode,
+                arg.flags,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Getlk
+    fn handle_getlk<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::GetLk { arg } = *self.request.operation() {
+            se.filesystem.getlk(
+                ctx,
+                FsGetlkParam {
+                    ino: self.request.nodeid(),
+                    fh: arg.fh,
+                    lock_owner: arg.owner,
+                    start: arg.lk.start,
+                    end: arg.lk.end,
+                    typ: arg.lk.typ,
+                    pid: arg.lk.pid,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// Setlk, and setlkw, which sleeps for the lock
+    fn handle_setlk<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        let (arg, sleep) = match *self.request.operation() {
+            ll_request::Operation::SetLk { arg } => (arg, false),
+            ll_request::Operation::SetLkW { arg } => (arg, true),
+            _ => return,
+        };
+        #[cfg(feature = "abi-7-9")]
+        let flock = !matches!(arg.lk_flags & FUSE_LK_FLOCK, 0);
+        #[cfg(not(feature = "abi-7-9"))]
+        let flock = false;
+        se.filesystem.setlk(
+            ctx,
+            FsSetlkParam {
+                ino: self.request.nodeid(),
+                fh: arg.fh,
+                lock_owner: arg.owner,
+                start: arg.lk.start,
+                end: arg.lk.end,
+                typ: arg.lk.typ,
+                pid: arg.lk.pid,
+                sleep,
+                flock,
+            },
+            self.reply(),
+        );
+    }
+
+    /// Bmap
+    fn handle_bmap<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::BMap { arg } = *self.request.operation() {
+            se.filesystem.bmap(
+                ctx,
+                self.request.nodeid(),
+                arg.blocksize,
+                arg.block,
+                self.reply(),
+            );
+        }
+    }
+
+    /// Ioctl
+    #[cfg(feature = "abi-7-11")]
+    fn handle_ioctl<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::IoCtl { arg, data } = *self.request.operation() {
+            se.filesystem.ioctl(
+                ctx,
+                FsIoctlParam {
+                    ino: self.request.nodeid(),
+                    fh: arg.fh,
+                    flags: arg.flags,
+                    cmd: arg.cmd,
+                    in_data: data,
+                    out_size: arg.out_size,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// Fallocate
+    #[cfg(feature = "abi-7-19")]
+    fn handle_fallocate<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::FAllocate { arg } = *self.request.operation() {
+            let offset = match self.checked_offset(arg.offset) {
+                Some(offset) => offset,
+                None => return,
+            };
+            // the length is of the range from the offset, in the range of `i64` too
+            let length = match self.checked_offset(arg.length) {
+                Some(length) => length,
+                None => return,
+            };
+            se.filesystem.fallocate(
+                ctx,
+                FsFallocateParam {
+                    ino: self.request.nodeid(),
+                    fh: arg.fh,
+                    offset,
+                    length,
+                    mode: arg.mode,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// Rename2, the flags of renameat2 are not implemented, the kernel takes
+    /// ENOSYS as such and fails the renames with flags with EINVAL
+    #[cfg(feature = "abi-7-23")]
+    fn handle_rename2<FS: Filesystem>(&self, _se: &mut Session<FS>, _ctx: &Context) {
+        self.reply::<ReplyEmpty>().error(ENOSYS);
+    }
+
+    /// Setvolname
+    #[cfg(target_os = "macos")]
+    fn handle_setvolname<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::SetVolName { name } = *self.request.operation() {
+            se.filesystem.setvolname(ctx, name, self.reply());
         }
     }
 
+    /// Getxtimes
+    #[cfg(target_os = "macos")]
+    fn handle_getxtimes<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        se.filesystem
+            .getxtimes(ctx, self.request.nodeid(), self.reply());
+    }
+
+    /// Exchange
+    #[cfg(target_os = "macos")]
+    fn handle_exchange<FS: Filesystem>(&self, se: &mut Session<FS>, ctx: &Context) {
+        if let ll_request::Operation::Exchange {
+            arg,
+            oldname,
+            newname,
+        } = *self.request.operation()
+        {
+            se.filesystem.exchange(
+                ctx,
+                FsExchangeParam {
+                    parent: arg.olddir,
+                    name: oldname,
+                    newparent: arg.newdir,
+                    newname,
+                    options: arg.options,
+                },
+                self.reply(),
+            );
+        }
+    }
+
+    /// The operations parsed but not implemented
+    fn handle_no_implementation<FS: Filesystem>(&self, _se: &mut Session<FS>, _ctx: &Context) {
+        error!("Operation is not implemented!");
+    }
+
     /// Create a reply object for this request that can be passed to the filesystem
     /// implementation and makes sure that a request is replied exactly once
     fn reply<T: Reply>(&self) -> T {
-        Reply::new(self.request.unique(), self.ch)
+        Reply::new(self.request.unique(), self.ch.clone())
+    }
+
+    /// Convert a file offset from the kernel, which must fit in `i64`, replies
+    /// `EINVAL` and returns `None` if it does not
+    fn checked_offset(&self, offset: u64) -> Option<i64> {
+        match offset.try_cast() {
+            Ok(offset) => Some(offset),
+            Err(err) => {
+                warn!("Invalid offset, {}: {}", err, self.request);
+                self.reply::<ReplyEmpty>().error(EINVAL);
+                None
+            }
+        }
+    }
+
+    /// Returns a notifier sending notifications to the kernel through the
+    /// channel of this request
+    #[cfg(feature = "abi-7-12")]
+    #[inline]
+    pub const fn notifier(&self) -> Notifier {
+        Notifier::new(self.ch.channel_sender())
     }
 
     /// Returns the unique identifier of this request
@@ -585,4 +1061,26 @@ impl<'a> Request<'a> {
     pub const fn pid(&self) -> u32 {
         self.request.pid()
     }
+
+    /// Returns the caller of this request
+    pub fn context(&self) -> Context {
+        #[cfg(feature = "abi-7-12")]
+        let umask = match *self.request.operation() {
+            ll_request::Operation::MkNod { arg, .. } => arg.umask,
+            ll_request::Operation::MkDir { arg, .. } => arg.umask,
+            ll_request::Operation::Create { arg, .. } => arg.umask,
+            _ => 0,
+        };
+        #[cfg(not(feature = "abi-7-12"))]
+        let umask = 0;
+        Context {
+            unique: self.request.unique(),
+            uid: self.request.uid(),
+            gid: self.request.gid(),
+            pid: self.request.pid(),
+            umask,
+            #[cfg(feature = "abi-7-12")]
+            notifier: Some(self.notifier()),
+        }
+    }
 }
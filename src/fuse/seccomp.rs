@@ -0,0 +1,506 @@
+//! Seccomp sandboxing of the daemon
+//!
+//! Once mounted, the daemon only reads requests from the FUSE device, works on
+//! the files of its backing store and writes replies. A seccomp-bpf filter
+//! restricting the process to the syscalls needed for that reduces what an
+//! exploit of the request parser could do. The filter cannot be lifted, so
+//! the daemon cannot mount again afterwards, and it is inherited by children,
+//! so unmounting through fusermount fails and is left to the caller.
+//!
+//! `clone` is only allowed without the flags creating namespaces, and `prctl`
+//! only for the thread names. The arguments of `clone3` are in memory the
+//! filter cannot read, so it fails with `ENOSYS` and libc falls back to `clone`.
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+use std::io;
+
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+pub use filter::apply;
+
+/// The action taken on a syscall not allowed by the filter
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SeccompAction {
+    /// Allow the syscall but log it to the audit log, to find the syscalls
+    /// missing from the filter
+    Log,
+    /// Fail the syscall with `EPERM`
+    Errno,
+    /// Kill the process
+    Kill,
+}
+
+impl SeccompAction {
+    /// The seccomp return value of the action
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    ))]
+    const fn ret(self) -> u32 {
+        match self {
+            Self::Log => libc::SECCOMP_RET_LOG,
+            #[allow(clippy::as_conversions)] // EPERM is a small positive constant
+            Self::Errno => libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32),
+            Self::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+        }
+    }
+}
+
+/// The filter on the supported targets
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod filter {
+    use libc::{c_long, c_ulong, sock_filter, sock_fprog};
+    use std::io;
+
+    use super::super::{Cast, OverflowArithmetic};
+    use super::SeccompAction;
+
+    /// The audit arch of the target, a syscall of another arch is refused
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    /// The audit arch of the target, a syscall of another arch is refused
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+    /// The audit arch of the target, a syscall of another arch is refused
+    #[cfg(target_arch = "riscv64")]
+    const AUDIT_ARCH: u32 = 0xC000_00F3;
+
+    /// The offset of the syscall number in `struct seccomp_data`
+    const NR_OFFSET: u32 = 0;
+    /// The offset of the audit arch in `struct seccomp_data`
+    const ARCH_OFFSET: u32 = 4;
+    /// The offset of the low 32 bits of the first argument in `struct
+    /// seccomp_data`, on the little endian targets
+    const ARG0_OFFSET: u32 = 16;
+
+    /// The flags of `clone` creating namespaces, refused by the filter
+    #[allow(clippy::as_conversions, clippy::cast_sign_loss)] // the flags are positive
+    const CLONE_NAMESPACE_FLAGS: u32 = (libc::CLONE_NEWNS
+        | libc::CLONE_NEWCGROUP
+        | libc::CLONE_NEWUTS
+        | libc::CLONE_NEWIPC
+        | libc::CLONE_NEWUSER
+        | libc::CLONE_NEWPID
+        | libc::CLONE_NEWNET) as u32;
+
+    /// The options of `prctl` allowed by the filter, naming the threads and
+    /// their memory
+    #[allow(clippy::as_conversions, clippy::cast_sign_loss)] // the options are positive
+    const ALLOWED_PRCTL_OPTIONS: &[u32] = &[
+        libc::PR_SET_NAME as u32,
+        libc::PR_GET_NAME as u32,
+        libc::PR_SET_VMA as u32,
+    ];
+    /// The bit of the syscalls of the x32 ABI, which shares the audit arch of x86_64
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// The syscalls of the daemon after mount
+    pub const ALLOWED_SYSCALLS: &[c_long] = &[
+        // requests, replies and the data of the files
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_readv,
+        libc::SYS_writev,
+        libc::SYS_pread64,
+        libc::SYS_pwrite64,
+        libc::SYS_preadv,
+        libc::SYS_pwritev,
+        libc::SYS_lseek,
+        libc::SYS_fsync,
+        libc::SYS_fdatasync,
+        libc::SYS_ftruncate,
+        libc::SYS_fallocate,
+        // the files and directories of the backing store
+        libc::SYS_openat,
+        libc::SYS_openat2,
+        libc::SYS_close,
+        libc::SYS_fstat,
+        libc::SYS_newfstatat,
+        libc::SYS_statx,
+        libc::SYS_statfs,
+        libc::SYS_fstatfs,
+        libc::SYS_getdents64,
+        libc::SYS_mkdirat,
+        libc::SYS_mknodat,
+        libc::SYS_unlinkat,
+        libc::SYS_renameat2,
+        libc::SYS_linkat,
+        libc::SYS_symlinkat,
+        libc::SYS_readlinkat,
+        libc::SYS_faccessat,
+        libc::SYS_fchmod,
+        libc::SYS_fchmodat,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_utimensat,
+        libc::SYS_fgetxattr,
+        libc::SYS_fsetxattr,
+        libc::SYS_flistxattr,
+        libc::SYS_fremovexattr,
+        libc::SYS_fcntl,
+        libc::SYS_flock,
+        libc::SYS_dup,
+        libc::SYS_dup3,
+        libc::SYS_ppoll,
+        libc::SYS_getcwd,
+        libc::SYS_umount2,
+        // memory
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        // threads, signals and time, `clone` and `prctl` are checked by their arguments
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_getuid,
+        libc::SYS_geteuid,
+        libc::SYS_getgid,
+        libc::SYS_getegid,
+        libc::SYS_tgkill,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_nanosleep,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    /// The legacy syscalls of x86_64 without the `at` suffix, still used by libc
+    #[cfg(target_arch = "x86_64")]
+    const ALLOWED_LEGACY_SYSCALLS: &[c_long] = &[
+        libc::SYS_open,
+        libc::SYS_stat,
+        libc::SYS_lstat,
+        libc::SYS_access,
+        libc::SYS_poll,
+        libc::SYS_dup2,
+        libc::SYS_mkdir,
+        libc::SYS_rmdir,
+        libc::SYS_rename,
+        libc::SYS_renameat,
+        libc::SYS_unlink,
+        libc::SYS_link,
+        libc::SYS_symlink,
+        libc::SYS_readlink,
+        libc::SYS_chmod,
+        libc::SYS_chown,
+        libc::SYS_getdents,
+        libc::SYS_arch_prctl,
+    ];
+
+    /// The legacy syscalls of aarch64, `renameat` is still used by libc
+    #[cfg(target_arch = "aarch64")]
+    const ALLOWED_LEGACY_SYSCALLS: &[c_long] = &[libc::SYS_renameat];
+
+    /// The legacy syscalls, none on riscv64
+    #[cfg(target_arch = "riscv64")]
+    const ALLOWED_LEGACY_SYSCALLS: &[c_long] = &[];
+
+    /// A BPF statement
+    const fn stmt(code: u32, k: u32) -> sock_filter {
+        sock_filter {
+            #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+            // the BPF codes fit in 16 bits
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    /// A BPF conditional jump skipping `jt` statements if true and `jf` if false
+    const fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter {
+            #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+            // the BPF codes fit in 16 bits
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Build the filter allowing the syscalls of the daemon and taking `action`
+    /// on the others, a syscall of another arch kills the process
+    pub fn build_filter(action: SeccompAction) -> Vec<sock_filter> {
+        let mut filter = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH_OFFSET),
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR_OFFSET),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend_from_slice(&[
+            jump(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, action.ret()),
+        ]);
+        // clone without the namespace flags
+        filter.extend_from_slice(&[
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                libc::SYS_clone.cast(),
+                0,
+                4,
+            ),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARG0_OFFSET),
+            jump(
+                libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K,
+                CLONE_NAMESPACE_FLAGS,
+                0,
+                1,
+            ),
+            stmt(libc::BPF_RET | libc::BPF_K, action.ret()),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        ]);
+        // clone3 falls back to clone
+        #[allow(clippy::as_conversions)] // ENOSYS is a small positive constant
+        filter.extend_from_slice(&[
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                libc::SYS_clone3.cast(),
+                0,
+                1,
+            ),
+            stmt(
+                libc::BPF_RET | libc::BPF_K,
+                libc::SECCOMP_RET_ERRNO | (libc::ENOSYS as u32),
+            ),
+        ]);
+        // prctl of the allowed options, each jumping to the allow at the end
+        let num_options: u8 = ALLOWED_PRCTL_OPTIONS.len().cast();
+        filter.extend_from_slice(&[
+            jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                libc::SYS_prctl.cast(),
+                0,
+                num_options.overflow_add(3),
+            ),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARG0_OFFSET),
+        ]);
+        for (i, &option) in ALLOWED_PRCTL_OPTIONS.iter().enumerate() {
+            let remaining = num_options.overflow_sub(i.cast());
+            filter.push(jump(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                option,
+                remaining,
+                0,
+            ));
+        }
+        filter.extend_from_slice(&[
+            stmt(libc::BPF_RET | libc::BPF_K, action.ret()),
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+        ]);
+        for &nr in ALLOWED_SYSCALLS.iter().chain(ALLOWED_LEGACY_SYSCALLS) {
+            filter.extend_from_slice(&[
+                jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr.cast(), 0, 1),
+                stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW),
+            ]);
+        }
+        filter.push(stmt(libc::BPF_RET | libc::BPF_K, action.ret()));
+        filter
+    }
+
+    /// Restrict all the threads of the process to the syscalls of the daemon,
+    /// taking `action` on the others. It cannot be undone.
+    pub fn apply(action: SeccompAction) -> io::Result<()> {
+        let mut filter = build_filter(action);
+        let prog = sock_fprog {
+            len: filter.len().cast(),
+            filter: filter.as_mut_ptr(),
+        };
+        // required to install a filter without CAP_SYS_ADMIN
+        #[allow(unsafe_code)]
+        let res = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags: c_ulong = libc::SECCOMP_FILTER_FLAG_TSYNC.cast();
+        let prog_ptr: *const sock_fprog = &prog;
+        #[allow(unsafe_code)]
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                flags,
+                prog_ptr,
+            )
+        };
+        match res {
+            0 => Ok(()),
+            // TSYNC returns the id of a thread which cannot be synchronized
+            tid if tid > 0 => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("thread {} cannot be synchronized to the filter", tid),
+            )),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Fail with `ENOSYS` on the targets without the filter
+#[cfg(not(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+)))]
+pub fn apply(_action: SeccompAction) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOSYS))
+}
+
+#[cfg(test)]
+#[cfg(all(
+    target_os = "linux",
+    any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )
+))]
+mod test {
+    use super::filter::{build_filter, ALLOWED_SYSCALLS};
+    use super::SeccompAction;
+    use crate::fuse::OverflowArithmetic;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process::{self, Command};
+    use std::thread;
+
+    /// Set in the environment of the test binary run again to install the
+    /// filter, which cannot be removed from the process of the other tests
+    const UNDER_FILTER_ENV: &str = "FUSE_TEST_SECCOMP_CHILD";
+
+    /// Install the filter and run the syscalls of the daemon under it, returns
+    /// the exit code of the check failing, 0 if none
+    fn run_under_filter() -> i32 {
+        const TEST_DIR: &str = "/tmp/fuse_test_seccomp";
+        fs::create_dir_all(TEST_DIR).unwrap_or_else(|_| panic!());
+        fs::write(format!("{}/from", TEST_DIR), "data").unwrap_or_else(|_| panic!());
+        if super::apply(SeccompAction::Errno).is_err() {
+            return 1;
+        }
+        if fs::rename(format!("{}/from", TEST_DIR), format!("{}/to", TEST_DIR)).is_err() {
+            return 2;
+        }
+        if thread::spawn(|| ()).join().is_err() {
+            return 3;
+        }
+        // a user namespace is refused, the child returns right away if not
+        #[allow(unsafe_code)]
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_clone,
+                libc::CLONE_NEWUSER | libc::SIGCHLD,
+                0,
+                0,
+                0,
+                0,
+            )
+        };
+        if res == 0 {
+            #[allow(unsafe_code)]
+            unsafe {
+                libc::_exit(0)
+            };
+        }
+        if res != -1 || io::Error::last_os_error().raw_os_error() != Some(libc::EPERM) {
+            return 4;
+        }
+        #[allow(unsafe_code)]
+        let res = unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) };
+        if res != -1 {
+            return 5;
+        }
+        if fs::remove_dir_all(TEST_DIR).is_err() {
+            return 6;
+        }
+        0
+    }
+
+    #[test]
+    fn test_seccomp_under_filter() {
+        if env::var_os(UNDER_FILTER_ENV).is_some() {
+            process::exit(run_under_filter());
+        }
+        let status = Command::new(env::current_exe().unwrap_or_else(|_| panic!()))
+            .args(&[
+                "--exact",
+                "fuse::seccomp::test::test_seccomp_under_filter",
+                "--test-threads=1",
+            ])
+            .env(UNDER_FILTER_ENV, "1")
+            .status()
+            .unwrap_or_else(|_| panic!());
+        assert_eq!(status.code(), Some(0));
+    }
+
+    #[test]
+    fn test_seccomp_filter() {
+        let filter = build_filter(SeccompAction::Errno);
+        // within the limit of the kernel
+        assert!(filter.len() < 4096);
+        assert!(filter.len() > ALLOWED_SYSCALLS.len() * 2);
+        let last = filter.last().unwrap_or_else(|| panic!());
+        assert_eq!(last.k, libc::SECCOMP_RET_ERRNO | 1);
+        // every jump stays within the filter
+        for (i, ins) in filter.iter().enumerate() {
+            // the low 3 bits are the class of the instruction
+            if u32::from(ins.code) & 0x07 != libc::BPF_JMP {
+                continue;
+            }
+            let target = i.overflow_add(1).overflow_add(ins.jt.max(ins.jf).into());
+            assert!(target < filter.len());
+        }
+    }
+}
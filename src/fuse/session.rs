@@ -5,16 +5,28 @@
 //! filesystem is mounted, the session loop receives, dispatches and replies to kernel requests
 //! for filesystem operations under its mount point.
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io;
-use std::iter;
+use std::os::unix::io::RawFd;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
 // use thread_scoped::{scoped, JoinGuard};
 use libc::{EAGAIN, EINTR, ENODEV, ENOENT};
-use log::info;
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::unistd::{self, Gid, Uid};
 
+use super::buffer::RequestBuffer;
 use super::channel::Channel;
+#[cfg(feature = "abi-7-12")]
+use super::notify::Notifier;
+use super::privilege;
 use super::request::Request;
-use super::Filesystem;
+use super::seccomp::{self, SeccompAction};
+use super::{Cast, Context, Filesystem, FsError, InitInfo, Operation, OverflowArithmetic};
 
 /// The max size of write requests from the kernel. The absolute minimum is 4k,
 /// FUSE recommends at least 128k, max 16M. The FUSE default is 16M on macOS
@@ -25,6 +37,134 @@ pub const MAX_WRITE_SIZE: usize = 16 * 1024 * 1024;
 /// up to `MAX_WRITE_SIZE` bytes in a write request, we use that value plus some extra space.
 pub const BUFFER_SIZE: usize = MAX_WRITE_SIZE + 4096;
 
+/// Hook notified of a session event along with the mount point, `None` if the
+/// session was created from an fd
+type MountHook = Box<dyn FnMut(Option<&Path>) + Send>;
+/// Hook notified of the error ending a session
+type ErrorHook = Box<dyn FnMut(&io::Error) + Send>;
+/// Hook called before the filesystem handles a request, failing the request
+/// with the error returned
+type BeforeOpHook = Box<dyn FnMut(&Context, &Operation<'_>) -> Result<(), FsError> + Send>;
+/// Hook called once the filesystem handled a request, along with the time it took
+type AfterOpHook = Box<dyn FnMut(&Context, &Operation<'_>, Duration) + Send>;
+
+/// Hooks of the embedding application notified of the events of a session, so
+/// that it keeps its own state without scraping the logs
+#[derive(Default)]
+struct SessionHooks {
+    /// Hooks called once the kernel initialized the mount
+    on_mount: Vec<MountHook>,
+    /// Hooks called once the session ends
+    on_unmount: Vec<MountHook>,
+    /// Hooks called on a fatal error of the channel
+    on_error: Vec<ErrorHook>,
+    /// Hooks called before the filesystem handles a request
+    before_op: Vec<BeforeOpHook>,
+    /// Hooks called once the filesystem handled a request
+    after_op: Vec<AfterOpHook>,
+}
+
+impl fmt::Debug for SessionHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionHooks")
+            .field("on_mount", &self.on_mount.len())
+            .field("on_unmount", &self.on_unmount.len())
+            .field("on_error", &self.on_error.len())
+            .field("before_op", &self.before_op.len())
+            .field("after_op", &self.after_op.len())
+            .finish()
+    }
+}
+
+/// Why a session loop ended
+#[derive(Debug)]
+pub enum SessionExit {
+    /// The mount point was unmounted, or the harness of a mock session hung up
+    Unmounted,
+    /// The kernel sent a request that could not be parsed
+    InvalidRequest,
+    /// The channel failed, or the session failed to drop its privileges
+    Error(io::Error),
+}
+
+/// The statistics of a session loop, returned once it ends
+#[derive(Debug)]
+pub struct SessionSummary {
+    /// The number of the requests served by the name of their operation
+    pub requests: BTreeMap<&'static str, u64>,
+    /// The bytes of the requests received from the kernel
+    pub bytes_received: u64,
+    /// The bytes of the replies sent to the kernel
+    pub bytes_sent: u64,
+    /// The number of the error replies by errno
+    pub errors: BTreeMap<i32, u64>,
+    /// The number of the requests the filesystem panicked on
+    pub panics: u64,
+    /// The number of the requests slower than the slow operation threshold
+    pub slow: u64,
+    /// The number of the interrupted receives retried
+    pub retries: u64,
+    /// How long the loop ran
+    pub uptime: Duration,
+    /// Why the loop ended
+    pub exit: SessionExit,
+}
+
+impl SessionSummary {
+    /// Count a request of the operation of the size
+    fn count(&mut self, operation: &'static str, size: usize) {
+        let count = self.requests.entry(operation).or_insert(0);
+        *count = count.overflow_add(1);
+        self.bytes_received = self.bytes_received.overflow_add(size.cast());
+    }
+
+    /// The total number of the requests served
+    #[must_use]
+    pub fn total_requests(&self) -> u64 {
+        self.requests.values().sum()
+    }
+
+    /// The total number of the error replies
+    #[must_use]
+    pub fn total_errors(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    /// The result of `run`, an error if the loop ended on an error
+    pub fn into_result(self) -> io::Result<()> {
+        match self.exit {
+            SessionExit::Unmounted | SessionExit::InvalidRequest => Ok(()),
+            SessionExit::Error(err) => Err(err),
+        }
+    }
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "session ran {:?}, ended as {:?}, served {} requests of {} bytes, \
+                replied {} bytes, {} errors, {} panics, {} slow, {} retries",
+            self.uptime,
+            self.exit,
+            self.total_requests(),
+            self.bytes_received,
+            self.bytes_sent,
+            self.total_errors(),
+            self.panics,
+            self.slow,
+            self.retries,
+        )?;
+        for (operation, count) in &self.requests {
+            write!(f, ", {} {}", operation, count)?;
+        }
+        for (errno, count) in &self.errors {
+            write!(f, ", {:?} {}", Errno::from_i32(*errno), count)?;
+        }
+        Ok(())
+    }
+}
+
 /// The session data structure
 #[derive(Debug)]
 pub struct Session<FS: Filesystem> {
@@ -38,67 +178,389 @@ pub struct Session<FS: Filesystem> {
     pub proto_minor: u32,
     /// True if the filesystem is initialized (init operation done)
     pub initialized: bool,
+    /// The results of the init negotiation, `None` before init
+    pub init_info: Option<InitInfo>,
+    /// True if the opens are answered with `ENOSYS` for the kernel to skip them
+    pub no_open: bool,
+    /// True if the opendirs are answered with `ENOSYS` for the kernel to skip them
+    pub no_opendir: bool,
     /// True if the filesystem was destroyed (destroy operation done)
     pub destroyed: bool,
+    /// True once the session loop found the mount point unmounted
+    unmounted: bool,
+    /// Requests taking longer than this to handle are logged as slow operations
+    pub slow_op_threshold: Option<Duration>,
+    /// Once no request arrives for this long, the filesystem is called idle to
+    /// do its background work off the request path, `None` never to call it
+    pub idle_interval: Option<Duration>,
+    /// True to back the buffer receiving requests with transparent huge pages
+    pub huge_page_buffer: bool,
+    /// True to let a panic of the filesystem end the session, otherwise the request
+    /// panicking fails with EIO and its inode is poisoned, keeping the mount alive
+    pub strict: bool,
+    /// The user switched to once the session runs along with `setgid`, `None` to
+    /// keep the user
+    pub setuid: Option<Uid>,
+    /// The group switched to once the session runs, `None` to keep the group
+    pub setgid: Option<Gid>,
+    /// The action on the syscalls not needed by the daemon once the session runs,
+    /// `None` not to sandbox the daemon
+    pub seccomp: Option<SeccompAction>,
+    /// Hooks notified of the events of the session
+    hooks: SessionHooks,
 }
 
 impl<FS: Filesystem> Session<FS> {
     /// Create a new session by mounting the given filesystem to the given mountpoint
     pub fn new(filesystem: FS, mountpoint: &Path, options: &[&str]) -> io::Result<Self> {
         info!("mounting {:?}", mountpoint);
-        Channel::new(mountpoint, options).map(|ch| Self {
+        Channel::new(mountpoint, options).map(|ch| Self::with_channel(filesystem, ch))
+    }
+
+    /// Create a new session of an open `/dev/fuse` fd, for callers that already
+    /// performed the mount, e.g. container runtimes or mount helpers. The session
+    /// takes over the fd and closes it when dropped, but leaves the unmount to the
+    /// caller.
+    ///
+    /// # Safety
+    ///
+    /// The fd must be open and owned by no one else, it is closed along with the
+    /// session.
+    #[allow(unsafe_code)]
+    pub unsafe fn from_fd(fd: RawFd, filesystem: FS) -> Self {
+        info!("serving fd {}", fd);
+        Self::with_channel(filesystem, Channel::from_fd(fd))
+    }
+
+    /// Create a new session mounting nothing, for testing a filesystem end to end
+    /// on the machines without `/dev/fuse`. The returned fd is the kernel side of
+    /// the session, a test harness writes FUSE requests to it and reads the
+    /// replies, and shuts down its writing to end the session.
+    pub fn mock(filesystem: FS) -> io::Result<(Self, RawFd)> {
+        Channel::mock().map(|(ch, harness_fd)| {
+            info!("mock mount, the harness fd is {}", harness_fd);
+            (Self::with_channel(filesystem, ch), harness_fd)
+        })
+    }
+
+    /// Create a new session on the channel
+    fn with_channel(filesystem: FS, ch: Channel) -> Self {
+        Self {
             filesystem,
             ch,
             proto_major: 0,
             proto_minor: 0,
             initialized: false,
+            init_info: None,
+            no_open: false,
+            no_opendir: false,
             destroyed: false,
-        })
+            unmounted: false,
+            slow_op_threshold: None,
+            idle_interval: None,
+            huge_page_buffer: false,
+            strict: false,
+            setuid: None,
+            setgid: None,
+            seccomp: None,
+            hooks: SessionHooks::default(),
+        }
+    }
+
+    /// Return path of the mounted filesystem, `None` if the session was created
+    /// from an fd
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.ch.mountpoint()
+    }
+
+    /// Returns a notifier sending notifications to the kernel through the channel
+    /// of this session, e.g. for another thread invalidating the caches of the
+    /// kernel while the session runs
+    #[cfg(feature = "abi-7-12")]
+    pub fn notifier(&self) -> Notifier {
+        Notifier::new(self.ch.sender())
+    }
+
+    /// Call hook once the kernel initialized the mount and the filesystem serves
+    /// requests, hooks are called in the order they are added
+    pub fn on_mount(&mut self, hook: impl FnMut(Option<&Path>) + Send + 'static) {
+        self.hooks.on_mount.push(Box::new(hook));
+    }
+
+    /// Call hook once the mount point is unmounted as the session ends, after the
+    /// filesystem is destroyed. Only for a mount the kernel initialized, and
+    /// never while the session is dropped by a panic. A session from an fd
+    /// leaves the unmount to the caller, the hook is called once its fd is
+    /// closed.
+    pub fn on_unmount(&mut self, hook: impl FnMut(Option<&Path>) + Send + 'static) {
+        self.hooks.on_unmount.push(Box::new(hook));
     }
 
-    /// Return path of the mounted filesystem
-    pub fn mountpoint(&self) -> &Path {
-        self.ch.mountpoint().as_ref()
+    /// Call hook with the error of the channel ending the session loop
+    pub fn on_error(&mut self, hook: impl FnMut(&io::Error) + Send + 'static) {
+        self.hooks.on_error.push(Box::new(hook));
+    }
+
+    /// Call hook with the caller and the operation of a request before the
+    /// filesystem handles it, the request fails with the error returned by the
+    /// hook, e.g. for a policy or a rate limit. Only the requests the filesystem
+    /// replies to are passed to the hooks, i.e. not init, destroy, forget or
+    /// interrupt, and the hooks are called before `authorize`.
+    pub fn before_op(
+        &mut self,
+        hook: impl FnMut(&Context, &Operation<'_>) -> Result<(), FsError> + Send + 'static,
+    ) {
+        self.hooks.before_op.push(Box::new(hook));
+    }
+
+    /// Call hook with the caller and the operation of a request once the
+    /// filesystem handled it, along with the time it took, e.g. for metrics or
+    /// auditing. The reply of the filesystem may still be pending.
+    pub fn after_op(
+        &mut self,
+        hook: impl FnMut(&Context, &Operation<'_>, Duration) + Send + 'static,
+    ) {
+        self.hooks.after_op.push(Box::new(hook));
+    }
+
+    /// Call the hooks before the filesystem handles a request, the error of the
+    /// first hook failing it
+    pub(crate) fn before_op_hooks(
+        &mut self,
+        ctx: &Context,
+        op: &Operation<'_>,
+    ) -> Result<(), FsError> {
+        self.hooks
+            .before_op
+            .iter_mut()
+            .try_for_each(|hook| hook(ctx, op))
+    }
+
+    /// Notify the hooks that the kernel initialized the mount
+    pub(crate) fn notify_mounted(&mut self) {
+        let mountpoint = self.ch.mountpoint();
+        for hook in &mut self.hooks.on_mount {
+            hook(mountpoint);
+        }
     }
 
     /// Run the session loop that receives kernel requests and dispatches them to method
     /// calls into the filesystem. This read-dispatch-loop is non-concurrent to prevent
     /// having multiple buffers (which take up much memory), but the filesystem methods
-    /// may run concurrent by spawning threads.
+    /// may run concurrent by spawning threads. Logs the summary of the loop once it ends.
     pub fn run(&mut self) -> io::Result<()> {
+        let summary = self.run_with_callbacks();
+        info!("{}", summary);
+        summary.into_result()
+    }
+
+    /// Run the session loop as `run` does, calling the hooks, returning the
+    /// statistics of the loop and why it ended, e.g. for the embedding
+    /// application to report them or to decide whether to mount again
+    pub fn run_with_callbacks(&mut self) -> SessionSummary {
+        let start = Instant::now();
+        let stats = self.ch.reply_stats();
+        let (bytes_sent, errors) = (stats.bytes_sent(), stats.errors());
+        let mut summary = SessionSummary {
+            requests: BTreeMap::new(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            errors: BTreeMap::new(),
+            panics: 0,
+            slow: 0,
+            retries: 0,
+            uptime: Duration::default(),
+            exit: SessionExit::Unmounted,
+        };
+        summary.exit = self.run_loop(&mut summary);
+        if let SessionExit::Unmounted = summary.exit {
+            self.unmounted = true;
+        }
+        summary.uptime = start.elapsed();
+        // the replies sent by the earlier loops of the session are not counted
+        let stats = self.ch.reply_stats();
+        summary.bytes_sent = stats.bytes_sent().overflow_sub(bytes_sent);
+        summary.errors = stats
+            .errors()
+            .into_iter()
+            .map(|(errno, count)| {
+                let before = errors.get(&errno).copied().unwrap_or(0);
+                (errno, count.overflow_sub(before))
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        summary
+    }
+
+    /// The session loop, counting the requests in the summary
+    fn run_loop(&mut self, summary: &mut SessionSummary) -> SessionExit {
         // Buffer for receiving requests from the kernel. Only one is allocated and
         // it is reused immediately after dispatching to conserve memory and allocations.
-        // let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
-        let mut buffer: Vec<u8> = iter::repeat(0_u8).take(BUFFER_SIZE).collect();
+        // The buffer is page aligned, so the arguments of requests are parsed in place.
+        let mut buffer = RequestBuffer::new(BUFFER_SIZE, self.huge_page_buffer);
+        // the mount is done, the privileges are no longer needed
+        if self.setuid.is_some() || self.setgid.is_some() {
+            if let Err(err) = privilege::drop_privileges(self.setuid, self.setgid) {
+                return SessionExit::Error(err);
+            }
+            info!(
+                "dropped privileges, running as uid {} gid {}",
+                unistd::getuid(),
+                unistd::getgid()
+            );
+        }
+        if let Some(action) = self.seccomp {
+            if let Err(err) = seccomp::apply(action) {
+                return SessionExit::Error(err);
+            }
+            info!(
+                "sandboxed by seccomp, other syscalls take action {:?}",
+                action
+            );
+        }
 
         loop {
+            if let (Some(interval), true) = (self.idle_interval, self.initialized) {
+                // the errors of the channel are reported by the receive
+                if let Ok(false) = self.ch.wait_readable(interval) {
+                    self.filesystem.idle();
+                    continue;
+                }
+            }
             // Read the next request from the given channel to kernel driver
             // The kernel driver makes sure that we get exactly one request per read
             match self.ch.receive(&mut buffer) {
-                Ok(()) => match Request::new(self.ch.sender(), &buffer) {
+                // the peer of a mock channel hung up
+                Ok(()) if buffer.as_slice().is_empty() => return SessionExit::Unmounted,
+                Ok(()) => match Request::new(self.ch.reply_sender(), buffer.as_slice()) {
                     // Dispatch request
-                    Some(req) => req.dispatch(self),
+                    Some(req) => {
+                        summary.count(req.request.operation().name(), buffer.as_slice().len());
+                        let start = self.slow_op_threshold.map(|_| Instant::now());
+                        if self.dispatch(&req) {
+                            summary.panics = summary.panics.overflow_add(1);
+                        }
+                        if let (Some(threshold), Some(start)) = (self.slow_op_threshold, start) {
+                            let elapsed = start.elapsed();
+                            if elapsed > threshold {
+                                summary.slow = summary.slow.overflow_add(1);
+                                warn!(
+                                    "slow operation took {:?} (threshold {:?}), pid {}: {}",
+                                    elapsed,
+                                    threshold,
+                                    req.request.pid(),
+                                    req.request,
+                                );
+                            }
+                        }
+                    }
                     // Quit loop on illegal request
-                    None => break,
+                    None => return SessionExit::InvalidRequest,
                 },
                 Err(err) => match err.raw_os_error() {
                     // ENOENT: Operation interrupted. Accordingly to FUSE, this is safe to retry
                     // EINTR: Interrupted system call, retry
                     // EAGAIN: Explicitly try again
-                    Some(ENOENT) | Some(EINTR) | Some(EAGAIN) => continue,
-                    Some(ENODEV) => break,
+                    Some(ENOENT) | Some(EINTR) | Some(EAGAIN) => {
+                        summary.retries = summary.retries.overflow_add(1);
+                        continue;
+                    }
+                    Some(ENODEV) => return SessionExit::Unmounted,
                     // Unhandled error
-                    None | Some(_) => return Err(err),
+                    None | Some(_) => {
+                        for hook in &mut self.hooks.on_error {
+                            hook(&err);
+                        }
+                        return SessionExit::Error(err);
+                    }
                 },
             }
         }
-        Ok(())
+    }
+
+    /// Dispatch the request, unless strict a panic of the filesystem is caught, the
+    /// reply not sent replies EIO once dropped and the target inode of the request
+    /// is poisoned,
+    /// `true` if the filesystem panicked
+    fn dispatch(&mut self, req: &Request<'_>) -> bool {
+        let start = Instant::now();
+        let panicked = if self.strict {
+            req.dispatch(self);
+            false
+        } else {
+            panic::catch_unwind(AssertUnwindSafe(|| req.dispatch(self))).is_err()
+        };
+        let elapsed = start.elapsed();
+        if !self.hooks.after_op.is_empty() {
+            let ctx = req.context();
+            for hook in &mut self.hooks.after_op {
+                hook(&ctx, req.request.operation(), elapsed);
+            }
+        }
+        if panicked {
+            // the parent directory of an operation by name stays usable, the
+            // inode the panic left inconsistent is not known
+            match req.request.target_ino() {
+                Some(ino) => {
+                    error!(
+                        "filesystem panicked handling {}, poisoning inode {}",
+                        req.request, ino
+                    );
+                    self.filesystem.poison(ino);
+                }
+                None => error!("filesystem panicked handling {}", req.request),
+            }
+        }
+        panicked
+    }
+
+    /// Flush and destroy the filesystem once the session ends without destroy
+    /// from the kernel, the filesystem is detached first. Only once, nothing to
+    /// do if the kernel sent destroy before.
+    pub fn destroy_filesystem(&mut self) {
+        if !self.destroyed {
+            self.filesystem.detach();
+            self.destroy_requested();
+        }
+    }
+
+    /// Flush and destroy the filesystem once the kernel sends destroy, only once
+    pub(crate) fn destroy_requested(&mut self) {
+        if !self.destroyed {
+            self.filesystem.pre_unmount();
+            self.filesystem.destroy();
+            self.destroyed = true;
+        }
     }
 }
 
 impl<FS: Filesystem> Drop for Session<FS> {
     fn drop(&mut self) {
-        info!("umounted {}", self.mountpoint().display());
+        // The channel is closed after this, so the filesystem still gets a chance to
+        // flush its data. Skip it when unwinding, since the filesystem may be broken.
+        let panicking = thread::panicking();
+        if self.initialized && !panicking {
+            self.destroy_filesystem();
+        }
+        // the loop ends once the mount point is unmounted by others, e.g. by
+        // `fusermount -u`, then unmounting it again fails
+        let closed = self.ch.close();
+        let unmounted = closed.is_ok() || self.unmounted;
+        match (self.ch.mountpoint(), closed) {
+            (Some(mountpoint), _) if unmounted => info!("umounted {}", mountpoint.display()),
+            (Some(mountpoint), Err(e)) => error!(
+                "failed to unmount {}, the error is: {}",
+                mountpoint.display(),
+                e
+            ),
+            (Some(_), Ok(())) | (None, _) => info!("session ended"),
+        }
+        if unmounted && self.initialized && !panicking {
+            let mountpoint = self.ch.mountpoint();
+            for hook in &mut self.hooks.on_unmount {
+                hook(mountpoint);
+            }
+        }
     }
 }
 
@@ -152,3 +614,529 @@ impl<'a> fmt::Debug for BackgroundSession<'a> {
     }
 }
 */
+
+#[cfg(test)]
+mod test {
+    use super::{Session, SessionExit};
+    use crate::fuse::harness::{error_of, init_request, request, request_of};
+    use crate::fuse::{
+        Cast, Context, Filesystem, FsError, FsInitConfig, FsReleaseParam, Operation,
+        OverflowArithmetic, ReplyData, ReplyEmpty, ReplyEntry,
+    };
+    use nix::sys::socket::{self, AddressFamily, Shutdown, SockFlag, SockType};
+    use nix::unistd;
+    use std::collections::BTreeSet;
+    use std::convert::TryInto;
+    use std::ffi::OsStr;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Filesystem of the default operations
+    struct NullFs;
+
+    impl Filesystem for NullFs {}
+
+    /// Filesystem needing no opens, of times in whole seconds, caching writes
+    struct NoOpenFs;
+
+    impl Filesystem for NoOpenFs {
+        fn init(&mut self, _ctx: &Context, config: &mut FsInitConfig) -> Result<(), FsError> {
+            config.no_open = true;
+            #[cfg(feature = "abi-7-23")]
+            {
+                config.writeback_cache = true;
+                config.time_gran = 1_000_000_000;
+            }
+            Ok(())
+        }
+    }
+
+    /// Filesystem recording whether it is destroyed
+    struct DestroyedFs(Arc<Mutex<bool>>);
+
+    impl Filesystem for DestroyedFs {
+        fn destroy(&mut self) {
+            *self.0.lock().unwrap_or_else(|_| panic!()) = true;
+        }
+    }
+
+    /// Filesystem counting the idle calls
+    struct IdleFs(Arc<Mutex<u32>>);
+
+    impl Filesystem for IdleFs {
+        fn idle(&mut self) {
+            let mut calls = self.0.lock().unwrap_or_else(|_| panic!());
+            *calls = calls.overflow_add(1);
+        }
+    }
+
+    /// Filesystem panicking on readlink and lookup, counting the calls
+    struct PanicFs {
+        /// The number of the readlink and lookup calls
+        calls: Arc<Mutex<u32>>,
+        /// The poisoned inodes
+        poisoned: BTreeSet<u64>,
+    }
+
+    impl Filesystem for PanicFs {
+        fn readlink(&mut self, _ctx: &Context, _ino: u64, _reply: ReplyData) {
+            let mut calls = self.calls.lock().unwrap_or_else(|_| panic!());
+            *calls = calls.overflow_add(1);
+            drop(calls);
+            panic!("PanicFs::readlink() panics");
+        }
+
+        fn lookup(&mut self, _ctx: &Context, _parent: u64, _name: &OsStr, _reply: ReplyEntry) {
+            let mut calls = self.calls.lock().unwrap_or_else(|_| panic!());
+            *calls = calls.overflow_add(1);
+            drop(calls);
+            panic!("PanicFs::lookup() panics");
+        }
+
+        fn poison(&mut self, ino: u64) {
+            self.poisoned.insert(ino);
+        }
+
+        fn is_poisoned(&self, ino: u64, _op: &Operation<'_>) -> bool {
+            self.poisoned.contains(&ino)
+        }
+    }
+
+    /// Filesystem denying the requests of a user, recording the releases
+    struct AuthFs {
+        /// The user denied
+        denied_uid: u32,
+        /// The names of the release calls
+        released: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl Filesystem for AuthFs {
+        fn authorize(&self, ctx: &Context, _op: &Operation<'_>) -> Result<(), FsError> {
+            if ctx.uid == self.denied_uid {
+                Err(FsError::PermissionDenied)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn release(&mut self, _ctx: &Context, _param: FsReleaseParam, reply: ReplyEmpty) {
+            self.released
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("release");
+            reply.ok();
+        }
+
+        fn releasedir(
+            &mut self,
+            _ctx: &Context,
+            _ino: u64,
+            _fh: u64,
+            _flags: u32,
+            reply: ReplyEmpty,
+        ) {
+            self.released
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("releasedir");
+            reply.ok();
+        }
+    }
+
+    /// Run a session of `PanicFs` on an init and two requests of the opcode on
+    /// the inode 2, returns the errors of the replies and the number of the calls
+    fn run_panic_fs(strict: bool, opcode: u32, arg: &[u8]) -> (Vec<i32>, u32) {
+        let (local_fd, remote_fd) = socket::socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::empty(),
+        )
+        .unwrap_or_else(|_| panic!());
+        let calls = Arc::new(Mutex::new(0));
+        let fs = PanicFs {
+            calls: Arc::clone(&calls),
+            poisoned: BTreeSet::new(),
+        };
+        #[allow(unsafe_code)]
+        let mut se = unsafe { Session::from_fd(local_fd, fs) };
+        se.strict = strict;
+        for req in &[
+            init_request(8, 0),
+            request(opcode, 2, 2, arg),
+            request(opcode, 3, 2, arg),
+        ] {
+            unistd::write(remote_fd, req).unwrap_or_else(|_| panic!());
+        }
+        // the session ends once the requests are read
+        socket::shutdown(remote_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        let res = panic::catch_unwind(AssertUnwindSafe(|| se.run()));
+        assert_eq!(res.is_ok(), !strict);
+        drop(se);
+
+        let mut errors = Vec::new();
+        let mut buf = [0_u8; 4096];
+        loop {
+            // the connection is reset if the session ends with requests unread
+            let size = unistd::read(remote_fd, &mut buf).unwrap_or(0);
+            if size == 0 {
+                break;
+            }
+            let error = buf.get(4..8).unwrap_or_else(|| panic!());
+            errors.push(i32::from_ne_bytes(
+                error.try_into().unwrap_or_else(|_| panic!()),
+            ));
+        }
+        unistd::close(remote_fd).unwrap_or_else(|_| panic!());
+        let count = *calls.lock().unwrap_or_else(|_| panic!());
+        (errors, count)
+    }
+
+    #[test]
+    fn test_poison_on_panic() {
+        // the panicking request fails, the next one fails without calling the filesystem
+        // readlink
+        assert_eq!(
+            run_panic_fs(false, 5, &[]),
+            (vec![0, -libc::EIO, -libc::EIO], 1)
+        );
+        // the parent directory of a lookup is not poisoned, the next lookup
+        // calls the filesystem again
+        assert_eq!(
+            run_panic_fs(false, 1, b"name\0"),
+            (vec![0, -libc::EIO, -libc::EIO], 2)
+        );
+        // the panic ends a strict session, resetting the connection with the
+        // requests unread, so the replies are lost
+        assert_eq!(run_panic_fs(true, 5, &[]).1, 1);
+    }
+
+    #[test]
+    fn test_mock_session() {
+        let (mut se, harness_fd) = Session::mock(NullFs).unwrap_or_else(|_| panic!());
+        assert!(se.mountpoint().is_none());
+        // init, then getattr of the root
+        for req in &[init_request(8, 0), request(3, 2, 1, &[0; 16])] {
+            unistd::write(harness_fd, req).unwrap_or_else(|_| panic!());
+        }
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        let summary = se.run_with_callbacks();
+        assert!(matches!(summary.exit, SessionExit::Unmounted));
+        assert_eq!(summary.total_requests(), 2);
+        assert_eq!(summary.requests.get("GETATTR"), Some(&1));
+        assert_eq!(summary.errors.get(&libc::ENOSYS), Some(&1));
+        assert_eq!(summary.total_errors(), 1);
+        assert_eq!(summary.panics, 0);
+        assert!(se.initialized);
+        drop(se);
+
+        let mut replies = Vec::new();
+        let mut bytes_sent = 0_usize;
+        let mut buf = [0_u8; 4096];
+        loop {
+            let size = unistd::read(harness_fd, &mut buf).unwrap_or(0);
+            if size == 0 {
+                break;
+            }
+            bytes_sent = bytes_sent.overflow_add(size);
+            let unique = u64::from_ne_bytes(
+                buf.get(8..16)
+                    .unwrap_or_else(|| panic!())
+                    .try_into()
+                    .unwrap_or_else(|_| panic!()),
+            );
+            let error = i32::from_ne_bytes(
+                buf.get(4..8)
+                    .unwrap_or_else(|| panic!())
+                    .try_into()
+                    .unwrap_or_else(|_| panic!()),
+            );
+            replies.push((unique, error));
+        }
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+        // the replies match the unique ids of the requests
+        assert_eq!(replies, vec![(1, 0), (2, -libc::ENOSYS)]);
+        assert_eq!(summary.bytes_sent, bytes_sent.cast::<u64>());
+    }
+
+    /// Run a session of `NoOpenFs` on an init of the minor version and the
+    /// flags and an open, returns the replies
+    fn run_no_open_fs(minor: u32, flags: u32) -> Vec<Vec<u8>> {
+        let (mut se, harness_fd) = Session::mock(NoOpenFs).unwrap_or_else(|_| panic!());
+        for req in &[init_request(minor, flags), request(14, 2, 2, &[0; 8])] {
+            unistd::write(harness_fd, req).unwrap_or_else(|_| panic!());
+        }
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        se.run().unwrap_or_else(|_| panic!());
+        drop(se);
+
+        let mut replies = Vec::new();
+        let mut buf = [0_u8; 4096];
+        loop {
+            let size = unistd::read(harness_fd, &mut buf).unwrap_or(0);
+            if size == 0 {
+                break;
+            }
+            replies.push(buf.get(..size).unwrap_or_else(|| panic!()).to_vec());
+        }
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+        replies
+    }
+
+    #[test]
+    fn test_init_config() {
+        // the open reaches the filesystem unless the kernel offers to skip it
+        let replies = run_no_open_fs(8, 0);
+        assert_eq!(
+            replies.iter().map(|r| error_of(r)).collect::<Vec<_>>(),
+            [0, 0]
+        );
+        // the reply of init is of the layout before ABI 7.23
+        assert_eq!(replies.first().map(Vec::len), Some(40));
+
+        // the writeback cache is enabled only if offered
+        let flags_of = |init: &[u8]| {
+            let flags = init.get(28..32).unwrap_or_else(|| panic!());
+            u32::from_ne_bytes(flags.try_into().unwrap_or_else(|_| panic!()))
+        };
+        let init = replies.first().unwrap_or_else(|| panic!());
+        assert_eq!(flags_of(init) & 1 << 16, 0);
+
+        let replies = run_no_open_fs(23, 1 << 17 | 1 << 16);
+        assert_eq!(
+            replies.iter().map(|r| error_of(r)).collect::<Vec<_>>(),
+            [0, -libc::ENOSYS]
+        );
+        let init = replies.first().unwrap_or_else(|| panic!());
+        #[cfg(not(feature = "abi-7-23"))]
+        assert_eq!(init.len(), 40);
+        #[cfg(feature = "abi-7-23")]
+        {
+            assert_eq!(init.len(), 80);
+            assert_eq!(flags_of(init) & 1 << 16, 1 << 16);
+            let time_gran = init.get(40..44).unwrap_or_else(|| panic!());
+            assert_eq!(
+                u32::from_ne_bytes(time_gran.try_into().unwrap_or_else(|_| panic!())),
+                1_000_000_000
+            );
+        }
+    }
+
+    #[test]
+    fn test_offset_out_of_range() {
+        use crate::fuse::harness::exchange;
+
+        let (mut se, harness_fd) = Session::mock(NullFs).unwrap_or_else(|_| panic!());
+        let session = thread::spawn(move || se.run().unwrap_or_else(|_| panic!()));
+        assert_eq!(error_of(&exchange(harness_fd, &init_request(8, 0))), 0);
+        // the offsets out of the range of off_t fail before the filesystem is asked
+        let mut read_arg = Vec::new();
+        read_arg.extend_from_slice(&0_u64.to_ne_bytes()); // fh
+        read_arg.extend_from_slice(&u64::MAX.to_ne_bytes()); // offset
+        read_arg.extend_from_slice(&[0; 8]); // size, read flags
+        #[cfg(feature = "abi-7-9")]
+        read_arg.extend_from_slice(&[0; 16]); // lock owner, flags, padding
+        assert_eq!(
+            error_of(&exchange(harness_fd, &request(15, 2, 1, &read_arg))),
+            -libc::EINVAL
+        );
+        #[cfg(feature = "abi-7-19")]
+        for (offset, length) in &[(u64::MAX, 1_u64), (0, u64::MAX)] {
+            let mut fallocate_arg = Vec::new();
+            for field in &[0, *offset, *length] {
+                fallocate_arg.extend_from_slice(&field.to_ne_bytes()); // fh, offset, length
+            }
+            fallocate_arg.extend_from_slice(&[0; 8]); // mode, padding
+            assert_eq!(
+                error_of(&exchange(harness_fd, &request(43, 3, 1, &fallocate_arg))),
+                -libc::EINVAL
+            );
+        }
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        session.join().unwrap_or_else(|_| panic!());
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_idle() {
+        let calls = Arc::new(Mutex::new(0));
+        let (mut se, harness_fd) =
+            Session::mock(IdleFs(Arc::clone(&calls))).unwrap_or_else(|_| panic!());
+        se.idle_interval = Some(Duration::from_millis(10));
+        unistd::write(harness_fd, &init_request(8, 0)).unwrap_or_else(|_| panic!());
+        let harness = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+            harness_fd
+        });
+        se.run().unwrap_or_else(|_| panic!());
+        drop(se);
+        let harness_fd = harness.join().unwrap_or_else(|_| panic!());
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+        // called while no request is pending
+        assert!(*calls.lock().unwrap_or_else(|_| panic!()) > 0);
+    }
+
+    #[test]
+    fn test_op_hooks() {
+        let (mut se, harness_fd) = Session::mock(NullFs).unwrap_or_else(|_| panic!());
+        se.before_op(|_ctx, op| match op.name() {
+            "READLINK" => Err(FsError::PermissionDenied),
+            _ => Ok(()),
+        });
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let after_handled = Arc::clone(&handled);
+        se.after_op(move |_ctx, op, _elapsed| {
+            after_handled
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push(op.name());
+        });
+        for req in &[
+            init_request(8, 0),
+            request(5, 2, 1, &[]),
+            request(3, 3, 1, &[0; 16]),
+        ] {
+            unistd::write(harness_fd, req).unwrap_or_else(|_| panic!());
+        }
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        se.run().unwrap_or_else(|_| panic!());
+        drop(se);
+
+        let mut errors = Vec::new();
+        let mut buf = [0_u8; 4096];
+        while unistd::read(harness_fd, &mut buf).unwrap_or(0) > 0 {
+            let error = buf.get(4..8).unwrap_or_else(|| panic!());
+            errors.push(i32::from_ne_bytes(
+                error.try_into().unwrap_or_else(|_| panic!()),
+            ));
+        }
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+        // the hook fails readlink before the filesystem is asked
+        assert_eq!(errors, vec![0, -libc::EACCES, -libc::ENOSYS]);
+        assert_eq!(
+            *handled.lock().unwrap_or_else(|_| panic!()),
+            vec!["INIT", "READLINK", "GETATTR"]
+        );
+    }
+
+    #[test]
+    fn test_authorize() {
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let fs = AuthFs {
+            denied_uid: 1000,
+            released: Arc::clone(&released),
+        };
+        let (mut se, harness_fd) = Session::mock(fs).unwrap_or_else(|_| panic!());
+        // getattr by the denied user and by root, release and releasedir by
+        // the denied user
+        for req in &[
+            init_request(8, 0),
+            request_of(1000, 3, 2, 1, &[0; 16]),
+            request_of(0, 3, 3, 1, &[0; 16]),
+            request_of(1000, 18, 4, 1, &[0; 24]),
+            request_of(1000, 29, 5, 1, &[0; 24]),
+        ] {
+            unistd::write(harness_fd, req).unwrap_or_else(|_| panic!());
+        }
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        se.run().unwrap_or_else(|_| panic!());
+        drop(se);
+
+        let mut errors = Vec::new();
+        let mut buf = [0_u8; 4096];
+        while unistd::read(harness_fd, &mut buf).unwrap_or(0) > 0 {
+            let error = buf.get(4..8).unwrap_or_else(|| panic!());
+            errors.push(i32::from_ne_bytes(
+                error.try_into().unwrap_or_else(|_| panic!()),
+            ));
+        }
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+        assert_eq!(errors, vec![0, -libc::EACCES, -libc::ENOSYS, 0, 0]);
+        // the handles of a denied user are released all the same
+        assert_eq!(
+            *released.lock().unwrap_or_else(|_| panic!()),
+            vec!["release", "releasedir"]
+        );
+    }
+
+    #[test]
+    fn test_session_hooks() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        // reading the write end of a pipe fails, which ends the session loop
+        let (read_fd, write_fd) = unistd::pipe().unwrap_or_else(|_| panic!());
+        #[allow(unsafe_code)]
+        let mut se = unsafe { Session::from_fd(write_fd, NullFs) };
+        let mount_events = Arc::clone(&events);
+        se.on_mount(move |_| {
+            mount_events
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("mount")
+        });
+        let error_events = Arc::clone(&events);
+        se.on_error(move |err| {
+            assert!(err.raw_os_error().is_some());
+            error_events
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("error");
+        });
+        let unmount_events = Arc::clone(&events);
+        se.on_unmount(move |mountpoint| {
+            assert!(mountpoint.is_none());
+            unmount_events
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("unmount");
+        });
+        assert!(se.run().is_err());
+        drop(se);
+        // never initialized by the kernel, so never mounted
+        assert_eq!(*events.lock().unwrap_or_else(|_| panic!()), vec!["error"]);
+        unistd::close(read_fd).unwrap_or_else(|_| panic!());
+
+        // a mount initialized, then unmounted once dropped
+        events.lock().unwrap_or_else(|_| panic!()).clear();
+        let (mut se, harness_fd) = Session::mock(NullFs).unwrap_or_else(|_| panic!());
+        let mount_events = Arc::clone(&events);
+        se.on_mount(move |_| {
+            mount_events
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("mount")
+        });
+        let unmount_events = Arc::clone(&events);
+        se.on_unmount(move |_| {
+            unmount_events
+                .lock()
+                .unwrap_or_else(|_| panic!())
+                .push("unmount")
+        });
+        unistd::write(harness_fd, &init_request(8, 0)).unwrap_or_else(|_| panic!());
+        socket::shutdown(harness_fd, Shutdown::Write).unwrap_or_else(|_| panic!());
+        se.run().unwrap_or_else(|_| panic!());
+        assert_eq!(*events.lock().unwrap_or_else(|_| panic!()), vec!["mount"]);
+        drop(se);
+        assert_eq!(
+            *events.lock().unwrap_or_else(|_| panic!()),
+            vec!["mount", "unmount"]
+        );
+        unistd::close(harness_fd).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_boxed_filesystem() {
+        let destroyed = Arc::new(Mutex::new(false));
+        let fs: Box<dyn Filesystem + Send> = Box::new(DestroyedFs(Arc::clone(&destroyed)));
+        let (read_fd, write_fd) = unistd::pipe().unwrap_or_else(|_| panic!());
+        #[allow(unsafe_code)]
+        let mut se = unsafe { Session::from_fd(read_fd, fs) };
+        se.destroy_filesystem();
+        assert!(*destroyed.lock().unwrap_or_else(|_| panic!()));
+        drop(se);
+        unistd::close(write_fd).unwrap_or_else(|_| panic!());
+    }
+}
@@ -0,0 +1,222 @@
+//! Session supervisor
+//!
+//! The supervisor runs a session in a background thread and periodically checks the health of
+//! its mount point from the supervising thread. If the mount point is wedged, i.e. it reports
+//! `ENOTCONN` or does not answer in time, or the session ends abnormally, the supervisor unmounts
+//! it by force and mounts a new session, which restores its state from the backing store.
+
+use log::{error, info, warn};
+use nix::errno::Errno;
+use nix::sys::statvfs;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::channel::{self, UnmountFlags};
+use super::session::Session;
+use super::{Filesystem, OverflowArithmetic};
+
+/// Supervisor config
+#[derive(Clone, Copy, Debug)]
+pub struct SupervisorConfig {
+    /// Interval between health checks of the mount point
+    pub check_interval: Duration,
+    /// Time a health check may take before the mount point is considered wedged, also the
+    /// time to wait for a wedged session to end after unmounting it
+    pub check_timeout: Duration,
+    /// Max number of remounts before giving up
+    pub max_remounts: u32,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(10),
+            check_timeout: Duration::from_secs(5),
+            max_remounts: 3,
+        }
+    }
+}
+
+/// Check the health of the mount point, statfs is not cached by the kernel so it always
+/// reaches the session. A check stuck on a wedged mount point ends once it is unmounted.
+pub fn check_mountpoint(mountpoint: &Path, timeout: Duration) -> bool {
+    let (tx, rx) = mpsc::channel();
+    let path = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        // the receiver is gone if the check timed out
+        tx.send(statvfs::statvfs(&path)).unwrap_or(());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(Ok(_)) => true,
+        Ok(Err(err)) => {
+            if err.as_errno() == Some(Errno::ENOTCONN) {
+                warn!("mount point {:?} is not connected", mountpoint);
+                false
+            } else {
+                warn!("failed to check mount point {:?}: {}", mountpoint, err);
+                true
+            }
+        }
+        Err(_) => {
+            warn!(
+                "mount point {:?} did not answer within {:?}",
+                mountpoint, timeout
+            );
+            false
+        }
+    }
+}
+
+/// Create, read back, rename and remove a probe file under the directory, returns an error
+/// if its content or name does not survive the round trip
+fn probe(dir: &Path) -> io::Result<()> {
+    let nonce = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let path = dir.join(format!(".selftest-{}", process::id()));
+    let renamed = dir.join(format!(".selftest-{}.renamed", process::id()));
+    let content = format!("self test of {:?} at {}", dir, nonce);
+    let mismatch = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("the probe file {:?} {}", path, what),
+        )
+    };
+
+    fs::write(&path, &content)?;
+    if fs::read(&path)? != content.as_bytes() {
+        return Err(mismatch("read back other data than written"));
+    }
+    fs::rename(&path, &renamed)?;
+    if fs::symlink_metadata(&path).is_ok() {
+        return Err(mismatch("is still there once renamed"));
+    }
+    if fs::read(&renamed)? != content.as_bytes() {
+        return Err(mismatch("lost its data once renamed"));
+    }
+    fs::remove_file(&renamed)?;
+    if fs::symlink_metadata(&renamed).is_ok() {
+        return Err(mismatch("is still there once removed"));
+    }
+    Ok(())
+}
+
+/// Check the round trip of a probe file through the mount point, i.e. create it, read it
+/// back, rename and remove it, within the timeout. Returns the time the round trip took.
+pub fn self_test(mountpoint: &Path, timeout: Duration) -> io::Result<Duration> {
+    let (tx, rx) = mpsc::channel();
+    let path = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        let start = Instant::now();
+        let res = probe(&path).map(|()| start.elapsed());
+        // the receiver is gone if the self test timed out
+        tx.send(res).unwrap_or(());
+    });
+    rx.recv_timeout(timeout).unwrap_or_else(|_| {
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "self test of {:?} did not end within {:?}",
+                mountpoint, timeout
+            ),
+        ))
+    })
+}
+
+/// Run sessions created by `mount` under supervision until a session ends normally, i.e. its
+/// mount point is unmounted. A session whose mount point is wedged is unmounted by force and
+/// replaced by a new one, as is a session that ends with an error or a panic. Returns an error
+/// if a wedged session does not end after being unmounted, or after `max_remounts` remounts.
+/// Sessions created from an fd cannot be supervised, since their mount point is unknown.
+pub fn supervise<FS: Filesystem + Send + 'static>(
+    config: &SupervisorConfig,
+    mut mount: impl FnMut() -> io::Result<Session<FS>>,
+) -> io::Result<()> {
+    let mut remounts = 0_u32;
+    loop {
+        let mut se = mount()?;
+        let mountpoint: PathBuf = se
+            .mountpoint()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cannot supervise a session not mounted by itself",
+                )
+            })?
+            .into();
+        let (done_tx, done_rx) = mpsc::channel();
+        let session_thread = thread::spawn(move || {
+            let res = se.run();
+            // unmount before telling the supervisor, which may mount again at the same place
+            drop(se);
+            done_tx.send(()).unwrap_or(());
+            res
+        });
+
+        let wedged = loop {
+            match done_rx.recv_timeout(config.check_interval) {
+                Ok(()) | Err(RecvTimeoutError::Disconnected) => break false,
+                Err(RecvTimeoutError::Timeout) => {
+                    if !check_mountpoint(&mountpoint, config.check_timeout) {
+                        break true;
+                    }
+                }
+            }
+        };
+        if wedged {
+            channel::unmount_options(&mountpoint, UnmountFlags::FORCE | UnmountFlags::LAZY)
+                .unwrap_or_else(|err| error!("failed to unmount {:?}: {}", mountpoint, err));
+            if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(config.check_timeout) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("wedged session of {:?} did not end", mountpoint),
+                ));
+            }
+        }
+        match session_thread.join() {
+            Ok(Ok(())) if !wedged => {
+                info!("session of {:?} ended", mountpoint);
+                return Ok(());
+            }
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("session of {:?} failed: {}", mountpoint, err),
+            Err(_) => error!("session of {:?} panicked", mountpoint),
+        }
+
+        if remounts >= config.max_remounts {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("gave up on {:?} after {} remounts", mountpoint, remounts),
+            ));
+        }
+        remounts = remounts.overflow_add(1);
+        warn!("remounting {:?}, remount {}", mountpoint, remounts);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+
+    use super::self_test;
+
+    #[test]
+    fn test_self_test() {
+        let dir = Path::new("/tmp/fuse_test_self_test");
+        fs::create_dir_all(dir).unwrap_or_else(|_| panic!());
+        let elapsed = self_test(dir, Duration::from_secs(10)).unwrap_or_else(|_| panic!());
+        assert!(elapsed < Duration::from_secs(10));
+        // the probe file is gone
+        assert_eq!(fs::read_dir(dir).unwrap_or_else(|_| panic!()).count(), 0);
+        fs::remove_dir(dir).unwrap_or_else(|_| panic!());
+
+        assert!(self_test(dir, Duration::from_secs(10)).is_err());
+    }
+}
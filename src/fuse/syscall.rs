@@ -0,0 +1,127 @@
+//! Wrappers of the mount syscalls
+//!
+//! The types of the arguments of the raw syscalls differ between the targets,
+//! e.g. `c_char` is signed on x86_64 but unsigned on aarch64 and riscv64, and
+//! the flags of mount are `c_ulong`. The wrappers take Rust types, convert
+//! them for the target and report the failures by `errno`, so the callers
+//! compile the same on every target including musl.
+
+use nix::errno::Errno;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+#[cfg(target_os = "macos")]
+use super::conversion;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use super::Cast;
+
+/// Convert the path to a nul terminated C string, fails with `EINVAL` if it
+/// contains a nul byte
+pub fn c_path(path: &Path) -> nix::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).or(Err(nix::Error::Sys(Errno::EINVAL)))
+}
+
+/// Mount the filesystem of `fstype` from `source` at `target`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount(
+    source: &CStr,
+    target: &CStr,
+    fstype: &CStr,
+    flags: u64,
+    data: &CStr,
+) -> nix::Result<()> {
+    #[allow(unsafe_code)]
+    let res = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fstype.as_ptr(),
+            flags.cast(),
+            data.as_ptr().cast(),
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Mount the filesystem of `fstype` at `target`, `data` is passed to the
+/// filesystem as is
+#[cfg(target_os = "macos")]
+pub fn mount<T>(fstype: &CStr, target: &CStr, flags: c_int, data: &mut T) -> nix::Result<()> {
+    #[allow(unsafe_code)]
+    let res = unsafe {
+        libc::mount(
+            fstype.as_ptr(),
+            target.as_ptr(),
+            flags,
+            conversion::cast_to_mut_ptr(data),
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+/// Unmount the filesystem at `target`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn umount(target: &CStr, flags: c_int) -> nix::Result<()> {
+    #[allow(unsafe_code)]
+    let res = unsafe { libc::umount2(target.as_ptr(), flags) };
+    Errno::result(res).map(drop)
+}
+
+/// Unmount the filesystem at `target`
+#[cfg(target_os = "macos")]
+pub fn umount(target: &CStr, flags: c_int) -> nix::Result<()> {
+    #[allow(unsafe_code)]
+    let res = unsafe { libc::unmount(target.as_ptr(), flags) };
+    Errno::result(res).map(drop)
+}
+
+/// Set `errno` of the calling thread, for the callers reporting a failure by
+/// `errno` that did not come from a syscall
+#[cfg(target_os = "linux")]
+pub fn set_errno(errno: Errno) {
+    #[allow(unsafe_code, clippy::as_conversions)] // Errno is a fieldless enum of the errno values
+    unsafe {
+        *libc::__errno_location() = errno as c_int;
+    }
+}
+
+/// Print `msg` along with the description of the current `errno` to stderr,
+/// `msg` is cut at the first nul byte if any
+pub fn perror(msg: &str) {
+    let msg = msg.split('\0').next().unwrap_or_default();
+    // safe to use panic!() here, because the nul bytes are cut off
+    let msg = CString::new(msg).unwrap_or_else(|_| panic!());
+    #[allow(unsafe_code)]
+    unsafe {
+        libc::perror(msg.as_ptr());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use nix::errno::Errno;
+    use std::path::Path;
+
+    #[cfg(target_os = "linux")]
+    use super::set_errno;
+    use super::{c_path, umount};
+
+    #[test]
+    fn test_syscall() {
+        let path = c_path(Path::new("/tmp/fuse_test_syscall")).unwrap_or_else(|_| panic!());
+        assert_eq!(path.as_bytes(), b"/tmp/fuse_test_syscall");
+        assert_eq!(
+            c_path(Path::new("/tmp/fuse\0test")),
+            Err(nix::Error::Sys(Errno::EINVAL))
+        );
+        // nothing is mounted there, the error is reported instead of a panic
+        assert!(umount(&path, 0).is_err());
+        #[cfg(target_os = "linux")]
+        {
+            set_errno(Errno::ETIMEDOUT);
+            assert_eq!(Errno::last(), Errno::ETIMEDOUT);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use super::conversion::NumericError;
 use super::Cast;
 use std::any::type_name;
 
@@ -78,6 +79,42 @@ macro_rules! impl_overflow_arithmetic {
     };
 }
 
+/// Implement `CheckedArithmetic` for an integer type
+macro_rules! impl_checked_arithmetic {
+    ($target: ty) => {
+        #[allow(clippy::use_self)]
+        impl CheckedArithmetic for $target {
+            #[inline]
+            fn try_add(self, other: Self) -> Result<Self, NumericError> {
+                self.checked_add(other)
+                    .ok_or_else(|| overflow_error("+", self, other))
+            }
+
+            #[inline]
+            fn try_sub(self, other: Self) -> Result<Self, NumericError> {
+                self.checked_sub(other)
+                    .ok_or_else(|| overflow_error("-", self, other))
+            }
+
+            #[inline]
+            fn try_mul(self, other: Self) -> Result<Self, NumericError> {
+                self.checked_mul(other)
+                    .ok_or_else(|| overflow_error("*", self, other))
+            }
+        }
+    };
+}
+
+/// Build the error of an overflowing operation
+fn overflow_error<T: std::fmt::Display>(op: &'static str, lhs: T, rhs: T) -> NumericError {
+    NumericError::Overflow {
+        op,
+        lhs: lhs.to_string(),
+        rhs: rhs.to_string(),
+        ty: type_name::<T>(),
+    }
+}
+
 impl_overflow_arithmetic!(u8);
 impl_overflow_arithmetic!(u16);
 impl_overflow_arithmetic!(u32);
@@ -91,7 +128,24 @@ impl_overflow_arithmetic!(i128);
 impl_overflow_arithmetic!(usize);
 impl_overflow_arithmetic!(isize);
 
+impl_checked_arithmetic!(u8);
+impl_checked_arithmetic!(u16);
+impl_checked_arithmetic!(u32);
+impl_checked_arithmetic!(u64);
+impl_checked_arithmetic!(u128);
+impl_checked_arithmetic!(i8);
+impl_checked_arithmetic!(i16);
+impl_checked_arithmetic!(i32);
+impl_checked_arithmetic!(i64);
+impl_checked_arithmetic!(i128);
+impl_checked_arithmetic!(usize);
+impl_checked_arithmetic!(isize);
+
 /// A type cast trait used to do the integer arithmetic.
+///
+/// Overflow is only checked in debug builds and wraps in release builds, so it
+/// is for values within known bounds, use `CheckedArithmetic` for values from
+/// the kernel or users.
 pub trait OverflowArithmetic {
     /// Overflow add.
     fn overflow_add(self, other: Self) -> Self;
@@ -108,3 +162,45 @@ pub trait OverflowArithmetic {
     /// Overflow shr.
     fn overflow_shr(self, other: Self) -> Self;
 }
+
+/// Checked integer arithmetic, failing instead of wrapping in release builds
+pub trait CheckedArithmetic: Sized {
+    /// Checked add.
+    fn try_add(self, other: Self) -> Result<Self, NumericError>;
+
+    /// Checked sub.
+    fn try_sub(self, other: Self) -> Result<Self, NumericError>;
+
+    /// Checked mul.
+    fn try_mul(self, other: Self) -> Result<Self, NumericError>;
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::conversion::{NumericError, TryCast};
+    use super::CheckedArithmetic;
+
+    #[test]
+    fn test_checked() {
+        assert_eq!(i64::MAX.try_sub(1), Ok(9_223_372_036_854_775_806));
+        assert!(i64::MAX.try_add(1).is_err());
+        assert!(0_usize.try_sub(1).is_err());
+        assert_eq!(
+            u64::MAX.try_mul(2),
+            Err(NumericError::Overflow {
+                op: "*",
+                lhs: u64::MAX.to_string(),
+                rhs: "2".to_owned(),
+                ty: "u64",
+            })
+        );
+
+        assert_eq!(i64::MAX.try_cast::<u64>(), Ok(9_223_372_036_854_775_807));
+        assert!((-1_i64).try_cast::<usize>().is_err());
+        assert!(u64::MAX.try_cast::<i64>().is_err());
+        assert_eq!(
+            (-1_i64).try_cast::<u32>().map_err(|e| e.to_string()),
+            Err("i64: -1 is out of the range of u32".to_owned())
+        );
+    }
+}
@@ -0,0 +1,453 @@
+//! Read-only filesystem of the tree of a commit in a git repository
+//!
+//! The files are read from the object database of the repository, so mounting
+//! any commit is instant and nothing is checked out. The trees are loaded when
+//! their directories are first looked up or listed, and a blob is inflated when
+//! its file is opened and dropped when the file is last released.
+
+use crate::fuse::{
+    Cast, Context, FileAttr, FileType, Filesystem, FsError, FsInitConfig, FsReleaseParam,
+    OverflowArithmetic, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
+    FUSE_ROOT_ID,
+};
+use crate::readonly::{self, NodeTable, OpenFiles};
+use git2::{Oid, Repository};
+use libc::{EIO, ENOENT, ENOTDIR, EROFS};
+use log::{debug, error}; // info, warn
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// TTL sec, the commit never changes once mounted
+const GIT_TTL_SEC: u64 = 3600;
+/// Generation
+const GIT_GENERATION: u64 = 1;
+/// Git file mode of a directory
+const GIT_MODE_TREE: i32 = 0o040_000;
+/// Git file mode of an executable file
+const GIT_MODE_BLOB_EXECUTABLE: i32 = 0o100_755;
+/// Git file mode of a symbolic link
+const GIT_MODE_LINK: i32 = 0o120_000;
+/// Git file mode of a submodule
+const GIT_MODE_COMMIT: i32 = 0o160_000;
+
+/// Node of the tree of the commit
+#[derive(Debug)]
+struct GitNode {
+    /// Parent ino
+    parent: u64,
+    /// The id of the tree or blob
+    oid: Oid,
+    /// Kind
+    kind: FileType,
+    /// Permission
+    perm: u16,
+    /// Size of the blob, zero for a directory
+    size: u64,
+    /// Children of a directory, `None` until the tree is loaded
+    children: Option<BTreeMap<OsString, u64>>,
+}
+
+/// Read-only filesystem of the tree of a commit
+pub struct GitFilesystem {
+    /// Repository
+    repo: Repository,
+    /// Nodes
+    nodes: NodeTable<GitNode>,
+    /// Attribute template, the commit time and the owner of the repository
+    attr: FileAttr,
+    /// Files open, inflated on first open and dropped on last release
+    open_files: OpenFiles,
+    /// Whether the kernel is to skip the opendirs
+    no_opendir: bool,
+}
+
+impl fmt::Debug for GitFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GitFilesystem")
+            .field("repo", &self.repo.path())
+            .field("nodes", &self.nodes.len())
+            .field("open_files", &self.open_files.len())
+            .finish()
+    }
+}
+
+impl GitFilesystem {
+    /// Open a repository and resolve the revision to mount, e.g. a branch, a
+    /// tag or a commit id
+    pub fn new<P: AsRef<Path>>(path: P, rev: &str) -> Result<Self, git2::Error> {
+        let repo = Repository::open(path)?;
+        let object = repo.revparse_single(rev)?;
+        let commit = object.peel_to_commit()?;
+        let tree_oid = commit.tree_id();
+        let commit_secs = commit.time().seconds();
+        drop(commit);
+        drop(object);
+
+        let mtime = UNIX_EPOCH
+            .checked_add(Duration::from_secs(u64::try_from(commit_secs).unwrap_or(0)))
+            .unwrap_or_else(SystemTime::now);
+        let (uid, gid) = match fs::metadata(repo.path()) {
+            Ok(metadata) => (metadata.uid(), metadata.gid()),
+            Err(_) => (0, 0),
+        };
+        debug!(
+            "GitFilesystem::new() mounts tree={} of revision={:?} of repository {:?}",
+            tree_oid,
+            rev,
+            repo.path(),
+        );
+        Ok(Self {
+            repo,
+            nodes: NodeTable::new(GitNode {
+                parent: FUSE_ROOT_ID,
+                oid: tree_oid,
+                kind: FileType::Directory,
+                perm: 0o555,
+                size: 0,
+                children: None,
+            }),
+            attr: FileAttr {
+                ino: FUSE_ROOT_ID,
+                size: 0,
+                blocks: 0,
+                atime: mtime,
+                mtime,
+                ctime: mtime,
+                crtime: mtime,
+                kind: FileType::Directory,
+                perm: 0o555,
+                nlink: 2,
+                uid,
+                gid,
+                rdev: 0,
+                flags: 0,
+            },
+            open_files: OpenFiles::default(),
+            no_opendir: false,
+        })
+    }
+
+    /// Let the kernel skip the opendirs and keep the listings cached, the
+    /// opens are still needed to inflate the blobs
+    pub fn set_no_open(&mut self, enabled: bool) {
+        self.no_opendir = enabled;
+    }
+
+    /// Helper load the tree of a directory unless loaded, returns the children
+    fn helper_load_children(&mut self, ino: u64) -> Result<&BTreeMap<OsString, u64>, i32> {
+        let node = self.nodes.get(ino).ok_or(ENOENT)?;
+        if node.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+        if node.children.is_none() {
+            let tree_oid = node.oid;
+            let children = self.helper_load_tree(ino, tree_oid).map_err(|e| {
+                error!(
+                    "helper_load_children() failed to load tree={} of ino={}, the error is: {}",
+                    tree_oid, ino, e,
+                );
+                EIO
+            })?;
+            self.nodes.helper_get_mut(ino).children = Some(children);
+        }
+        Ok(self
+            .nodes
+            .get(ino)
+            .and_then(|node| node.children.as_ref())
+            .unwrap_or_else(|| {
+                panic!(
+                    "helper_load_children() found fs is inconsistent, no children of ino={}",
+                    ino
+                )
+            }))
+    }
+
+    /// Helper add a node for each entry of a tree
+    fn helper_load_tree(
+        &mut self,
+        parent: u64,
+        tree_oid: Oid,
+    ) -> Result<BTreeMap<OsString, u64>, git2::Error> {
+        let tree = self.repo.find_tree(tree_oid)?;
+        let odb = self.repo.odb()?;
+        let mut children = BTreeMap::new();
+        for entry in tree.iter() {
+            let mode = entry.filemode();
+            let (kind, perm, size, node_children) = match mode {
+                GIT_MODE_TREE => (FileType::Directory, 0o555, 0, None),
+                // the commit of a submodule is not in this repository
+                GIT_MODE_COMMIT => (FileType::Directory, 0o555, 0, Some(BTreeMap::new())),
+                _ => {
+                    let (size, _) = odb.read_header(entry.id())?;
+                    let (kind, perm) = match mode {
+                        GIT_MODE_LINK => (FileType::Symlink, 0o777),
+                        GIT_MODE_BLOB_EXECUTABLE => (FileType::RegularFile, 0o555),
+                        _ => (FileType::RegularFile, 0o444),
+                    };
+                    (kind, perm, size.cast(), None)
+                }
+            };
+            let ino = self.nodes.add(GitNode {
+                parent,
+                oid: entry.id(),
+                kind,
+                perm,
+                size,
+                children: node_children,
+            });
+            children.insert(OsStr::from_bytes(entry.name_bytes()).to_owned(), ino);
+        }
+        debug!(
+            "helper_load_tree() loaded {} entries of tree={} of ino={}",
+            children.len(),
+            tree_oid,
+            parent,
+        );
+        Ok(children)
+    }
+
+    /// Attr of a node
+    fn helper_get_attr(&self, ino: u64) -> FileAttr {
+        let node = self.nodes.helper_get(ino);
+        let mut attr = self.attr;
+        attr.ino = ino;
+        attr.kind = node.kind;
+        attr.perm = node.perm;
+        attr.size = node.size;
+        attr.blocks = node.size.overflow_add(511).overflow_div(512);
+        if node.kind != FileType::Directory {
+            attr.nlink = 1;
+        }
+        attr
+    }
+
+    /// Helper read the blob of a file or a symbolic link
+    fn helper_read_blob(&self, ino: u64) -> Result<Vec<u8>, i32> {
+        Self::helper_read_blob_of(&self.repo, &self.nodes, ino)
+    }
+
+    /// Helper read the blob of a file or a symbolic link from the repository,
+    /// borrowing only the repository and the nodes
+    fn helper_read_blob_of(
+        repo: &Repository,
+        nodes: &NodeTable<GitNode>,
+        ino: u64,
+    ) -> Result<Vec<u8>, i32> {
+        let node = nodes.get(ino).ok_or(ENOENT)?;
+        match repo.find_blob(node.oid) {
+            Ok(blob) => Ok(blob.content().to_vec()),
+            Err(e) => {
+                error!(
+                    "helper_read_blob() failed to read blob={} of ino={}, the error is: {}",
+                    node.oid, ino, e,
+                );
+                Err(EIO)
+            }
+        }
+    }
+}
+
+impl Filesystem for GitFilesystem {
+    fn init(&mut self, _ctx: &Context, config: &mut FsInitConfig) -> Result<(), FsError> {
+        config.no_opendir = self.no_opendir;
+        Ok(())
+    }
+
+    fn lookup(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name={:?}, ctx={:?})", parent, name, ctx,);
+        match self.helper_load_children(parent) {
+            Ok(children) => match children.get(name).copied() {
+                Some(ino) => {
+                    let ttl = Duration::new(GIT_TTL_SEC, 0);
+                    reply.entry(&ttl, &self.helper_get_attr(ino), GIT_GENERATION);
+                }
+                None => reply.error(ENOENT),
+            },
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, ctx: &Context, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        debug!("getattr(ino={}, fh={:?}, ctx={:?})", ino, fh, ctx);
+        if self.nodes.get(ino).is_some() {
+            let ttl = Duration::new(GIT_TTL_SEC, 0);
+            reply.attr(&ttl, &self.helper_get_attr(ino));
+        } else {
+            reply.error(ENOENT);
+        }
+    }
+
+    fn readlink(&mut self, ctx: &Context, ino: u64, reply: ReplyData) {
+        debug!("readlink(ino={}, ctx={:?})", ino, ctx);
+        match self.helper_read_blob(ino) {
+            Ok(target) => reply.data(&target),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, ctx: &Context, ino: u64, flags: u32, mut reply: ReplyOpen) {
+        debug!("open(ino={}, flags={}, ctx={:?})", ino, flags, ctx);
+        if readonly::opens_for_write(flags) {
+            reply.error(EROFS);
+            return;
+        }
+        let repo = &self.repo;
+        let nodes = &self.nodes;
+        if let Err(errno) = self
+            .open_files
+            .open(ino, || Self::helper_read_blob_of(repo, nodes, ino))
+        {
+            reply.error(errno);
+            return;
+        }
+        // the data never changes while mounted
+        reply.keep_cache(true);
+        reply.opened(0, 0);
+    }
+
+    fn read(&mut self, ctx: &Context, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        debug!(
+            "read(ino={}, fh={}, offset={}, size={}, ctx={:?})",
+            ino, fh, offset, size, ctx,
+        );
+        match self.open_files.read(ino, offset, size) {
+            Some(data) => reply.data(data),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn release(&mut self, ctx: &Context, param: FsReleaseParam, reply: ReplyEmpty) {
+        debug!("release(ino={}, fh={}, ctx={:?})", param.ino, param.fh, ctx,);
+        self.open_files.release(param.ino);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        ctx: &Context,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(
+            "readdir(ino={}, fh={}, offset={}, ctx={:?})",
+            ino, fh, offset, ctx,
+        );
+        if let Err(errno) = self.helper_load_children(ino) {
+            reply.error(errno);
+            return;
+        }
+        let node = self.nodes.helper_get(ino);
+        let nodes = &self.nodes;
+        let children = node.children.iter().flatten().map(|(name, child_ino)| {
+            (
+                *child_ino,
+                nodes.helper_get(*child_ino).kind,
+                name.as_os_str(),
+            )
+        });
+        readonly::add_dir_entries(&mut reply, ino, node.parent, offset, children);
+        reply.ok();
+    }
+
+    impl_read_only!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::GitFilesystem;
+    use crate::fuse::{FileType, FUSE_ROOT_ID};
+    use git2::{Repository, Signature};
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::Path;
+
+    /// Look up a path, returns the ino
+    fn lookup_path(fs: &mut GitFilesystem, path: &str) -> Option<u64> {
+        let mut ino = FUSE_ROOT_ID;
+        for name in path.split('/') {
+            ino = *fs.helper_load_children(ino).ok()?.get(OsStr::new(name))?;
+        }
+        Some(ino)
+    }
+
+    /// Commit a tree of blobs to a branch
+    fn commit_files(repo: &Repository, branch: &str, files: &[(&str, &[u8], i32)]) {
+        let mut builder = repo.treebuilder(None).unwrap_or_else(|_| panic!());
+        let mut sub_builder = repo.treebuilder(None).unwrap_or_else(|_| panic!());
+        for &(path, data, mode) in files {
+            let oid = repo.blob(data).unwrap_or_else(|_| panic!());
+            match path.strip_prefix("sub/") {
+                Some(name) => sub_builder.insert(name, oid, mode),
+                None => builder.insert(path, oid, mode),
+            }
+            .unwrap_or_else(|_| panic!());
+        }
+        let sub_oid = sub_builder.write().unwrap_or_else(|_| panic!());
+        builder
+            .insert("sub", sub_oid, 0o040_000)
+            .unwrap_or_else(|_| panic!());
+        let tree_oid = builder.write().unwrap_or_else(|_| panic!());
+        let tree = repo.find_tree(tree_oid).unwrap_or_else(|_| panic!());
+        let sig = Signature::now("test", "test@example.com").unwrap_or_else(|_| panic!());
+        let refname = format!("refs/heads/{}", branch);
+        repo.commit(Some(&refname), &sig, &sig, branch, &tree, &[])
+            .unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_git_tree() {
+        const REPO_DIR: &str = "/tmp/fuse_test_gitfs";
+        let repo_dir = Path::new(REPO_DIR);
+        if repo_dir.exists() {
+            fs::remove_dir_all(repo_dir).unwrap_or_else(|_| panic!());
+        }
+        let repo = Repository::init_bare(repo_dir).unwrap_or_else(|_| panic!());
+        commit_files(
+            &repo,
+            "main",
+            &[
+                ("readme", b"main readme\n", 0o100_644),
+                ("run.sh", b"#!/bin/sh\n", 0o100_755),
+                ("link", b"sub/nested", 0o120_000),
+                ("sub/nested", b"nested file", 0o100_644),
+            ],
+        );
+        commit_files(&repo, "other", &[("readme", b"other\n", 0o100_644)]);
+
+        let mut fs = GitFilesystem::new(repo_dir, "main").unwrap_or_else(|_| panic!());
+        let readme = lookup_path(&mut fs, "readme").unwrap_or_else(|| panic!());
+        assert_eq!(fs.helper_get_attr(readme).size, 12);
+        assert_eq!(fs.helper_get_attr(readme).perm, 0o444);
+        assert_eq!(
+            fs.helper_read_blob(readme).ok(),
+            Some(b"main readme\n".to_vec())
+        );
+        let run = lookup_path(&mut fs, "run.sh").unwrap_or_else(|| panic!());
+        assert_eq!(fs.helper_get_attr(run).perm, 0o555);
+        let link = lookup_path(&mut fs, "link").unwrap_or_else(|| panic!());
+        assert_eq!(fs.helper_get_attr(link).kind, FileType::Symlink);
+        assert_eq!(fs.helper_read_blob(link).ok(), Some(b"sub/nested".to_vec()));
+        let nested = lookup_path(&mut fs, "sub/nested").unwrap_or_else(|| panic!());
+        assert_eq!(
+            fs.helper_read_blob(nested).ok(),
+            Some(b"nested file".to_vec())
+        );
+        assert!(lookup_path(&mut fs, "readme/x").is_none());
+
+        let mut fs = GitFilesystem::new(repo_dir, "other").unwrap_or_else(|_| panic!());
+        let readme = lookup_path(&mut fs, "readme").unwrap_or_else(|| panic!());
+        assert_eq!(fs.helper_read_blob(readme).ok(), Some(b"other\n".to_vec()));
+        assert!(lookup_path(&mut fs, "run.sh").is_none());
+
+        fs::remove_dir_all(repo_dir).unwrap_or_else(|_| panic!());
+    }
+}
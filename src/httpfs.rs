@@ -0,0 +1,949 @@
+//! Read-only filesystem of a remote HTTP directory tree or WebDAV share
+//!
+//! A directory is listed from its index page, or by a `PROPFIND` request of a
+//! WebDAV share, when first looked up or listed and again once its listing
+//! expires. The attributes of a file come from the WebDAV listing or a `HEAD`
+//! request and expire likewise. File data is downloaded by range requests of
+//! whole chunks and kept in a chunk store, the least recently used chunks are
+//! dropped once the cache is full, and the chunks of a file are dropped when
+//! its size or modification time changes. The nodes gone from a listing are
+//! freed once the kernel forgets them.
+
+use crate::fuse::{
+    Cast, Context, FileAttr, FileType, Filesystem, FsError, FsInitConfig, OverflowArithmetic,
+    ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, FUSE_ROOT_ID,
+};
+use crate::memfs::{ChunkStore, DEFAULT_CHUNK_SIZE};
+use crate::readonly::{self, NodeTable};
+use blake3::Hash;
+use libc::{EIO, ENOENT, ENOTDIR, EROFS};
+use log::{debug, error, warn}; // info
+use nix::unistd;
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, Instant, SystemTime};
+use ureq::{Agent, AgentBuilder, Response};
+
+/// Listing module
+mod listing;
+
+use listing::{parse_index, parse_multistatus, RemoteEntry};
+
+/// TTL sec of attributes and listings
+const HTTP_ATTR_TTL_SEC: u64 = 60;
+/// Generation
+const HTTP_GENERATION: u64 = 1;
+/// Timeout sec of a request
+const HTTP_TIMEOUT_SEC: u64 = 60;
+/// Default number of chunks cached, 64MB of 64KB chunks
+pub const DEFAULT_CACHE_CHUNKS: usize = 1024;
+/// Maximum byte size of a listing
+const HTTP_MAX_LISTING_SIZE: u64 = 16 * 1024 * 1024;
+/// The body of a `PROPFIND` request
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:propfind xmlns:D="DAV:"><D:prop>
+<D:resourcetype/><D:getcontentlength/><D:getlastmodified/>
+</D:prop></D:propfind>"#;
+/// Characters kept as is when encoding a name into a URL path segment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How the remote directories are listed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListingKind {
+    /// Index pages generated by the HTTP server
+    Index,
+    /// `PROPFIND` requests of a WebDAV share
+    WebDav,
+}
+
+/// Node of the remote tree
+#[derive(Debug)]
+struct HttpNode {
+    /// Parent ino
+    parent: u64,
+    /// URL, ending with a slash for a directory
+    url: String,
+    /// Kind
+    kind: FileType,
+    /// Size, zero for a directory
+    size: u64,
+    /// Modification time
+    mtime: SystemTime,
+    /// When the size and modification time expire, `None` if never fetched
+    attr_expire: Option<Instant>,
+    /// Children of a directory, `None` until listed
+    children: Option<BTreeMap<OsString, u64>>,
+    /// When the children expire
+    children_expire: Instant,
+    /// Lookup count of the kernel
+    lookup_count: u64,
+    /// Whether the node is gone from the remote tree, it is freed once the
+    /// kernel forgets it
+    vanished: bool,
+}
+
+/// A downloaded chunk
+#[derive(Debug)]
+struct CachedChunk {
+    /// Hash in the chunk store
+    hash: Hash,
+    /// The tick of the last use
+    last_use: u64,
+}
+
+/// The total size in a `Content-Range` header, e.g. `bytes 0-0/1234`, `None`
+/// if it is unknown
+fn content_range_size(content_range: &str) -> Option<u64> {
+    content_range.rsplit_once('/')?.1.trim().parse().ok()
+}
+
+/// Read-only filesystem of a remote directory tree
+pub struct HttpFilesystem {
+    /// HTTP agent, keeping the connections alive
+    agent: Agent,
+    /// How the directories are listed
+    listing: ListingKind,
+    /// Nodes
+    nodes: NodeTable<HttpNode>,
+    /// Attribute template, the owner is the user mounting
+    attr: FileAttr,
+    /// Chunk store of the downloaded data
+    chunk_store: ChunkStore,
+    /// Downloaded chunks indexed by ino and chunk index
+    cached_chunks: FxHashMap<(u64, u64), CachedChunk>,
+    /// Downloaded chunks indexed by the tick of their last use
+    cache_order: BTreeMap<u64, (u64, u64)>,
+    /// The tick of the next use of a chunk
+    next_use: u64,
+    /// Maximum number of chunks cached
+    cache_capacity: usize,
+    /// Whether the kernel is to skip the opens and opendirs
+    no_open: bool,
+}
+
+impl fmt::Debug for HttpFilesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpFilesystem")
+            .field("listing", &self.listing)
+            .field("nodes", &self.nodes.len())
+            .field("cached_chunks", &self.cached_chunks.len())
+            .field("cache_capacity", &self.cache_capacity)
+            .finish()
+    }
+}
+
+impl HttpFilesystem {
+    /// New filesystem of the remote directory of `url`, nothing is requested
+    /// until the mount point is accessed
+    pub fn new(url: &str, listing: ListingKind) -> Self {
+        let mut url = url.to_owned();
+        if !url.ends_with('/') {
+            url.push('/');
+        }
+        let now = SystemTime::now();
+        debug!(
+            "HttpFilesystem::new() mounts {:?} listed by {:?}",
+            url, listing
+        );
+        Self {
+            agent: AgentBuilder::new()
+                .timeout(Duration::from_secs(HTTP_TIMEOUT_SEC))
+                .build(),
+            listing,
+            nodes: NodeTable::new(HttpNode {
+                parent: FUSE_ROOT_ID,
+                url,
+                kind: FileType::Directory,
+                size: 0,
+                mtime: now,
+                attr_expire: None,
+                children: None,
+                children_expire: Instant::now(),
+                lookup_count: 0,
+                vanished: false,
+            }),
+            attr: FileAttr {
+                ino: FUSE_ROOT_ID,
+                size: 0,
+                blocks: 0,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                crtime: now,
+                kind: FileType::Directory,
+                perm: 0o555,
+                nlink: 2,
+                uid: unistd::getuid().as_raw(),
+                gid: unistd::getgid().as_raw(),
+                rdev: 0,
+                flags: 0,
+            },
+            chunk_store: ChunkStore::new(DEFAULT_CHUNK_SIZE),
+            cached_chunks: FxHashMap::default(),
+            cache_order: BTreeMap::new(),
+            next_use: 0,
+            cache_capacity: DEFAULT_CACHE_CHUNKS,
+            no_open: false,
+        }
+    }
+
+    /// Set the maximum number of chunks cached, at least one
+    pub fn set_cache_capacity(&mut self, cache_capacity: usize) {
+        self.cache_capacity = cache_capacity.max(1);
+        self.helper_evict_chunks();
+    }
+
+    /// Let the kernel skip the opens and opendirs, which saves a round trip
+    /// per access, but the kernel then keeps the data and listings cached, so
+    /// the remote changes of a file or directory already read may go unseen
+    pub fn set_no_open(&mut self, enabled: bool) {
+        self.no_open = enabled;
+    }
+
+    /// Helper send a request, returns the errno to reply if failed
+    fn helper_call(
+        &self,
+        method: &str,
+        url: &str,
+        range: Option<(u64, u64)>,
+    ) -> Result<Response, i32> {
+        let mut request = self.agent.request(method, url);
+        if let Some((start, end)) = range {
+            request = request.set("Range", &format!("bytes={}-{}", start, end));
+        }
+        let result = if method == "PROPFIND" {
+            request
+                .set("Depth", "1")
+                .set("Content-Type", "application/xml")
+                .send_string(PROPFIND_BODY)
+        } else {
+            request.call()
+        };
+        result.map_err(|e| Self::helper_errno(method, url, &e))
+    }
+
+    /// Helper the errno to reply of a failed request
+    fn helper_errno(method: &str, url: &str, err: &ureq::Error) -> i32 {
+        match *err {
+            ureq::Error::Status(404, _) | ureq::Error::Status(410, _) => ENOENT,
+            _ => {
+                error!(
+                    "helper_call() failed to {} {:?}, the error is: {}",
+                    method, url, err,
+                );
+                EIO
+            }
+        }
+    }
+
+    /// Helper list a directory unless the listing is fresh, returns the children
+    fn helper_load_children(&mut self, ino: u64) -> Result<&BTreeMap<OsString, u64>, i32> {
+        let node = self.nodes.get(ino).ok_or(ENOENT)?;
+        if node.kind != FileType::Directory {
+            return Err(ENOTDIR);
+        }
+        if node.children.is_none() || node.children_expire <= Instant::now() {
+            let url = node.url.clone();
+            let entries = self.helper_list(&url)?;
+            self.helper_update_children(ino, entries);
+        }
+        Ok(self
+            .nodes
+            .get(ino)
+            .and_then(|node| node.children.as_ref())
+            .unwrap_or_else(|| {
+                panic!(
+                    "helper_load_children() found fs is inconsistent, no children of ino={}",
+                    ino
+                )
+            }))
+    }
+
+    /// Helper request the listing of the directory of `url`
+    fn helper_list(&self, url: &str) -> Result<Vec<RemoteEntry>, i32> {
+        let method = match self.listing {
+            ListingKind::Index => "GET",
+            ListingKind::WebDav => "PROPFIND",
+        };
+        let response = self.helper_call(method, url, None)?;
+        let mut body = String::new();
+        if let Err(e) = response
+            .into_reader()
+            .take(HTTP_MAX_LISTING_SIZE)
+            .read_to_string(&mut body)
+        {
+            error!(
+                "helper_list() failed to read the listing of {:?}, the error is: {}",
+                url, e,
+            );
+            return Err(EIO);
+        }
+        let entries = match self.listing {
+            ListingKind::Index => parse_index(url, &body),
+            ListingKind::WebDav => parse_multistatus(url, &body),
+        };
+        debug!(
+            "helper_list() listed {} entries of {:?}",
+            entries.len(),
+            url
+        );
+        Ok(entries)
+    }
+
+    /// Helper update the children of a directory by its listing, the children
+    /// listed again keep their ino
+    fn helper_update_children(&mut self, ino: u64, entries: Vec<RemoteEntry>) {
+        let now = Instant::now();
+        let expire = now
+            .checked_add(Duration::from_secs(HTTP_ATTR_TTL_SEC))
+            .unwrap_or(now);
+        let dir_node = self.nodes.helper_get_mut(ino);
+        let mut old_children = dir_node.children.take().unwrap_or_default();
+        let dir_url = dir_node.url.clone();
+        let mut children = BTreeMap::new();
+        for entry in entries {
+            let kind = if entry.is_dir {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            let existing = match old_children.remove(&entry.name) {
+                Some(child_ino) if self.nodes.helper_get(child_ino).kind == kind => Some(child_ino),
+                // replaced by a node of another kind
+                Some(child_ino) => {
+                    self.helper_vanish(child_ino);
+                    None
+                }
+                None => None,
+            };
+            let child_ino = match existing {
+                Some(child_ino) => child_ino,
+                None => {
+                    let mut url = dir_url.clone();
+                    url.extend(percent_encode(entry.name.as_bytes(), PATH_SEGMENT));
+                    if entry.is_dir {
+                        url.push('/');
+                    }
+                    self.nodes.add(HttpNode {
+                        parent: ino,
+                        url,
+                        kind,
+                        size: 0,
+                        mtime: self.attr.mtime,
+                        attr_expire: None,
+                        children: None,
+                        children_expire: now,
+                        lookup_count: 0,
+                        vanished: false,
+                    })
+                }
+            };
+            if let (Some(size), false) = (entry.size, entry.is_dir) {
+                let mtime = entry.mtime.unwrap_or(self.attr.mtime);
+                self.helper_set_file_attr(child_ino, size, mtime);
+                self.nodes.helper_get_mut(child_ino).attr_expire = Some(expire);
+            }
+            children.insert(entry.name, child_ino);
+        }
+        // the children gone are never looked up again
+        for (_, child_ino) in old_children {
+            self.helper_vanish(child_ino);
+        }
+        let dir_node = self.nodes.helper_get_mut(ino);
+        dir_node.children = Some(children);
+        dir_node.children_expire = expire;
+    }
+
+    /// Helper mark a node and its descendants gone from the remote tree, the
+    /// nodes the kernel does not know are freed at once, the others once the
+    /// kernel forgets them
+    fn helper_vanish(&mut self, ino: u64) {
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            let node = self.nodes.helper_get_mut(ino);
+            node.vanished = true;
+            if let Some(children) = node.children.as_mut() {
+                pending.extend(children.values());
+                children.clear();
+            }
+            let known = node.lookup_count > 0;
+            self.helper_invalidate_chunks(ino);
+            if !known {
+                self.nodes.remove(ino);
+            }
+        }
+    }
+
+    /// Helper forget `nlookup` lookups of a node, a node gone from the remote
+    /// tree is freed once forgotten
+    fn helper_forget(&mut self, ino: u64, nlookup: u64) {
+        if let Some(node) = self.nodes.get_mut(ino) {
+            node.lookup_count = node.lookup_count.saturating_sub(nlookup);
+            if node.vanished && node.lookup_count == 0 {
+                self.nodes.remove(ino);
+                debug!("helper_forget() freed the vanished node of ino={}", ino);
+            }
+        }
+    }
+
+    /// Helper refresh the size and modification time of a file unless fresh
+    fn helper_refresh_attr(&mut self, ino: u64) -> Result<(), i32> {
+        let node = self.nodes.get(ino).ok_or(ENOENT)?;
+        let now = Instant::now();
+        if node.kind == FileType::Directory || matches!(node.attr_expire, Some(t) if t > now) {
+            return Ok(());
+        }
+        let url = node.url.clone();
+        let response = self.helper_call("HEAD", &url, None)?;
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok());
+        let mtime = response
+            .header("Last-Modified")
+            .and_then(|mtime| httpdate::parse_http_date(mtime).ok())
+            .unwrap_or(self.attr.mtime);
+        let size = match length {
+            Some(size) => size,
+            None => self.helper_probe_size(&url)?,
+        };
+        self.helper_set_file_attr(ino, size, mtime);
+        self.nodes.helper_get_mut(ino).attr_expire =
+            now.checked_add(Duration::from_secs(HTTP_ATTR_TTL_SEC));
+        Ok(())
+    }
+
+    /// Helper get the size of a file not told by a `HEAD` request, from the
+    /// `Content-Range` of a request of its first byte
+    fn helper_probe_size(&self, url: &str) -> Result<u64, i32> {
+        let result = self.agent.get(url).set("Range", "bytes=0-0").call();
+        let size = match result {
+            Ok(response) if response.status() == 206 => response
+                .header("Content-Range")
+                .and_then(content_range_size),
+            Ok(response) => response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok()),
+            // the range of an empty file is not satisfiable
+            Err(ureq::Error::Status(416, response)) => response
+                .header("Content-Range")
+                .and_then(content_range_size),
+            Err(e) => return Err(Self::helper_errno("GET", url, &e)),
+        };
+        size.ok_or_else(|| {
+            error!("helper_probe_size() found the size of {:?} is unknown", url);
+            EIO
+        })
+    }
+
+    /// Helper set the size and modification time of a file, the cached chunks
+    /// are dropped if either changed
+    fn helper_set_file_attr(&mut self, ino: u64, size: u64, mtime: SystemTime) {
+        let node = self.nodes.helper_get_mut(ino);
+        if node.attr_expire.is_some() && (node.size != size || node.mtime != mtime) {
+            debug!(
+                "helper_set_file_attr() found the file of ino={} changed, size {} -> {}",
+                ino, node.size, size,
+            );
+            node.size = size;
+            node.mtime = mtime;
+            self.helper_invalidate_chunks(ino);
+        } else {
+            node.size = size;
+            node.mtime = mtime;
+        }
+    }
+
+    /// Helper drop the cached chunks of a file
+    fn helper_invalidate_chunks(&mut self, ino: u64) {
+        let chunk_store = &mut self.chunk_store;
+        let cache_order = &mut self.cache_order;
+        self.cached_chunks.retain(|&(chunk_ino, _), chunk| {
+            if chunk_ino == ino {
+                chunk_store.release(&chunk.hash);
+                cache_order.remove(&chunk.last_use);
+            }
+            chunk_ino != ino
+        });
+    }
+
+    /// Helper the tick of a use of a chunk
+    fn helper_next_use(&mut self) -> u64 {
+        let tick = self.next_use;
+        self.next_use = tick.overflow_add(1);
+        tick
+    }
+
+    /// Helper get the hash of a cached chunk and mark it used
+    fn helper_use_chunk(&mut self, key: (u64, u64)) -> Option<Hash> {
+        let tick = self.helper_next_use();
+        let chunk = self.cached_chunks.get_mut(&key)?;
+        self.cache_order.remove(&chunk.last_use);
+        chunk.last_use = tick;
+        self.cache_order.insert(tick, key);
+        Some(chunk.hash)
+    }
+
+    /// Helper drop the least recently used chunks until the cache fits
+    fn helper_evict_chunks(&mut self) {
+        while self.cached_chunks.len() > self.cache_capacity {
+            let (tick, key) = match self.cache_order.iter().next() {
+                Some((tick, key)) => (*tick, *key),
+                None => break,
+            };
+            self.cache_order.remove(&tick);
+            if let Some(chunk) = self.cached_chunks.remove(&key) {
+                self.chunk_store.release(&chunk.hash);
+            }
+        }
+    }
+
+    /// Helper download the chunks of a file from `first` to `last` inclusive,
+    /// returns the errno to reply if failed
+    fn helper_download_chunks(&mut self, ino: u64, first: u64, last: u64) -> Result<(), i32> {
+        let node = self.nodes.helper_get(ino);
+        let url = node.url.clone();
+        let size = node.size;
+        let chunk_size: u64 = self.chunk_store.chunk_size().cast();
+        let start = first.overflow_mul(chunk_size);
+        let end = last.overflow_add(1).overflow_mul(chunk_size).min(size);
+        let response = self.helper_call("GET", &url, Some((start, end.overflow_sub(1))))?;
+        // servers ignoring the range send the whole file, which is read only
+        // up to the end of the range
+        let skip = if response.status() == 206 {
+            0
+        } else {
+            warn!(
+                "helper_download_chunks() found {:?} does not support range requests",
+                url
+            );
+            start
+        };
+        let mut reader = response.into_reader();
+        let mut data = Vec::with_capacity(end.overflow_sub(start).cast());
+        let read = io::copy(&mut (&mut reader).take(skip), &mut io::sink())
+            .and_then(|_| reader.take(end.overflow_sub(start)).read_to_end(&mut data));
+        if let Err(e) = read {
+            error!(
+                "helper_download_chunks() failed to download {:?}, the error is: {}",
+                url, e,
+            );
+            return Err(EIO);
+        }
+        if data.len().cast::<u64>() != end.overflow_sub(start) {
+            error!(
+                "helper_download_chunks() downloaded {} byte of {:?}, expected {}",
+                data.len(),
+                url,
+                end.overflow_sub(start),
+            );
+            return Err(EIO);
+        }
+        for (i, chunk) in data.chunks(chunk_size.cast()).enumerate() {
+            let key = (ino, first.overflow_add(i.cast()));
+            if self.cached_chunks.contains_key(&key) {
+                continue;
+            }
+            let hash = self.chunk_store.insert(chunk);
+            let last_use = self.helper_next_use();
+            self.cached_chunks
+                .insert(key, CachedChunk { hash, last_use });
+            self.cache_order.insert(last_use, key);
+        }
+        debug!(
+            "helper_download_chunks() downloaded {} byte of {:?} from offset={}",
+            data.len(),
+            url,
+            start,
+        );
+        Ok(())
+    }
+
+    /// Helper read a range of a file, downloading the chunks not cached
+    fn helper_read(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, i32> {
+        let file_size = self.nodes.helper_get(ino).size;
+        let start = offset.min(file_size);
+        let end = offset.overflow_add(size).min(file_size);
+        if start == end {
+            return Ok(Vec::new());
+        }
+        let chunk_size: u64 = self.chunk_store.chunk_size().cast();
+        let first = start.overflow_div(chunk_size);
+        let last = end.overflow_sub(1).overflow_div(chunk_size);
+        let missing: Vec<u64> = (first..=last)
+            .filter(|index| !self.cached_chunks.contains_key(&(ino, *index)))
+            .collect();
+        if let (Some(&first_missing), Some(&last_missing)) = (missing.first(), missing.last()) {
+            self.helper_download_chunks(ino, first_missing, last_missing)?;
+        }
+
+        let mut data = Vec::with_capacity(end.overflow_sub(start).cast());
+        for index in first..=last {
+            let hash = self.helper_use_chunk((ino, index)).ok_or(EIO)?;
+            let chunk = self.chunk_store.get(&hash);
+            let chunk_start = index.overflow_mul(chunk_size);
+            let from = start.saturating_sub(chunk_start).cast::<usize>();
+            let to = end
+                .overflow_sub(chunk_start)
+                .min(chunk.len().cast())
+                .cast::<usize>();
+            data.extend_from_slice(chunk.get(from..to).unwrap_or(&[]));
+        }
+        // drop the chunks beyond the capacity only after they are read
+        self.helper_evict_chunks();
+        Ok(data)
+    }
+
+    /// Attr of a node
+    fn helper_get_attr(&self, ino: u64) -> FileAttr {
+        let node = self.nodes.helper_get(ino);
+        let mut attr = self.attr;
+        attr.ino = ino;
+        attr.kind = node.kind;
+        if node.kind != FileType::Directory {
+            attr.size = node.size;
+            attr.blocks = node.size.overflow_add(511).overflow_div(512);
+            attr.atime = node.mtime;
+            attr.mtime = node.mtime;
+            attr.ctime = node.mtime;
+            attr.perm = 0o444;
+            attr.nlink = 1;
+        }
+        attr
+    }
+}
+
+impl Filesystem for HttpFilesystem {
+    fn init(&mut self, _ctx: &Context, config: &mut FsInitConfig) -> Result<(), FsError> {
+        config.no_open = self.no_open;
+        config.no_opendir = self.no_open;
+        Ok(())
+    }
+
+    fn lookup(&mut self, ctx: &Context, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        debug!("lookup(parent={}, name={:?}, ctx={:?})", parent, name, ctx,);
+        let ino = match self.helper_load_children(parent) {
+            Ok(children) => match children.get(name) {
+                Some(ino) => *ino,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            },
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        match self.helper_refresh_attr(ino) {
+            Ok(()) => {
+                let node = self.nodes.helper_get_mut(ino);
+                node.lookup_count = node.lookup_count.overflow_add(1);
+                let ttl = Duration::new(HTTP_ATTR_TTL_SEC, 0);
+                reply.entry(&ttl, &self.helper_get_attr(ino), HTTP_GENERATION);
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn forget(&mut self, ctx: &Context, ino: u64, nlookup: u64) {
+        debug!("forget(ino={}, nlookup={}, ctx={:?})", ino, nlookup, ctx);
+        self.helper_forget(ino, nlookup);
+    }
+
+    fn getattr(&mut self, ctx: &Context, ino: u64, fh: Option<u64>, reply: ReplyAttr) {
+        debug!("getattr(ino={}, fh={:?}, ctx={:?})", ino, fh, ctx);
+        match self.helper_refresh_attr(ino) {
+            Ok(()) => {
+                let ttl = Duration::new(HTTP_ATTR_TTL_SEC, 0);
+                reply.attr(&ttl, &self.helper_get_attr(ino));
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, ctx: &Context, ino: u64, flags: u32, reply: ReplyOpen) {
+        debug!("open(ino={}, flags={}, ctx={:?})", ino, flags, ctx);
+        if readonly::opens_for_write(flags) {
+            reply.error(EROFS);
+            return;
+        }
+        match self.helper_refresh_attr(ino) {
+            Ok(()) => reply.opened(0, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(&mut self, ctx: &Context, ino: u64, fh: u64, offset: i64, size: u32, reply: ReplyData) {
+        debug!(
+            "read(ino={}, fh={}, offset={}, size={}, ctx={:?})",
+            ino, fh, offset, size, ctx,
+        );
+        if self.nodes.get(ino).is_none() {
+            reply.error(ENOENT);
+            return;
+        }
+        match self.helper_read(ino, offset.cast(), size.cast()) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        ctx: &Context,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        debug!(
+            "readdir(ino={}, fh={}, offset={}, ctx={:?})",
+            ino, fh, offset, ctx,
+        );
+        // list again only when starting, so the offsets stay valid
+        let loaded = if offset == 0 {
+            self.helper_load_children(ino).map(|_| ())
+        } else {
+            Ok(())
+        };
+        if let Err(errno) = loaded {
+            reply.error(errno);
+            return;
+        }
+        let node = match self.nodes.get(ino) {
+            Some(node) => node,
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        };
+        let nodes = &self.nodes;
+        let children = node.children.iter().flatten().map(|(name, child_ino)| {
+            (
+                *child_ino,
+                nodes.helper_get(*child_ino).kind,
+                name.as_os_str(),
+            )
+        });
+        readonly::add_dir_entries(&mut reply, ino, node.parent, offset, children);
+        reply.ok();
+    }
+
+    impl_read_only!();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HttpFilesystem, ListingKind};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// The file served by `serve`
+    const FILE_DATA: &[u8] = b"0123456789ab";
+
+    /// Serve an index page listing `a.bin` and the file itself on a local
+    /// port, returns the URL of the index
+    fn serve(support_range: bool, head_length: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap_or_else(|_| panic!());
+        let addr = listener.local_addr().unwrap_or_else(|_| panic!());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                let mut range = None;
+                if reader.read_line(&mut request_line).is_err() {
+                    continue;
+                }
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Range: bytes=") {
+                        range = value.trim().split_once('-').map(|(start, end)| {
+                            (
+                                start.parse::<usize>().unwrap_or_else(|_| panic!()),
+                                end.parse::<usize>().unwrap_or_else(|_| panic!()),
+                            )
+                        });
+                    }
+                }
+                let mut parts = request_line.split_whitespace();
+                let method = parts.next().unwrap_or_default();
+                let path = parts.next().unwrap_or_default();
+                let (status, headers, body) = match (path, range) {
+                    ("/", _) => (
+                        "200 OK",
+                        String::new(),
+                        b"<html><a href=\"a.bin\">a.bin</a></html>".to_vec(),
+                    ),
+                    ("/a.bin", Some((start, end))) if support_range => (
+                        "206 Partial Content",
+                        format!(
+                            "Content-Range: bytes {}-{}/{}\r\n",
+                            start,
+                            end,
+                            FILE_DATA.len()
+                        ),
+                        FILE_DATA
+                            .get(start..=end)
+                            .unwrap_or_else(|| panic!())
+                            .to_vec(),
+                    ),
+                    ("/a.bin", _) => ("200 OK", String::new(), FILE_DATA.to_vec()),
+                    _ => ("404 Not Found", String::new(), Vec::new()),
+                };
+                let length = if method == "HEAD" && !head_length {
+                    String::new()
+                } else {
+                    format!("Content-Length: {}\r\n", body.len())
+                };
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\n{}{}Connection: close\r\n\r\n",
+                    status, headers, length
+                )
+                .into_bytes();
+                if method != "HEAD" {
+                    response.extend(body);
+                }
+                // the client may hang up early
+                let _ = stream.write_all(&response);
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    /// Mount the index of `url` with chunks of 4 bytes and a cache of 2
+    /// chunks, returns the filesystem and the ino of `a.bin`
+    fn mount(url: &str) -> (HttpFilesystem, u64) {
+        use crate::fuse::FUSE_ROOT_ID;
+        use std::ffi::OsStr;
+
+        let mut fs = HttpFilesystem::new(url, ListingKind::Index);
+        fs.chunk_store.set_chunk_size(4);
+        fs.set_cache_capacity(2);
+        let ino = *fs
+            .helper_load_children(FUSE_ROOT_ID)
+            .unwrap_or_else(|_| panic!())
+            .get(OsStr::new("a.bin"))
+            .unwrap_or_else(|| panic!());
+        fs.helper_refresh_attr(ino).unwrap_or_else(|_| panic!());
+        (fs, ino)
+    }
+
+    #[test]
+    fn test_lru_chunks() {
+        let (mut fs, ino) = mount(&serve(true, true));
+        assert_eq!(fs.helper_read(ino, 0, 4), Ok(b"0123".to_vec()));
+        assert_eq!(fs.helper_read(ino, 4, 4), Ok(b"4567".to_vec()));
+        // the first chunk is used again after the second one
+        assert_eq!(fs.helper_read(ino, 2, 2), Ok(b"23".to_vec()));
+        assert_eq!(fs.helper_read(ino, 8, 10), Ok(b"89ab".to_vec()));
+        assert!(fs.cached_chunks.contains_key(&(ino, 0)));
+        assert!(!fs.cached_chunks.contains_key(&(ino, 1)));
+        assert!(fs.cached_chunks.contains_key(&(ino, 2)));
+        assert_eq!(fs.cache_order.len(), 2);
+        assert_eq!(fs.chunk_store.chunk_count(), 2);
+    }
+
+    #[test]
+    fn test_range_ignored() {
+        let (mut fs, ino) = mount(&serve(false, true));
+        assert_eq!(fs.helper_read(ino, 5, 2), Ok(b"56".to_vec()));
+        // only the requested chunk is kept out of the whole file sent
+        assert_eq!(
+            fs.cached_chunks.keys().copied().collect::<Vec<_>>(),
+            [(ino, 1)]
+        );
+        assert_eq!(fs.helper_read(ino, 10, 4), Ok(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn test_size_without_content_length() {
+        let (fs, ino) = mount(&serve(true, false));
+        assert_eq!(fs.nodes.helper_get(ino).size, 12);
+        let (fs, ino) = mount(&serve(false, false));
+        assert_eq!(fs.nodes.helper_get(ino).size, 12);
+        assert_eq!(super::content_range_size("bytes */0"), Some(0));
+        assert_eq!(super::content_range_size("bytes 0-0/*"), None);
+    }
+
+    #[test]
+    fn test_vanished_nodes() {
+        use super::RemoteEntry;
+        use crate::fuse::FUSE_ROOT_ID;
+        use std::ffi::{OsStr, OsString};
+
+        let entry = |name: &str, is_dir| RemoteEntry {
+            name: OsString::from(name),
+            is_dir,
+            size: Some(1),
+            mtime: None,
+        };
+        // nothing is requested from the URL
+        let mut fs = HttpFilesystem::new("http://127.0.0.1:1/", ListingKind::Index);
+        fs.helper_update_children(
+            FUSE_ROOT_ID,
+            vec![
+                entry("dir", true),
+                entry("file", false),
+                entry("kept", false),
+            ],
+        );
+        let children = fs
+            .nodes
+            .helper_get(FUSE_ROOT_ID)
+            .children
+            .clone()
+            .unwrap_or_else(|| panic!());
+        let dir = children[OsStr::new("dir")];
+        let file = children[OsStr::new("file")];
+        let kept = children[OsStr::new("kept")];
+        fs.helper_update_children(dir, vec![entry("sub", false)]);
+        let sub = fs
+            .nodes
+            .helper_get(dir)
+            .children
+            .as_ref()
+            .unwrap_or_else(|| panic!())[OsStr::new("sub")];
+        // the kernel looked up the directory twice
+        fs.nodes.helper_get_mut(dir).lookup_count = 2;
+
+        // the file becomes a directory and the directory goes away
+        fs.helper_update_children(
+            FUSE_ROOT_ID,
+            vec![entry("file", true), entry("kept", false)],
+        );
+        let children = fs
+            .nodes
+            .helper_get(FUSE_ROOT_ID)
+            .children
+            .clone()
+            .unwrap_or_else(|| panic!());
+        assert_eq!(children[OsStr::new("kept")], kept);
+        assert_ne!(children[OsStr::new("file")], file);
+        assert!(fs.nodes.get(file).is_none());
+        assert!(fs.nodes.get(sub).is_none());
+        assert!(fs.nodes.helper_get(dir).vanished);
+
+        fs.helper_forget(dir, 1);
+        assert!(fs.nodes.get(dir).is_some());
+        fs.helper_forget(dir, 1);
+        assert!(fs.nodes.get(dir).is_none());
+        assert_eq!(fs.nodes.len(), 3);
+    }
+}
@@ -0,0 +1,228 @@
+//! Parsers of remote directory listings, the index pages generated by HTTP
+//! servers and the multistatus responses of WebDAV `PROPFIND` requests
+//!
+//! Index pages only tell the names of the entries, a directory is told by the
+//! trailing slash of its link. Links to other pages, e.g. the parent directory
+//! or the sorting links, are skipped.
+
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::time::SystemTime;
+
+/// Entry of a remote directory
+#[derive(Debug, PartialEq)]
+pub struct RemoteEntry {
+    /// Name
+    pub name: OsString,
+    /// Whether it is a directory
+    pub is_dir: bool,
+    /// Size, `None` if the listing does not tell
+    pub size: Option<u64>,
+    /// Modification time, `None` if the listing does not tell
+    pub mtime: Option<SystemTime>,
+}
+
+/// The path of a URL, the URL itself if it is already a path
+pub fn url_path(url: &str) -> &str {
+    match url.find("://") {
+        Some(scheme_end) => {
+            let host_start = scheme_end.wrapping_add(3);
+            url.get(host_start..)
+                .and_then(|rest| rest.find('/'))
+                .and_then(|path_start| url.get(host_start.wrapping_add(path_start)..))
+                .unwrap_or("/")
+        }
+        None => url,
+    }
+}
+
+/// The decoded name of the last segment of a path, `None` for the root or a
+/// segment of `.` or `..`
+fn segment_name(path: &str) -> Option<OsString> {
+    let segment = path.trim_end_matches('/').rsplit('/').next()?;
+    let name: Vec<u8> = percent_decode_str(segment).collect();
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+        None
+    } else {
+        Some(OsString::from_vec(name))
+    }
+}
+
+/// Parse the index page of the directory of `dir_url`
+pub fn parse_index(dir_url: &str, html: &str) -> Vec<RemoteEntry> {
+    // safe to use panic!() here, because the regex is valid
+    let link_regex =
+        Regex::new(r#"(?i)<a\s[^>]*?href\s*=\s*["']([^"']+)["']"#).unwrap_or_else(|_| panic!());
+    let dir_path = url_path(dir_url).trim_end_matches('/');
+    let mut entries: Vec<RemoteEntry> = Vec::new();
+    for captures in link_regex.captures_iter(html) {
+        let href = match captures.get(1) {
+            Some(href) => href.as_str(),
+            None => continue,
+        };
+        if href.contains('?') || href.starts_with('#') {
+            continue;
+        }
+        // links of the children are relative, or absolute paths under the directory
+        let relative = if href.contains("://") || href.starts_with('/') {
+            match url_path(href).strip_prefix(dir_path) {
+                Some(rest) => match rest.strip_prefix('/') {
+                    Some(relative) => relative,
+                    None => continue,
+                },
+                None => continue,
+            }
+        } else {
+            href.strip_prefix("./").unwrap_or(href)
+        };
+        if relative.trim_end_matches('/').contains('/') {
+            continue;
+        }
+        if let Some(name) = segment_name(relative) {
+            if entries.iter().all(|entry| entry.name != name) {
+                entries.push(RemoteEntry {
+                    name,
+                    is_dir: relative.ends_with('/'),
+                    size: None,
+                    mtime: None,
+                });
+            }
+        }
+    }
+    entries
+}
+
+/// Parse the multistatus response of a `PROPFIND` request of depth one of the
+/// directory of `dir_url`
+pub fn parse_multistatus(dir_url: &str, xml: &str) -> Vec<RemoteEntry> {
+    // safe to use panic!() here, because the regexes are valid
+    let response_regex = Regex::new(r"(?s)<(?:[\w-]+:)?response[\s>](.*?)</(?:[\w-]+:)?response>");
+    let response_regex = response_regex.unwrap_or_else(|_| panic!());
+    let href_regex =
+        Regex::new(r"<(?:[\w-]+:)?href[^>]*>\s*([^<]*?)\s*<").unwrap_or_else(|_| panic!());
+    let collection_regex =
+        Regex::new(r"<(?:[\w-]+:)?collection\s*/?>").unwrap_or_else(|_| panic!());
+    let length_regex =
+        Regex::new(r"<(?:[\w-]+:)?getcontentlength[^>]*>\s*(\d+)\s*<").unwrap_or_else(|_| panic!());
+    let mtime_regex = Regex::new(r"<(?:[\w-]+:)?getlastmodified[^>]*>\s*([^<]*?)\s*<")
+        .unwrap_or_else(|_| panic!());
+
+    let dir_path: Vec<u8> = percent_decode_str(url_path(dir_url).trim_end_matches('/')).collect();
+    let mut entries = Vec::new();
+    for response in response_regex.captures_iter(xml) {
+        let response = match response.get(1) {
+            Some(response) => response.as_str(),
+            None => continue,
+        };
+        let href = match href_regex.captures(response).and_then(|c| c.get(1)) {
+            Some(href) => href.as_str().replace("&amp;", "&"),
+            None => continue,
+        };
+        let path = url_path(&href).trim_end_matches('/');
+        // the response of the directory itself
+        if percent_decode_str(path).collect::<Vec<_>>() == dir_path {
+            continue;
+        }
+        if let Some(name) = segment_name(path) {
+            entries.push(RemoteEntry {
+                name,
+                is_dir: collection_regex.is_match(response),
+                size: length_regex
+                    .captures(response)
+                    .and_then(|c| c.get(1))
+                    .and_then(|length| length.as_str().parse().ok()),
+                mtime: mtime_regex
+                    .captures(response)
+                    .and_then(|c| c.get(1))
+                    .and_then(|mtime| httpdate::parse_http_date(mtime.as_str()).ok()),
+            });
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_index, parse_multistatus, url_path, RemoteEntry};
+    use std::ffi::OsString;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_url_path() {
+        assert_eq!(url_path("http://host:8080/a/b/"), "/a/b/");
+        assert_eq!(url_path("https://host"), "/");
+        assert_eq!(url_path("/a/b"), "/a/b");
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let html = r#"<html><body><h1>Index of /pub/</h1>
+            <a href="?C=N;O=D">Name</a>
+            <a href="../">Parent Directory</a>
+            <a href="docs/">docs/</a>
+            <a href="read%20me.txt">read me.txt</a>
+            <A HREF='/pub/abs.bin'>abs.bin</A>
+            <a href="/other/x">x</a>
+            <a href="http://example.com/pub/full/">full/</a>
+            <a href="docs/nested.txt">nested</a>
+            <a href="docs/">docs/</a>
+            </body></html>"#;
+        let entries = parse_index("http://example.com/pub/", html);
+        let names: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                (
+                    entry.name.to_str().unwrap_or_else(|| panic!()),
+                    entry.is_dir,
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("docs", true),
+                ("read me.txt", false),
+                ("abs.bin", false),
+                ("full", true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_multistatus() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+            <D:multistatus xmlns:D="DAV:">
+            <D:response><D:href>/dav/dir/</D:href><D:propstat><D:prop>
+                <D:resourcetype><D:collection/></D:resourcetype>
+            </D:prop></D:propstat></D:response>
+            <D:response><D:href>/dav/dir/sub/</D:href><D:propstat><D:prop>
+                <D:resourcetype><D:collection /></D:resourcetype>
+            </D:prop></D:propstat></D:response>
+            <D:response><D:href>http://host/dav/dir/a%20b.txt</D:href><D:propstat><D:prop>
+                <D:getcontentlength>42</D:getcontentlength>
+                <D:getlastmodified>Thu, 01 Jan 1970 00:01:40 GMT</D:getlastmodified>
+                <D:resourcetype/>
+            </D:prop></D:propstat></D:response>
+            </D:multistatus>"#;
+        let entries = parse_multistatus("http://host/dav/dir", xml);
+        assert_eq!(
+            entries,
+            vec![
+                RemoteEntry {
+                    name: OsString::from("sub"),
+                    is_dir: true,
+                    size: None,
+                    mtime: None,
+                },
+                RemoteEntry {
+                    name: OsString::from("a b.txt"),
+                    is_dir: false,
+                    size: Some(42),
+                    mtime: Some(UNIX_EPOCH + Duration::from_secs(100)),
+                },
+            ]
+        );
+    }
+}
@@ -1,2 +1,10 @@
+#[macro_use]
+mod readonly;
+
+pub mod archivefs;
 pub mod fuse;
+#[cfg(feature = "git")]
+pub mod gitfs;
+#[cfg(feature = "http")]
+pub mod httpfs;
 pub mod memfs;
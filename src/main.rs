@@ -36,51 +36,432 @@
 )]
 
 //! Fuse Low Level
-use log::debug;
+use clap::ArgMatches;
+use log::{error, info, warn};
+use nix::fcntl::{self, FcntlArg};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
-use clap::{App, Arg};
+/// Read-only filesystem module
+#[macro_use]
+mod readonly;
 
+/// Archivefs module
+mod archivefs;
+/// Cli module
+mod cli;
 /// Fuse module
 mod fuse;
+/// Gitfs module
+#[cfg(feature = "git")]
+mod gitfs;
+/// Httpfs module
+#[cfg(feature = "http")]
+mod httpfs;
 /// Memfs module
 mod memfs;
 
-use memfs::MemoryFilesystem;
+use archivefs::ArchiveFilesystem;
+use cli::{Config, MountSettings};
+#[cfg(feature = "git")]
+use gitfs::GitFilesystem;
+#[cfg(feature = "http")]
+use httpfs::{HttpFilesystem, ListingKind};
+use memfs::{AttrMap, MemoryFilesystem, OpenFlags};
+
+/// The `fsname` and `subtype` options naming a mount of `source` in the mount
+/// table, unless given in `options`. The `subtype` option is only known on
+/// Linux and Android, the mount fails with it on macOS.
+fn default_name_options(options: &[&str], source: &OsStr, subtype: &str) -> Vec<String> {
+    let mut name_options = Vec::new();
+    if !options.iter().any(|op| op.starts_with("fsname=")) {
+        name_options.push(format!("fsname={}", source.to_string_lossy()));
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if !options.iter().any(|op| op.starts_with("subtype=")) {
+        name_options.push(format!("subtype={}", subtype));
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let _ = subtype;
+    name_options
+}
+
+/// New memory filesystem backed by `mountpoint` with the settings of the
+/// cache and of opening the backing files
+fn new_memfs(mountpoint: &OsStr, settings: &MountSettings) -> MemoryFilesystem {
+    let fs = if settings.dedup {
+        let chunk_size = settings.chunk_size.unwrap_or(memfs::DEFAULT_CHUNK_SIZE);
+        let mut fs = MemoryFilesystem::new_with_dedup(mountpoint, chunk_size);
+        fs.set_adaptive_chunk_size(settings.chunk_size.is_none());
+        fs
+    } else {
+        MemoryFilesystem::new(mountpoint)
+    };
+    fs.set_open_flags(OpenFlags {
+        cloexec: !settings.no_cloexec,
+        noatime: !settings.update_atime,
+    });
+    fs
+}
+
+/// Time the self test of a mount point may take
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Run the self test of the mount point once the session is mounted, a
+/// failed self test sets `failed` and unmounts the mount point to end the
+/// session
+fn add_self_test<FS: fuse::Filesystem>(se: &mut fuse::Session<FS>, failed: &Arc<AtomicBool>) {
+    let failed = Arc::clone(failed);
+    se.on_mount(move |mountpoint| {
+        let mountpoint = match mountpoint {
+            Some(mountpoint) => mountpoint.to_path_buf(),
+            None => return,
+        };
+        let failed = Arc::clone(&failed);
+        // the probe file goes through the kernel to the session thread
+        thread::spawn(
+            move || match fuse::self_test(&mountpoint, SELF_TEST_TIMEOUT) {
+                Ok(elapsed) => info!("self test of {:?} passed in {:?}", mountpoint, elapsed),
+                Err(e) => {
+                    error!("self test of {:?} failed, the error is: {}", mountpoint, e);
+                    failed.store(true, Ordering::Release);
+                    fuse::unmount_options(&mountpoint, fuse::UnmountFlags::LAZY).unwrap_or_else(
+                        |e| error!("Couldn't unmount {:?}, the error is: {}", mountpoint, e),
+                    );
+                }
+            },
+        );
+    });
+}
+
+/// Mount a memory filesystem, for the bare form and the `mount` subcommand
+fn mount_memfs(matches: &ArgMatches<'_>, settings: &MountSettings) {
+    // safe to use panic!() here, because mountpoint is required
+    let mountpoint = matches
+        .value_of_os("mountpoint")
+        .unwrap_or_else(|| panic!("Couldn't new mount point {:?}", matches));
+    let mut options: Vec<&str> = settings.options.iter().map(String::as_str).collect();
+    // the mount point is the backing directory, which names the mount
+    let backing_path = fs::canonicalize(mountpoint).unwrap_or_else(|_| PathBuf::from(mountpoint));
+    let name_options = default_name_options(&options, backing_path.as_os_str(), "memfs");
+    options.extend(name_options.iter().map(String::as_str));
+    let (memfs_options, mut options) = memfs::split_mount_options(&options);
+    // the access is checked by memfs in open, which the kernel then skips
+    if settings.no_open && !options.contains(&"kernel:default_permissions") {
+        options.push("kernel:default_permissions");
+    }
+    let attr_map = AttrMap::from_options(&memfs_options).unwrap_or_else(|e| {
+        panic!(
+            "Invalid mount options {:?}, the error is: {}",
+            memfs_options, e
+        )
+    });
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if settings.preflight {
+        let report = fuse::preflight(&options);
+        println!("{}", report);
+        if !report.passed() {
+            process::exit(1);
+        }
+    }
+    let selftest_failed = Arc::new(AtomicBool::new(false));
+    let mount = || {
+        let mut fs = new_memfs(mountpoint, settings);
+        if let Some(timeout) = settings.io_timeout {
+            fs.set_io_timeout(timeout);
+        }
+        if let Some(limit) = settings.cache_limit {
+            fs.set_cache_limit(limit);
+        }
+        if let Some(concurrency) = settings.flush_concurrency {
+            fs.set_flush_concurrency(concurrency);
+        }
+        if let Some(threshold) = settings.mmap_threshold {
+            fs.set_mmap_threshold(threshold);
+        }
+        fs.set_private_backing(settings.private_backing);
+        if let Some(size) = settings.prealloc_size {
+            fs.set_prealloc_size(size);
+        }
+        if let Some(limit) = settings.max_inodes {
+            fs.set_max_inodes(limit);
+        }
+        if let Some(limit) = settings.max_file_size {
+            fs.set_max_file_size(limit);
+        }
+        if let Some(encoding) = settings.name_encoding {
+            fs.set_name_encoding(encoding);
+        }
+        if let Some(reserve) = settings.reserve_space {
+            fs.set_reserve_space(reserve);
+        }
+        if let Some(ref name) = settings.snapshot_dir {
+            fs.set_snapshot_dir(name);
+        }
+        if let Some(ref dir) = settings.spill_dir {
+            fs.set_spill_dir(dir, settings.spill_limit)
+                .unwrap_or_else(|e| {
+                    panic!(
+                        "Couldn't create the spill file under {:?}, the error is: {:?}",
+                        dir, e
+                    )
+                });
+        }
+        if let Some(ref path) = settings.debug_refcounts {
+            fs.set_debug_refcounts(path).unwrap_or_else(|e| {
+                panic!(
+                    "Couldn't trace the reference counts to {:?}, the error is: {}",
+                    path, e
+                )
+            });
+        }
+        if let Some(ref path) = settings.state_file {
+            // the saved i-nodes are only known to the kernel of the mount taken
+            // over on stdin, a new mount discards them
+            if settings.dry_run_mount {
+                fs.restore_state(path).unwrap_or_else(|e| {
+                    panic!(
+                        "Couldn't restore the i-nodes saved to {:?}, the error is: {}",
+                        path, e
+                    )
+                });
+            } else if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    panic!(
+                        "Couldn't discard the i-nodes saved to {:?}, the error is: {}",
+                        path, e
+                    );
+                }
+            }
+            fs.set_state_file(path);
+        }
+        if settings.confine {
+            fs.confine_backend().unwrap_or_else(|e| {
+                panic!(
+                    "Couldn't confine the backing directory, the error is: {}",
+                    e
+                )
+            });
+        }
+        fs.set_readdir_ino_order(settings.readdir_ino_order);
+        fs.set_no_open(settings.no_open);
+        fs.set_writeback_cache(settings.writeback_cache);
+        fs.set_evict_forgotten(settings.evict_forgotten);
+        if let Some(interval) = settings.revalidate_interval {
+            fs.set_revalidate(interval, settings.revalidate_sample.unwrap_or(64));
+        }
+        if let Some(ref pattern) = settings.preload {
+            fs.set_preload(pattern, settings.preload_data.unwrap_or(0))
+                .unwrap_or_else(|e| {
+                    panic!("Couldn't preload {:?}, the error is: {:?}", pattern, e)
+                });
+        }
+        fs.set_privileged_xattr(memfs_options.contains(&memfs::PRIVILEGED_XATTR_OPTION));
+        fs.set_attr_map(attr_map.clone());
+        let session = if settings.dry_run_mount {
+            info!(
+                "dry run, serving the requests on stdin instead of mounting {:?}",
+                mountpoint
+            );
+            // the session closes the fd handed over, a dup of stdin, which
+            // stays open
+            fcntl::fcntl(libc::STDIN_FILENO, FcntlArg::F_DUPFD_CLOEXEC(0))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .map(|fd| {
+                    #[allow(unsafe_code)]
+                    let se = unsafe { fuse::Session::from_fd(fd, fs) };
+                    se
+                })
+        } else {
+            fuse::Session::new(fs, Path::new(mountpoint), &options)
+        };
+        session.map(|mut se| {
+            se.slow_op_threshold = settings.slow_op_threshold;
+            se.idle_interval = Some(memfs::IDLE_INTERVAL);
+            se.huge_page_buffer = settings.huge_pages;
+            se.strict = settings.strict;
+            se.setuid = settings.setuid;
+            se.setgid = settings.setgid;
+            se.seccomp = settings.seccomp;
+            if settings.selftest {
+                add_self_test(&mut se, &selftest_failed);
+            }
+            se
+        })
+    };
+    let mut invalid_request = false;
+    let res = if settings.supervise {
+        fuse::supervise(&fuse::SupervisorConfig::default(), mount)
+    } else {
+        mount().and_then(|mut se| {
+            let summary: fuse::SessionSummary = se.run_with_callbacks();
+            info!("{}", summary);
+            invalid_request = matches!(summary.exit, fuse::SessionExit::InvalidRequest);
+            summary.into_result()
+        })
+    };
+    res.unwrap_or_else(|_| panic!("Couldn't mount filesystem {:?}", mountpoint));
+    // ended without an unmount, let the service manager mount again
+    if invalid_request || selftest_failed.load(Ordering::Acquire) {
+        process::exit(1);
+    }
+}
+
+/// Mount the filesystem chosen by a subcommand read-only, named after its
+/// `source` argument and the `subtype`
+fn mount_read_only(
+    matches: &ArgMatches<'_>,
+    fs: Box<dyn fuse::Filesystem + Send>,
+    options: Vec<&str>,
+    source: &str,
+    subtype: &str,
+) {
+    // safe to use panic!() here, because mountpoint and source are required
+    let mountpoint = matches
+        .value_of_os("mountpoint")
+        .unwrap_or_else(|| panic!("Couldn't get mount point {:?}", matches));
+    let source = matches
+        .value_of_os(source)
+        .unwrap_or_else(|| panic!("Couldn't get {} {:?}", source, matches));
+    let name_options = default_name_options(&options, source, subtype);
+    let (memfs_options, mut options) = memfs::split_mount_options(&options);
+    if !memfs_options.is_empty() {
+        warn!("ignore the memfs options {:?}", memfs_options);
+    }
+    options.extend(name_options.iter().map(String::as_str));
+    options.push("ro");
+    fuse::mount(fs, Path::new(mountpoint), &options)
+        .unwrap_or_else(|_| panic!("Couldn't mount filesystem {:?}", mountpoint));
+}
+
+/// Open an archive
+fn archive_filesystem(matches: &ArgMatches<'_>) -> Box<dyn fuse::Filesystem + Send> {
+    // safe to use panic!() here, because the archive is required
+    let archive = matches
+        .value_of_os("archive")
+        .unwrap_or_else(|| panic!("Couldn't get archive {:?}", matches));
+    let mut fs = ArchiveFilesystem::new(archive)
+        .unwrap_or_else(|e| panic!("Couldn't open archive {:?}, the error is: {}", archive, e));
+    fs.set_no_open(matches.is_present("no-open"));
+    Box::new(fs)
+}
+
+/// Open a revision of a git repository
+#[cfg(feature = "git")]
+fn git_filesystem(matches: &ArgMatches<'_>) -> Box<dyn fuse::Filesystem + Send> {
+    // safe to use panic!() here, because the arguments are required
+    let repository = matches
+        .value_of_os("repository")
+        .unwrap_or_else(|| panic!("Couldn't get repository {:?}", matches));
+    let revision = matches
+        .value_of("revision")
+        .unwrap_or_else(|| panic!("Couldn't get revision {:?}", matches));
+    let mut fs = GitFilesystem::new(repository, revision).unwrap_or_else(|e| {
+        panic!(
+            "Couldn't open revision {:?} of repository {:?}, the error is: {}",
+            revision, repository, e
+        )
+    });
+    fs.set_no_open(matches.is_present("no-open"));
+    Box::new(fs)
+}
+
+/// Open a remote HTTP directory tree or WebDAV share
+#[cfg(feature = "http")]
+fn http_filesystem(matches: &ArgMatches<'_>) -> Box<dyn fuse::Filesystem + Send> {
+    // safe to use panic!() here, because the URL is required
+    let url = matches
+        .value_of("url")
+        .unwrap_or_else(|| panic!("Couldn't get URL {:?}", matches));
+    let listing = if matches.is_present("webdav") {
+        ListingKind::WebDav
+    } else {
+        ListingKind::Index
+    };
+    let mut fs = HttpFilesystem::new(url, listing);
+    fs.set_no_open(matches.is_present("no-open"));
+    if let Some(count) = matches.value_of("cache-chunks") {
+        // safe to use panic!() here, because the count is validated
+        fs.set_cache_capacity(
+            count
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid cache chunk count {:?}", count)),
+        );
+    }
+    Box::new(fs)
+}
 
 fn main() {
     env_logger::init();
 
-    let matches = App::new("Fuse Low Level")
-        .arg(Arg::with_name("mountpoint").required(true).index(1))
-        .arg(
-            Arg::with_name("options")
-                .short("o")
-                .value_name("OPTIONS")
-                .help("Mount options")
-                .multiple(true)
-                .takes_value(true)
-                .validator(|option| fuse::options_validator(option.as_str()))
-                .number_of_values(1),
-        )
-        .get_matches();
-
-    let mountpoint = OsStr::new(
-        matches
-            .value_of("mountpoint")
-            .unwrap_or_else(|| panic!("Couldn't new mount point {:?}", matches)),
-    ); // safe to use unwrap() here, because mountpoint is required
-    let options: Vec<&str> = match matches.values_of("options") {
-        Some(options) => options.flat_map(|o| o.split(',')).collect(),
-        None => Vec::new(),
+    let matches = cli::app().get_matches();
+
+    let config = match matches.value_of_os("config") {
+        Some(path) => Config::load(Path::new(path))
+            .unwrap_or_else(|e| panic!("Couldn't load config {:?}, the error is: {}", path, e)),
+        None => Config::from_env(),
+    };
+    let resolve = |matches: &ArgMatches<'_>| {
+        MountSettings::resolve(matches, &config)
+            .unwrap_or_else(|e| panic!("Invalid mount settings, the error is: {}", e))
     };
-    debug!("{:?}", &options);
     // TODO: add check function for mutual exclusive options
+    let settings = resolve(&matches);
+    let options: Vec<&str> = settings.options.iter().map(String::as_str).collect();
 
-    let fs = MemoryFilesystem::new(&mountpoint);
-    fuse::mount(fs, Path::new(&mountpoint), &options)
-        .unwrap_or_else(|_| panic!("Couldn't mount filesystem {:?}", mountpoint));
+    match matches.subcommand() {
+        ("mount", Some(mount_matches)) => mount_memfs(mount_matches, &resolve(mount_matches)),
+        ("umount", Some(umount_matches)) => cli::umount(umount_matches)
+            .unwrap_or_else(|e| panic!("Couldn't unmount, the error is: {}", e)),
+        ("check", Some(check_matches)) => {
+            if !cli::check(check_matches) {
+                process::exit(1);
+            }
+        }
+        ("bench", Some(bench_matches)) => cli::bench(bench_matches)
+            .unwrap_or_else(|e| panic!("Benchmark failed, the error is: {}", e)),
+        ("stats", Some(stats_matches)) => cli::stats(stats_matches)
+            .unwrap_or_else(|e| panic!("Couldn't get stats, the error is: {}", e)),
+        ("options", Some(_)) => cli::options(),
+        ("completions", Some(completions_matches)) => cli::completions(completions_matches),
+        ("mount-archive", Some(archive_matches)) => {
+            mount_read_only(
+                archive_matches,
+                archive_filesystem(archive_matches),
+                options,
+                "archive",
+                "archive",
+            );
+        }
+        #[cfg(feature = "git")]
+        ("mount-git", Some(git_matches)) => {
+            mount_read_only(
+                git_matches,
+                git_filesystem(git_matches),
+                options,
+                "repository",
+                "git",
+            );
+        }
+        #[cfg(feature = "http")]
+        ("mount-http", Some(http_matches)) => {
+            mount_read_only(
+                http_matches,
+                http_filesystem(http_matches),
+                options,
+                "url",
+                "http",
+            );
+        }
+        _ => mount_memfs(&matches, &settings),
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,358 @@
+//! Translation of the attributes of files between the backing directory and
+//! the mount point, set by mount options
+//!
+//! `uid=<n>` and `gid=<n>` report all the files as owned by the given ids,
+//! `uid_map=<path>` maps ranges of ids. The map file has a line per range: `u`
+//! for user ids or `g` for group ids, the first id seen through the mount
+//! point, the first id on the backing directory and the length of the range,
+//! the same as `/proc/<pid>/uid_map`, e.g. `u 1000 0 1` shows the files owned
+//! by root as owned by user 1000. `#` starts a comment. Backing ids out of
+//! the map are reported as the overflow id.
+//!
+//! `fmask=<mode>` and `dmask=<mode>` clear the permission bits of files and of
+//! directories, in octal as vfat, `umask=<mode>` sets both. The bits are
+//! cleared from the attributes reported to the kernel, and access to the
+//! files is checked against the cleared bits, the kernel does not check
+//! permissions without the `default_permissions` option.
+
+use super::{FileAttr, FileType, OverflowArithmetic};
+use libc::{R_OK, W_OK, X_OK};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Mount option of the owner of all the files
+const UID_OPTION: &str = "uid";
+/// Mount option of the group of all the files
+const GID_OPTION: &str = "gid";
+/// Mount option of the file mapping ranges of ids
+const UID_MAP_OPTION: &str = "uid_map";
+/// Mount option of the permission bits cleared from files and directories
+const UMASK_OPTION: &str = "umask";
+/// Mount option of the permission bits cleared from files
+const FMASK_OPTION: &str = "fmask";
+/// Mount option of the permission bits cleared from directories
+const DMASK_OPTION: &str = "dmask";
+/// The permission bits a mask applies to
+const PERM_BITS: u16 = 0o777;
+
+/// Id reported for the backing ids out of the map, the same as the overflow id
+/// of the kernel
+const OVERFLOW_ID: u32 = 65534;
+
+/// A range of ids mapped to the backing directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct IdRange {
+    /// The first id seen through the mount point
+    mounted: u32,
+    /// The first id on the backing directory
+    backing: u32,
+    /// Length of the range
+    count: u32,
+}
+
+impl IdRange {
+    /// Map an id between the ranges starting at `from` and at `to`
+    fn helper_map(&self, id: u32, from: u32, to: u32) -> Option<u32> {
+        id.checked_sub(from)
+            .filter(|offset| *offset < self.count)
+            .and_then(|offset| to.checked_add(offset))
+    }
+}
+
+/// Mapping of user or group ids
+#[derive(Clone, Debug, Default)]
+struct IdTable {
+    /// The id reporting all the files as owned by
+    fixed: Option<u32>,
+    /// Mapped ranges, ids are not mapped if empty
+    ranges: Vec<IdRange>,
+}
+
+impl IdTable {
+    /// Whether ids are passed through as is
+    fn is_identity(&self) -> bool {
+        self.fixed.is_none() && self.ranges.is_empty()
+    }
+
+    /// The id seen through the mount point of a backing id
+    fn to_mounted(&self, backing: u32) -> u32 {
+        if let Some(id) = self.fixed {
+            return id;
+        }
+        if self.ranges.is_empty() {
+            return backing;
+        }
+        self.ranges
+            .iter()
+            .find_map(|range| range.helper_map(backing, range.backing, range.mounted))
+            .unwrap_or(OVERFLOW_ID)
+    }
+
+    /// The backing id of an id seen through the mount point, `None` if it is
+    /// out of the map, changing the id of a file to the fixed one keeps its
+    /// `current` backing id
+    fn to_backing(&self, mounted: u32, current: u32) -> Option<u32> {
+        if let Some(id) = self.fixed {
+            return if id == mounted { Some(current) } else { None };
+        }
+        if self.ranges.is_empty() {
+            return Some(mounted);
+        }
+        self.ranges
+            .iter()
+            .find_map(|range| range.helper_map(mounted, range.mounted, range.backing))
+    }
+}
+
+/// Translation of the attributes of files
+#[derive(Clone, Debug, Default)]
+pub struct AttrMap {
+    /// Mapping of user ids
+    uids: IdTable,
+    /// Mapping of group ids
+    gids: IdTable,
+    /// Permission bits cleared from files
+    fmask: Option<u16>,
+    /// Permission bits cleared from directories
+    dmask: Option<u16>,
+}
+
+impl AttrMap {
+    /// Build from the mount options, ignoring the options of others, fails if
+    /// the map file cannot be read or is invalid
+    pub fn from_options(options: &[&str]) -> io::Result<Self> {
+        let mut map = Self::default();
+        let mut umask = None;
+        for option in options {
+            let (key, value) = match option.split_once('=') {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let parse_id = || {
+                value.parse::<u32>().map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid mount option {:?}: {}", option, e),
+                    )
+                })
+            };
+            let parse_mask = || {
+                u16::from_str_radix(value, 8)
+                    .ok()
+                    .filter(|mask| *mask <= PERM_BITS)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid mount option {:?}, expect an octal mode", option),
+                        )
+                    })
+            };
+            match key {
+                UID_OPTION => map.uids.fixed = Some(parse_id()?),
+                GID_OPTION => map.gids.fixed = Some(parse_id()?),
+                UID_MAP_OPTION => map.helper_load_map(Path::new(value))?,
+                UMASK_OPTION => umask = Some(parse_mask()?),
+                FMASK_OPTION => map.fmask = Some(parse_mask()?),
+                DMASK_OPTION => map.dmask = Some(parse_mask()?),
+                _ => {}
+            }
+        }
+        // fmask and dmask take precedence over umask in any order
+        map.fmask = map.fmask.or(umask);
+        map.dmask = map.dmask.or(umask);
+        Ok(map)
+    }
+
+    /// Helper load the ranges of the map file
+    fn helper_load_map(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        self.helper_parse_map(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+    }
+
+    /// Helper parse the lines of a map file
+    fn helper_parse_map(&mut self, content: &str) -> Result<(), String> {
+        for (idx, line) in content.lines().enumerate() {
+            let line_no = idx.overflow_add(1);
+            let fields: Vec<&str> = line
+                .split('#')
+                .next()
+                .unwrap_or("")
+                .split_whitespace()
+                .collect();
+            let (kind, ids) = match fields.split_first() {
+                Some(pair) => pair,
+                None => continue,
+            };
+            let ids = ids
+                .iter()
+                .map(|id| id.parse::<u32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("line {}: {}", line_no, e))?;
+            let range = match *ids.as_slice() {
+                [mounted, backing, count] => IdRange {
+                    mounted,
+                    backing,
+                    count,
+                },
+                _ => {
+                    return Err(format!(
+                        "line {}: expected u|g <mounted id> <backing id> <count>",
+                        line_no
+                    ))
+                }
+            };
+            match *kind {
+                "u" => self.uids.ranges.push(range),
+                "g" => self.gids.ranges.push(range),
+                _ => return Err(format!("line {}: unknown id kind {:?}", line_no, kind)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the ownership of files is passed through as is
+    pub fn is_identity(&self) -> bool {
+        self.uids.is_identity() && self.gids.is_identity()
+    }
+
+    /// Whether access is checked against the masked permission bits
+    pub const fn checks_access(&self) -> bool {
+        self.fmask.is_some() || self.dmask.is_some()
+    }
+
+    /// The attributes seen through the mount point
+    pub fn to_mounted(&self, attr: &FileAttr) -> FileAttr {
+        let mask = if attr.kind == FileType::Directory {
+            self.dmask
+        } else {
+            self.fmask
+        };
+        FileAttr {
+            uid: self.uids.to_mounted(attr.uid),
+            gid: self.gids.to_mounted(attr.gid),
+            perm: attr.perm & !mask.unwrap_or(0),
+            ..*attr
+        }
+    }
+
+    /// Whether the user and group of a request may access a file of the
+    /// attributes seen through the mount point, `mask` is of `R_OK`, `W_OK`
+    /// and `X_OK` as access(2), supplementary groups are not known
+    pub fn allows_access(attr: &FileAttr, uid: u32, gid: u32, mask: i32) -> bool {
+        let perm: i32 = attr.perm.into();
+        if uid == 0 {
+            // root needs an execute bit to execute a file
+            return mask & X_OK == 0 || attr.kind == FileType::Directory || perm & 0o111 != 0;
+        }
+        let granted = if uid == attr.uid {
+            perm >> 6_i32
+        } else if gid == attr.gid {
+            perm >> 3_i32
+        } else {
+            perm
+        };
+        granted & mask & (R_OK | W_OK | X_OK) == mask
+    }
+
+    /// The backing user id of a user id seen through the mount point, `None`
+    /// if it is out of the map
+    pub fn backing_uid(&self, mounted: u32, current: u32) -> Option<u32> {
+        self.uids.to_backing(mounted, current)
+    }
+
+    /// The backing group id of a group id seen through the mount point, `None`
+    /// if it is out of the map
+    pub fn backing_gid(&self, mounted: u32, current: u32) -> Option<u32> {
+        self.gids.to_backing(mounted, current)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AttrMap, OVERFLOW_ID};
+    use crate::fuse::{FileAttr, FileType};
+    use libc::{R_OK, W_OK, X_OK};
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_id_map() {
+        let map = AttrMap::from_options(&["ro", "uid=1000", "privileged_xattr"])
+            .unwrap_or_else(|_| panic!());
+        assert_eq!(map.uids.to_mounted(0), 1000);
+        assert_eq!(map.gids.to_mounted(0), 0);
+        assert_eq!(map.backing_uid(1000, 7), Some(7));
+        assert_eq!(map.backing_uid(0, 7), None);
+        assert_eq!(map.backing_gid(5, 7), Some(5));
+        assert!(AttrMap::from_options(&["gid=abc"]).is_err());
+        assert!(AttrMap::from_options(&["ro"])
+            .unwrap_or_else(|_| panic!())
+            .is_identity());
+
+        let mut map = AttrMap::default();
+        map.helper_parse_map(
+            "# root to the user\nu 1000 0 1\n\nu 0 100000 1000\ng 1000 0 10 # group\n",
+        )
+        .unwrap_or_else(|_| panic!());
+        assert_eq!(map.uids.to_mounted(0), 1000);
+        assert_eq!(map.uids.to_mounted(100_999), 999);
+        assert_eq!(map.uids.to_mounted(1), OVERFLOW_ID);
+        assert_eq!(map.gids.to_mounted(9), 1009);
+        assert_eq!(map.backing_uid(1000, 7), Some(0));
+        assert_eq!(map.backing_uid(5, 7), Some(100_005));
+        assert_eq!(map.backing_uid(2000, 7), None);
+        assert_eq!(map.backing_gid(1010, 7), None);
+
+        let mut map = AttrMap::default();
+        assert!(map.helper_parse_map("u 1 2").is_err());
+        assert!(map.helper_parse_map("x 1 2 3").is_err());
+        assert!(map.helper_parse_map("u 1 -2 3").is_err());
+    }
+
+    #[test]
+    fn test_mode_mask() {
+        let file = FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o775,
+            nlink: 1,
+            uid: 1000,
+            gid: 100,
+            rdev: 0,
+            flags: 0,
+        };
+        let dir = FileAttr {
+            kind: FileType::Directory,
+            ..file
+        };
+
+        let map = AttrMap::from_options(&["dmask=027", "umask=022"]).unwrap_or_else(|_| panic!());
+        assert!(map.checks_access());
+        assert_eq!(map.to_mounted(&file).perm, 0o755);
+        assert_eq!(map.to_mounted(&dir).perm, 0o750);
+        let map = AttrMap::from_options(&["fmask=133"]).unwrap_or_else(|_| panic!());
+        assert_eq!(map.to_mounted(&file).perm, 0o644);
+        assert_eq!(map.to_mounted(&dir).perm, 0o775);
+        assert!(AttrMap::from_options(&["umask=1000"]).is_err());
+        assert!(AttrMap::from_options(&["fmask=089"]).is_err());
+        assert!(!AttrMap::from_options(&["uid=1"])
+            .unwrap_or_else(|_| panic!())
+            .checks_access());
+
+        let file = map.to_mounted(&file);
+        assert!(AttrMap::allows_access(&file, 1000, 1, R_OK | W_OK));
+        assert!(!AttrMap::allows_access(&file, 1000, 1, X_OK));
+        assert!(AttrMap::allows_access(&file, 2000, 100, R_OK));
+        assert!(!AttrMap::allows_access(&file, 2000, 100, W_OK));
+        assert!(AttrMap::allows_access(&file, 0, 0, R_OK | W_OK));
+        assert!(!AttrMap::allows_access(&file, 0, 0, X_OK));
+        assert!(AttrMap::allows_access(&dir, 0, 0, X_OK));
+    }
+}
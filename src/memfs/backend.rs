@@ -0,0 +1,983 @@
+//! Backing store of memfs
+//!
+//! memfs caches the files and directories of a backing store, all the I/O on
+//! the backing store goes through the `Backend` trait. Files and directories
+//! of a backend are referred to by fds, which memfs also hands out to the
+//! kernel as file handles, so a backend must not reuse an fd until it is closed.
+
+use super::mapping::Mapping;
+use super::util;
+use super::{Cast, FileAttr, OverflowArithmetic};
+use crate::fuse::time_to_secs;
+use libc::c_int;
+use log::warn;
+use nix::dir::{Dir, Type};
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::fcntl::FallocateFlags;
+use nix::fcntl::{self, AtFlags, FcntlArg, OFlag};
+use nix::sys::stat::{self, Mode, SFlag};
+use nix::sys::statvfs;
+use nix::sys::uio;
+use nix::unistd::{self, UnlinkatFlags};
+use rustc_hash::FxHashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt::Debug;
+#[cfg(target_os = "linux")]
+use std::mem;
+use std::mem::ManuallyDrop;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Entry read from a directory of a backend
+#[derive(Clone, Debug)]
+pub struct BackendDirEntry {
+    /// Inode number
+    pub ino: u64,
+    /// Device of the filesystem of the entry, 0 if unknown, e.g. when read
+    /// from a directory
+    pub dev: u64,
+    /// Name
+    pub name: OsString,
+    /// Type, `None` if unknown
+    pub file_type: Option<Type>,
+}
+
+/// Space of the filesystem of a backend, in bytes
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BackendSpace {
+    /// Size of the filesystem
+    pub total: u64,
+    /// Free space available to unprivileged users
+    pub available: u64,
+    /// Number of the i-nodes of the filesystem
+    pub files: u64,
+    /// Number of the free i-nodes
+    pub files_free: u64,
+}
+
+/// The flags a backend adds to the opens of the backing files and directories
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpenFlags {
+    /// Close the fds on exec, so that the processes spawned by the daemon, e.g.
+    /// fusermount, do not inherit them
+    pub cloexec: bool,
+    /// Not update the access times on reading, where permitted, which saves a
+    /// write to the backing store for each read
+    pub noatime: bool,
+}
+
+impl Default for OpenFlags {
+    fn default() -> Self {
+        Self {
+            cloexec: true,
+            noatime: true,
+        }
+    }
+}
+
+/// The mode of allocating or deallocating a range of a file
+#[cfg(feature = "abi-7-19")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FallocateMode {
+    /// Allocate the space of the range, extending the file to cover it unless
+    /// the size is kept
+    Allocate {
+        /// Whether to keep the size of the file
+        keep_size: bool,
+    },
+    /// Deallocate the space of the range, which reads as zeros afterwards,
+    /// the size of the file is kept
+    PunchHole,
+    /// Zero the range, extending the file to cover it unless the size is kept
+    ZeroRange {
+        /// Whether to keep the size of the file
+        keep_size: bool,
+    },
+}
+
+#[cfg(feature = "abi-7-19")]
+impl FallocateMode {
+    /// `FALLOC_FL_KEEP_SIZE` of Linux
+    const KEEP_SIZE: u32 = 0x01;
+    /// `FALLOC_FL_PUNCH_HOLE` of Linux
+    const PUNCH_HOLE: u32 = 0x02;
+    /// `FALLOC_FL_ZERO_RANGE` of Linux
+    const ZERO_RANGE: u32 = 0x10;
+
+    /// The mode of the `FALLOC_FL_*` flags of Linux, which FUSE passes on all
+    /// the platforms, `None` if not supported, e.g. collapsing a range. A hole
+    /// is only punched along with `FALLOC_FL_KEEP_SIZE`, as fallocate(2).
+    pub const fn from_bits(mode: u32) -> Option<Self> {
+        let keep_size = mode & Self::KEEP_SIZE != 0;
+        match mode & !Self::KEEP_SIZE {
+            0 => Some(Self::Allocate { keep_size }),
+            Self::PUNCH_HOLE if keep_size => Some(Self::PunchHole),
+            Self::ZERO_RANGE => Some(Self::ZeroRange { keep_size }),
+            _ => None,
+        }
+    }
+
+    /// Whether the size of the file is kept
+    pub const fn keeps_size(self) -> bool {
+        match self {
+            Self::Allocate { keep_size } | Self::ZeroRange { keep_size } => keep_size,
+            Self::PunchHole => true,
+        }
+    }
+}
+
+/// Entries read from a directory, each along with the offset after it, and the
+/// offset to read the next entries from
+pub type ReadDirEntries = (Vec<(i64, BackendDirEntry)>, Option<i64>);
+
+/// Backing store of memfs
+pub trait Backend: Debug + Send + Sync {
+    /// Open the directory of path
+    fn open_dir(&self, path: &Path) -> nix::Result<RawFd>;
+
+    /// Open the child directory of name under dir
+    fn open_dir_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<RawFd>;
+
+    /// Create a child directory of name under dir
+    fn mkdir_at(&self, dir: RawFd, name: &OsStr, mode: Mode) -> nix::Result<()>;
+
+    /// Open, or create if `O_CREAT` is set, the child file of name under dir
+    fn open_at(&self, dir: RawFd, name: &OsStr, oflags: OFlag, mode: Mode) -> nix::Result<RawFd>;
+
+    /// Read at most `max_entries` entries of dir from offset, each along with the offset
+    /// after it, and the offset to read the next entries from, `None` once reaching the
+    /// end. The offset of an entry is the number of entries before it, '.' and '..'
+    /// included if the backend has them.
+    fn read_dir(&self, dir: RawFd, offset: i64, max_entries: usize) -> nix::Result<ReadDirEntries>;
+
+    /// Get the entry of name under dir without following symlinks
+    fn stat_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<BackendDirEntry>;
+
+    /// Get the attribute of fd
+    fn fstat(&self, fd: RawFd) -> nix::Result<FileAttr>;
+
+    /// Read at offset into buf, returns the read size
+    fn read_at(&self, fd: RawFd, buf: &mut [u8], offset: i64) -> nix::Result<usize>;
+
+    /// Write data at offset, returns the written size
+    fn write_at(&self, fd: RawFd, data: &[u8], offset: i64) -> nix::Result<usize>;
+
+    /// Write the buffers one after another at offset in one call, returns the
+    /// written size, which may end in the middle of a buffer
+    fn write_vectored_at(&self, fd: RawFd, bufs: &[&[u8]], offset: i64) -> nix::Result<usize>;
+
+    /// Map the first `len` bytes of fd read-only, fails if the backend cannot
+    /// map files, the caller reads the file instead
+    fn map(&self, fd: RawFd, len: usize) -> nix::Result<Mapping>;
+
+    /// Allocate the space of `len` bytes from `offset` of fd without changing
+    /// its size, fails with `EOPNOTSUPP` if the backend cannot
+    fn preallocate(&self, fd: RawFd, offset: i64, len: i64) -> nix::Result<()>;
+
+    /// Truncate or extend the file of fd to `size` bytes, which also gives back
+    /// the space preallocated beyond it
+    fn truncate(&self, fd: RawFd, size: i64) -> nix::Result<()>;
+
+    /// Set the modification time of fd, leaving the access time as is
+    fn set_mtime(&self, fd: RawFd, mtime: SystemTime) -> nix::Result<()>;
+
+    /// Allocate or deallocate the `len` bytes from `offset` of fd by `mode`,
+    /// fails with `EOPNOTSUPP` if the backend cannot
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&self, fd: RawFd, mode: FallocateMode, offset: i64, len: i64) -> nix::Result<()>;
+
+    /// Set the status flags of fd
+    fn set_flags(&self, fd: RawFd, oflags: OFlag) -> nix::Result<()>;
+
+    /// Duplicate fd with flags
+    fn dup(&self, fd: RawFd, oflags: OFlag) -> nix::Result<RawFd>;
+
+    /// Sync the data of fd to the backing store
+    fn fsync(&self, fd: RawFd) -> nix::Result<()>;
+
+    /// Remove the child of name under dir
+    fn unlink_at(&self, dir: RawFd, name: &OsStr, flags: UnlinkatFlags) -> nix::Result<()>;
+
+    /// Rename the child of old name under old dir to new name under new dir
+    fn rename_at(
+        &self,
+        old_dir: RawFd,
+        old_name: &OsStr,
+        new_dir: RawFd,
+        new_name: &OsStr,
+    ) -> nix::Result<()>;
+
+    /// Get the value of the extended attribute of name of fd
+    fn get_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<Vec<u8>>;
+
+    /// List the names of the extended attributes of fd
+    fn list_xattr(&self, fd: RawFd) -> nix::Result<Vec<OsString>>;
+
+    /// Set the extended attribute of name of fd, `flags` may have `XATTR_CREATE` to
+    /// fail if it exists, or `XATTR_REPLACE` to fail if it does not
+    fn set_xattr(&self, fd: RawFd, name: &OsStr, value: &[u8], flags: c_int) -> nix::Result<()>;
+
+    /// Remove the extended attribute of name of fd
+    fn remove_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<()>;
+
+    /// Close fd
+    fn close(&self, fd: RawFd) -> nix::Result<()>;
+
+    /// Get the space of the filesystem of fd
+    fn space(&self, fd: RawFd) -> nix::Result<BackendSpace>;
+
+    /// Confine the following I/O to beneath the directories the names are
+    /// resolved in, so that a name cannot lead out of the backing store.
+    /// Fails if the backend cannot confine its I/O.
+    fn confine(&self) -> nix::Result<()>;
+
+    /// Set the flags added to the following opens and dups
+    fn set_open_flags(&self, flags: OpenFlags);
+}
+
+/// Extended attribute syscalls, which take extra position and options
+/// arguments on macOS
+mod xattr {
+    use libc::{c_char, c_int, c_void, size_t, ssize_t};
+
+    /// `fgetxattr`
+    #[allow(unsafe_code)]
+    pub unsafe fn fgetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return libc::fgetxattr(fd, name, value, size);
+        #[cfg(target_os = "macos")]
+        return libc::fgetxattr(fd, name, value, size, 0, 0);
+    }
+
+    /// `flistxattr`
+    #[allow(unsafe_code)]
+    pub unsafe fn flistxattr(fd: c_int, list: *mut c_char, size: size_t) -> ssize_t {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return libc::flistxattr(fd, list, size);
+        #[cfg(target_os = "macos")]
+        return libc::flistxattr(fd, list, size, 0);
+    }
+
+    /// `fsetxattr`
+    #[allow(unsafe_code)]
+    pub unsafe fn fsetxattr(
+        fd: c_int,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+        flags: c_int,
+    ) -> c_int {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return libc::fsetxattr(fd, name, value, size, flags);
+        #[cfg(target_os = "macos")]
+        return libc::fsetxattr(fd, name, value, size, 0, flags);
+    }
+
+    /// `fremovexattr`
+    #[allow(unsafe_code)]
+    pub unsafe fn fremovexattr(fd: c_int, name: *const c_char) -> c_int {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return libc::fremovexattr(fd, name);
+        #[cfg(target_os = "macos")]
+        return libc::fremovexattr(fd, name, 0);
+    }
+}
+
+/// Convert the name of an extended attribute to a C string
+fn xattr_name(name: &OsStr) -> nix::Result<CString> {
+    CString::new(name.as_bytes()).map_err(|_| nix::Error::Sys(Errno::EINVAL))
+}
+
+/// Check that `name` is a single component other than `..`, which does not
+/// lead out of the directory it is resolved in
+fn check_component(name: &OsStr) -> nix::Result<()> {
+    let name_bytes = name.as_bytes();
+    if name_bytes == b".." || name_bytes.contains(&b'/') {
+        warn!("refused the name {:?} leading out of its directory", name);
+        Err(nix::Error::Sys(Errno::EPERM))
+    } else {
+        Ok(())
+    }
+}
+
+/// The argument of `openat2`, `libc::open_how` cannot be constructed
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct OpenHow {
+    /// The flags of `openat`
+    flags: u64,
+    /// The mode of `openat`
+    mode: u64,
+    /// The `RESOLVE_*` flags
+    resolve: u64,
+}
+
+/// Open the child of name under dir without following symbolic links or
+/// resolving out of dir by `..`, so that an entry replaced by a symbolic link
+/// after it was read from the directory is not opened. Kernels before 5.6 have
+/// no `openat2`, where only the last component is not followed instead.
+#[cfg(target_os = "linux")]
+fn open_beneath(dir: RawFd, name: &OsStr, oflags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+    let c_name = CString::new(name.as_bytes()).or(Err(nix::Error::Sys(Errno::EINVAL)))?;
+    let how = OpenHow {
+        flags: oflags.bits().cast(),
+        mode: mode.bits().into(),
+        resolve: libc::RESOLVE_BENEATH | libc::RESOLVE_NO_SYMLINKS,
+    };
+    let how_ptr: *const OpenHow = &how;
+    #[allow(unsafe_code)]
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dir,
+            c_name.as_ptr(),
+            how_ptr,
+            mem::size_of::<OpenHow>(),
+        )
+    };
+    match Errno::result(res) {
+        Ok(fd) => Ok(fd.cast()),
+        Err(nix::Error::Sys(Errno::ENOSYS)) => {
+            fcntl::openat(dir, name, oflags | OFlag::O_NOFOLLOW, mode)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the child of name under dir without following symbolic links, which
+/// is the best effort without `openat2`
+#[cfg(not(target_os = "linux"))]
+fn open_beneath(dir: RawFd, name: &OsStr, oflags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+    fcntl::openat(dir, name, oflags | OFlag::O_NOFOLLOW, mode)
+}
+
+/// `O_NOATIME`, which macOS does not have
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_NOATIME: OFlag = OFlag::O_NOATIME;
+/// `O_NOATIME`, which macOS does not have
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_NOATIME: OFlag = OFlag::empty();
+
+/// Directory opened by the local backend
+#[derive(Debug)]
+struct LocalDir {
+    /// Dir
+    dir: Dir,
+    /// The offset of the dir, zero once the dir reached the end and rewound
+    offset: i64,
+}
+
+/// Backend on the local filesystem
+#[derive(Debug)]
+pub struct LocalBackend {
+    /// Directories opened, keyed by their fds, so that reading a directory in
+    /// batches continues from where the previous batch ends
+    dirs: Mutex<FxHashMap<RawFd, LocalDir>>,
+    /// Whether the I/O is confined to beneath the directories
+    confined: AtomicBool,
+    /// Whether to open and dup with `O_CLOEXEC`
+    cloexec: AtomicBool,
+    /// Whether to open with `O_NOATIME`
+    noatime: AtomicBool,
+}
+
+impl Default for LocalBackend {
+    fn default() -> Self {
+        let flags = OpenFlags::default();
+        Self {
+            dirs: Mutex::default(),
+            confined: AtomicBool::new(false),
+            cloexec: AtomicBool::new(flags.cloexec),
+            noatime: AtomicBool::new(flags.noatime),
+        }
+    }
+}
+
+impl LocalBackend {
+    /// New
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Helper add `O_CLOEXEC` to the flags if set
+    fn helper_cloexec(&self, oflags: OFlag) -> OFlag {
+        if self.cloexec.load(Ordering::Relaxed) {
+            oflags | OFlag::O_CLOEXEC
+        } else {
+            oflags
+        }
+    }
+
+    /// Helper call `f` with `O_NOATIME` added to the flags if set. It is only
+    /// permitted to the owner of the file or with `CAP_FOWNER`, `f` is called
+    /// again without it otherwise.
+    fn helper_noatime<T>(
+        &self,
+        oflags: OFlag,
+        f: impl Fn(OFlag) -> nix::Result<T>,
+    ) -> nix::Result<T> {
+        if self.noatime.load(Ordering::Relaxed) && !O_NOATIME.is_empty() {
+            match f(oflags | O_NOATIME) {
+                Err(nix::Error::Sys(Errno::EPERM)) => {}
+                res => return res,
+            }
+        }
+        f(oflags)
+    }
+
+    /// Helper open the child of name under dir with the flags of the backend
+    fn helper_open_at(
+        &self,
+        dir: RawFd,
+        name: &OsStr,
+        oflags: OFlag,
+        mode: Mode,
+    ) -> nix::Result<RawFd> {
+        self.helper_noatime(self.helper_cloexec(oflags), |oflags| {
+            open_beneath(dir, name, oflags, mode)
+        })
+    }
+
+    /// Helper add dir
+    fn helper_add_dir(&self, dir: Dir) -> RawFd {
+        let fd = dir.as_raw_fd();
+        self.dirs
+            .lock()
+            .unwrap_or_else(|_| panic!("LocalBackend found the dirs lock poisoned"))
+            .insert(fd, LocalDir { dir, offset: 0 });
+        fd
+    }
+
+    /// Helper check the name resolved under a directory if confined
+    fn helper_check_name(&self, name: &OsStr) -> nix::Result<()> {
+        if self.confined.load(Ordering::Relaxed) {
+            check_component(name)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Backend for LocalBackend {
+    fn open_dir(&self, path: &Path) -> nix::Result<RawFd> {
+        let oflags = self.helper_cloexec(OFlag::O_RDONLY | OFlag::O_DIRECTORY);
+        let dir = self.helper_noatime(oflags, |oflags| Dir::open(path, oflags, Mode::empty()))?;
+        Ok(self.helper_add_dir(dir))
+    }
+
+    fn open_dir_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<RawFd> {
+        let oflags = OFlag::O_RDONLY | OFlag::O_DIRECTORY;
+        self.helper_check_name(name)?;
+        let child_dir = Dir::from_fd(self.helper_open_at(dir, name, oflags, Mode::empty())?)?;
+        Ok(self.helper_add_dir(child_dir))
+    }
+
+    fn mkdir_at(&self, dir: RawFd, name: &OsStr, mode: Mode) -> nix::Result<()> {
+        self.helper_check_name(name)?;
+        stat::mkdirat(dir, name, mode)
+    }
+
+    fn open_at(&self, dir: RawFd, name: &OsStr, oflags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+        self.helper_check_name(name)?;
+        self.helper_open_at(dir, name, oflags, mode)
+    }
+
+    fn read_dir(&self, dir: RawFd, offset: i64, max_entries: usize) -> nix::Result<ReadDirEntries> {
+        let mut dirs = self
+            .dirs
+            .lock()
+            .unwrap_or_else(|_| panic!("LocalBackend found the dirs lock poisoned"));
+        let local_dir = dirs.get_mut(&dir).ok_or(nix::Error::Sys(Errno::EBADF))?;
+        if offset < local_dir.offset {
+            drop(local_dir.dir.iter()); // dropping the iterator rewinds the dir fd
+            local_dir.offset = 0;
+        }
+        // the iterator is not dropped unless reaching the end,
+        // so that the dir fd keeps its offset for the next read
+        let mut dir_iter = ManuallyDrop::new(local_dir.dir.iter());
+        let mut dir_offset = local_dir.offset;
+        let mut eof = false;
+        while dir_offset < offset {
+            if dir_iter.next().is_none() {
+                eof = true;
+                break;
+            }
+            dir_offset = dir_offset.overflow_add(1);
+        }
+
+        let mut entries = Vec::new();
+        while !eof && entries.len() < max_entries {
+            let entry = if let Some(entry) = dir_iter.next() {
+                entry
+            } else {
+                eof = true;
+                break;
+            };
+            dir_offset = dir_offset.overflow_add(1);
+            if let Ok(e) = entry {
+                entries.push((
+                    dir_offset,
+                    BackendDirEntry {
+                        ino: e.ino(),
+                        dev: 0,
+                        name: OsStr::from_bytes(e.file_name().to_bytes()).to_os_string(),
+                        file_type: e.file_type(),
+                    },
+                ));
+            }
+        }
+
+        if eof {
+            drop(ManuallyDrop::into_inner(dir_iter));
+            local_dir.offset = 0;
+            Ok((entries, None))
+        } else {
+            local_dir.offset = dir_offset;
+            Ok((entries, Some(dir_offset)))
+        }
+    }
+
+    fn stat_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<BackendDirEntry> {
+        self.helper_check_name(name)?;
+        let st = stat::fstatat(dir, name, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+        let file_type = match util::parse_sflag(st.st_mode.cast()) {
+            SFlag::S_IFDIR => Some(Type::Directory),
+            SFlag::S_IFREG => Some(Type::File),
+            _ => None,
+        };
+        Ok(BackendDirEntry {
+            ino: st.st_ino,
+            dev: st.st_dev.cast(),
+            name: name.to_os_string(),
+            file_type,
+        })
+    }
+
+    fn fstat(&self, fd: RawFd) -> nix::Result<FileAttr> {
+        util::read_attr(fd)
+    }
+
+    fn read_at(&self, fd: RawFd, buf: &mut [u8], offset: i64) -> nix::Result<usize> {
+        uio::pread(fd, buf, offset)
+    }
+
+    fn write_at(&self, fd: RawFd, data: &[u8], offset: i64) -> nix::Result<usize> {
+        uio::pwrite(fd, data, offset)
+    }
+
+    #[cfg(not(target_os = "android"))]
+    fn write_vectored_at(&self, fd: RawFd, bufs: &[&[u8]], offset: i64) -> nix::Result<usize> {
+        let iovecs: Vec<_> = bufs.iter().map(|buf| uio::IoVec::from_slice(buf)).collect();
+        uio::pwritev(fd, &iovecs, offset)
+    }
+
+    /// nix has no `pwritev` on Android, which bionic has since API level 24
+    #[cfg(target_os = "android")]
+    fn write_vectored_at(&self, fd: RawFd, bufs: &[&[u8]], offset: i64) -> nix::Result<usize> {
+        let iovecs: Vec<_> = bufs
+            .iter()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_ptr().cast_mut().cast(),
+                iov_len: buf.len(),
+            })
+            .collect();
+        #[allow(unsafe_code)]
+        let res = unsafe { libc::pwritev(fd, iovecs.as_ptr(), iovecs.len().cast(), offset) };
+        Errno::result(res).map(Cast::cast)
+    }
+
+    fn map(&self, fd: RawFd, len: usize) -> nix::Result<Mapping> {
+        Mapping::new(fd, len)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn preallocate(&self, fd: RawFd, offset: i64, len: i64) -> nix::Result<()> {
+        fcntl::fallocate(fd, FallocateFlags::FALLOC_FL_KEEP_SIZE, offset, len).map(|_| ())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn preallocate(&self, _fd: RawFd, _offset: i64, _len: i64) -> nix::Result<()> {
+        Err(nix::Error::Sys(Errno::EOPNOTSUPP))
+    }
+
+    fn truncate(&self, fd: RawFd, size: i64) -> nix::Result<()> {
+        unistd::ftruncate(fd, size)
+    }
+
+    fn set_mtime(&self, fd: RawFd, mtime: SystemTime) -> nix::Result<()> {
+        let (secs, nsecs) = time_to_secs(&mtime);
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: secs.cast(),
+                tv_nsec: nsecs.cast(),
+            },
+        ];
+        #[allow(unsafe_code)]
+        Errno::result(unsafe { libc::futimens(fd, times.as_ptr()) }).map(|_| ())
+    }
+
+    #[cfg(all(feature = "abi-7-19", target_os = "linux"))]
+    fn fallocate(&self, fd: RawFd, mode: FallocateMode, offset: i64, len: i64) -> nix::Result<()> {
+        let flags = match mode {
+            FallocateMode::Allocate { .. } => FallocateFlags::empty(),
+            FallocateMode::PunchHole => FallocateFlags::FALLOC_FL_PUNCH_HOLE,
+            FallocateMode::ZeroRange { .. } => FallocateFlags::FALLOC_FL_ZERO_RANGE,
+        };
+        let flags = if mode.keeps_size() {
+            flags | FallocateFlags::FALLOC_FL_KEEP_SIZE
+        } else {
+            flags
+        };
+        fcntl::fallocate(fd, flags, offset, len).map(|_| ())
+    }
+
+    #[cfg(all(feature = "abi-7-19", not(target_os = "linux")))]
+    fn fallocate(
+        &self,
+        _fd: RawFd,
+        _mode: FallocateMode,
+        _offset: i64,
+        _len: i64,
+    ) -> nix::Result<()> {
+        Err(nix::Error::Sys(Errno::EOPNOTSUPP))
+    }
+
+    fn set_flags(&self, fd: RawFd, oflags: OFlag) -> nix::Result<()> {
+        // `O_NOATIME` is one of the status flags, which would be cleared
+        self.helper_noatime(oflags, |oflags| {
+            fcntl::fcntl(fd, FcntlArg::F_SETFL(oflags)).map(|_| ())
+        })
+    }
+
+    fn dup(&self, fd: RawFd, oflags: OFlag) -> nix::Result<RawFd> {
+        // the only flag of an fd not shared with its dups
+        if self.helper_cloexec(oflags).contains(OFlag::O_CLOEXEC) {
+            fcntl::fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0))
+        } else {
+            unistd::dup(fd)
+        }
+    }
+
+    fn fsync(&self, fd: RawFd) -> nix::Result<()> {
+        unistd::fsync(fd)
+    }
+
+    fn unlink_at(&self, dir: RawFd, name: &OsStr, flags: UnlinkatFlags) -> nix::Result<()> {
+        self.helper_check_name(name)?;
+        unistd::unlinkat(Some(dir), name, flags)
+    }
+
+    fn rename_at(
+        &self,
+        old_dir: RawFd,
+        old_name: &OsStr,
+        new_dir: RawFd,
+        new_name: &OsStr,
+    ) -> nix::Result<()> {
+        self.helper_check_name(old_name)?;
+        self.helper_check_name(new_name)?;
+        fcntl::renameat(Some(old_dir), old_name, Some(new_dir), new_name)
+    }
+
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        let local_dir = self
+            .dirs
+            .lock()
+            .unwrap_or_else(|_| panic!("LocalBackend found the dirs lock poisoned"))
+            .remove(&fd);
+        match local_dir {
+            // dropping the dir closes its fd
+            Some(local_dir) => {
+                drop(local_dir);
+                Ok(())
+            }
+            None => unistd::close(fd),
+        }
+    }
+
+    fn confine(&self) -> nix::Result<()> {
+        self.confined.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_open_flags(&self, flags: OpenFlags) {
+        self.cloexec.store(flags.cloexec, Ordering::Relaxed);
+        self.noatime.store(flags.noatime, Ordering::Relaxed);
+    }
+
+    fn space(&self, fd: RawFd) -> nix::Result<BackendSpace> {
+        let st = statvfs::fstatvfs(&fd)?;
+        let fragment_size: u64 = st.fragment_size().cast();
+        Ok(BackendSpace {
+            total: fragment_size.overflow_mul(st.blocks().cast()),
+            available: fragment_size.overflow_mul(st.blocks_available().cast()),
+            files: st.files().cast(),
+            files_free: st.files_free().cast(),
+        })
+    }
+
+    fn get_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<Vec<u8>> {
+        let name = xattr_name(name)?;
+        loop {
+            // query the size first
+            #[allow(unsafe_code)]
+            let size =
+                Errno::result(unsafe { xattr::fgetxattr(fd, name.as_ptr(), ptr::null_mut(), 0) })?;
+            let mut value = vec![0_u8; size.cast()];
+            #[allow(unsafe_code)]
+            let res = Errno::result(unsafe {
+                xattr::fgetxattr(fd, name.as_ptr(), value.as_mut_ptr().cast(), value.len())
+            });
+            match res {
+                Ok(size) => {
+                    value.truncate(size.cast());
+                    return Ok(value);
+                }
+                // the value grew after the size query
+                Err(nix::Error::Sys(Errno::ERANGE)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn list_xattr(&self, fd: RawFd) -> nix::Result<Vec<OsString>> {
+        loop {
+            // query the size first
+            #[allow(unsafe_code)]
+            let size = Errno::result(unsafe { xattr::flistxattr(fd, ptr::null_mut(), 0) })?;
+            let mut list = vec![0_u8; size.cast()];
+            #[allow(unsafe_code)]
+            let res = Errno::result(unsafe {
+                xattr::flistxattr(fd, list.as_mut_ptr().cast(), list.len())
+            });
+            match res {
+                Ok(size) => {
+                    list.truncate(size.cast());
+                    // names are nul terminated
+                    return Ok(list
+                        .split(|&byte| byte == 0)
+                        .filter(|name| !name.is_empty())
+                        .map(|name| OsStr::from_bytes(name).to_os_string())
+                        .collect());
+                }
+                // the list grew after the size query
+                Err(nix::Error::Sys(Errno::ERANGE)) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn set_xattr(&self, fd: RawFd, name: &OsStr, value: &[u8], flags: c_int) -> nix::Result<()> {
+        let name = xattr_name(name)?;
+        #[allow(unsafe_code)]
+        let res = unsafe {
+            xattr::fsetxattr(fd, name.as_ptr(), value.as_ptr().cast(), value.len(), flags)
+        };
+        Errno::result(res).map(drop)
+    }
+
+    fn remove_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<()> {
+        let name = xattr_name(name)?;
+        #[allow(unsafe_code)]
+        let res = unsafe { xattr::fremovexattr(fd, name.as_ptr()) };
+        Errno::result(res).map(drop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Backend, LocalBackend};
+    use nix::errno::Errno;
+    use nix::fcntl::OFlag;
+    use nix::sys::stat::Mode;
+    use nix::unistd::UnlinkatFlags;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::Path;
+
+    #[test]
+    fn test_confine() {
+        let root = Path::new("/tmp/fuse_test_confine");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(root.join("dir")).unwrap_or_else(|_| panic!());
+        fs::write(root.join("file"), b"data").unwrap_or_else(|_| panic!());
+        symlink("/etc/passwd", root.join("escape")).unwrap_or_else(|_| panic!());
+
+        let backend = LocalBackend::new();
+        let dir = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let open =
+            |name: &str| backend.open_at(dir, OsStr::new(name), OFlag::O_RDONLY, Mode::empty());
+        // symbolic links are not followed even if not confined
+        assert_eq!(open("escape"), Err(nix::Error::Sys(Errno::ELOOP)));
+        let fd = open("dir/../file").unwrap_or_else(|_| panic!());
+        backend.close(fd).unwrap_or_else(|_| panic!());
+
+        backend.confine().unwrap_or_else(|_| panic!());
+        let fd = open("file").unwrap_or_else(|_| panic!());
+        backend.close(fd).unwrap_or_else(|_| panic!());
+        let child = backend
+            .open_dir_at(dir, OsStr::new("dir"))
+            .unwrap_or_else(|_| panic!());
+        assert!(open("escape").is_err());
+        assert_eq!(open(".."), Err(nix::Error::Sys(Errno::EPERM)));
+        assert_eq!(open("dir/../.."), Err(nix::Error::Sys(Errno::EPERM)));
+        assert_eq!(
+            backend.open_dir_at(child, OsStr::new("..")),
+            Err(nix::Error::Sys(Errno::EPERM))
+        );
+        assert_eq!(
+            backend.unlink_at(child, OsStr::new("../file"), UnlinkatFlags::NoRemoveDir),
+            Err(nix::Error::Sys(Errno::EPERM))
+        );
+        assert!(root.join("file").exists());
+
+        backend.close(child).unwrap_or_else(|_| panic!());
+        backend.close(dir).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_xattr() {
+        use super::super::mem_backend::ENOATTR;
+        use libc::{XATTR_CREATE, XATTR_REPLACE};
+
+        let root = Path::new("/tmp/fuse_test_local_xattr");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(root).unwrap_or_else(|_| panic!());
+        fs::write(root.join("file"), b"data").unwrap_or_else(|_| panic!());
+
+        let backend = LocalBackend::new();
+        let dir = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let fd = backend
+            .open_at(dir, OsStr::new("file"), OFlag::O_RDONLY, Mode::empty())
+            .unwrap_or_else(|_| panic!());
+        let name = OsStr::new("user.comment");
+        assert_eq!(backend.get_xattr(fd, name), Err(nix::Error::Sys(ENOATTR)));
+        assert_eq!(
+            backend.set_xattr(fd, name, b"old", XATTR_REPLACE),
+            Err(nix::Error::Sys(ENOATTR))
+        );
+        assert_eq!(backend.set_xattr(fd, name, b"old", XATTR_CREATE), Ok(()));
+        assert_eq!(
+            backend.set_xattr(fd, name, b"new", XATTR_CREATE),
+            Err(nix::Error::Sys(Errno::EEXIST))
+        );
+        let value = vec![0xA5_u8; 1000];
+        assert_eq!(backend.set_xattr(fd, name, &value, XATTR_REPLACE), Ok(()));
+        assert_eq!(backend.get_xattr(fd, name), Ok(value));
+        assert_eq!(backend.list_xattr(fd), Ok(vec![name.to_owned()]));
+        assert_eq!(backend.remove_xattr(fd, name), Ok(()));
+        assert_eq!(backend.list_xattr(fd), Ok(Vec::new()));
+        assert_eq!(
+            backend.remove_xattr(fd, name),
+            Err(nix::Error::Sys(ENOATTR))
+        );
+
+        backend.close(fd).unwrap_or_else(|_| panic!());
+        backend.close(dir).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_set_mtime() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let root = Path::new("/tmp/fuse_test_set_mtime");
+        fs::create_dir_all(root).unwrap_or_else(|_| panic!());
+        let backend = LocalBackend::new();
+        let dir = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let atime = backend.fstat(dir).unwrap_or_else(|_| panic!()).atime;
+        for mtime in &[
+            UNIX_EPOCH + Duration::new(1_000_000_000, 500),
+            UNIX_EPOCH - Duration::from_millis(1250),
+        ] {
+            backend.set_mtime(dir, *mtime).unwrap_or_else(|_| panic!());
+            let attr = backend.fstat(dir).unwrap_or_else(|_| panic!());
+            assert_eq!(attr.mtime, *mtime);
+            // the access time is left as is
+            assert_eq!(attr.atime, atime);
+        }
+        backend.close(dir).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn test_open_flags() {
+        use super::OpenFlags;
+        use nix::fcntl::{self, FcntlArg, FdFlag};
+
+        let root = Path::new("/tmp/fuse_test_open_flags");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(root).unwrap_or_else(|_| panic!());
+        fs::write(root.join("file"), b"data").unwrap_or_else(|_| panic!());
+        let cloexec = |fd| {
+            let flags = fcntl::fcntl(fd, FcntlArg::F_GETFD).unwrap_or_else(|_| panic!());
+            FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC)
+        };
+        let noatime = |fd| {
+            let flags = fcntl::fcntl(fd, FcntlArg::F_GETFL).unwrap_or_else(|_| panic!());
+            OFlag::from_bits_truncate(flags).contains(OFlag::O_NOATIME)
+        };
+
+        let backend = LocalBackend::new();
+        let dir = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let open = || {
+            backend
+                .open_at(dir, OsStr::new("file"), OFlag::O_RDONLY, Mode::empty())
+                .unwrap_or_else(|_| panic!())
+        };
+        // both by default, the tests run as the owner of the file
+        let fd = open();
+        assert!(cloexec(dir) && noatime(dir) && cloexec(fd) && noatime(fd));
+        let dup_fd = backend.dup(fd, OFlag::empty()).unwrap_or_else(|_| panic!());
+        assert!(cloexec(dup_fd) && noatime(dup_fd));
+        // kept by setting the status flags
+        backend
+            .set_flags(fd, OFlag::O_APPEND)
+            .unwrap_or_else(|_| panic!());
+        assert!(noatime(fd));
+        for fd in &[fd, dup_fd] {
+            backend.close(*fd).unwrap_or_else(|_| panic!());
+        }
+
+        backend.set_open_flags(OpenFlags {
+            cloexec: false,
+            noatime: false,
+        });
+        let fd = open();
+        assert!(!cloexec(fd) && !noatime(fd));
+        let dup_fd = backend.dup(fd, OFlag::empty()).unwrap_or_else(|_| panic!());
+        assert!(!cloexec(dup_fd));
+        let dup_fd2 = backend
+            .dup(fd, OFlag::O_CLOEXEC)
+            .unwrap_or_else(|_| panic!());
+        assert!(cloexec(dup_fd2));
+        for fd in &[fd, dup_fd, dup_fd2, dir] {
+            backend.close(*fd).unwrap_or_else(|_| panic!());
+        }
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+}
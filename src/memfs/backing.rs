@@ -0,0 +1,441 @@
+//! I/O on the backing files with timeouts
+//!
+//! A syscall hanging on the backing store, e.g. a network filesystem, would
+//! stall the session indefinitely. With a timeout, backing I/O runs on a pool
+//! of worker threads and the caller gives up on it once the timeout expires,
+//! leaving the worker to finish the syscall. Each job works on its own
+//! duplicate of the fd, so it never touches another file even if it runs after
+//! the caller gave up and the original fd was closed and reused.
+//!
+//! A write cannot be given up on once it started, the caller would report a
+//! failure for data that is on disk nonetheless. A write still queued when the
+//! timeout expires is cancelled, a write already running is waited for.
+
+use super::{Backend, Cast, OverflowArithmetic};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+#[cfg(feature = "abi-7-11")]
+use std::cmp;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Maximum number of buffers written in one call, `IOV_MAX` on Linux and macOS
+#[cfg(feature = "abi-7-11")]
+const MAX_WRITE_BUFS: usize = 1024;
+
+/// Job run by a worker thread
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Fd owned by a job, closed when the job is done or dropped without running
+#[derive(Debug)]
+struct JobFd {
+    /// Backend of the fd
+    backend: Arc<dyn Backend>,
+    /// Fd
+    fd: RawFd,
+}
+
+impl JobFd {
+    /// Duplicate fd for a job
+    fn dup(backend: &Arc<dyn Backend>, fd: RawFd) -> nix::Result<Self> {
+        Ok(Self {
+            backend: Arc::clone(backend),
+            fd: backend.dup(fd, OFlag::empty())?,
+        })
+    }
+}
+
+impl Drop for JobFd {
+    fn drop(&mut self) {
+        // nothing to do if closing a duplicated fd fails
+        self.backend.close(self.fd).unwrap_or(());
+    }
+}
+
+/// Backing I/O
+#[derive(Debug, Default)]
+pub struct BackingIo {
+    /// Timeout of each operation, operations run in the calling thread without timeout
+    timeout: Option<Duration>,
+    /// Queue of the jobs of the worker threads
+    jobs: Option<Sender<Job>>,
+}
+
+impl BackingIo {
+    /// New backing I/O running in the calling thread without timeout
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// New backing I/O giving up on operations after `timeout`, run by `num_workers` threads,
+    /// the worker threads exit once the backing I/O is dropped
+    pub fn with_timeout(timeout: Duration, num_workers: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..num_workers {
+            let rx = Arc::clone(&rx);
+            thread::spawn(move || loop {
+                let job = match rx.lock() {
+                    Ok(rx) => rx.recv(),
+                    Err(_) => break,
+                };
+                match job {
+                    Ok(job) => job(),
+                    Err(_) => break,
+                }
+            });
+        }
+        Self {
+            timeout: Some(timeout),
+            jobs: Some(tx),
+        }
+    }
+
+    /// Run an operation on a worker thread, fails with `ETIMEDOUT` if it does not finish in time
+    fn run<T: Send + 'static>(
+        timeout: Duration,
+        jobs: &Sender<Job>,
+        op: impl FnOnce() -> nix::Result<T> + Send + 'static,
+    ) -> nix::Result<T> {
+        let (tx, rx) = mpsc::channel();
+        jobs.send(Box::new(move || {
+            // the receiver is gone if the caller gave up
+            tx.send(op()).unwrap_or(());
+        }))
+        .map_err(|_| nix::Error::Sys(Errno::EIO))?;
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => Err(nix::Error::Sys(Errno::ETIMEDOUT)),
+            // the worker panicked
+            Err(RecvTimeoutError::Disconnected) => Err(nix::Error::Sys(Errno::EIO)),
+        }
+    }
+
+    /// Run a write on a worker thread, fails with `ETIMEDOUT` if it does not
+    /// start in time, otherwise waits for it to finish
+    fn run_write<T: Send + 'static>(
+        timeout: Duration,
+        jobs: &Sender<Job>,
+        op: impl FnOnce() -> nix::Result<T> + Send + 'static,
+    ) -> nix::Result<T> {
+        // set by whichever comes first, the worker starting the write or the
+        // caller cancelling it
+        let claimed = Arc::new(AtomicBool::new(false));
+        let job_claimed = Arc::clone(&claimed);
+        let (tx, rx) = mpsc::channel();
+        jobs.send(Box::new(move || {
+            if !job_claimed.swap(true, Ordering::AcqRel) {
+                tx.send(op()).unwrap_or(());
+            }
+        }))
+        .map_err(|_| nix::Error::Sys(Errno::EIO))?;
+        match rx.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => {
+                if claimed.swap(true, Ordering::AcqRel) {
+                    // the write is running, its result is the one to reply
+                    rx.recv().unwrap_or(Err(nix::Error::Sys(Errno::EIO)))
+                } else {
+                    Err(nix::Error::Sys(Errno::ETIMEDOUT))
+                }
+            }
+            // the worker panicked
+            Err(RecvTimeoutError::Disconnected) => Err(nix::Error::Sys(Errno::EIO)),
+        }
+    }
+
+    /// Read up to `size` byte at `offset`, fewer only at the end of the file
+    pub fn pread(
+        &self,
+        backend: &Arc<dyn Backend>,
+        fd: RawFd,
+        size: usize,
+        offset: i64,
+    ) -> nix::Result<Vec<u8>> {
+        match (self.timeout, &self.jobs) {
+            (Some(timeout), Some(jobs)) => {
+                let job_fd = JobFd::dup(backend, fd)?;
+                Self::run(timeout, jobs, move || {
+                    pread_all(&*job_fd.backend, job_fd.fd, size, offset)
+                })
+            }
+            _ => pread_all(&**backend, fd, size, offset),
+        }
+    }
+
+    /// Write data at `offset`, the data is copied if the write runs on a worker
+    /// thread. Returns the number of byte written, which may be short.
+    pub fn pwrite(
+        &self,
+        backend: &Arc<dyn Backend>,
+        fd: RawFd,
+        data: &[u8],
+        offset: i64,
+    ) -> nix::Result<usize> {
+        match (self.timeout, &self.jobs) {
+            (Some(timeout), Some(jobs)) => {
+                let job_fd = JobFd::dup(backend, fd)?;
+                let data = data.to_vec();
+                Self::run_write(timeout, jobs, move || {
+                    job_fd.backend.write_at(job_fd.fd, &data, offset)
+                })
+            }
+            _ => backend.write_at(fd, data, offset),
+        }
+    }
+
+    /// Write the buffers one after another at `offset`, e.g. the chunks of
+    /// the cached data without joining them first, in as few calls as
+    /// possible. The buffers are joined if the write runs on a worker thread.
+    #[cfg(feature = "abi-7-11")]
+    pub fn pwritev(
+        &self,
+        backend: &Arc<dyn Backend>,
+        fd: RawFd,
+        bufs: &[&[u8]],
+        offset: i64,
+    ) -> nix::Result<usize> {
+        match (self.timeout, &self.jobs) {
+            (Some(timeout), Some(jobs)) => {
+                let job_fd = JobFd::dup(backend, fd)?;
+                let data = bufs.concat();
+                Self::run_write(timeout, jobs, move || {
+                    job_fd.backend.write_at(job_fd.fd, &data, offset)
+                })
+            }
+            _ => pwritev_all(&**backend, fd, bufs, offset),
+        }
+    }
+}
+
+/// Read up to `size` byte at `offset`, retrying short reads until the end of the file
+fn pread_all(backend: &dyn Backend, fd: RawFd, size: usize, offset: i64) -> nix::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if data.try_reserve_exact(size).is_err() {
+        return Err(nix::Error::Sys(Errno::ENOMEM));
+    }
+    data.resize(size, 0_u8);
+    let mut read_size = 0;
+    while let Some(buf) = data.get_mut(read_size..) {
+        if buf.is_empty() {
+            break;
+        }
+        let n = backend.read_at(fd, buf, offset.overflow_add(read_size.cast()))?;
+        if n == 0 {
+            break;
+        }
+        read_size = read_size.overflow_add(n);
+    }
+    data.truncate(read_size);
+    Ok(data)
+}
+
+/// Write the buffers one after another at `offset`, at most `MAX_WRITE_BUFS`
+/// buffers per call, retrying short writes from where they stopped
+#[cfg(feature = "abi-7-11")]
+fn pwritev_all(
+    backend: &dyn Backend,
+    fd: RawFd,
+    bufs: &[&[u8]],
+    offset: i64,
+) -> nix::Result<usize> {
+    let mut pending: Vec<&[u8]> = bufs.iter().copied().filter(|buf| !buf.is_empty()).collect();
+    let mut first = 0_usize;
+    let mut written_size = 0_usize;
+    while let Some(batch) =
+        pending.get(first..cmp::min(first.overflow_add(MAX_WRITE_BUFS), pending.len()))
+    {
+        if batch.is_empty() {
+            break;
+        }
+        let n = backend.write_vectored_at(fd, batch, offset.overflow_add(written_size.cast()))?;
+        if n == 0 {
+            break;
+        }
+        written_size = written_size.overflow_add(n);
+        // skip the buffers written through and trim the one written in part
+        let mut remaining = n;
+        while let Some(buf) = pending.get_mut(first) {
+            if remaining < buf.len() {
+                *buf = buf.get(remaining..).unwrap_or(&[]);
+                break;
+            }
+            remaining = remaining.overflow_sub(buf.len());
+            first = first.overflow_add(1);
+        }
+    }
+    Ok(written_size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::BackingIo;
+    use crate::memfs::{Backend, LocalBackend};
+    use nix::errno::Errno;
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::stat::Mode;
+    use nix::unistd;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backing_io() {
+        let path = "/tmp/fuse_test_backing_io";
+        let fd = fcntl::open(
+            path,
+            OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
+            Mode::from_bits_truncate(0o644),
+        )
+        .unwrap_or_else(|_| panic!());
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        for io in &[
+            BackingIo::new(),
+            BackingIo::with_timeout(Duration::from_secs(5), 2),
+        ] {
+            assert_eq!(io.pwrite(&backend, fd, b"0123456789", 0), Ok(10));
+            assert_eq!(io.pread(&backend, fd, 4, 3), Ok(b"3456".to_vec()));
+            assert_eq!(io.pread(&backend, fd, 100, 8), Ok(b"89".to_vec()));
+        }
+        unistd::close(fd).unwrap_or_else(|_| panic!());
+        fs::remove_file(path).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn test_backing_io_vectored() {
+        use crate::memfs::Cast;
+
+        let path = "/tmp/fuse_test_backing_io_vectored";
+        let fd = fcntl::open(
+            path,
+            OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
+            Mode::from_bits_truncate(0o644),
+        )
+        .unwrap_or_else(|_| panic!());
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        for io in &[
+            BackingIo::new(),
+            BackingIo::with_timeout(Duration::from_secs(5), 2),
+        ] {
+            let bufs: [&[u8]; 4] = [b"01", b"", b"234", b"56789"];
+            assert_eq!(io.pwritev(&backend, fd, &bufs, 0), Ok(10));
+            assert_eq!(io.pread(&backend, fd, 100, 0), Ok(b"0123456789".to_vec()));
+        }
+        // more buffers than one call takes
+        let bytes: Vec<u8> = (0..3000_u32).map(|i| (i % 251).cast()).collect();
+        let bufs: Vec<&[u8]> = bytes.chunks(1).collect();
+        assert_eq!(BackingIo::new().pwritev(&backend, fd, &bufs, 0), Ok(3000));
+        assert_eq!(BackingIo::new().pread(&backend, fd, 4000, 0), Ok(bytes));
+        unistd::close(fd).unwrap_or_else(|_| panic!());
+        fs::remove_file(path).unwrap_or_else(|_| panic!());
+    }
+
+    /// Benchmark writing the chunks of cached data with one pwritev against
+    /// joining them for one pwrite, run with
+    /// `cargo test --release --features abi-7-19 -- --ignored --nocapture bench_vectored_write`
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    #[ignore = "benchmark"]
+    fn bench_vectored_write() {
+        use crate::memfs::DEFAULT_CHUNK_SIZE;
+        use std::time::Instant;
+
+        const NUM_CHUNKS: usize = 256;
+        const NUM_ROUNDS: usize = 50;
+        let path = "/tmp/fuse_test_bench_vectored_write";
+        let fd = fcntl::open(
+            path,
+            OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
+            Mode::from_bits_truncate(0o644),
+        )
+        .unwrap_or_else(|_| panic!());
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        let io = BackingIo::new();
+        let chunks: Vec<Vec<u8>> = (0..NUM_CHUNKS)
+            .map(|idx| vec![idx.to_le_bytes()[0]; DEFAULT_CHUNK_SIZE])
+            .collect();
+        let bufs: Vec<&[u8]> = chunks.iter().map(Vec::as_slice).collect();
+
+        let start = Instant::now();
+        for _ in 0..NUM_ROUNDS {
+            let joined = bufs.concat();
+            assert_eq!(io.pwrite(&backend, fd, &joined, 0), Ok(joined.len()));
+        }
+        let joined_elapsed = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..NUM_ROUNDS {
+            assert_eq!(
+                io.pwritev(&backend, fd, &bufs, 0),
+                Ok(NUM_CHUNKS * DEFAULT_CHUNK_SIZE)
+            );
+        }
+        let vectored_elapsed = start.elapsed();
+
+        println!(
+            "{} writes of {} chunks of {} byte: joined pwrite {:?}, pwritev {:?}",
+            NUM_ROUNDS, NUM_CHUNKS, DEFAULT_CHUNK_SIZE, joined_elapsed, vectored_elapsed,
+        );
+        unistd::close(fd).unwrap_or_else(|_| panic!());
+        fs::remove_file(path).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_backing_io_timeout() {
+        let io = BackingIo::with_timeout(Duration::from_millis(100), 1);
+        let (timeout, jobs) = (
+            io.timeout.unwrap_or_else(|| panic!()),
+            io.jobs.as_ref().unwrap_or_else(|| panic!()),
+        );
+        let res = BackingIo::run(timeout, jobs, || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(())
+        });
+        assert_eq!(res, Err(nix::Error::Sys(Errno::ETIMEDOUT)));
+        // the next operation waits for the hanging one and times out as well
+        assert_eq!(
+            BackingIo::run(timeout, jobs, || Ok(())),
+            Err(nix::Error::Sys(Errno::ETIMEDOUT))
+        );
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(BackingIo::run(timeout, jobs, || Ok(())), Ok(()));
+    }
+
+    #[test]
+    fn test_backing_io_write_timeout() {
+        let io = BackingIo::with_timeout(Duration::from_millis(100), 1);
+        let (timeout, jobs) = (
+            io.timeout.unwrap_or_else(|| panic!()),
+            io.jobs.as_ref().unwrap_or_else(|| panic!()),
+        );
+        // a running write is waited for beyond the timeout
+        let res = BackingIo::run_write(timeout, jobs, || {
+            thread::sleep(Duration::from_millis(300));
+            Ok(1)
+        });
+        assert_eq!(res, Ok(1));
+
+        // a write queued behind a hanging operation is cancelled
+        let written = Arc::new(AtomicBool::new(false));
+        let res = BackingIo::run(timeout, jobs, || {
+            thread::sleep(Duration::from_millis(500));
+            Ok(0)
+        });
+        assert_eq!(res, Err(nix::Error::Sys(Errno::ETIMEDOUT)));
+        let job_written = Arc::clone(&written);
+        let res = BackingIo::run_write(timeout, jobs, move || {
+            job_written.store(true, Ordering::SeqCst);
+            Ok(1)
+        });
+        assert_eq!(res, Err(nix::Error::Sys(Errno::ETIMEDOUT)));
+        thread::sleep(Duration::from_millis(500));
+        assert!(!written.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,1097 @@
+//! Content-addressed storage of cached file data
+//!
+//! File data is split into fixed-size chunks, each chunk is hashed with
+//! blake3 and stored once, files sharing identical content share chunks.
+
+use super::{Cast, OverflowArithmetic};
+use blake3::Hash;
+use log::debug;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
+use std::collections::TryReserveError;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+/// Default chunk size, 64KB
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+/// Maximum number of chunk slices `FileData::read_slices()` collects without allocating
+const MAX_READ_SLICES: usize = 8;
+
+/// Memory usage of cached data
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheUsage {
+    /// Byte size of the data
+    pub used: usize,
+    /// Byte size of the memory allocated for the data
+    pub allocated: usize,
+}
+
+impl CacheUsage {
+    /// Usage of a vector of `len` items out of `capacity`, each of `item_size` byte
+    fn of_vec(len: usize, capacity: usize, item_size: usize) -> Self {
+        Self {
+            used: len.overflow_mul(item_size),
+            allocated: capacity.overflow_mul(item_size),
+        }
+    }
+
+    /// Sum of the usages
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            used: self.used.overflow_add(other.used),
+            allocated: self.allocated.overflow_add(other.allocated),
+        }
+    }
+
+    /// The percentage of the allocated memory not holding data
+    pub fn fragmentation_percent(&self) -> usize {
+        if self.allocated == 0 {
+            return 0;
+        }
+        self.allocated
+            .saturating_sub(self.used)
+            .overflow_mul(100)
+            .overflow_div(self.allocated)
+    }
+}
+
+/// The fragmentation of the cache and the work of compacting it, the same
+/// layout is returned by `MEMFS_IOC_CACHE_STATS`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Byte size of the cached data
+    pub used: u64,
+    /// Byte size of the memory allocated for the cached data
+    pub allocated: u64,
+    /// The number of the compactions run
+    pub compactions: u64,
+    /// Byte size of the memory given back by the compactions
+    pub reclaimed: u64,
+    /// The number of the chunks merged into larger chunks
+    pub merged_chunks: u64,
+    /// Byte size of the data of the files not open dropped over the limit
+    pub dropped: u64,
+}
+
+impl CacheStats {
+    /// The percentage of the allocated memory not holding data
+    pub fn fragmentation_percent(&self) -> usize {
+        CacheUsage {
+            used: self.used.cast(),
+            allocated: self.allocated.cast(),
+        }
+        .fragmentation_percent()
+    }
+
+    /// Parse from the bytes returned by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        let mut fields = data
+            .chunks_exact(mem::size_of::<u64>())
+            .filter_map(|bytes| bytes.try_into().ok().map(u64::from_ne_bytes));
+        Some(Self {
+            used: fields.next()?,
+            allocated: fields.next()?,
+            compactions: fields.next()?,
+            reclaimed: fields.next()?,
+            merged_chunks: fields.next()?,
+            dropped: fields.next()?,
+        })
+    }
+
+    /// Serialize the fields in order in native endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.used,
+            self.allocated,
+            self.compactions,
+            self.reclaimed,
+            self.merged_chunks,
+            self.dropped,
+        ]
+        .iter()
+        .flat_map(|field| field.to_ne_bytes().to_vec())
+        .collect()
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cache: {} byte data in {} byte allocated, {}% fragmented\n\
+             compactions: {}, {} byte reclaimed, {} chunks merged, {} byte dropped",
+            self.used,
+            self.allocated,
+            self.fragmentation_percent(),
+            self.compactions,
+            self.reclaimed,
+            self.merged_chunks,
+            self.dropped,
+        )
+    }
+}
+
+/// Chunk
+#[derive(Debug)]
+struct Chunk {
+    /// Data
+    data: Vec<u8>,
+    /// Number of file chunk slots referencing this chunk
+    ref_count: usize,
+}
+
+/// Chunk store
+#[derive(Debug)]
+pub struct ChunkStore {
+    /// Chunk size, zero means deduplication is disabled
+    chunk_size: usize,
+    /// Chunks indexed by content hash
+    chunks: HashMap<Hash, Chunk>,
+}
+
+impl ChunkStore {
+    /// New chunk store splitting file data into chunks of `chunk_size` byte
+    pub fn new(chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "chunk size cannot be zero");
+        Self {
+            chunk_size,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// New disabled chunk store, file data is cached without deduplication
+    pub fn disabled() -> Self {
+        Self {
+            chunk_size: 0,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Is enabled
+    pub const fn is_enabled(&self) -> bool {
+        self.chunk_size > 0
+    }
+
+    /// Chunk size
+    pub const fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Set the chunk size of the data written from now on, the data already
+    /// chunked keeps its chunk size until it is emptied
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        assert!(
+            self.is_enabled() && chunk_size > 0,
+            "chunk size cannot be set to {} on a store of chunk size {}",
+            chunk_size,
+            self.chunk_size,
+        );
+        self.chunk_size = chunk_size;
+    }
+
+    /// Number of distinct chunks
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Byte size of distinct chunks
+    pub fn stored_bytes(&self) -> usize {
+        self.chunks
+            .values()
+            .fold(0, |sum, chunk| sum.overflow_add(chunk.data.len()))
+    }
+
+    /// Memory usage of the chunks and their index
+    pub fn usage(&self) -> CacheUsage {
+        let index_usage = CacheUsage::of_vec(
+            self.chunks.len(),
+            self.chunks.capacity(),
+            mem::size_of::<(Hash, Chunk)>(),
+        );
+        self.chunks.values().fold(index_usage, |usage, chunk| {
+            usage.add(CacheUsage::of_vec(
+                chunk.data.len(),
+                chunk.data.capacity(),
+                1,
+            ))
+        })
+    }
+
+    /// Compact the index once less than half of it is used, keeping room for
+    /// half as many chunks again so that inserting after it does not grow it
+    /// at once. The chunks are allocated to their size when inserted.
+    pub fn compact(&mut self) {
+        let len = self.chunks.len();
+        if self.chunks.capacity() > len.overflow_mul(2) {
+            self.chunks.shrink_to(len.overflow_add(len.overflow_div(2)));
+        }
+    }
+
+    /// Insert a chunk, or take one more reference if the content is stored
+    pub fn insert(&mut self, data: &[u8]) -> Hash {
+        debug_assert!(data.len() <= self.chunk_size);
+        let hash = blake3::hash(data);
+        let chunk = self.chunks.entry(hash).or_insert_with(|| Chunk {
+            data: data.to_vec(),
+            ref_count: 0,
+        });
+        debug_assert_eq!(chunk.data.as_slice(), data, "blake3 hash collision");
+        chunk.ref_count = chunk.ref_count.overflow_add(1);
+        hash
+    }
+
+    /// Take one more reference of a stored chunk
+    pub fn retain(&mut self, hash: &Hash) {
+        let chunk = self.chunks.get_mut(hash).unwrap_or_else(|| {
+            panic!(
+                "retain() found chunk store is inconsistent, chunk {} not found",
+                hash.to_hex()
+            )
+        });
+        chunk.ref_count = chunk.ref_count.overflow_add(1);
+    }
+
+    /// Release one reference of a chunk, the chunk is freed when unreferenced
+    pub fn release(&mut self, hash: &Hash) {
+        let chunk = self.chunks.get_mut(hash).unwrap_or_else(|| {
+            panic!(
+                "release() found chunk store is inconsistent, chunk {} not found",
+                hash.to_hex()
+            )
+        });
+        chunk.ref_count = chunk.ref_count.overflow_sub(1);
+        if chunk.ref_count == 0 {
+            self.chunks.remove(hash);
+        }
+    }
+
+    /// Number of file chunk slots referencing the chunk, zero if not stored
+    pub fn ref_count(&self, hash: &Hash) -> usize {
+        self.chunks.get(hash).map_or(0, |chunk| chunk.ref_count)
+    }
+
+    /// Get chunk data
+    pub fn get(&self, hash: &Hash) -> &[u8] {
+        self.chunks.get(hash).map_or_else(
+            || {
+                panic!(
+                    "get() found chunk store is inconsistent, chunk {} not found",
+                    hash.to_hex()
+                )
+            },
+            |chunk| chunk.data.as_slice(),
+        )
+    }
+}
+
+/// File data cached in memory
+#[derive(Debug)]
+pub enum FileData {
+    /// Contiguous data, not deduplicated, shared by snapshots until written
+    Flat(Arc<Vec<u8>>),
+    /// Chunks in the chunk store
+    Chunked {
+        /// Chunk hashes in file order
+        chunks: Vec<Hash>,
+        /// Data length
+        len: usize,
+        /// Chunk size the data is split into, the chunk size of the store when
+        /// the data was first written
+        chunk_size: usize,
+    },
+}
+
+impl FileData {
+    /// New empty data
+    pub fn new() -> Self {
+        Self::Flat(Arc::default())
+    }
+
+    /// Share the data, chunked data retains its chunks, the shared data is
+    /// copied once either side writes to it
+    pub fn share(&self, store: &mut ChunkStore) -> Self {
+        match self {
+            Self::Flat(data) => Self::Flat(Arc::clone(data)),
+            Self::Chunked {
+                chunks,
+                len,
+                chunk_size,
+            } => {
+                for hash in chunks {
+                    store.retain(hash);
+                }
+                Self::Chunked {
+                    chunks: chunks.clone(),
+                    len: *len,
+                    chunk_size: *chunk_size,
+                }
+            }
+        }
+    }
+
+    /// Length
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Flat(data) => data.len(),
+            Self::Chunked { len, .. } => *len,
+        }
+    }
+
+    /// Is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Memory usage out of the chunk store, the contiguous data or the chunk
+    /// hashes
+    pub fn usage(&self) -> CacheUsage {
+        match self {
+            Self::Flat(data) => CacheUsage::of_vec(data.len(), data.capacity(), 1),
+            Self::Chunked { chunks, .. } => {
+                CacheUsage::of_vec(chunks.len(), chunks.capacity(), mem::size_of::<Hash>())
+            }
+        }
+    }
+
+    /// Give back the memory reserved beyond the data, contiguous data shared
+    /// with snapshots is left alone
+    pub fn compact(&mut self) {
+        match self {
+            Self::Flat(data) => {
+                if let Some(data) = Arc::get_mut(data) {
+                    data.shrink_to_fit();
+                }
+            }
+            Self::Chunked { chunks, .. } => chunks.shrink_to_fit(),
+        }
+    }
+
+    /// Whether the data is split into chunks smaller than the chunk size of
+    /// the store, e.g. after the chunk size adapted to larger requests
+    pub fn has_small_chunks(&self, store: &ChunkStore) -> bool {
+        matches!(*self, Self::Chunked { chunk_size, .. } if chunk_size < store.chunk_size())
+    }
+
+    /// Merge the chunks smaller than the chunk size of the store into chunks
+    /// of its chunk size, returns the number of the chunks merged away. The
+    /// chunks shared with other data are left alone, merging would copy them.
+    pub fn merge_chunks(&mut self, store: &mut ChunkStore) -> usize {
+        if !self.has_small_chunks(store) || !self.is_exclusive(store) {
+            return 0;
+        }
+        let data = self.read(store, 0, self.len()).into_owned();
+        let old_count = match *self {
+            Self::Chunked { ref chunks, .. } => chunks.len(),
+            Self::Flat(_) => 0,
+        };
+        self.release(store);
+        self.load(store, data);
+        let new_count = match *self {
+            Self::Chunked { ref chunks, .. } => chunks.len(),
+            Self::Flat(_) => 0,
+        };
+        old_count.saturating_sub(new_count)
+    }
+
+    /// Whether the memory of the data is only referenced by this data, so that
+    /// it is freed with it
+    pub fn is_exclusive(&self, store: &ChunkStore) -> bool {
+        match self {
+            Self::Flat(data) => Arc::strong_count(data) == 1,
+            Self::Chunked { chunks, .. } => chunks.iter().all(|hash| store.ref_count(hash) == 1),
+        }
+    }
+
+    /// Reserve the memory to write up to `end` in place, copying the data
+    /// shared with snapshots, fails instead of aborting if the memory cannot be
+    /// allocated. Chunked data only reserves the chunk slots, the chunks are
+    /// written one at a time.
+    pub fn try_reserve(&mut self, store: &ChunkStore, end: usize) -> Result<(), TryReserveError> {
+        self.helper_prepare_layout(store);
+        match self {
+            Self::Flat(shared_data) => {
+                let capacity = cmp::max(shared_data.len(), end);
+                if let Some(file_data) = Arc::get_mut(shared_data) {
+                    file_data.try_reserve(capacity.saturating_sub(file_data.len()))?;
+                } else {
+                    let mut file_data = Vec::new();
+                    file_data.try_reserve_exact(capacity)?;
+                    file_data.extend_from_slice(shared_data);
+                    *shared_data = Arc::new(file_data);
+                }
+            }
+            Self::Chunked {
+                chunks, chunk_size, ..
+            } => {
+                let chunk_count = end.overflow_div(*chunk_size).overflow_add(1);
+                chunks.try_reserve(chunk_count.saturating_sub(chunks.len()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Switch empty data to chunked layout of the current chunk size if the
+    /// chunk store is enabled
+    fn helper_prepare_layout(&mut self, store: &ChunkStore) {
+        let current =
+            matches!(*self, Self::Chunked { chunk_size, .. } if chunk_size == store.chunk_size());
+        if store.is_enabled() && self.is_empty() && !current {
+            *self = Self::Chunked {
+                chunks: Vec::new(),
+                len: 0,
+                chunk_size: store.chunk_size(),
+            };
+        }
+    }
+
+    /// Load data read from disk into empty file data
+    pub fn load(&mut self, store: &mut ChunkStore, data: Vec<u8>) {
+        debug_assert!(self.is_empty());
+        self.helper_prepare_layout(store);
+        match self {
+            Self::Flat(flat) => *flat = Arc::new(data),
+            Self::Chunked {
+                chunks,
+                len,
+                chunk_size,
+            } => {
+                *chunks = data
+                    .chunks(*chunk_size)
+                    .map(|chunk| store.insert(chunk))
+                    .collect();
+                *len = data.len();
+                debug!(
+                    "load() split {} byte data into {} chunks, chunk store has {} chunks of {} byte",
+                    len,
+                    chunks.len(),
+                    store.chunk_count(),
+                    store.stored_bytes(),
+                );
+            }
+        }
+    }
+
+    /// Read at most `size` byte from `offset`
+    pub fn read<'a>(&'a self, store: &'a ChunkStore, offset: usize, size: usize) -> Cow<'a, [u8]> {
+        let mut read_data = Cow::Borrowed(&[][..]);
+        self.helper_visit_slices(store, offset, size, |bytes| {
+            if read_data.is_empty() {
+                read_data = Cow::Borrowed(bytes);
+            } else {
+                read_data.to_mut().extend_from_slice(bytes);
+            }
+        });
+        read_data
+    }
+
+    /// The slices of the cached data of at most `size` byte from `offset`, one
+    /// per chunk, e.g. to write them to disk without joining them
+    #[cfg(feature = "abi-7-11")]
+    pub fn slices<'a>(
+        &'a self,
+        store: &'a ChunkStore,
+        offset: usize,
+        size: usize,
+    ) -> Vec<&'a [u8]> {
+        let mut slices = Vec::new();
+        self.helper_visit_slices(store, offset, size, |bytes| slices.push(bytes));
+        slices
+    }
+
+    /// Read at most `size` byte from `offset` as slices of the cached data and
+    /// pass them to `func`, the slices are collected without allocating unless
+    /// the data spans more than `MAX_READ_SLICES` chunks
+    pub fn read_slices<R>(
+        &self,
+        store: &ChunkStore,
+        offset: usize,
+        size: usize,
+        func: impl FnOnce(&[&[u8]]) -> R,
+    ) -> R {
+        let mut slices: [&[u8]; MAX_READ_SLICES] = [&[]; MAX_READ_SLICES];
+        let mut slice_count = 0_usize;
+        self.helper_visit_slices(store, offset, size, |bytes| {
+            if let Some(slice) = slices.get_mut(slice_count) {
+                *slice = bytes;
+            }
+            slice_count = slice_count.overflow_add(1);
+        });
+        match slices.get(..slice_count) {
+            Some(collected) => func(collected),
+            None => func(&[&self.read(store, offset, size)]),
+        }
+    }
+
+    /// Helper visit the slices of the cached data of at most `size` byte from `offset`
+    fn helper_visit_slices<'a>(
+        &'a self,
+        store: &'a ChunkStore,
+        offset: usize,
+        size: usize,
+        mut func: impl FnMut(&'a [u8]),
+    ) {
+        // saturate, reading up to the end with a size of usize::MAX is fine
+        let end = cmp::min(offset.saturating_add(size), self.len());
+        if offset >= end {
+            return;
+        }
+        match self {
+            Self::Flat(data) => func(data.get(offset..end).unwrap_or_else(|| {
+                panic!(
+                    "Indexing is out of bounds, offset={}, end={}, content length={}",
+                    offset,
+                    end,
+                    data.len()
+                )
+            })),
+            Self::Chunked {
+                chunks, chunk_size, ..
+            } => {
+                let chunk_size = *chunk_size;
+                let first = offset.overflow_div(chunk_size);
+                let last = end.overflow_sub(1).overflow_div(chunk_size);
+                for (idx, hash) in chunks
+                    .iter()
+                    .enumerate()
+                    .take(last.overflow_add(1))
+                    .skip(first)
+                {
+                    let chunk_start = idx.overflow_mul(chunk_size);
+                    let chunk = store.get(hash);
+                    let from = cmp::max(offset, chunk_start).overflow_sub(chunk_start);
+                    let to = cmp::min(end, chunk_start.overflow_add(chunk.len()))
+                        .overflow_sub(chunk_start);
+                    func(chunk.get(from..to).unwrap_or_else(|| {
+                        panic!(
+                            "Indexing is out of bounds, from={}, to={}, chunk length={}",
+                            from,
+                            to,
+                            chunk.len()
+                        )
+                    }));
+                }
+            }
+        }
+    }
+
+    /// Write data at `offset`, overwriting the data in place and keeping the data
+    /// after it, the gap beyond the end is filled with zero
+    pub fn write(&mut self, store: &mut ChunkStore, offset: usize, data: &[u8]) {
+        self.helper_prepare_layout(store);
+        match self {
+            Self::Flat(shared_data) => {
+                let file_data = Arc::make_mut(shared_data);
+                let size_after_write = offset.overflow_add(data.len());
+                if file_data.capacity() < size_after_write {
+                    let before_cap = file_data.capacity();
+                    let extra_space_size = size_after_write.overflow_sub(file_data.capacity());
+                    // aborts on OOM, call `try_reserve()` before to fail instead
+                    file_data.reserve(extra_space_size);
+                    debug!(
+                        "write() enlarged the file data vector capacity from {} to {}",
+                        before_cap,
+                        file_data.capacity(),
+                    );
+                }
+                if file_data.len() < size_after_write {
+                    file_data.resize(size_after_write, 0);
+                }
+                file_data
+                    .get_mut(offset..size_after_write)
+                    .unwrap_or_else(|| panic!("write() data index is out of bounds"))
+                    .copy_from_slice(data);
+            }
+            Self::Chunked {
+                chunks,
+                len,
+                chunk_size,
+            } => {
+                if data.is_empty() {
+                    return;
+                }
+                let chunk_size = *chunk_size;
+                let end = offset.overflow_add(data.len());
+                let new_len = cmp::max(*len, end);
+                // when growing, the last partial chunk and the gap are rewritten as well
+                let first = if end > *len {
+                    cmp::min(offset, *len).overflow_div(chunk_size)
+                } else {
+                    offset.overflow_div(chunk_size)
+                };
+                let last = end.overflow_sub(1).overflow_div(chunk_size);
+                for idx in first..=last {
+                    let chunk_start = idx.overflow_mul(chunk_size);
+                    let chunk_end = cmp::min(chunk_start.overflow_add(chunk_size), new_len);
+                    let mut buf = chunks
+                        .get(idx)
+                        .map_or_else(Vec::new, |hash| store.get(hash).to_vec());
+                    buf.resize(chunk_end.overflow_sub(chunk_start), 0);
+                    let from = cmp::max(offset, chunk_start);
+                    let to = cmp::min(end, chunk_end);
+                    if from < to {
+                        let dst = buf
+                            .get_mut(from.overflow_sub(chunk_start)..to.overflow_sub(chunk_start))
+                            .unwrap_or_else(|| panic!("write() chunk index is out of bounds"));
+                        let src = data
+                            .get(from.overflow_sub(offset)..to.overflow_sub(offset))
+                            .unwrap_or_else(|| panic!("write() data index is out of bounds"));
+                        dst.copy_from_slice(src);
+                    }
+                    let new_hash = store.insert(&buf);
+                    match chunks.get_mut(idx) {
+                        Some(hash) => {
+                            let old_hash = mem::replace(hash, new_hash);
+                            store.release(&old_hash);
+                        }
+                        None => chunks.push(new_hash),
+                    }
+                }
+                *len = new_len;
+                debug!(
+                    "write() updated chunks {}..={}, chunk store has {} chunks of {} byte",
+                    first,
+                    last,
+                    store.chunk_count(),
+                    store.stored_bytes(),
+                );
+            }
+        }
+    }
+
+    /// Write `len` zero bytes at `offset`, a chunk at a time, so the whole
+    /// zero chunks are deduplicated
+    #[cfg(feature = "abi-7-19")]
+    pub fn write_zeros(&mut self, store: &mut ChunkStore, offset: usize, len: usize) {
+        self.helper_prepare_layout(store);
+        let piece = match *self {
+            Self::Flat(_) => DEFAULT_CHUNK_SIZE,
+            Self::Chunked { chunk_size, .. } => chunk_size,
+        };
+        let zeros = vec![0; cmp::min(piece, len)];
+        let end = offset.overflow_add(len);
+        let mut from = offset;
+        while from < end {
+            // up to the next chunk boundary
+            let to = cmp::min(
+                end,
+                from.overflow_div(piece).overflow_add(1).overflow_mul(piece),
+            );
+            self.write(
+                store,
+                from,
+                zeros
+                    .get(..to.overflow_sub(from))
+                    .unwrap_or_else(|| panic!("write_zeros() index is out of bounds")),
+            );
+            from = to;
+        }
+    }
+
+    /// Clone `length` byte of `src` from `src_offset` to `dest_offset`,
+    /// returns the cloned size. Chunk aligned ranges share the chunks of `src`
+    /// until either file modifies them, the rest is copied.
+    #[cfg(feature = "abi-7-11")]
+    pub fn clone_range(
+        &mut self,
+        store: &mut ChunkStore,
+        src: &Self,
+        src_offset: usize,
+        length: usize,
+        dest_offset: usize,
+    ) -> usize {
+        let length = cmp::min(length, src.len().saturating_sub(src_offset));
+        if length == 0 {
+            return 0;
+        }
+        if dest_offset > self.len() {
+            let zero_padding_vec = vec![0_u8; dest_offset.overflow_sub(self.len())];
+            self.write(store, self.len(), &zero_padding_vec);
+        }
+        self.helper_prepare_layout(store);
+
+        let mut shared_size = 0;
+        if let (
+            Self::Chunked {
+                chunks,
+                len,
+                chunk_size,
+            },
+            Self::Chunked {
+                chunks: src_chunks,
+                chunk_size: src_chunk_size,
+                ..
+            },
+        ) = (&mut *self, src)
+        {
+            let chunk_size = *chunk_size;
+            // the chunks are only shared between data of the same chunk size
+            if chunk_size == *src_chunk_size
+                && src_offset.checked_rem(chunk_size) == Some(0)
+                && dest_offset.checked_rem(chunk_size) == Some(0)
+            {
+                let src_first = src_offset.overflow_div(chunk_size);
+                let dest_first = dest_offset.overflow_div(chunk_size);
+                let full_chunks = length.overflow_div(chunk_size);
+                for (idx, src_hash) in src_chunks
+                    .iter()
+                    .skip(src_first)
+                    .take(full_chunks)
+                    .enumerate()
+                {
+                    let dest_idx = dest_first.overflow_add(idx);
+                    store.retain(src_hash);
+                    match chunks.get_mut(dest_idx) {
+                        Some(hash) => {
+                            let old_hash = mem::replace(hash, *src_hash);
+                            store.release(&old_hash);
+                        }
+                        None => chunks.push(*src_hash),
+                    }
+                }
+                shared_size = full_chunks.overflow_mul(chunk_size);
+                *len = cmp::max(*len, dest_offset.overflow_add(shared_size));
+            }
+        }
+
+        if shared_size < length {
+            let rest = src
+                .read(
+                    store,
+                    src_offset.overflow_add(shared_size),
+                    length.overflow_sub(shared_size),
+                )
+                .into_owned();
+            self.write(store, dest_offset.overflow_add(shared_size), &rest);
+        }
+        debug!(
+            "clone_range() cloned {} byte of which {} byte share chunks, chunk store has {} chunks of {} byte",
+            length,
+            shared_size,
+            store.chunk_count(),
+            store.stored_bytes(),
+        );
+        length
+    }
+
+    /// Release the chunks referenced by this data
+    pub fn release(&mut self, store: &mut ChunkStore) {
+        match self {
+            Self::Flat(data) => *data = Arc::default(),
+            Self::Chunked { chunks, len, .. } => {
+                for hash in chunks.iter() {
+                    store.release(hash);
+                }
+                chunks.clear();
+                *len = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CacheUsage, ChunkStore, FileData};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_dedup_identical_files() {
+        let mut store = ChunkStore::new(4);
+        let content = b"0123456789ABCDEF0123".to_vec();
+        let mut file1 = FileData::new();
+        let mut file2 = FileData::new();
+        file1.load(&mut store, content.clone());
+        file2.load(&mut store, content.clone());
+        assert_eq!(store.chunk_count(), 4); // "0123" is shared inside the file too
+        assert_eq!(store.stored_bytes(), 16);
+        assert_eq!(file1.read(&store, 0, 100).as_ref(), content.as_slice());
+        assert_eq!(file2.read(&store, 6, 5).as_ref(), b"6789A");
+
+        file1.release(&mut store);
+        assert_eq!(store.chunk_count(), 4);
+        file2.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+    }
+
+    #[test]
+    fn test_chunked_write() {
+        let mut store = ChunkStore::new(4);
+        let mut file = FileData::new();
+        file.write(&mut store, 0, b"abcdef");
+        file.write(&mut store, 2, b"XY");
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"abXYef");
+        file.write(&mut store, 9, b"Z");
+        assert_eq!(file.len(), 10);
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"abXYef\0\0\0Z");
+        assert_eq!(store.chunk_count(), 3);
+        file.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    #[test]
+    fn test_write_zeros() {
+        let mut store = ChunkStore::new(4);
+        let mut file = FileData::new();
+        file.write(&mut store, 0, b"abcdefghijklmn");
+        file.write_zeros(&mut store, 2, 10);
+        assert_eq!(
+            file.read(&store, 0, 100).as_ref(),
+            b"ab\0\0\0\0\0\0\0\0\0\0mn"
+        );
+        // "ab\0\0", two zero chunks and "\0\0mn"
+        assert_eq!(store.chunk_count(), 3);
+        file.write_zeros(&mut store, 14, 2);
+        assert_eq!(file.len(), 16);
+        file.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+
+        let mut store = ChunkStore::disabled();
+        let mut file = FileData::new();
+        file.write(&mut store, 0, b"abc");
+        file.write_zeros(&mut store, 1, 1);
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"a\0c");
+    }
+
+    #[test]
+    fn test_set_chunk_size() {
+        let mut store = ChunkStore::new(4);
+        let mut file1 = FileData::new();
+        file1.write(&mut store, 0, b"abcdefgh");
+        assert_eq!(store.chunk_count(), 2);
+        store.set_chunk_size(8);
+        // the chunked data keeps its chunk size, the new data takes the new one
+        file1.write(&mut store, 6, b"XYZ");
+        assert_eq!(file1.read(&store, 0, 100).as_ref(), b"abcdefXYZ");
+        let mut file2 = FileData::new();
+        file2.write(&mut store, 0, b"abcdefgh");
+        assert_eq!(file2.read(&store, 2, 100).as_ref(), b"cdefgh");
+        assert_eq!(store.chunk_count(), 4);
+        // the chunks of another size are copied instead of shared
+        #[cfg(feature = "abi-7-11")]
+        assert_eq!(file2.clone_range(&mut store, &file1, 0, 8, 0), 8);
+        file2.write(&mut store, 6, b"XY");
+        assert_eq!(file2.read(&store, 0, 100).as_ref(), b"abcdefXY");
+        // emptied data takes the new chunk size
+        file1.release(&mut store);
+        file1.write(&mut store, 0, b"abcdefXY");
+        assert_eq!(store.chunk_count(), 1);
+        file1.release(&mut store);
+        file2.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+    }
+
+    #[test]
+    fn test_flat_write() {
+        let mut store = ChunkStore::disabled();
+        let mut file = FileData::new();
+        file.write(&mut store, 0, b"0123456789");
+        // overwrite in the middle keeps the tail
+        file.write(&mut store, 3, b"ab");
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"012ab56789");
+        // overwrite across the end extends the data
+        file.write(&mut store, 8, b"XYZ");
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"012ab567XYZ");
+        file.write(&mut store, 13, b"!");
+        assert_eq!(file.len(), 14);
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"012ab567XYZ\0\0!");
+        file.write(&mut store, 0, b"");
+        assert_eq!(file.len(), 14);
+    }
+
+    #[test]
+    fn test_read_slices() {
+        let mut store = ChunkStore::new(4);
+        let mut file = FileData::new();
+        file.load(&mut store, b"0123456789ABCDEF".to_vec());
+        file.read_slices(&store, 2, 7, |slices| {
+            assert_eq!(slices, [&b"23"[..], &b"4567"[..], &b"8"[..]]);
+        });
+        file.release(&mut store);
+
+        // more chunks than the slices collected without allocating
+        let content = b"0123456789".repeat(4);
+        file.load(&mut store, content.clone());
+        file.read_slices(&store, 0, 100, |slices| {
+            assert_eq!(slices, [content.as_slice()]);
+        });
+
+        let flat = FileData::Flat(Arc::new(b"0123456789".to_vec()));
+        let disabled = ChunkStore::disabled();
+        flat.read_slices(&disabled, 8, 100, |slices| {
+            assert_eq!(slices, [&b"89"[..]]);
+        });
+        // reading to the end does not overflow
+        assert_eq!(&*flat.read(&disabled, 8, usize::MAX), b"89");
+        file.release(&mut store);
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut store = ChunkStore::disabled();
+        let mut flat = FileData::new();
+        flat.write(&mut store, 0, b"abc");
+        assert!(flat.is_exclusive(&store));
+        assert!(flat.try_reserve(&store, usize::MAX).is_err());
+        assert_eq!(flat.read(&store, 0, 10).as_ref(), b"abc");
+
+        // the data shared with a snapshot is copied once reserved
+        let shared = flat.share(&mut store);
+        assert!(!flat.is_exclusive(&store));
+        assert!(flat.try_reserve(&store, 100).is_ok());
+        assert!(flat.is_exclusive(&store));
+        assert!(flat.usage().allocated >= 100);
+        flat.write(&mut store, 0, b"X");
+        assert_eq!(flat.read(&store, 0, 10).as_ref(), b"Xbc");
+        assert_eq!(shared.read(&store, 0, 10).as_ref(), b"abc");
+
+        let mut store = ChunkStore::new(2);
+        let mut chunked = FileData::new();
+        chunked.write(&mut store, 0, b"abcd");
+        let mut shared = chunked.share(&mut store);
+        assert!(!chunked.is_exclusive(&store));
+        shared.release(&mut store);
+        assert!(chunked.is_exclusive(&store));
+        assert!(chunked.try_reserve(&store, usize::MAX).is_err());
+        assert!(chunked.try_reserve(&store, 100).is_ok());
+        chunked.release(&mut store);
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut flat = FileData::new();
+        let mut disabled = ChunkStore::disabled();
+        flat.write(&mut disabled, 0, b"a");
+        assert_eq!(flat.usage().used, 1);
+        assert!(flat.usage().allocated >= 1);
+        flat.compact();
+        assert_eq!(
+            flat.usage(),
+            CacheUsage {
+                used: 1,
+                allocated: 1
+            }
+        );
+        assert_eq!(flat.usage().fragmentation_percent(), 0);
+        // shared data is not reallocated under the snapshot
+        flat.write(&mut disabled, 1, b"b");
+        let shared = flat.share(&mut disabled);
+        let before = flat.usage();
+        flat.compact();
+        assert_eq!(flat.usage(), before);
+        assert_eq!(shared.read(&disabled, 0, 10).as_ref(), b"ab");
+
+        let mut store = ChunkStore::new(4);
+        let mut files: Vec<FileData> = (0_u8..64)
+            .map(|idx| {
+                let mut file = FileData::new();
+                file.load(&mut store, vec![idx; 8]);
+                file
+            })
+            .collect();
+        let full_usage = store.usage();
+        for file in files.iter_mut().skip(1) {
+            file.release(&mut store);
+        }
+        let sparse_usage = store.usage();
+        assert!(sparse_usage.fragmentation_percent() > 50);
+        store.compact();
+        assert_eq!(store.usage().used, sparse_usage.used);
+        assert!(store.usage().allocated < full_usage.allocated);
+        assert!(store.usage().fragmentation_percent() < sparse_usage.fragmentation_percent());
+        assert_eq!(
+            files
+                .get(0)
+                .map(|file| file.read(&store, 0, 100).into_owned()),
+            Some(vec![0; 8])
+        );
+
+        assert_eq!(CacheUsage::default().fragmentation_percent(), 0);
+        assert_eq!(
+            CacheUsage {
+                used: 1,
+                allocated: 4
+            }
+            .add(CacheUsage {
+                used: 3,
+                allocated: 4
+            })
+            .fragmentation_percent(),
+            50
+        );
+    }
+
+    #[test]
+    fn test_merge_chunks() {
+        let mut store = ChunkStore::new(2);
+        let mut file = FileData::new();
+        file.load(&mut store, b"aabbccdde".to_vec());
+        assert_eq!(file.merge_chunks(&mut store), 0);
+        store.set_chunk_size(4);
+        assert!(file.has_small_chunks(&store));
+        // the chunks shared with a snapshot are not copied
+        let mut shared = file.share(&mut store);
+        assert_eq!(file.merge_chunks(&mut store), 0);
+        shared.release(&mut store);
+        assert_eq!(file.merge_chunks(&mut store), 2);
+        assert!(!file.has_small_chunks(&store));
+        assert_eq!(store.chunk_count(), 3);
+        assert_eq!(file.read(&store, 0, 100).as_ref(), b"aabbccdde");
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        use super::CacheStats;
+
+        let stats = CacheStats {
+            used: 300,
+            allocated: 400,
+            compactions: 2,
+            reclaimed: 100,
+            merged_chunks: 5,
+            dropped: 0,
+        };
+        assert_eq!(stats.fragmentation_percent(), 25);
+        assert_eq!(CacheStats::from_bytes(&stats.to_bytes()), Some(stats));
+        assert_eq!(CacheStats::from_bytes(&[0; 8]), None);
+        assert_eq!(
+            stats.to_string(),
+            "cache: 300 byte data in 400 byte allocated, 25% fragmented\n\
+             compactions: 2, 100 byte reclaimed, 5 chunks merged, 0 byte dropped"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "abi-7-11")]
+    fn test_clone_range_share_chunks() {
+        let mut store = ChunkStore::new(4);
+        let mut src = FileData::new();
+        let mut dest = FileData::new();
+        src.load(&mut store, b"aaaabbbbccccdd".to_vec());
+        assert_eq!(store.chunk_count(), 4);
+
+        assert_eq!(dest.clone_range(&mut store, &src, 4, 0, 0), 0);
+        assert_eq!(dest.clone_range(&mut store, &src, 4, 100, 0), 10);
+        assert_eq!(dest.read(&store, 0, 100).as_ref(), b"bbbbccccdd");
+        assert_eq!(store.chunk_count(), 4); // all chunks are shared
+
+        dest.write(&mut store, 0, b"B");
+        assert_eq!(dest.read(&store, 0, 100).as_ref(), b"Bbbbccccdd");
+        assert_eq!(src.read(&store, 0, 100).as_ref(), b"aaaabbbbccccdd");
+        assert_eq!(store.chunk_count(), 5);
+
+        // unaligned clone falls back to copy
+        assert_eq!(dest.clone_range(&mut store, &src, 1, 2, 12), 2);
+        assert_eq!(dest.read(&store, 0, 100).as_ref(), b"Bbbbccccdd\0\0aa");
+
+        src.release(&mut store);
+        dest.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+    }
+}
@@ -0,0 +1,250 @@
+//! Index of the cached entries of a directory
+//!
+//! Entries are looked up by name through a hash set, and iterated in name
+//! order through a separate ordered set for readdir. Both sets hold the same
+//! entries keyed by their own names, and the entries are shared by `Arc`, so
+//! the name of an entry is stored once and handing it out does not copy it.
+
+use nix::dir::Type;
+use rustc_hash::FxHashSet;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, TryReserveError};
+use std::ffi::{OsStr, OsString};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+/// Dir Entry
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    /// Inode
+    pub ino: u64,
+    /// Name
+    pub name: OsString,
+    /// Entry type
+    pub entry_type: Type,
+}
+
+/// An entry keyed by its name, compared, hashed and borrowed as its name
+#[derive(Clone, Debug)]
+struct ByName(Arc<DirEntry>);
+
+impl Borrow<OsStr> for ByName {
+    fn borrow(&self) -> &OsStr {
+        &self.0.name
+    }
+}
+
+impl PartialEq for ByName {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.name == other.0.name
+    }
+}
+
+impl Eq for ByName {}
+
+impl Hash for ByName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // the same as the hash of the borrowed `OsStr`
+        self.0.name.as_os_str().hash(state);
+    }
+}
+
+impl PartialOrd for ByName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ByName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.name.cmp(&other.0.name)
+    }
+}
+
+/// Dir data, the entries of a directory indexed by name
+#[derive(Debug, Default)]
+pub struct DirData {
+    /// Entries indexed by name
+    entries: FxHashSet<ByName>,
+    /// The same entries in name order
+    ordered: BTreeSet<ByName>,
+}
+
+impl DirData {
+    /// New empty dir data
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there is no entry
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Whether there is an entry of name
+    pub fn contains_key(&self, name: &OsStr) -> bool {
+        self.entries.contains(name)
+    }
+
+    /// Get the entry of name
+    pub fn get(&self, name: &OsStr) -> Option<&Arc<DirEntry>> {
+        self.entries.get(name).map(|entry| &entry.0)
+    }
+
+    /// Insert an entry, returns the previous entry of the same name
+    pub fn insert(&mut self, entry: Arc<DirEntry>) -> Option<Arc<DirEntry>> {
+        let _previous = self.ordered.replace(ByName(Arc::clone(&entry)));
+        self.entries
+            .replace(ByName(entry))
+            .map(|previous_entry| previous_entry.0)
+    }
+
+    /// Reserve the memory of `additional` more entries, fails instead of
+    /// aborting if the memory cannot be allocated
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.entries.try_reserve(additional)
+    }
+
+    /// Remove the entry of name
+    pub fn remove(&mut self, name: &OsStr) -> Option<Arc<DirEntry>> {
+        let entry = self.entries.take(name)?;
+        self.ordered.remove(name);
+        Some(entry.0)
+    }
+
+    /// Entries in name order
+    pub fn values(&self) -> impl Iterator<Item = &Arc<DirEntry>> {
+        self.ordered.iter().map(|entry| &entry.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DirData, DirEntry};
+    use crate::fuse::OverflowArithmetic;
+    use nix::dir::Type;
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    /// Build an entry of name
+    fn build_entry(ino: u64, name: &str) -> Arc<DirEntry> {
+        Arc::new(DirEntry {
+            ino,
+            name: OsString::from(name),
+            entry_type: Type::File,
+        })
+    }
+
+    #[test]
+    fn test_dir_data_order() {
+        let mut data = DirData::new();
+        for (ino, name) in &[(2, "b"), (3, "c"), (1, "a")] {
+            assert!(data.insert(build_entry(*ino, name)).is_none());
+        }
+        let previous_entry = data.insert(build_entry(4, "b"));
+        assert_eq!(previous_entry.map(|e| e.ino), Some(2));
+        assert_eq!(data.len(), 3);
+        assert_eq!(data.get(&OsString::from("b")).map(|e| e.ino), Some(4));
+
+        let inos: Vec<u64> = data.values().map(|e| e.ino).collect();
+        assert_eq!(inos, vec![1, 4, 3]);
+        // the name is held by the entry alone, indexed twice
+        let entry = data.get(&OsString::from("b")).unwrap_or_else(|| panic!());
+        assert_eq!(Arc::strong_count(entry), 2);
+
+        assert!(data.remove(&OsString::from("a")).is_some());
+        assert!(data.remove(&OsString::from("a")).is_none());
+        assert!(!data.contains_key(&OsString::from("a")));
+        let inos: Vec<u64> = data.values().map(|e| e.ino).collect();
+        assert_eq!(inos, vec![4, 3]);
+
+        assert!(data.try_reserve(1).is_ok());
+        assert!(data.try_reserve(usize::MAX).is_err());
+    }
+
+    /// Benchmark name lookups in a directory of 1M entries against `BTreeMap`,
+    /// run with `cargo test --release -- --ignored --nocapture bench_dir_data`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_dir_data_lookup() {
+        const NUM_ENTRIES: u64 = 1_000_000;
+        let names: Vec<OsString> = (0..NUM_ENTRIES)
+            .map(|i| OsString::from(format!("file_name_{:016}", i.overflow_mul(7919))))
+            .collect();
+        let mut data = DirData::new();
+        let mut tree = BTreeMap::new();
+        for (ino, name) in (0..NUM_ENTRIES).zip(names.iter()) {
+            let entry = DirEntry {
+                ino,
+                name: name.clone(),
+                entry_type: Type::File,
+            };
+            tree.insert(name.clone(), entry.clone());
+            data.insert(Arc::new(entry));
+        }
+
+        let start = Instant::now();
+        let found = names.iter().filter(|name| tree.contains_key(*name)).count();
+        let tree_elapsed = start.elapsed();
+        assert_eq!(found, names.len());
+
+        let start = Instant::now();
+        let found = names.iter().filter(|name| data.contains_key(name)).count();
+        let data_elapsed = start.elapsed();
+        assert_eq!(found, names.len());
+
+        println!(
+            "lookup of {} entries: BTreeMap {:?}, DirData {:?}",
+            NUM_ENTRIES, tree_elapsed, data_elapsed,
+        );
+    }
+
+    /// Benchmark handing out the entries of a directory of 1M entries by
+    /// copying them against sharing them, run with
+    /// `cargo test --release -- --ignored --nocapture bench_dir_entry`
+    #[test]
+    #[ignore = "benchmark"]
+    fn bench_dir_entry_get() {
+        const NUM_ENTRIES: u64 = 1_000_000;
+        let names: Vec<OsString> = (0..NUM_ENTRIES)
+            .map(|i| OsString::from(format!("file_name_{:016}", i.overflow_mul(7919))))
+            .collect();
+        let mut data = DirData::new();
+        for (ino, name) in (0..NUM_ENTRIES).zip(names.iter()) {
+            data.insert(Arc::new(DirEntry {
+                ino,
+                name: name.clone(),
+                entry_type: Type::File,
+            }));
+        }
+
+        let start = Instant::now();
+        let copied: Vec<DirEntry> = names
+            .iter()
+            .filter_map(|name| data.get(name).map(|entry| DirEntry::clone(entry)))
+            .collect();
+        let copy_elapsed = start.elapsed();
+        assert_eq!(copied.len(), names.len());
+
+        let start = Instant::now();
+        let shared: Vec<Arc<DirEntry>> = names
+            .iter()
+            .filter_map(|name| data.get(name).map(Arc::clone))
+            .collect();
+        let share_elapsed = start.elapsed();
+        assert_eq!(shared.len(), names.len());
+
+        println!(
+            "get of {} entries: copied {:?}, shared {:?}",
+            NUM_ENTRIES, copy_elapsed, share_elapsed,
+        );
+    }
+}
@@ -0,0 +1,324 @@
+//! Bookkeeping of the file handles
+//!
+//! Every open used to dup the fd of the i-node, so a process opening a file
+//! thousands of times exhausted the fds of the daemon. The opens of a file with
+//! the same flags share a single fd instead, which is closed once the last of
+//! them is released, and only an open with other flags dups a new fd.
+//!
+//! Each open is recorded with a unique id and the pid of the opener, and the
+//! file handle given to the kernel is the id, which maps to the shared fd. So
+//! a release drops the very open it names, and the opens never released, e.g.
+//! once the kernel aborts the connection, show up in the stats and are listed
+//! along with their owners when they are closed at destroy.
+
+use nix::fcntl::OFlag;
+use rustc_hash::FxHashMap;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use super::{Cast, OverflowArithmetic};
+
+/// An open of a file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OpenHandle {
+    /// The id of the open, unique within the filesystem, which is the file
+    /// handle given to the kernel
+    pub id: u64,
+    /// The pid of the opener
+    pub pid: u32,
+}
+
+/// The fd shared by the opens of a file with the same flags
+#[derive(Debug)]
+struct SharedFd {
+    /// The ino of the file
+    ino: u64,
+    /// The open flags
+    flags: OFlag,
+    /// The opens sharing the fd, oldest first
+    opens: VecDeque<OpenHandle>,
+}
+
+/// An fd whose opens were never released
+#[derive(Debug)]
+pub struct LeakedFd {
+    /// The fd
+    pub fd: RawFd,
+    /// The ino of the file
+    pub ino: u64,
+    /// The opens not released
+    pub opens: Vec<OpenHandle>,
+}
+
+/// The fds shared by the opens of the files
+#[derive(Debug, Default)]
+pub struct FileHandles {
+    /// The fd of each file and open flags
+    fds: FxHashMap<(u64, OFlag), RawFd>,
+    /// The sharing of each fd
+    shared: FxHashMap<RawFd, SharedFd>,
+    /// The fd of each open by its id
+    opens: FxHashMap<u64, RawFd>,
+    /// The id of the next open
+    next_id: u64,
+}
+
+impl FileHandles {
+    /// New empty handle table, the ids start at 1
+    pub fn new() -> Self {
+        Self {
+            next_id: 1,
+            ..Self::default()
+        }
+    }
+
+    /// Helper record a new open of `fd` by `pid`
+    fn helper_new_open(&mut self, fd: RawFd, pid: u32) -> OpenHandle {
+        let id = self.next_id;
+        self.next_id = id.overflow_add(1);
+        let _previous = self.opens.insert(id, fd);
+        OpenHandle { id, pid }
+    }
+
+    /// Share the fd of the file of `ino` opened with `flags` by `pid` if any,
+    /// along with the open recorded
+    pub fn share(&mut self, ino: u64, flags: OFlag, pid: u32) -> Option<(RawFd, OpenHandle)> {
+        let fd = *self.fds.get(&(ino, flags))?;
+        let open = self.helper_new_open(fd, pid);
+        if let Some(shared) = self.shared.get_mut(&fd) {
+            shared.opens.push_back(open);
+        }
+        Some((fd, open))
+    }
+
+    /// Add the fd newly opened for the file of `ino` with `flags` by `pid`,
+    /// returns the open recorded
+    pub fn insert(&mut self, ino: u64, flags: OFlag, fd: RawFd, pid: u32) -> OpenHandle {
+        let open = self.helper_new_open(fd, pid);
+        let _previous = self.fds.insert((ino, flags), fd);
+        let _previous = self.shared.insert(
+            fd,
+            SharedFd {
+                ino,
+                flags,
+                opens: vec![open].into(),
+            },
+        );
+        open
+    }
+
+    /// The fd of the open of `id`, `None` if it is not open
+    pub fn fd_of(&self, id: u64) -> Option<RawFd> {
+        self.opens.get(&id).copied()
+    }
+
+    /// The open flags of the open of `id`, `None` if it is not open
+    #[cfg(feature = "abi-7-11")]
+    pub fn flags_of(&self, id: u64) -> Option<OFlag> {
+        let fd = self.opens.get(&id)?;
+        self.shared.get(fd).map(|shared| shared.flags)
+    }
+
+    /// Release the open of `id`, returns its fd and whether it is the last
+    /// open of the fd, after which the fd is to be closed, `None` if it is not
+    /// open
+    pub fn release(&mut self, id: u64) -> Option<(RawFd, bool)> {
+        let fd = self.opens.remove(&id)?;
+        let shared = if let Some(shared) = self.shared.get_mut(&fd) {
+            shared
+        } else {
+            return Some((fd, true));
+        };
+        shared.opens.retain(|open| open.id != id);
+        if !shared.opens.is_empty() {
+            return Some((fd, false));
+        }
+        let key = (shared.ino, shared.flags);
+        let _shared = self.shared.remove(&fd);
+        let _fd = self.fds.remove(&key);
+        Some((fd, true))
+    }
+
+    /// The opens of the file of `ino` not released, oldest first per fd
+    pub fn opens_of(&self, ino: u64) -> Vec<OpenHandle> {
+        self.shared
+            .values()
+            .filter(|shared| shared.ino == ino)
+            .flat_map(|shared| shared.opens.iter().copied())
+            .collect()
+    }
+
+    /// The numbers of the files, fds and opens not released, along with
+    /// `dirs` directory handles
+    pub fn stats(&self, dirs: usize) -> HandleStats {
+        let mut files: Vec<u64> = self.shared.values().map(|shared| shared.ino).collect();
+        files.sort_unstable();
+        files.dedup();
+        HandleStats {
+            files: files.len().cast(),
+            fds: self.shared.len().cast(),
+            opens: self
+                .shared
+                .values()
+                .map(|shared| shared.opens.len())
+                .sum::<usize>()
+                .cast(),
+            dirs: dirs.cast(),
+        }
+    }
+
+    /// Take the fds not released, e.g. to close them at destroy, ordered by
+    /// their first open
+    pub fn take_leaked(&mut self) -> Vec<LeakedFd> {
+        self.fds.clear();
+        self.opens.clear();
+        let mut leaked: Vec<LeakedFd> = mem::take(&mut self.shared)
+            .into_iter()
+            .map(|(fd, shared)| LeakedFd {
+                fd,
+                ino: shared.ino,
+                opens: shared.opens.into(),
+            })
+            .collect();
+        leaked.sort_by_key(|leaked| leaked.opens.first().map(|open| open.id));
+        leaked
+    }
+}
+
+/// The numbers of the handles not released, the same layout is returned by
+/// `MEMFS_IOC_HANDLE_STATS`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HandleStats {
+    /// The number of the files open
+    pub files: u64,
+    /// The number of the fds of the open files
+    pub fds: u64,
+    /// The number of the opens of the files
+    pub opens: u64,
+    /// The number of the directory handles
+    pub dirs: u64,
+}
+
+impl HandleStats {
+    /// Parse from the bytes returned by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        let mut fields = data
+            .chunks_exact(mem::size_of::<u64>())
+            .filter_map(|bytes| bytes.try_into().ok().map(u64::from_ne_bytes));
+        Some(Self {
+            files: fields.next()?,
+            fds: fields.next()?,
+            opens: fields.next()?,
+            dirs: fields.next()?,
+        })
+    }
+
+    /// Serialize the fields in order in native endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.files, self.fds, self.opens, self.dirs]
+            .iter()
+            .flat_map(|field| field.to_ne_bytes().to_vec())
+            .collect()
+    }
+}
+
+impl fmt::Display for HandleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "open files: {} ({} opens sharing {} fds)\nopen directories: {}",
+            self.files, self.opens, self.fds, self.dirs
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileHandles, HandleStats, OpenHandle, OverflowArithmetic};
+    use nix::fcntl::OFlag;
+
+    #[test]
+    fn test_file_handles() {
+        let mut handles = FileHandles::new();
+        let fd = |shared: Option<(i32, OpenHandle)>| shared.map(|(fd, _)| fd);
+        assert_eq!(fd(handles.share(2, OFlag::O_RDONLY, 100)), None);
+        let first = handles.insert(2, OFlag::O_RDONLY, 10, 100);
+        let (_, second) = handles
+            .share(2, OFlag::O_RDONLY, 101)
+            .unwrap_or_else(|| panic!());
+        let (_, third) = handles
+            .share(2, OFlag::O_RDONLY, 102)
+            .unwrap_or_else(|| panic!());
+        // each open has its own handle mapped to the shared fd
+        assert_ne!(first.id, second.id);
+        assert_eq!(handles.fd_of(second.id), Some(10));
+        #[cfg(feature = "abi-7-11")]
+        assert_eq!(handles.flags_of(second.id), Some(OFlag::O_RDONLY));
+        // other flags or another file get another fd
+        assert_eq!(fd(handles.share(2, OFlag::O_RDWR, 100)), None);
+        assert_eq!(fd(handles.share(3, OFlag::O_RDONLY, 100)), None);
+        let rdwr = handles.insert(2, OFlag::O_RDWR, 11, 102);
+        let (_, rdwr_shared) = handles
+            .share(2, OFlag::O_RDWR, 102)
+            .unwrap_or_else(|| panic!());
+        assert_eq!(handles.opens_of(2).len(), 5);
+        assert_eq!(
+            handles.stats(1),
+            HandleStats {
+                files: 1,
+                fds: 2,
+                opens: 5,
+                dirs: 1,
+            }
+        );
+
+        // the open released is the one named, not the oldest
+        assert_eq!(handles.release(second.id), Some((10, false)));
+        assert_eq!(handles.opens_of(2).first(), Some(&first));
+        assert_eq!(handles.release(second.id), None);
+        assert_eq!(handles.fd_of(second.id), None);
+        #[cfg(feature = "abi-7-11")]
+        assert_eq!(handles.flags_of(second.id), None);
+        assert_eq!(handles.release(first.id), Some((10, false)));
+        assert_eq!(handles.release(third.id), Some((10, true)));
+        assert_eq!(fd(handles.share(2, OFlag::O_RDONLY, 100)), None);
+        assert_eq!(handles.release(rdwr_shared.id), Some((11, false)));
+        assert_eq!(handles.release(rdwr.id), Some((11, true)));
+        assert_eq!(fd(handles.share(2, OFlag::O_RDWR, 100)), None);
+        assert_eq!(handles.stats(0), HandleStats::default());
+    }
+
+    #[test]
+    fn test_leaked_handles() {
+        let mut handles = FileHandles::new();
+        let first = handles.insert(2, OFlag::O_RDONLY, 10, 100);
+        let _shared = handles.share(2, OFlag::O_RDONLY, 101);
+        let _other = handles.insert(3, OFlag::O_RDONLY, 11, 102);
+        assert_eq!(handles.release(first.id), Some((10, false)));
+        let stats = handles.stats(0);
+        assert_eq!(HandleStats::from_bytes(&stats.to_bytes()), Some(stats));
+        assert_eq!(
+            stats.to_string(),
+            "open files: 2 (2 opens sharing 2 fds)\nopen directories: 0"
+        );
+
+        let leaked = handles.take_leaked();
+        assert_eq!(leaked.len(), 2);
+        let oldest = leaked.first().unwrap_or_else(|| panic!());
+        // the released open is gone
+        assert_eq!((oldest.fd, oldest.ino), (10, 2));
+        assert_eq!(oldest.opens.len(), 1);
+        assert_ne!(oldest.opens.first(), Some(&first));
+        assert_eq!(oldest.opens.first().map(|open| open.pid), Some(101));
+        assert_eq!(handles.stats(0), HandleStats::default());
+        assert_eq!(handles.share(2, OFlag::O_RDONLY, 100), None);
+        assert_eq!(handles.fd_of(first.id.overflow_add(1)), None);
+    }
+}
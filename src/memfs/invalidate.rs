@@ -0,0 +1,136 @@
+//! Invalidation of the caches on behalf of out-of-band writers
+//!
+//! An embedding application changing the backing directory behind the back of
+//! the filesystem tells it through an `Invalidator`, which notifies the kernel
+//! to drop its caches at once and queues the invalidation for memfs. The caches
+//! of memfs are only touched on the session thread, so the queue is drained
+//! along with the next requests, which the kernel sends once its own caches are
+//! dropped. The kernel is only notified with the `abi-7-12` feature, and once
+//! the session is initialized. The kernel is notified without holding the lock
+//! of the queue, as the notification blocks until the kernel has dropped its
+//! caches, which may wait for a request memfs handles while draining the queue.
+
+use log::debug;
+#[cfg(feature = "abi-7-12")]
+use log::warn;
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "abi-7-12")]
+use crate::fuse::Notifier;
+
+use super::name::NameEncoding;
+
+/// A cache invalidation queued for memfs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Invalidation {
+    /// The entry of the backing name under the directory of parent
+    Entry {
+        /// The i-node number of the directory
+        parent: u64,
+        /// The backing name
+        name: OsString,
+    },
+    /// The attributes and the data of the i-node
+    Inode {
+        /// The i-node number
+        ino: u64,
+    },
+}
+
+/// The state shared by the invalidators and memfs
+#[derive(Debug, Default)]
+struct Shared {
+    /// The notifier of the session, `None` before init
+    #[cfg(feature = "abi-7-12")]
+    notifier: Option<Notifier>,
+    /// The encoding of the names seen by the kernel
+    encoding: NameEncoding,
+    /// The invalidations not drained by memfs yet
+    pending: Vec<Invalidation>,
+}
+
+/// Handle invalidating the caches of memfs and of the kernel, it may be cloned
+/// and sent to other threads
+#[derive(Clone, Debug, Default)]
+pub struct Invalidator {
+    /// The state shared with memfs
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Invalidator {
+    /// Invalidate the entry of the backing `name` under the directory of
+    /// `parent`, e.g. once a file is created, removed or renamed behind the back
+    /// of the filesystem. The entry is looked up from disk again, a cached file
+    /// or subtree replaced or removed on disk is dropped.
+    pub fn invalidate_entry(&self, parent: u64, name: &OsStr) {
+        let mut shared = self.shared.lock().unwrap_or_else(|_| panic!());
+        shared.pending.push(Invalidation::Entry {
+            parent,
+            name: name.to_owned(),
+        });
+        #[cfg(feature = "abi-7-12")]
+        let notify = (shared.notifier, shared.encoding.to_mounted(name));
+        drop(shared);
+        #[cfg(feature = "abi-7-12")]
+        if let (Some(notifier), Some(mounted_name)) = notify {
+            // the kernel may not cache the entry, which is fine
+            if let Err(e) = notifier.inval_entry(parent, &mounted_name) {
+                debug!(
+                    "invalidate_entry() failed to notify the kernel of the entry name={:?} under parent ino={}, {}",
+                    name, parent, e,
+                );
+            }
+        }
+        debug!(
+            "invalidate_entry() queued the entry name={:?} under parent ino={}",
+            name, parent,
+        );
+    }
+
+    /// Invalidate the attributes of the i-node and its data in the range from
+    /// `offset` of `len` byte, e.g. once the file is written behind the back of
+    /// the filesystem. The range is passed to the kernel as
+    /// `Notifier::inval_inode` takes it, memfs drops all the cached data of the
+    /// i-node and reads its attributes from disk again.
+    pub fn invalidate_inode(&self, ino: u64, offset: i64, len: i64) {
+        let mut shared = self.shared.lock().unwrap_or_else(|_| panic!());
+        shared.pending.push(Invalidation::Inode { ino });
+        #[cfg(feature = "abi-7-12")]
+        let notifier = shared.notifier;
+        drop(shared);
+        #[cfg(feature = "abi-7-12")]
+        if let Some(notifier) = notifier {
+            if let Err(e) = notifier.inval_inode(ino, offset, len) {
+                debug!(
+                    "invalidate_inode() failed to notify the kernel of ino={}, {}",
+                    ino, e,
+                );
+            }
+        }
+        debug!(
+            "invalidate_inode() queued ino={}, offset={}, len={}",
+            ino, offset, len,
+        );
+    }
+
+    /// Set the notifier of the session, once it is initialized
+    #[cfg(feature = "abi-7-12")]
+    pub(super) fn set_notifier(&self, notifier: Option<Notifier>) {
+        if notifier.is_none() {
+            warn!("set_notifier() got no notifier, the kernel is not notified of invalidations");
+        }
+        self.shared.lock().unwrap_or_else(|_| panic!()).notifier = notifier;
+    }
+
+    /// Set the encoding of the names seen by the kernel
+    pub(super) fn set_name_encoding(&self, encoding: NameEncoding) {
+        self.shared.lock().unwrap_or_else(|_| panic!()).encoding = encoding;
+    }
+
+    /// Take the invalidations queued, in the order they are queued
+    pub(super) fn take(&self) -> Vec<Invalidation> {
+        mem::take(&mut self.shared.lock().unwrap_or_else(|_| panic!()).pending)
+    }
+}
@@ -0,0 +1,240 @@
+//! Statistics of the sizes of the read and write requests
+//!
+//! The chunk size of the deduplicated cache trades the cost of the small
+//! requests against the cost of the large ones: a write rewrites and hashes
+//! every chunk it touches, however little of it is written, while a read
+//! collects a slice of every chunk it covers. The requests are counted per
+//! power of two size from 4KiB to 1MiB, and the chunk size may follow the size
+//! of the median request, within 64KiB to 1MiB. The counts are halved once
+//! they add up to a limit, so that a change of the workload shows up.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+
+use super::{Cast, OverflowArithmetic};
+
+/// The smallest chunk size picked after the requests
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+/// The largest chunk size picked after the requests
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+/// The number of the size buckets, from 4KiB to 1MiB
+pub const SIZE_BUCKETS: usize = 9;
+/// The shift of the size of the first bucket, 4KiB
+const FIRST_BUCKET_SHIFT: u32 = 12;
+/// The number of the requests counted before a chunk size is picked
+const MIN_SAMPLES: u64 = 1024;
+/// The number of the requests counted before the counts are halved
+const DECAY_SAMPLES: u64 = 64 * 1024;
+
+/// The upper bound of the sizes of the bucket `index`
+fn bucket_size(index: usize) -> usize {
+    1_usize.wrapping_shl(FIRST_BUCKET_SHIFT.overflow_add(index.cast()))
+}
+
+/// The bucket of the requests of `size` byte, the smallest bucket holding
+/// it or the last bucket
+fn bucket_index(size: usize) -> usize {
+    (0..SIZE_BUCKETS)
+        .find(|&index| size <= bucket_size(index))
+        .unwrap_or(SIZE_BUCKETS.overflow_sub(1))
+}
+
+/// Distribution of the sizes of the read and write requests
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IoSizeStats {
+    /// The number of the reads per size bucket
+    reads: [u64; SIZE_BUCKETS],
+    /// The number of the writes per size bucket
+    writes: [u64; SIZE_BUCKETS],
+}
+
+impl IoSizeStats {
+    /// New empty statistics
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Helper count a request into `buckets`, halving all the counts once
+    /// they add up to the limit
+    fn helper_record(&mut self, size: usize, read: bool) {
+        let buckets = if read {
+            &mut self.reads
+        } else {
+            &mut self.writes
+        };
+        if let Some(count) = buckets.get_mut(bucket_index(size)) {
+            *count = count.overflow_add(1);
+        }
+        if self.total() >= DECAY_SAMPLES {
+            for count in self.reads.iter_mut().chain(self.writes.iter_mut()) {
+                *count = count.overflow_div(2);
+            }
+        }
+    }
+
+    /// Count a read of `size` byte
+    pub fn record_read(&mut self, size: usize) {
+        self.helper_record(size, true);
+    }
+
+    /// Count a write of `size` byte
+    pub fn record_write(&mut self, size: usize) {
+        self.helper_record(size, false);
+    }
+
+    /// The number of the requests counted
+    fn total(&self) -> u64 {
+        self.reads.iter().chain(self.writes.iter()).sum()
+    }
+
+    /// The chunk size fitting the median request, `None` until enough
+    /// requests are counted
+    pub fn suggest_chunk_size(&self) -> Option<usize> {
+        let total = self.total();
+        if total < MIN_SAMPLES {
+            return None;
+        }
+        let mut counted = 0_u64;
+        let median = (0..SIZE_BUCKETS).find(|&index| {
+            counted = counted
+                .overflow_add(self.reads.get(index).copied().unwrap_or(0))
+                .overflow_add(self.writes.get(index).copied().unwrap_or(0));
+            counted.overflow_mul(2) >= total
+        })?;
+        Some(bucket_size(median).clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE))
+    }
+}
+
+/// The statistics of the requests and the chunk size, the same layout is
+/// returned by `MEMFS_IOC_IO_STATS`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IoStats {
+    /// The chunk size of the data written from now on, 0 if dedup is disabled
+    pub chunk_size: u64,
+    /// 1 if the chunk size follows the requests, 0 if it is fixed
+    pub adaptive: u64,
+    /// The number of the reads per size bucket, from 4KiB to 1MiB
+    pub reads: [u64; SIZE_BUCKETS],
+    /// The number of the writes per size bucket, from 4KiB to 1MiB
+    pub writes: [u64; SIZE_BUCKETS],
+}
+
+impl IoStats {
+    /// The statistics of `sizes` along with the chunk size
+    pub fn new(sizes: &IoSizeStats, chunk_size: usize, adaptive: bool) -> Self {
+        Self {
+            chunk_size: chunk_size.cast(),
+            adaptive: adaptive.into(),
+            reads: sizes.reads,
+            writes: sizes.writes,
+        }
+    }
+
+    /// Parse from the bytes returned by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        let mut fields = data
+            .chunks_exact(mem::size_of::<u64>())
+            .filter_map(|bytes| bytes.try_into().ok().map(u64::from_ne_bytes));
+        let mut stats = Self {
+            chunk_size: fields.next()?,
+            adaptive: fields.next()?,
+            ..Self::default()
+        };
+        for count in stats.reads.iter_mut().chain(stats.writes.iter_mut()) {
+            *count = fields.next()?;
+        }
+        Some(stats)
+    }
+
+    /// Serialize the fields in order in native endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.chunk_size, self.adaptive]
+            .iter()
+            .chain(self.reads.iter())
+            .chain(self.writes.iter())
+            .flat_map(|field| field.to_ne_bytes().to_vec())
+            .collect()
+    }
+}
+
+/// Write the non-zero counts of `buckets` as `<=SIZE COUNT`, `-` if none
+fn fmt_buckets(f: &mut fmt::Formatter<'_>, buckets: &[u64]) -> fmt::Result {
+    let counts: Vec<String> = buckets
+        .iter()
+        .enumerate()
+        .filter(|&(_, &count)| count > 0)
+        .map(|(index, count)| format!("<={}K {}", bucket_size(index).overflow_div(1024), count))
+        .collect();
+    if counts.is_empty() {
+        write!(f, "-")
+    } else {
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+impl fmt::Display for IoStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.chunk_size, self.adaptive) {
+            (0, _) => writeln!(f, "chunk size: - (dedup disabled)")?,
+            (size, 0) => writeln!(f, "chunk size: {} (fixed)", size)?,
+            (size, _) => writeln!(f, "chunk size: {} (adaptive)", size)?,
+        }
+        write!(f, "read sizes: ")?;
+        fmt_buckets(f, &self.reads)?;
+        write!(f, "\nwrite sizes: ")?;
+        fmt_buckets(f, &self.writes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IoSizeStats, IoStats, MAX_CHUNK_SIZE, MIN_CHUNK_SIZE};
+
+    #[test]
+    fn test_suggest_chunk_size() {
+        let mut sizes = IoSizeStats::new();
+        for _ in 0..1000 {
+            sizes.record_write(4096);
+        }
+        // not enough requests yet
+        assert_eq!(sizes.suggest_chunk_size(), None);
+        for _ in 0..100 {
+            sizes.record_read(100);
+        }
+        assert_eq!(sizes.suggest_chunk_size(), Some(MIN_CHUNK_SIZE));
+        for _ in 0..3000 {
+            sizes.record_read(256 * 1024);
+        }
+        assert_eq!(sizes.suggest_chunk_size(), Some(256 * 1024));
+        for _ in 0..10000 {
+            sizes.record_write(16 * 1024 * 1024);
+        }
+        assert_eq!(sizes.suggest_chunk_size(), Some(MAX_CHUNK_SIZE));
+        // the old requests fade out
+        for _ in 0..200_000 {
+            sizes.record_write(8192);
+        }
+        assert_eq!(sizes.suggest_chunk_size(), Some(MIN_CHUNK_SIZE));
+
+        let mut sizes = IoSizeStats::new();
+        sizes.record_read(100);
+        sizes.record_read(4096);
+        sizes.record_write(300_000);
+        let stats = IoStats::new(&sizes, MIN_CHUNK_SIZE, true);
+        assert_eq!(IoStats::from_bytes(&stats.to_bytes()), Some(stats));
+        assert_eq!(IoStats::from_bytes(&[0; 8]), None);
+        assert_eq!(
+            stats.to_string(),
+            "chunk size: 65536 (adaptive)\nread sizes: <=4K 2\nwrite sizes: <=512K 1"
+        );
+        assert_eq!(
+            IoStats::default().to_string(),
+            "chunk size: - (dedup disabled)\nread sizes: -\nwrite sizes: -"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Bookkeeping of file locks
+//!
+//! POSIX locks are owned by a lock owner and cover a byte range, locks of an
+//! owner are split when part of their range is unlocked or relocked. flock(2)
+//! locks always cover the whole file and never conflict with POSIX locks.
+//! Locks only live in memory, they are not taken on the backing files.
+
+use super::{Cast, OverflowArithmetic};
+use libc::{F_UNLCK, F_WRLCK};
+use rustc_hash::FxHashMap;
+
+/// File lock
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileLock {
+    /// Lock owner
+    pub owner: u64,
+    /// Start of the locked range
+    pub start: u64,
+    /// End of the locked range, inclusive
+    pub end: u64,
+    /// Type, one of `F_RDLCK`, `F_WRLCK` or `F_UNLCK`
+    pub typ: u32,
+    /// Process id of the owner
+    pub pid: u32,
+    /// Whether it is a flock(2) lock rather than a POSIX lock
+    pub flock: bool,
+}
+
+impl FileLock {
+    /// Whether the lock conflicts with another lock
+    fn conflicts_with(&self, other: &Self) -> bool {
+        self.owner != other.owner
+            && self.flock == other.flock
+            && self.start <= other.end
+            && other.start <= self.end
+            && (self.typ == F_WRLCK.cast() || other.typ == F_WRLCK.cast())
+    }
+}
+
+/// Locks of all the files, indexed by ino
+#[derive(Debug, Default)]
+pub struct LockTable {
+    /// Locks of each file
+    locks: FxHashMap<u64, Vec<FileLock>>,
+}
+
+impl LockTable {
+    /// New empty lock table
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the first lock that conflicts with `lock`
+    pub fn get_conflict(&self, ino: u64, lock: &FileLock) -> Option<&FileLock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts_with(lock))
+    }
+
+    /// Set or, if its type is `F_UNLCK`, clear `lock`, returns the conflicting
+    /// lock without setting anything if the range is locked by another owner
+    pub fn set(&mut self, ino: u64, lock: FileLock) -> Result<(), FileLock> {
+        if lock.typ != F_UNLCK.cast() {
+            if let Some(conflict) = self.get_conflict(ino, &lock) {
+                return Err(*conflict);
+            }
+        }
+        let locks = self.locks.entry(ino).or_insert_with(Vec::new);
+        // cut the range out of the locks of the owner, keeping the parts around it
+        let mut kept = Vec::with_capacity(locks.len().overflow_add(1));
+        for held in locks.drain(..) {
+            if held.owner != lock.owner
+                || held.flock != lock.flock
+                || held.end < lock.start
+                || lock.end < held.start
+            {
+                kept.push(held);
+                continue;
+            }
+            if held.start < lock.start {
+                kept.push(FileLock {
+                    end: lock.start.overflow_sub(1),
+                    ..held
+                });
+            }
+            if lock.end < held.end {
+                kept.push(FileLock {
+                    start: lock.end.overflow_add(1),
+                    ..held
+                });
+            }
+        }
+        if lock.typ != F_UNLCK.cast() {
+            kept.push(lock);
+        }
+        if kept.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = kept;
+        }
+        Ok(())
+    }
+
+    /// Release all the POSIX locks, or all the flock locks, of an owner on a file
+    pub fn release_owner(&mut self, ino: u64, owner: u64, flock: bool) {
+        if let Some(locks) = self.locks.get_mut(&ino) {
+            locks.retain(|held| held.owner != owner || held.flock != flock);
+            if locks.is_empty() {
+                self.locks.remove(&ino);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileLock, LockTable};
+    use crate::fuse::Cast;
+    use libc::{F_RDLCK, F_UNLCK, F_WRLCK};
+
+    fn posix_lock(owner: u64, start: u64, end: u64, typ: i32) -> FileLock {
+        FileLock {
+            owner,
+            start,
+            end,
+            typ: typ.cast(),
+            pid: 0,
+            flock: false,
+        }
+    }
+
+    #[test]
+    fn test_posix_locks() {
+        let mut table = LockTable::new();
+        assert!(table.set(1, posix_lock(10, 0, 99, F_RDLCK)).is_ok());
+        assert!(table.set(1, posix_lock(20, 50, 149, F_RDLCK)).is_ok());
+        assert_eq!(
+            table.set(1, posix_lock(20, 0, 9, F_WRLCK)),
+            Err(posix_lock(10, 0, 99, F_RDLCK))
+        );
+        // locks of other files and of the same owner do not conflict
+        assert!(table.set(2, posix_lock(20, 0, 9, F_WRLCK)).is_ok());
+        assert!(table.set(1, posix_lock(10, 0, 9, F_WRLCK)).is_ok());
+
+        // unlocking the middle splits the lock
+        assert!(table.set(1, posix_lock(10, 20, 29, F_UNLCK)).is_ok());
+        assert!(table
+            .get_conflict(1, &posix_lock(30, 20, 29, F_WRLCK))
+            .is_none());
+        assert_eq!(
+            table.get_conflict(1, &posix_lock(30, 30, 30, F_WRLCK)),
+            Some(&posix_lock(10, 30, 99, F_RDLCK))
+        );
+
+        // closing releases the POSIX locks of the owner only
+        table.release_owner(1, 10, false);
+        assert!(table
+            .get_conflict(1, &posix_lock(30, 0, 49, F_WRLCK))
+            .is_none());
+        assert!(table
+            .get_conflict(1, &posix_lock(30, 50, 50, F_WRLCK))
+            .is_some());
+        table.release_owner(1, 20, false);
+        assert!(table.locks.get(&1).is_none());
+    }
+
+    #[test]
+    fn test_flock_locks() {
+        let mut table = LockTable::new();
+        let flock = FileLock {
+            flock: true,
+            ..posix_lock(10, 0, u64::MAX, F_WRLCK)
+        };
+        assert!(table.set(1, flock).is_ok());
+        // flock locks and POSIX locks do not conflict
+        assert!(table.set(1, posix_lock(20, 0, 9, F_WRLCK)).is_ok());
+        assert_eq!(table.set(1, FileLock { owner: 20, ..flock }), Err(flock));
+        table.release_owner(1, 10, true);
+        assert!(table.set(1, FileLock { owner: 20, ..flock }).is_ok());
+    }
+}
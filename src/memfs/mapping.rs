@@ -0,0 +1,119 @@
+//! Read-only memory mapping of backing files
+//!
+//! Large files read far more often than written are served from a shared
+//! mapping of the backing file instead of being copied into the cache, reads
+//! reply the mapped pages directly and the kernel pages them in and out. The
+//! writes through memfs go to the same page cache, so the mapping sees them
+//! without being remapped, but a file truncated on the backing store behind
+//! memfs raises `SIGBUS` on access to the pages beyond its new end. memfs
+//! checks the size of the file on disk before each read from a mapping.
+
+use log::error;
+use nix::sys::mman::{self, MapFlags, ProtFlags};
+use std::os::unix::io::RawFd;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+/// Read-only shared mapping of a file, unmapped on drop
+#[derive(Debug)]
+pub struct Mapping {
+    /// Start address of the mapping
+    addr: NonNull<u8>,
+    /// Byte length of the mapping
+    len: usize,
+}
+
+// the mapping is read-only and owned, it is safe to access from any thread
+#[allow(unsafe_code)]
+unsafe impl Send for Mapping {}
+#[allow(unsafe_code)]
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// Map the first `len` bytes of the file of fd, `len` must not be zero
+    pub fn new(fd: RawFd, len: usize) -> nix::Result<Self> {
+        #[allow(unsafe_code)]
+        let addr = unsafe {
+            mman::mmap(
+                ptr::null_mut(),
+                len,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_SHARED,
+                fd,
+                0,
+            )?
+        };
+        let addr = NonNull::new(addr.cast::<u8>())
+            .unwrap_or_else(|| panic!("Mapping::new() got a null mapping of fd={}", fd));
+        Ok(Self { addr, len })
+    }
+
+    /// Byte length of the mapping
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The mapped bytes
+    pub fn as_slice(&self) -> &[u8] {
+        // safe because the mapping is readable and lives as long as self
+        #[allow(unsafe_code)]
+        unsafe {
+            slice::from_raw_parts(self.addr.as_ptr(), self.len)
+        }
+    }
+
+    /// The mapped bytes from offset, at most size bytes
+    pub fn read(&self, offset: usize, size: usize) -> &[u8] {
+        let data = self.as_slice();
+        let start = offset.min(data.len());
+        let end = start.saturating_add(size).min(data.len());
+        data.get(start..end).unwrap_or_default()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        #[allow(unsafe_code)]
+        let res = unsafe { mman::munmap(self.addr.as_ptr().cast(), self.len) };
+        if let Err(e) = res {
+            error!(
+                "Mapping::drop() failed to unmap {} bytes, the error is: {:?}",
+                self.len, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Mapping;
+    use nix::fcntl::{self, OFlag};
+    use nix::sys::stat::Mode;
+    use nix::sys::uio;
+    use nix::unistd;
+
+    #[test]
+    fn test_mapping() {
+        let path = "/tmp/fuse_test_mapping";
+        let fd = fcntl::open(
+            path,
+            OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_RDWR,
+            Mode::from_bits_truncate(0o644),
+        )
+        .unwrap_or_else(|_| panic!());
+        uio::pwrite(fd, b"hello world", 0).unwrap_or_else(|_| panic!());
+        let mapping = Mapping::new(fd, 11).unwrap_or_else(|_| panic!());
+        assert_eq!(mapping.len(), 11);
+        assert_eq!(mapping.read(6, 100), b"world");
+        assert_eq!(mapping.read(20, 5), b"");
+
+        // writes to the file are seen through the shared mapping
+        uio::pwrite(fd, b"H", 0).unwrap_or_else(|_| panic!());
+        assert_eq!(mapping.read(0, 5), b"Hello");
+        assert!(Mapping::new(fd, 0).is_err());
+        unistd::close(fd).unwrap_or_else(|_| panic!());
+        // the mapping outlives the fd
+        assert_eq!(mapping.as_slice(), b"Hello world");
+        unistd::unlink(path).unwrap_or_else(|_| panic!());
+    }
+}
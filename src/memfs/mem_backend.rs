@@ -0,0 +1,526 @@
+//! In-memory backend, so that memfs is tested without touching the local filesystem
+//!
+//! Every path opens the root directory, and unlinked files live on until their
+//! last fd is closed, the same as on disk. The directories do not count their
+//! subdirectories in their link counts, which are 1 as on btrfs.
+
+#[cfg(feature = "abi-7-19")]
+use super::backend::FallocateMode;
+use super::backend::{Backend, BackendDirEntry, BackendSpace, OpenFlags, ReadDirEntries};
+use super::mapping::Mapping;
+use super::{Cast, FileAttr, FileType, OverflowArithmetic};
+use libc::{c_int, XATTR_CREATE, XATTR_REPLACE};
+use nix::dir::Type;
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use nix::unistd::UnlinkatFlags;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+/// The ino of the root directory
+const MEM_ROOT_INO: u64 = 2;
+
+/// Error of a missing extended attribute
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub const ENOATTR: Errno = Errno::ENODATA;
+/// Error of a missing extended attribute
+#[cfg(target_os = "macos")]
+pub const ENOATTR: Errno = Errno::ENOATTR;
+
+/// Node of the in-memory backend
+#[derive(Debug)]
+struct MemNode {
+    /// Entries of a directory, `None` for a file
+    entries: Option<BTreeMap<OsString, u64>>,
+    /// Data of a file
+    data: Vec<u8>,
+    /// Permission
+    perm: u16,
+    /// Link count
+    nlink: u32,
+    /// Modification time
+    mtime: SystemTime,
+    /// Extended attributes
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+impl MemNode {
+    /// New directory
+    fn new_dir(perm: u16) -> Self {
+        Self {
+            entries: Some(BTreeMap::new()),
+            data: Vec::new(),
+            perm,
+            nlink: 1,
+            mtime: SystemTime::now(),
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// New file
+    fn new_file(perm: u16) -> Self {
+        Self {
+            entries: None,
+            data: Vec::new(),
+            perm,
+            nlink: 1,
+            mtime: SystemTime::now(),
+            xattrs: BTreeMap::new(),
+        }
+    }
+
+    /// Type
+    const fn file_type(&self) -> Type {
+        if self.entries.is_some() {
+            Type::Directory
+        } else {
+            Type::File
+        }
+    }
+}
+
+/// State of the in-memory backend
+#[derive(Debug)]
+struct MemState {
+    /// Nodes, indexed by ino
+    nodes: FxHashMap<u64, MemNode>,
+    /// Open fds, each refers to the ino of a node
+    fds: FxHashMap<RawFd, u64>,
+    /// The ino of the next new node
+    next_ino: u64,
+    /// The next new fd
+    next_fd: RawFd,
+}
+
+impl MemState {
+    /// Get the ino an fd refers to
+    fn get_ino(&self, fd: RawFd) -> nix::Result<u64> {
+        self.fds
+            .get(&fd)
+            .copied()
+            .ok_or(nix::Error::Sys(Errno::EBADF))
+    }
+
+    /// Get the node an fd refers to
+    fn get_node(&self, fd: RawFd) -> nix::Result<&MemNode> {
+        let ino = self.get_ino(fd)?;
+        self.nodes.get(&ino).ok_or(nix::Error::Sys(Errno::EBADF))
+    }
+
+    /// Get the node an fd refers to for update
+    fn get_node_mut(&mut self, fd: RawFd) -> nix::Result<&mut MemNode> {
+        let ino = self.get_ino(fd)?;
+        self.nodes
+            .get_mut(&ino)
+            .ok_or(nix::Error::Sys(Errno::EBADF))
+    }
+
+    /// Get the entries of the directory an fd refers to
+    fn get_entries(&mut self, dir: RawFd) -> nix::Result<&mut BTreeMap<OsString, u64>> {
+        self.get_node_mut(dir)?
+            .entries
+            .as_mut()
+            .ok_or(nix::Error::Sys(Errno::ENOTDIR))
+    }
+
+    /// Set the modification time of the directory an fd refers to once its
+    /// entries change, as the filesystems on disk do
+    fn touch(&mut self, dir: RawFd) -> nix::Result<()> {
+        self.get_node_mut(dir)?.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    /// Look up the ino of name under dir
+    fn lookup(&mut self, dir: RawFd, name: &OsStr) -> nix::Result<u64> {
+        self.get_entries(dir)?
+            .get(name)
+            .copied()
+            .ok_or(nix::Error::Sys(Errno::ENOENT))
+    }
+
+    /// Add a node of name under dir
+    fn add_node(&mut self, dir: RawFd, name: &OsStr, node: MemNode) -> nix::Result<u64> {
+        let ino = self.next_ino;
+        let entries = self.get_entries(dir)?;
+        if entries.contains_key(name) {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+        entries.insert(name.to_os_string(), ino);
+        self.nodes.insert(ino, node);
+        self.next_ino = ino.overflow_add(1);
+        self.touch(dir)?;
+        Ok(ino)
+    }
+
+    /// Open a new fd to the node of ino
+    fn open_fd(&mut self, ino: u64) -> RawFd {
+        let fd = self.next_fd;
+        self.fds.insert(fd, ino);
+        self.next_fd = fd.overflow_add(1);
+        fd
+    }
+
+    /// Drop one link to the node of ino, removing the node once it has neither links nor fds
+    fn unlink_node(&mut self, ino: u64) {
+        if let Some(node) = self.nodes.get_mut(&ino) {
+            node.nlink = if node.entries.is_some() {
+                0
+            } else {
+                node.nlink.overflow_sub(1)
+            };
+        }
+        self.may_remove_node(ino);
+    }
+
+    /// Remove the node of ino if it has neither links nor fds
+    fn may_remove_node(&mut self, ino: u64) {
+        let unlinked = self.nodes.get(&ino).map_or(false, |node| node.nlink == 0);
+        if unlinked && self.fds.values().all(|fd_ino| *fd_ino != ino) {
+            self.nodes.remove(&ino);
+        }
+    }
+}
+
+/// In-memory backend
+#[derive(Debug)]
+pub struct MemBackend {
+    /// State
+    state: Mutex<MemState>,
+}
+
+impl MemBackend {
+    /// New backend with an empty root directory
+    pub fn new() -> Self {
+        let mut nodes = FxHashMap::default();
+        nodes.insert(MEM_ROOT_INO, MemNode::new_dir(0o755));
+        Self {
+            state: Mutex::new(MemState {
+                nodes,
+                fds: FxHashMap::default(),
+                next_ino: MEM_ROOT_INO.overflow_add(1),
+                next_fd: 3,
+            }),
+        }
+    }
+
+    /// Lock the state
+    fn lock(&self) -> MutexGuard<'_, MemState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|_| panic!("MemBackend found the state lock poisoned"))
+    }
+}
+
+impl Backend for MemBackend {
+    fn open_dir(&self, _path: &Path) -> nix::Result<RawFd> {
+        Ok(self.lock().open_fd(MEM_ROOT_INO))
+    }
+
+    fn open_dir_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<RawFd> {
+        let mut state = self.lock();
+        let ino = state.lookup(dir, name)?;
+        match state.nodes.get(&ino).map(MemNode::file_type) {
+            Some(Type::Directory) => Ok(state.open_fd(ino)),
+            _ => Err(nix::Error::Sys(Errno::ENOTDIR)),
+        }
+    }
+
+    fn mkdir_at(&self, dir: RawFd, name: &OsStr, mode: Mode) -> nix::Result<()> {
+        self.lock()
+            .add_node(dir, name, MemNode::new_dir(mode.bits().cast()))
+            .map(|_| ())
+    }
+
+    fn open_at(&self, dir: RawFd, name: &OsStr, oflags: OFlag, mode: Mode) -> nix::Result<RawFd> {
+        let mut state = self.lock();
+        let ino = match state.lookup(dir, name) {
+            Ok(_) if oflags.contains(OFlag::O_CREAT | OFlag::O_EXCL) => {
+                return Err(nix::Error::Sys(Errno::EEXIST));
+            }
+            Ok(ino) => ino,
+            Err(nix::Error::Sys(Errno::ENOENT)) if oflags.contains(OFlag::O_CREAT) => {
+                state.add_node(dir, name, MemNode::new_file(mode.bits().cast()))?
+            }
+            Err(e) => return Err(e),
+        };
+        let node = state
+            .nodes
+            .get_mut(&ino)
+            .ok_or(nix::Error::Sys(Errno::ENOENT))?;
+        if node.entries.is_some() {
+            return Err(nix::Error::Sys(Errno::EISDIR));
+        }
+        if oflags.contains(OFlag::O_TRUNC) {
+            node.data.clear();
+        }
+        Ok(state.open_fd(ino))
+    }
+
+    fn read_dir(&self, dir: RawFd, offset: i64, max_entries: usize) -> nix::Result<ReadDirEntries> {
+        let mut state = self.lock();
+        let listed: Vec<(OsString, u64)> = state
+            .get_entries(dir)?
+            .iter()
+            .map(|(name, ino)| (name.clone(), *ino))
+            .collect();
+        let mut entries = Vec::new();
+        let mut next_offset = offset;
+        for (name, ino) in listed.into_iter().skip(offset.cast()).take(max_entries) {
+            next_offset = next_offset.overflow_add(1);
+            let file_type = state.nodes.get(&ino).map(MemNode::file_type);
+            entries.push((
+                next_offset,
+                BackendDirEntry {
+                    ino,
+                    dev: 0,
+                    name,
+                    file_type,
+                },
+            ));
+        }
+        let eof = next_offset.cast::<usize>() >= state.get_entries(dir)?.len();
+        Ok((entries, if eof { None } else { Some(next_offset) }))
+    }
+
+    fn stat_at(&self, dir: RawFd, name: &OsStr) -> nix::Result<BackendDirEntry> {
+        let mut state = self.lock();
+        let ino = state.lookup(dir, name)?;
+        Ok(BackendDirEntry {
+            ino,
+            dev: 0,
+            name: name.to_os_string(),
+            file_type: state.nodes.get(&ino).map(MemNode::file_type),
+        })
+    }
+
+    fn fstat(&self, fd: RawFd) -> nix::Result<FileAttr> {
+        let state = self.lock();
+        let ino = state.get_ino(fd)?;
+        let node = state.get_node(fd)?;
+        let size: u64 = node.data.len().cast();
+        Ok(FileAttr {
+            ino,
+            size,
+            blocks: size.overflow_add(511).overflow_div(512),
+            atime: node.mtime,
+            mtime: node.mtime,
+            ctime: node.mtime,
+            crtime: node.mtime,
+            kind: match node.file_type() {
+                Type::Directory => FileType::Directory,
+                _ => FileType::RegularFile,
+            },
+            perm: node.perm,
+            nlink: node.nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        })
+    }
+
+    fn read_at(&self, fd: RawFd, buf: &mut [u8], offset: i64) -> nix::Result<usize> {
+        let state = self.lock();
+        let data = state
+            .get_node(fd)?
+            .data
+            .get(offset.cast::<usize>()..)
+            .unwrap_or(&[]);
+        let size = buf.len().min(data.len());
+        buf.get_mut(..size)
+            .unwrap_or_else(|| panic!())
+            .copy_from_slice(data.get(..size).unwrap_or_else(|| panic!()));
+        Ok(size)
+    }
+
+    fn write_at(&self, fd: RawFd, data: &[u8], offset: i64) -> nix::Result<usize> {
+        let mut state = self.lock();
+        let node = state.get_node_mut(fd)?;
+        if node.entries.is_some() {
+            return Err(nix::Error::Sys(Errno::EISDIR));
+        }
+        let start: usize = offset.cast();
+        let end = start.overflow_add(data.len());
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data
+            .get_mut(start..end)
+            .unwrap_or_else(|| panic!())
+            .copy_from_slice(data);
+        node.mtime = SystemTime::now();
+        Ok(data.len())
+    }
+
+    fn write_vectored_at(&self, fd: RawFd, bufs: &[&[u8]], offset: i64) -> nix::Result<usize> {
+        self.write_at(fd, &bufs.concat(), offset)
+    }
+
+    fn map(&self, fd: RawFd, _len: usize) -> nix::Result<Mapping> {
+        // the files live in the heap of the backend, not in mappable fds
+        self.lock().get_ino(fd)?;
+        Err(nix::Error::Sys(Errno::ENODEV))
+    }
+
+    fn preallocate(&self, fd: RawFd, _offset: i64, _len: i64) -> nix::Result<()> {
+        // the files grow in the heap of the backend on write
+        self.lock().get_ino(fd)?;
+        Err(nix::Error::Sys(Errno::EOPNOTSUPP))
+    }
+
+    fn truncate(&self, fd: RawFd, size: i64) -> nix::Result<()> {
+        let mut state = self.lock();
+        let node = state.get_node_mut(fd)?;
+        if node.entries.is_some() {
+            return Err(nix::Error::Sys(Errno::EISDIR));
+        }
+        node.data.resize(size.cast(), 0);
+        node.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    fn set_mtime(&self, fd: RawFd, mtime: SystemTime) -> nix::Result<()> {
+        self.lock().get_node_mut(fd)?.mtime = mtime;
+        Ok(())
+    }
+
+    #[cfg(feature = "abi-7-19")]
+    fn fallocate(&self, fd: RawFd, mode: FallocateMode, offset: i64, len: i64) -> nix::Result<()> {
+        let mut state = self.lock();
+        let node = state.get_node_mut(fd)?;
+        if node.entries.is_some() {
+            return Err(nix::Error::Sys(Errno::EISDIR));
+        }
+        let size = node.data.len();
+        let end: usize = offset.overflow_add(len).cast();
+        if !mode.keeps_size() && end > size {
+            node.data.resize(end, 0);
+        }
+        if !matches!(mode, FallocateMode::Allocate { .. }) {
+            let start = offset.cast::<usize>().min(node.data.len());
+            let end = end.min(node.data.len());
+            if let Some(range) = node.data.get_mut(start..end) {
+                range.fill(0);
+            }
+        }
+        node.mtime = SystemTime::now();
+        Ok(())
+    }
+
+    fn set_flags(&self, fd: RawFd, _oflags: OFlag) -> nix::Result<()> {
+        self.lock().get_ino(fd).map(|_| ())
+    }
+
+    fn dup(&self, fd: RawFd, _oflags: OFlag) -> nix::Result<RawFd> {
+        let mut state = self.lock();
+        let ino = state.get_ino(fd)?;
+        Ok(state.open_fd(ino))
+    }
+
+    fn fsync(&self, fd: RawFd) -> nix::Result<()> {
+        self.lock().get_ino(fd).map(|_| ())
+    }
+
+    fn unlink_at(&self, dir: RawFd, name: &OsStr, flags: UnlinkatFlags) -> nix::Result<()> {
+        let mut state = self.lock();
+        let ino = state.lookup(dir, name)?;
+        match (state.nodes.get(&ino).map(|node| &node.entries), flags) {
+            (Some(Some(entries)), UnlinkatFlags::RemoveDir) if !entries.is_empty() => {
+                return Err(nix::Error::Sys(Errno::ENOTEMPTY));
+            }
+            (Some(Some(_)), UnlinkatFlags::NoRemoveDir) => {
+                return Err(nix::Error::Sys(Errno::EISDIR));
+            }
+            (Some(None), UnlinkatFlags::RemoveDir) => {
+                return Err(nix::Error::Sys(Errno::ENOTDIR));
+            }
+            _ => {}
+        }
+        state.get_entries(dir)?.remove(name);
+        state.unlink_node(ino);
+        state.touch(dir)
+    }
+
+    fn rename_at(
+        &self,
+        old_dir: RawFd,
+        old_name: &OsStr,
+        new_dir: RawFd,
+        new_name: &OsStr,
+    ) -> nix::Result<()> {
+        let mut state = self.lock();
+        let ino = state.lookup(old_dir, old_name)?;
+        state.get_entries(new_dir)?;
+        state.get_entries(old_dir)?.remove(old_name);
+        if let Some(replaced) = state
+            .get_entries(new_dir)?
+            .insert(new_name.to_os_string(), ino)
+        {
+            state.unlink_node(replaced);
+        }
+        state.touch(old_dir)?;
+        state.touch(new_dir)
+    }
+
+    fn get_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<Vec<u8>> {
+        self.lock()
+            .get_node(fd)?
+            .xattrs
+            .get(name)
+            .cloned()
+            .ok_or(nix::Error::Sys(ENOATTR))
+    }
+
+    fn list_xattr(&self, fd: RawFd) -> nix::Result<Vec<OsString>> {
+        Ok(self.lock().get_node(fd)?.xattrs.keys().cloned().collect())
+    }
+
+    fn set_xattr(&self, fd: RawFd, name: &OsStr, value: &[u8], flags: c_int) -> nix::Result<()> {
+        let mut state = self.lock();
+        let xattrs = &mut state.get_node_mut(fd)?.xattrs;
+        let exists = xattrs.contains_key(name);
+        if flags & XATTR_CREATE != 0 && exists {
+            return Err(nix::Error::Sys(Errno::EEXIST));
+        }
+        if flags & XATTR_REPLACE != 0 && !exists {
+            return Err(nix::Error::Sys(ENOATTR));
+        }
+        xattrs.insert(name.to_os_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove_xattr(&self, fd: RawFd, name: &OsStr) -> nix::Result<()> {
+        self.lock()
+            .get_node_mut(fd)?
+            .xattrs
+            .remove(name)
+            .map(drop)
+            .ok_or(nix::Error::Sys(ENOATTR))
+    }
+
+    fn close(&self, fd: RawFd) -> nix::Result<()> {
+        let mut state = self.lock();
+        let ino = state.fds.remove(&fd).ok_or(nix::Error::Sys(Errno::EBADF))?;
+        state.may_remove_node(ino);
+        Ok(())
+    }
+
+    /// The names are looked up in memory, nothing leads out of the backend
+    fn confine(&self) -> nix::Result<()> {
+        Ok(())
+    }
+
+    /// The fds are not real
+    fn set_open_flags(&self, _flags: OpenFlags) {}
+
+    /// The memory has no fixed size
+    fn space(&self, _fd: RawFd) -> nix::Result<BackendSpace> {
+        Err(nix::Error::Sys(Errno::ENOSYS))
+    }
+}
@@ -0,0 +1,187 @@
+//! Mount options handled by memfs rather than by the mount, e.g. to remap the
+//! owners of the files. They are checked and listed along with the mount
+//! options of `fuse`, and split out of the options before mounting, `fuse`
+//! does not know them.
+
+use super::PRIVILEGED_XATTR_OPTION;
+use crate::fuse::{self, MountOptionInfo};
+use std::env;
+
+/// The platforms memfs runs on
+const ALL_PLATFORMS: &[&str] = &["linux", "android", "macos"];
+
+/// A mount option of memfs
+struct MemfsMountOption {
+    /// Name, with a placeholder for the value if any, e.g. `uid=<n>`
+    name: &'static str,
+    /// Check the value, `None` if the option takes no value
+    validator: Option<fn(&str) -> bool>,
+    /// Description
+    description: &'static str,
+    /// Platforms supporting the option
+    platforms: &'static [&'static str],
+}
+
+impl MemfsMountOption {
+    /// Whether the option is supported on this platform
+    fn is_supported(&self) -> bool {
+        self.platforms.contains(&env::consts::OS)
+    }
+
+    /// The name before `=`
+    fn key(&self) -> &'static str {
+        self.name.split('=').next().unwrap_or(self.name)
+    }
+
+    /// Whether the option is this one with a valid value
+    fn is_match(&self, option: &str) -> bool {
+        match self.validator {
+            None => option == self.name,
+            Some(validator) => option
+                .split_once('=')
+                .map_or(false, |(key, value)| key == self.key() && validator(value)),
+        }
+    }
+}
+
+/// Whether the value is an id, a 32 bits number
+fn is_id(value: &str) -> bool {
+    value.parse::<u32>().is_ok()
+}
+
+/// Whether the value is permission bits in octal
+fn is_mode(value: &str) -> bool {
+    u16::from_str_radix(value, 8).map_or(false, |mode| mode <= 0o777)
+}
+
+/// Whether the value is a path
+fn is_path(value: &str) -> bool {
+    !value.is_empty() && !value.contains(char::is_whitespace)
+}
+
+/// The mount options of memfs
+const MOUNT_OPTIONS: &[MemfsMountOption] = &[
+    MemfsMountOption {
+        name: PRIVILEGED_XATTR_OPTION,
+        validator: None,
+        description: "Allow the trusted.* and security.* extended attributes of memfs",
+        // the names of extended attributes have no namespace on macOS
+        platforms: &["linux", "android"],
+    },
+    MemfsMountOption {
+        name: "uid=<n>",
+        validator: Some(is_id),
+        description: "Report the files of memfs as owned by the user id",
+        platforms: ALL_PLATFORMS,
+    },
+    MemfsMountOption {
+        name: "gid=<n>",
+        validator: Some(is_id),
+        description: "Report the files of memfs as owned by the group id",
+        platforms: ALL_PLATFORMS,
+    },
+    MemfsMountOption {
+        name: "uid_map=<path>",
+        validator: Some(is_path),
+        description: "Map the user and group ids of memfs by the ranges in the file",
+        platforms: ALL_PLATFORMS,
+    },
+    MemfsMountOption {
+        name: "umask=<mode>",
+        validator: Some(is_mode),
+        description: "Clear the permission bits in octal from the files and directories of memfs",
+        platforms: ALL_PLATFORMS,
+    },
+    MemfsMountOption {
+        name: "fmask=<mode>",
+        validator: Some(is_mode),
+        description: "Clear the permission bits in octal from the files of memfs",
+        platforms: ALL_PLATFORMS,
+    },
+    MemfsMountOption {
+        name: "dmask=<mode>",
+        validator: Some(is_mode),
+        description: "Clear the permission bits in octal from the directories of memfs",
+        platforms: ALL_PLATFORMS,
+    },
+];
+
+/// Whether the option is one of memfs, whatever its value
+fn is_memfs_option(option: &str) -> bool {
+    let key = option.split('=').next().unwrap_or(option);
+    MOUNT_OPTIONS.iter().any(|op| op.key() == key)
+}
+
+/// List the mount options of `fuse` supported on this platform followed by
+/// the ones of memfs
+pub fn mount_options_info() -> Vec<MountOptionInfo> {
+    let mut options = fuse::mount_options_info();
+    options.extend(
+        MOUNT_OPTIONS
+            .iter()
+            .filter(|op| op.is_supported())
+            .map(|op| MountOptionInfo {
+                name: op.name.to_owned(),
+                description: op.description,
+                platforms: op.platforms,
+            }),
+    );
+    options
+}
+
+/// Check if the comma separated options are valid mount options of `fuse` or
+/// of memfs
+pub fn options_validator(option: &str) -> Result<(), String> {
+    let ret = option.split(',').all(|op| {
+        MOUNT_OPTIONS
+            .iter()
+            .any(|memfs_op| memfs_op.is_supported() && memfs_op.is_match(op))
+            || (!is_memfs_option(op) && fuse::options_validator(op).is_ok())
+    });
+    if ret {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid option \"{}\", valid options: {}",
+            option,
+            mount_options_info()
+                .into_iter()
+                .map(|op| op.name)
+                .collect::<Vec<_>>()
+                .join(",")
+        ))
+    }
+}
+
+/// Split the options into the ones of memfs and the ones to mount with
+pub fn split_mount_options<'a>(options: &[&'a str]) -> (Vec<&'a str>, Vec<&'a str>) {
+    options.iter().copied().partition(|op| is_memfs_option(op))
+}
+
+#[cfg(test)]
+mod test {
+    #[test]
+    fn test_mount_options() {
+        use super::{mount_options_info, options_validator, split_mount_options};
+
+        assert!(options_validator("ro,uid=1000,gid=1000,umask=077,uid_map=/etc/map").is_ok());
+        assert!(options_validator("uid=-1").is_err());
+        assert!(options_validator("umask=1000").is_err());
+        assert!(options_validator("fmask=").is_err());
+        assert!(options_validator("uid_map=").is_err());
+        assert!(options_validator("noatime,dmask").is_err());
+        assert_eq!(
+            options_validator("privileged_xattr").is_ok(),
+            cfg!(any(target_os = "linux", target_os = "android"))
+        );
+
+        let (memfs_options, mount_options) =
+            split_mount_options(&["ro", "uid=1000", "fsname=memfs", "dmask=022"]);
+        assert_eq!(memfs_options, ["uid=1000", "dmask=022"]);
+        assert_eq!(mount_options, ["ro", "fsname=memfs"]);
+
+        let options = mount_options_info();
+        assert!(options.iter().any(|op| op.name == "uid_map=<path>"));
+        assert!(options.iter().any(|op| op.name == "ro"));
+    }
+}
@@ -0,0 +1,269 @@
+//! Encoding of the names not in UTF-8
+//!
+//! The names on the backing store are arbitrary bytes but `/` and NUL, and
+//! they are passed through the mount point as is by default. Some clients,
+//! e.g. SMB or a desktop file manager, cannot show names not in UTF-8 or fail
+//! on them. The names may be hidden from such clients instead, or
+//! percent-encoded: the bytes not in UTF-8 and the `%` of such a name are
+//! written as `%XX`, e.g. `caf\xE9` is shown as `caf%E9`, and a name given
+//! through the mount point that decodes to a name not in UTF-8 is decoded
+//! back. The names in UTF-8 are always passed as is, so a backing name in
+//! UTF-8 which is the encoding of a name not in UTF-8, e.g. `caf%E9` itself,
+//! cannot be reached through the mount point.
+
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::str::{self, FromStr};
+
+/// The encoding of the names not in UTF-8 seen through the mount point
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NameEncoding {
+    /// The names are passed as is
+    Raw,
+    /// The names not in UTF-8 are hidden
+    Hide,
+    /// The names not in UTF-8 are percent-encoded
+    Percent,
+}
+
+impl Default for NameEncoding {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+impl FromStr for NameEncoding {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "raw" => Ok(Self::Raw),
+            "hide" => Ok(Self::Hide),
+            "percent" => Ok(Self::Percent),
+            _ => Err(format!(
+                "invalid name encoding {:?}, expected raw, hide or percent",
+                value
+            )),
+        }
+    }
+}
+
+impl NameEncoding {
+    /// The name seen through the mount point of a backing name, `None` if
+    /// the name is hidden
+    pub fn to_mounted(self, name: &OsStr) -> Option<Cow<'_, OsStr>> {
+        if self == Self::Raw || str::from_utf8(name.as_bytes()).is_ok() {
+            return Some(Cow::Borrowed(name));
+        }
+        match self {
+            Self::Raw | Self::Hide => None,
+            Self::Percent => Some(Cow::Owned(encode(name.as_bytes()))),
+        }
+    }
+
+    /// The backing name of a name given through the mount point, `None` if
+    /// the name is hidden
+    pub fn to_backing(self, name: &OsStr) -> Option<Cow<'_, OsStr>> {
+        let bytes = name.as_bytes();
+        match self {
+            Self::Raw => Some(Cow::Borrowed(name)),
+            Self::Hide => str::from_utf8(bytes).ok().map(|_| Cow::Borrowed(name)),
+            Self::Percent => Some(
+                decode(bytes)
+                    .filter(|decoded| {
+                        str::from_utf8(decoded).is_err() && encode(decoded).as_bytes() == bytes
+                    })
+                    .map_or(Cow::Borrowed(name), |decoded| {
+                        Cow::Owned(OsString::from_vec(decoded))
+                    }),
+            ),
+        }
+    }
+}
+
+/// Push the percent-encoding of `byte`
+fn push_encoded(encoded: &mut String, byte: u8) {
+    encoded.push_str(&format!("%{:02X}", byte));
+}
+
+/// Percent-encode the bytes not in UTF-8 and the `%` of a name
+fn encode(name: &[u8]) -> OsString {
+    let mut encoded = String::with_capacity(name.len());
+    for chunk in name.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '%' {
+                push_encoded(&mut encoded, b'%');
+            } else {
+                encoded.push(c);
+            }
+        }
+        for &byte in chunk.invalid() {
+            push_encoded(&mut encoded, byte);
+        }
+    }
+    OsString::from(encoded)
+}
+
+/// Decode the `%XX` of a name, `None` if a `%` is not followed by two hex
+/// digits
+fn decode(name: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [*bytes.next()?, *bytes.next()?];
+            let hex = str::from_utf8(&hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Cast, OverflowArithmetic};
+    use super::NameEncoding;
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::str;
+
+    /// Random names of the bytes but `/` and NUL, generated by xorshift with
+    /// a fixed seed to be reproducible
+    fn random_names(count: usize) -> Vec<Vec<u8>> {
+        let mut state = 0x2545_F491_4F6C_DD1D_u64;
+        let mut next = move || {
+            state ^= state.wrapping_shl(13);
+            state ^= state.wrapping_shr(7);
+            state ^= state.wrapping_shl(17);
+            state
+        };
+        (0..count)
+            .map(|_| {
+                let len = (next() & 0x0F).overflow_add(1);
+                (0..len)
+                    .map(|_| (next() & 0xFF).cast())
+                    .map(|byte| {
+                        if byte == b'/' || byte == 0 {
+                            b'%'
+                        } else {
+                            byte
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_name_encoding() {
+        let name = OsStr::from_bytes(b"caf\xE9 100%");
+        assert_eq!(
+            NameEncoding::Percent.to_mounted(name).as_deref(),
+            Some(OsStr::new("caf%E9 100%25"))
+        );
+        assert_eq!(
+            NameEncoding::Percent
+                .to_backing(OsStr::new("caf%E9 100%25"))
+                .as_deref(),
+            Some(name)
+        );
+        assert_eq!(NameEncoding::Hide.to_mounted(name), None);
+        assert_eq!(NameEncoding::Hide.to_backing(name), None);
+        assert_eq!(NameEncoding::Raw.to_mounted(name).as_deref(), Some(name));
+        // the names in UTF-8 are passed as is
+        for encoding in &[NameEncoding::Raw, NameEncoding::Hide, NameEncoding::Percent] {
+            for name in &["100%", "%41", "%E9", "%", "é"] {
+                let name = OsStr::new(name);
+                assert_eq!(encoding.to_mounted(name).as_deref(), Some(name));
+            }
+        }
+        // decoded only if it decodes to a name not in UTF-8
+        for name in &["100%", "%41", "%e9", "%E9%", "%%E9", "%C3%A9"] {
+            let name = OsStr::new(name);
+            assert_eq!(
+                NameEncoding::Percent.to_backing(name).as_deref(),
+                Some(name)
+            );
+        }
+        assert_eq!("percent".parse(), Ok(NameEncoding::Percent));
+        assert!("utf8".parse::<NameEncoding>().is_err());
+    }
+
+    #[test]
+    fn test_random_names_on_backend() {
+        use crate::memfs::backend::{Backend, LocalBackend};
+        use std::collections::BTreeSet;
+        use std::ffi::OsString;
+        use std::fs;
+        use std::path::Path;
+
+        const TEST_DIR: &str = "/tmp/fuse_test_random_names";
+        let test_dir = Path::new(TEST_DIR);
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(test_dir).unwrap_or_else(|_| panic!());
+        let names: BTreeSet<OsString> = random_names(200)
+            .into_iter()
+            .map(|name| OsStr::from_bytes(&name).to_os_string())
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        for name in &names {
+            fs::write(test_dir.join(name), name.as_bytes()).unwrap_or_else(|_| panic!());
+        }
+
+        // the names are read back as is
+        let backend = LocalBackend::new();
+        let fd = backend.open_dir(test_dir).unwrap_or_else(|_| panic!());
+        let (entries, _) = backend
+            .read_dir(fd, 0, names.len().overflow_add(2))
+            .unwrap_or_else(|_| panic!());
+        let read: BTreeSet<OsString> = entries
+            .into_iter()
+            .map(|(_, entry)| entry.name)
+            .filter(|name| name != "." && name != "..")
+            .collect();
+        assert_eq!(read, names);
+        for name in &names {
+            let entry = backend.stat_at(fd, name).unwrap_or_else(|_| panic!());
+            assert_eq!(&entry.name, name);
+        }
+        backend.close(fd).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(test_dir).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_name_encoding_random() {
+        for name in random_names(10000) {
+            let name = OsStr::from_bytes(&name);
+            let utf8 = str::from_utf8(name.as_bytes()).is_ok();
+            // the names round trip
+            for encoding in &[NameEncoding::Raw, NameEncoding::Hide, NameEncoding::Percent] {
+                match encoding.to_mounted(name) {
+                    // unless the name is the encoding of another
+                    Some(mounted) if *encoding == NameEncoding::Percent && utf8 => {
+                        let backing = encoding.to_backing(&mounted).unwrap_or_else(|| panic!());
+                        assert!(
+                            backing == name
+                                || encoding.to_mounted(&backing).as_deref() == Some(name)
+                        );
+                    }
+                    Some(mounted) => {
+                        assert_eq!(encoding.to_backing(&mounted).as_deref(), Some(name));
+                    }
+                    None => assert!(*encoding == NameEncoding::Hide && !utf8),
+                }
+            }
+            // the encoded names are in UTF-8 and a valid name
+            let mounted = NameEncoding::Percent
+                .to_mounted(name)
+                .unwrap_or_else(|| panic!());
+            assert!(str::from_utf8(mounted.as_bytes()).is_ok());
+            assert!(!mounted.as_bytes().contains(&b'/'));
+            assert_eq!(mounted == name, utf8);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! Preload of the backing tree at mount time
+//!
+//! The first access to a cold tree pays a backing syscall for every entry
+//! looked up and every file read. The preloader walks the backing tree on a
+//! background thread, stats the entries whose relative paths match a glob and
+//! reads the small files, which warms the caches of the backing store.
+//! The walk starts at the directory named by the literal prefix of the glob,
+//! so the rest of the tree is not walked at all.
+//! The caches of the filesystem are only touched on the session thread, so
+//! the walker hands the paths of the entries over to it through a bounded
+//! queue, which blocks the walker until the session adopts the directories
+//! while no request is pending. The files are left to be opened by the
+//! lookups, so the preload holds no fd of a file.
+//!
+//! Symbolic links are never followed, but bind mounts of an ancestor may still
+//! form a loop of directories, so the walk keeps the device and i-node numbers
+//! of the directories it is in and never descends into one of them again. The
+//! depth is bounded as well, for the stack of the walker.
+
+use log::debug;
+use nix::dir::Type;
+use nix::fcntl::OFlag;
+use nix::sys::stat::Mode;
+use regex::bytes::Regex;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+use super::backend::Backend;
+use super::{Cast, OverflowArithmetic, DIR_LOAD_BATCH_SIZE};
+
+/// The maximum depth of the directories walked, far deeper than a real tree
+/// and shallow enough for the stack of the walker
+const MAX_WALK_DEPTH: usize = 256;
+/// The maximum number of the walked paths queued for the session to adopt
+const PRELOAD_QUEUE_SIZE: usize = 1024;
+
+/// Preloader walking the backing tree on a background thread
+#[derive(Debug)]
+pub struct Preloader {
+    /// The relative paths of the walked entries, parents before children
+    paths: Receiver<PathBuf>,
+}
+
+impl Preloader {
+    /// Start walking the tree of the directory `root_fd` on `backend` for the
+    /// entries matching the glob `pattern`, reading at most `data_size` byte
+    /// of each file
+    pub fn spawn(
+        backend: Arc<dyn Backend>,
+        root_fd: RawFd,
+        pattern: &str,
+        data_size: u64,
+    ) -> nix::Result<Self> {
+        // safe to use panic!() here, because the literals are escaped
+        let matcher = Regex::new(&glob_to_regex(pattern)).unwrap_or_else(|_| panic!());
+        let prefix = literal_prefix(pattern);
+        let (sender, paths) = mpsc::sync_channel(PRELOAD_QUEUE_SIZE);
+        // the walker reads its own fd, so it never moves the offset of the root
+        let dir_fd = match open_prefix(&*backend, root_fd, &prefix) {
+            Ok(dir_fd) => dir_fd,
+            // nothing matches under a missing prefix, the queue is closed
+            Err(nix::Error::Sys(nix::errno::Errno::ENOENT))
+            | Err(nix::Error::Sys(nix::errno::Errno::ENOTDIR)) => return Ok(Self { paths }),
+            Err(e) => return Err(e),
+        };
+        thread::spawn(move || {
+            let walker = Walker {
+                backend,
+                matcher,
+                data_size,
+                sender,
+            };
+            // the walk stops once the filesystem is dropped
+            // the root is known to the backends resolving `.`
+            let mut ancestors: Vec<(u64, u64)> = walker
+                .backend
+                .stat_at(dir_fd, OsStr::new("."))
+                .map(|root| (root.dev, root.ino))
+                .into_iter()
+                .collect();
+            if let Err(e) = walker.walk(dir_fd, &prefix, 0, &mut ancestors) {
+                debug!("Preloader::spawn() stopped walking the tree: {:?}", e);
+            }
+            let _res = walker.backend.close(dir_fd);
+        });
+        Ok(Self { paths })
+    }
+
+    /// The next walked path if any, never blocks
+    pub fn next_path(&self) -> Option<PathBuf> {
+        self.paths.try_recv().ok()
+    }
+}
+
+/// Walker of the backing tree
+struct Walker {
+    /// The backend of the tree
+    backend: Arc<dyn Backend>,
+    /// The matcher of the relative paths
+    matcher: Regex,
+    /// The size of the data to read per file
+    data_size: u64,
+    /// The sender of the relative paths of the walked entries
+    sender: SyncSender<PathBuf>,
+}
+
+impl Walker {
+    /// Walk the directory `dir_fd` of `rel_path` recursively, sending the
+    /// relative paths of the matching regular files and directories. A path
+    /// names its parent directories, so the parents are adopted along with it.
+    /// The directories among the `ancestors` of the walk, by their device and
+    /// i-node numbers, and the ones beyond `MAX_WALK_DEPTH` below the prefix
+    /// are skipped.
+    fn walk(
+        &self,
+        dir_fd: RawFd,
+        rel_path: &Path,
+        depth: usize,
+        ancestors: &mut Vec<(u64, u64)>,
+    ) -> nix::Result<()> {
+        let mut offset = 0;
+        loop {
+            let (entries, next_offset) =
+                self.backend.read_dir(dir_fd, offset, DIR_LOAD_BATCH_SIZE)?;
+            for (_, entry) in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                // stat to warm the attributes of the entry, which also tells
+                // the type if the directory does not
+                let entry = match self.backend.stat_at(dir_fd, &entry.name) {
+                    Ok(entry) => entry,
+                    Err(_) => continue, // removed meanwhile
+                };
+                let child_path = rel_path.join(&entry.name);
+                if entry.file_type.is_some()
+                    && self.matcher.is_match(child_path.as_os_str().as_bytes())
+                {
+                    if entry.file_type == Some(Type::File) {
+                        self.read_small_file(dir_fd, &entry.name);
+                    }
+                    if self.sender.send(child_path.clone()).is_err() {
+                        return Err(nix::Error::Sys(nix::errno::Errno::EPIPE));
+                    }
+                }
+                if entry.file_type == Some(Type::Directory) && depth >= MAX_WALK_DEPTH {
+                    debug!(
+                        "Walker::walk() skipped {:?} beyond the depth of {}, e.g. a loop of bind mounts",
+                        child_path, MAX_WALK_DEPTH,
+                    );
+                } else if entry.file_type == Some(Type::Directory)
+                    && ancestors.contains(&(entry.dev, entry.ino))
+                {
+                    debug!(
+                        "Walker::walk() skipped {:?} already walked above it, e.g. a bind mount of an ancestor",
+                        child_path,
+                    );
+                } else if entry.file_type == Some(Type::Directory) {
+                    if let Ok(child_fd) = self.backend.open_dir_at(dir_fd, &entry.name) {
+                        ancestors.push((entry.dev, entry.ino));
+                        let res =
+                            self.walk(child_fd, &child_path, depth.overflow_add(1), ancestors);
+                        let _ancestor = ancestors.pop();
+                        let _res = self.backend.close(child_fd);
+                        res?;
+                    }
+                }
+            }
+            match next_offset {
+                Some(next_offset) => offset = next_offset,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Read the file of `name` under the directory `dir_fd` if it is within
+    /// the data size, the file may be gone or unreadable meanwhile
+    fn read_small_file(&self, dir_fd: RawFd, name: &OsStr) {
+        if self.data_size == 0 {
+            return;
+        }
+        let fd = match self
+            .backend
+            .open_at(dir_fd, name, OFlag::O_RDONLY, Mode::empty())
+        {
+            Ok(fd) => fd,
+            Err(_) => return,
+        };
+        if let Ok(attr) = self.backend.fstat(fd) {
+            if attr.size <= self.data_size {
+                let mut buf = vec![0_u8; attr.size.cast()];
+                let mut read = 0_usize;
+                while let Some(rest) = buf.get_mut(read..) {
+                    match self.backend.read_at(fd, rest, read.cast()) {
+                        Ok(size) if size > 0 => read = read.overflow_add(size),
+                        Ok(_) | Err(_) => break,
+                    }
+                }
+            }
+        }
+        let _res = self.backend.close(fd);
+    }
+}
+
+/// Open the directory of the relative `prefix` under the directory `root_fd`
+/// a component at a time, as the backend may refuse to resolve a path
+fn open_prefix(backend: &dyn Backend, root_fd: RawFd, prefix: &Path) -> nix::Result<RawFd> {
+    let mut dir_fd = backend.open_dir_at(root_fd, OsStr::new("."))?;
+    for name in prefix.iter() {
+        let res = backend.open_dir_at(dir_fd, name);
+        let _res = backend.close(dir_fd);
+        dir_fd = res?;
+    }
+    Ok(dir_fd)
+}
+
+/// The directory named by the leading components of the glob without a
+/// wildcard, the last component may name a file and is never part of it
+fn literal_prefix(pattern: &str) -> PathBuf {
+    let mut components: Vec<&str> = pattern.split('/').collect();
+    let _last = components.pop();
+    components
+        .into_iter()
+        .take_while(|component| *component != ".." && !component.contains(|c| c == '*' || c == '?'))
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect()
+}
+
+/// Convert the glob to an anchored regular expression of bytes, `**` matches
+/// any path, `*` and `?` match within a path component
+fn glob_to_regex(pattern: &str) -> String {
+    // match the bytes of the paths, which may not be in UTF-8
+    let mut regex = String::from("(?s-u)^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => {
+                if chars.peek() == Some(&'*') {
+                    let _star = chars.next();
+                    if chars.peek() == Some(&'/') {
+                        // `**/` also matches no directory at all
+                        let _slash = chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                } else {
+                    regex.push_str("[^/]*");
+                }
+            }
+            // a character, or a byte not in UTF-8
+            '?' => regex.push_str("(?:(?u:[^/])|[^/])"),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod test {
+    use regex::bytes::Regex;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::Arc;
+
+    use super::super::backend::{Backend, LocalBackend};
+    use super::{glob_to_regex, literal_prefix, Preloader, MAX_WALK_DEPTH};
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |pattern: &str, path: &str| {
+            Regex::new(&glob_to_regex(pattern))
+                .unwrap_or_else(|_| panic!())
+                .is_match(path.as_bytes())
+        };
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("a/*.rs", "a/b.rs"));
+        assert!(!matches("a/*.rs", "a/b/c.rs"));
+        assert!(matches("a/**/*.rs", "a/b/c.rs"));
+        assert!(matches("a/**/*.rs", "a/b.rs"));
+        assert!(matches("a/?.rs", "a/b.rs"));
+        assert!(!matches("a/?.rs", "a/bc.rs"));
+        assert!(!matches("a.rs", "abrs"));
+        assert!(matches("[a]", "[a]"));
+        assert!(matches("é/*", "é/ü"));
+        assert!(matches("é/?", "é/ü"));
+        let matches_bytes = |pattern: &str, path: &[u8]| {
+            Regex::new(&glob_to_regex(pattern))
+                .unwrap_or_else(|_| panic!())
+                .is_match(path)
+        };
+        assert!(matches_bytes("a/*.rs", b"a/\xE9\n.rs"));
+        assert!(matches_bytes("a/?.rs", b"a/\xE9.rs"));
+        assert!(!matches_bytes("a/*.rs", b"a/\xE9/.rs"));
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix("**"), PathBuf::new());
+        assert_eq!(literal_prefix("a.rs"), PathBuf::new());
+        assert_eq!(literal_prefix("a/b/*.rs"), PathBuf::from("a/b"));
+        assert_eq!(literal_prefix("a/b/c.rs"), PathBuf::from("a/b"));
+        assert_eq!(literal_prefix("a/**/b/*.rs"), PathBuf::from("a"));
+        assert_eq!(literal_prefix("./a//b?/c"), PathBuf::from("a"));
+        // never walks out of the root
+        assert_eq!(literal_prefix("a/../../b/c"), PathBuf::from("a"));
+    }
+
+    #[test]
+    fn test_preloader() {
+        let root = Path::new("/tmp/fuse_test_preloader");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(root.join("a/b")).unwrap_or_else(|_| panic!());
+        fs::write(root.join("a/b/c.txt"), b"data").unwrap_or_else(|_| panic!());
+        fs::write(root.join("a/d.bin"), b"data").unwrap_or_else(|_| panic!());
+
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        let root_fd = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let preloader = Preloader::spawn(Arc::clone(&backend), root_fd, "a/**/*.txt", 4)
+            .unwrap_or_else(|_| panic!());
+        // wait for the walker to finish
+        let paths: Vec<PathBuf> = preloader.paths.iter().collect();
+        assert_eq!(paths, vec![PathBuf::from("a/b/c.txt")]);
+        assert!(preloader.next_path().is_none());
+        // the walk starts at the prefix
+        let preloader = Preloader::spawn(Arc::clone(&backend), root_fd, "a/b/*", 0)
+            .unwrap_or_else(|_| panic!());
+        let paths: Vec<PathBuf> = preloader.paths.iter().collect();
+        assert_eq!(paths, vec![PathBuf::from("a/b/c.txt")]);
+        // nothing is walked under a missing prefix
+        let preloader = Preloader::spawn(Arc::clone(&backend), root_fd, "missing/*", 0)
+            .unwrap_or_else(|_| panic!());
+        assert_eq!(preloader.paths.iter().count(), 0);
+
+        backend.close(root_fd).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_preload_loop() {
+        let root = Path::new("/tmp/fuse_test_preload_loop");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        fs::create_dir_all(root.join("a/loop")).unwrap_or_else(|_| panic!());
+        fs::write(root.join("a/file"), b"data").unwrap_or_else(|_| panic!());
+        // a bind mount of an ancestor loops, which needs root
+        let looped = Command::new("mount")
+            .arg("--bind")
+            .arg(root.join("a"))
+            .arg(root.join("a/loop"))
+            .status()
+            .map_or(false, |status| status.success());
+        if !looped {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+            return;
+        }
+
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        let root_fd = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let preloader =
+            Preloader::spawn(Arc::clone(&backend), root_fd, "**", 0).unwrap_or_else(|_| panic!());
+        let mut paths: Vec<PathBuf> = preloader.paths.iter().collect();
+        paths.sort();
+        // the looping directory is listed but not walked again
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("a/file"),
+                PathBuf::from("a/loop"),
+            ]
+        );
+
+        backend.close(root_fd).unwrap_or_else(|_| panic!());
+        let _status = Command::new("umount").arg(root.join("a/loop")).status();
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+
+    #[test]
+    fn test_preload_depth() {
+        let root = Path::new("/tmp/fuse_test_preload_depth");
+        if root.exists() {
+            fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+        }
+        let deepest: PathBuf = (0..MAX_WALK_DEPTH + 3).map(|_| "d").collect();
+        fs::create_dir_all(root.join(&deepest)).unwrap_or_else(|_| panic!());
+
+        let backend: Arc<dyn Backend> = Arc::new(LocalBackend::new());
+        let root_fd = backend.open_dir(root).unwrap_or_else(|_| panic!());
+        let preloader =
+            Preloader::spawn(Arc::clone(&backend), root_fd, "**", 0).unwrap_or_else(|_| panic!());
+        let paths: Vec<PathBuf> = preloader.paths.iter().collect();
+        // the directories below the max depth are listed but not walked
+        assert_eq!(paths.len(), MAX_WALK_DEPTH + 1);
+        let depth = paths.last().map(|path| path.components().count());
+        assert_eq!(depth, Some(MAX_WALK_DEPTH + 1));
+
+        backend.close(root_fd).unwrap_or_else(|_| panic!());
+        fs::remove_dir_all(root).unwrap_or_else(|_| panic!());
+    }
+}
@@ -0,0 +1,184 @@
+//! Tracing of the reference counts of the i-nodes
+//!
+//! An i-node is dropped only once the kernel forgets all its lookups and
+//! closes all its handles, so a count never dropping to zero leaks the node
+//! along with its fd and cached data. With tracing enabled, every change of
+//! the lookup count and the open count is written to a trace file along with
+//! the request causing it, and the table of the live counts is kept to be
+//! dumped to the same file on demand and on unmount.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The kind of the reference count
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RefCountKind {
+    /// The lookups of the kernel
+    Lookup,
+    /// The handles of the i-node, including its own
+    Open,
+}
+
+/// The state of the tracer
+#[derive(Debug, Default)]
+struct TraceState {
+    /// The trace file, tracing is disabled without it
+    out: Option<LineWriter<File>>,
+    /// The lookup count and the open count of the live i-nodes
+    live: BTreeMap<u64, (i64, i64)>,
+}
+
+/// Tracer of the reference counts, shared by the i-nodes
+#[derive(Debug, Default)]
+pub struct RefCountTracer {
+    /// Whether tracing is enabled, checked before taking the state lock
+    enabled: AtomicBool,
+    /// The unique id of the request being handled
+    request: AtomicU64,
+    /// The state
+    state: Mutex<TraceState>,
+}
+
+impl RefCountTracer {
+    /// Start tracing to the file of `path`, appending to it if it exists
+    pub fn enable(&self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.lock().out = Some(LineWriter::new(file));
+        self.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether tracing is enabled
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Set the unique id of the request being handled, which is traced as the
+    /// cause of the following changes
+    pub fn set_request(&self, unique: u64) {
+        self.request.store(unique, Ordering::Relaxed);
+    }
+
+    /// Trace the change of the count of `kind` of the i-node `ino` from
+    /// `previous` to `count`
+    pub fn record(&self, ino: u64, kind: RefCountKind, previous: i64, count: i64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let request = self.request.load(Ordering::Relaxed);
+        let mut state = self.lock();
+        let state = &mut *state;
+        if let Some(ref mut out) = state.out {
+            let counts = state.live.entry(ino).or_insert((0, 0));
+            match kind {
+                RefCountKind::Lookup => counts.0 = count,
+                RefCountKind::Open => counts.1 = count,
+            }
+            if *counts == (0, 0) {
+                let _counts = state.live.remove(&ino);
+            }
+            // the trace is best effort
+            let _res = writeln!(
+                out,
+                "request={} ino={} {:?} {} -> {}",
+                request, ino, kind, previous, count,
+            );
+        }
+    }
+
+    /// Trace the drop of the i-node `ino`
+    pub fn record_drop(&self, ino: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        let request = self.request.load(Ordering::Relaxed);
+        let mut state = self.lock();
+        let state = &mut *state;
+        if let Some(ref mut out) = state.out {
+            let (lookup_count, open_count) = state.live.remove(&ino).unwrap_or((0, 0));
+            let _res = writeln!(
+                out,
+                "request={} ino={} dropped with lookup count {} and open count {}",
+                request, ino, lookup_count, open_count,
+            );
+        }
+    }
+
+    /// Dump the table of the i-nodes of nonzero counts to the trace file,
+    /// returns the number of the i-nodes
+    pub fn dump(&self) -> io::Result<usize> {
+        let request = self.request.load(Ordering::Relaxed);
+        let mut state = self.lock();
+        let state = &mut *state;
+        let out = if let Some(ref mut out) = state.out {
+            out
+        } else {
+            return Ok(0);
+        };
+        writeln!(out, "request={} {} live i-nodes", request, state.live.len())?;
+        for (ino, &(lookup_count, open_count)) in &state.live {
+            writeln!(
+                out,
+                "ino={} lookup count {} open count {}",
+                ino, lookup_count, open_count,
+            )?;
+        }
+        Ok(state.live.len())
+    }
+
+    /// Lock the state
+    fn lock(&self) -> std::sync::MutexGuard<'_, TraceState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|_| panic!("RefCountTracer found the state lock poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{RefCountKind, RefCountTracer};
+
+    #[test]
+    fn test_refcount_tracer() {
+        let path = Path::new("/tmp/fuse_test_refcount_tracer");
+        if path.exists() {
+            fs::remove_file(path).unwrap_or_else(|_| panic!());
+        }
+        let tracer = RefCountTracer::default();
+        // nothing is traced until enabled
+        tracer.record(2, RefCountKind::Lookup, 0, 1);
+        assert_eq!(tracer.dump().unwrap_or_else(|_| panic!()), 0);
+        assert!(!tracer.is_enabled());
+
+        tracer.enable(path).unwrap_or_else(|_| panic!());
+        assert!(tracer.is_enabled());
+        tracer.set_request(7);
+        tracer.record(2, RefCountKind::Lookup, 0, 1);
+        tracer.record(2, RefCountKind::Open, 0, 1);
+        tracer.record(3, RefCountKind::Lookup, 0, 1);
+        tracer.set_request(8);
+        tracer.record(3, RefCountKind::Lookup, 1, 0);
+        tracer.record_drop(3);
+        assert_eq!(tracer.dump().unwrap_or_else(|_| panic!()), 1);
+        let trace = fs::read_to_string(path).unwrap_or_else(|_| panic!());
+        assert_eq!(
+            trace,
+            "request=7 ino=2 Lookup 0 -> 1\n\
+             request=7 ino=2 Open 0 -> 1\n\
+             request=7 ino=3 Lookup 0 -> 1\n\
+             request=8 ino=3 Lookup 1 -> 0\n\
+             request=8 ino=3 dropped with lookup count 0 and open count 0\n\
+             request=8 1 live i-nodes\n\
+             ino=2 lookup count 1 open count 1\n"
+        );
+
+        fs::remove_file(path).unwrap_or_else(|_| panic!());
+    }
+}
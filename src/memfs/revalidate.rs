@@ -0,0 +1,177 @@
+//! Revalidation of the cached attributes against the backing store
+//!
+//! The files of the backing store may be changed behind the back of the
+//! filesystem, by another process or on another host sharing the store, and
+//! the cached attributes and data would never notice. Watching the whole tree
+//! with inotify is costly, instead the revalidator picks a few cached i-nodes
+//! once per interval, round robin in i-node order, to be stated again. The
+//! caches are only touched on the session thread, so the revalidation runs
+//! while the session is idle like the preload.
+//!
+//! The changes through the filesystem change the attributes on disk too, so
+//! each i-node keeps a stamp of its size and times in the cache and on disk at
+//! its last revalidation. The i-node is changed behind the back of the
+//! filesystem if the ones on disk changed since, while the cached ones did not.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::fuse::FileAttr;
+
+/// The size, the modification time and the change time of an i-node
+type Times = (u64, SystemTime, SystemTime);
+
+/// The size and the times of the attributes
+const fn times(attr: &FileAttr) -> Times {
+    (attr.size, attr.mtime, attr.ctime)
+}
+
+/// The size and the times of an i-node in the cache and on disk at its last
+/// revalidation
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Stamp {
+    /// The ones in the cache
+    cached: Times,
+    /// The ones on disk
+    disk: Times,
+}
+
+impl Stamp {
+    /// The stamp of the attributes just read from disk
+    pub const fn new(attr: &FileAttr) -> Self {
+        Self {
+            cached: times(attr),
+            disk: times(attr),
+        }
+    }
+
+    /// Check the `cached` attributes against the ones on `disk`, returns
+    /// whether the i-node is changed behind the back of the filesystem since
+    /// the stamp, along with the new stamp
+    pub fn check(self, cached: &FileAttr, disk: &FileAttr) -> (bool, Self) {
+        let stamp = Self {
+            cached: times(cached),
+            disk: times(disk),
+        };
+        // a change through the filesystem changes the cached ones as well
+        let changed = stamp.cached == self.cached && stamp.disk != self.disk;
+        (changed, stamp)
+    }
+}
+
+/// Revalidator sampling the cached i-nodes
+#[derive(Debug)]
+pub struct Revalidator {
+    /// The interval between samples
+    interval: Duration,
+    /// The number of the i-nodes per sample
+    sample_size: usize,
+    /// The time of the last sample
+    last_sample: Instant,
+    /// The next sample starts after this ino
+    cursor: u64,
+}
+
+impl Revalidator {
+    /// New revalidator taking `sample_size` i-nodes every `interval`
+    pub fn new(interval: Duration, sample_size: usize) -> Self {
+        Self {
+            interval,
+            sample_size,
+            last_sample: Instant::now(),
+            cursor: 0,
+        }
+    }
+
+    /// Take the next sample of the cached `inos` if the interval is elapsed,
+    /// the smallest ones after the last sample, starting over from the
+    /// smallest once all of them are sampled
+    pub fn sample(&mut self, inos: impl Iterator<Item = u64>) -> Vec<u64> {
+        let now = Instant::now();
+        if now.duration_since(self.last_sample) < self.interval {
+            return Vec::new();
+        }
+        self.last_sample = now;
+        let cached: Vec<u64> = inos.collect();
+        let mut sample = self.helper_sample_after(&cached, self.cursor);
+        if sample.is_empty() {
+            sample = self.helper_sample_after(&cached, 0);
+        }
+        self.cursor = match sample.last() {
+            Some(&last) if sample.len() == self.sample_size => last,
+            _ => 0,
+        };
+        sample
+    }
+
+    /// Helper pick the smallest of `inos` after `cursor`, sorted
+    fn helper_sample_after(&self, inos: &[u64], cursor: u64) -> Vec<u64> {
+        let mut sample: Vec<u64> = inos.iter().copied().filter(|&ino| ino > cursor).collect();
+        if sample.len() > self.sample_size {
+            let _partition = sample.select_nth_unstable(self.sample_size);
+            sample.truncate(self.sample_size);
+        }
+        sample.sort_unstable();
+        sample
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Revalidator, Stamp};
+    use crate::fuse::{FileAttr, FileType};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn test_stamp() {
+        let attr = FileAttr {
+            ino: 2,
+            size: 3,
+            blocks: 1,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        let stamp = Stamp::new(&attr);
+        assert_eq!(stamp.check(&attr, &attr), (false, stamp));
+        // chmod behind the back of the filesystem only changes the change time
+        let chmod = FileAttr {
+            ctime: SystemTime::now(),
+            ..attr
+        };
+        assert!(stamp.check(&attr, &chmod).0);
+        // a write through the filesystem changes the cache along with the disk
+        let write = FileAttr {
+            size: 5,
+            mtime: SystemTime::now(),
+            ..attr
+        };
+        let (changed, stamp) = stamp.check(&write, &write);
+        assert!(!changed);
+        assert_eq!(stamp.check(&write, &write), (false, stamp));
+    }
+
+    #[test]
+    fn test_revalidator_sample() {
+        let inos = [9, 1, 5, 3, 7, 2];
+        let mut revalidator = Revalidator::new(Duration::from_secs(0), 2);
+        assert_eq!(revalidator.sample(inos.iter().copied()), vec![1, 2]);
+        assert_eq!(revalidator.sample(inos.iter().copied()), vec![3, 5]);
+        assert_eq!(revalidator.sample(inos.iter().copied()), vec![7, 9]);
+        // nothing after 9, start over
+        assert_eq!(revalidator.sample(inos.iter().copied()), vec![1, 2]);
+        assert_eq!(revalidator.sample([4, 3].iter().copied()), vec![3, 4]);
+        assert_eq!(revalidator.sample([1].iter().copied()), vec![1]);
+
+        // not before the interval is elapsed
+        let mut revalidator = Revalidator::new(Duration::from_secs(3600), 2);
+        assert_eq!(revalidator.sample(inos.iter().copied()), vec![]);
+    }
+}
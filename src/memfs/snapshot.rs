@@ -0,0 +1,677 @@
+//! Read-only snapshots of the file tree
+//!
+//! Snapshots are opt in, served under a hidden directory of the mount point
+//! named by `set_dir_name()`, e.g. `.snapshots`: `mkdir .snapshots/<name>`
+//! takes a snapshot of the whole tree and `rmdir .snapshots/<name>` drops it.
+//! A snapshot shares the cached data of the files with the live tree, the live
+//! files copy the shared data on write. The data not cached yet is not read
+//! when the snapshot is taken, the snapshot keeps an fd of the backing file
+//! and reads from it until the data is loaded, either while memfs is idle or
+//! before memfs modifies the live file. The changes made to the backing files
+//! behind memfs show through such a file until it is loaded.
+//!
+//! Snapshot nodes have i-node numbers of their own, counted down from
+//! `SNAPSHOT_ROOT_INO` at the top of the i-node numbers, away from the ones
+//! the backing filesystems hand out from the bottom. The data of the snapshot
+//! files is spilled to the spill file, if any, once the cache cannot grow.
+
+use super::backend::Backend;
+use super::backing::BackingIo;
+use super::chunk::{CacheUsage, ChunkStore, FileData};
+use super::spill::{SpillExtent, SpillFile};
+use super::{Cast, FileAttr, FileType, OverflowArithmetic};
+use log::debug;
+use nix::errno::Errno;
+use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The i-node number of the directory of the snapshots, the snapshot nodes
+/// take the numbers below it
+pub const SNAPSHOT_ROOT_INO: u64 = u64::MAX;
+/// Maximum number of the files read from the backing files, the data of the
+/// files beyond it is loaded when the snapshot is taken
+pub const MAX_PENDING_FILES: usize = 256;
+
+/// Backing file of a snapshot file, whose data is not loaded yet
+#[derive(Debug)]
+pub struct PendingFile {
+    /// Fd of the backing file
+    pub fd: RawFd,
+    /// Backend of the fd
+    pub backend: Arc<dyn Backend>,
+    /// The i-node number of the live file
+    pub live_ino: u64,
+}
+
+/// Content of a snapshot node
+#[derive(Debug)]
+enum SnapshotContent {
+    /// Entries of a directory, the i-node number of each child by name
+    Dir(BTreeMap<OsString, u64>),
+    /// Data of a file, shared with the live file until either is modified
+    File(FileData),
+    /// Data of a file spilled to the spill file
+    Spilled(SpillExtent),
+    /// Data of a file not loaded yet, read from the backing file
+    Pending(PendingFile),
+}
+
+/// Snapshot node
+#[derive(Debug)]
+struct SnapshotNode {
+    /// Attr, as of the time the snapshot was taken
+    attr: FileAttr,
+    /// Content
+    content: SnapshotContent,
+}
+
+/// All the snapshots, the nodes of which are indexed by ino
+#[derive(Debug)]
+pub struct Snapshots {
+    /// Name of the directory of the snapshots under the root directory,
+    /// `None` if snapshots are disabled
+    dir_name: Option<OsString>,
+    /// Nodes, including the directory of the snapshots
+    nodes: FxHashMap<u64, SnapshotNode>,
+    /// The i-node number of the next node, counted down
+    next_ino: u64,
+    /// The snapshot files of each live file whose data is not loaded yet
+    pending: FxHashMap<u64, Vec<u64>>,
+    /// The spill file of the data evicted from memory
+    spill: Option<SpillFile>,
+}
+
+impl Snapshots {
+    /// New without snapshot and disabled, the directory of the snapshots
+    /// takes the attributes of the root directory
+    pub fn new(root_attr: &FileAttr) -> Self {
+        let mut nodes = FxHashMap::default();
+        nodes.insert(
+            SNAPSHOT_ROOT_INO,
+            SnapshotNode {
+                attr: FileAttr {
+                    ino: SNAPSHOT_ROOT_INO,
+                    nlink: 2,
+                    ..*root_attr
+                },
+                content: SnapshotContent::Dir(BTreeMap::new()),
+            },
+        );
+        Self {
+            dir_name: None,
+            nodes,
+            next_ino: SNAPSHOT_ROOT_INO.overflow_sub(1),
+            pending: FxHashMap::default(),
+            spill: None,
+        }
+    }
+
+    /// Serve the snapshots under the directory of name under the root
+    /// directory
+    pub fn set_dir_name(&mut self, name: &OsStr) {
+        self.dir_name = Some(name.to_os_string());
+    }
+
+    /// Whether name is the directory of the snapshots under the root directory
+    pub fn is_dir_name(&self, name: &OsStr) -> bool {
+        self.dir_name.as_deref() == Some(name)
+    }
+
+    /// Whether the i-node of ino is a snapshot node, including the ones
+    /// dropped, always false if snapshots are disabled
+    pub fn owns(&self, ino: u64) -> bool {
+        self.dir_name.is_some() && ino > self.next_ino
+    }
+
+    /// Whether the i-node of ino is the directory of the snapshots
+    pub fn is_root(&self, ino: u64) -> bool {
+        self.owns(ino) && ino == SNAPSHOT_ROOT_INO
+    }
+
+    /// The number of the snapshot files whose data is not loaded yet
+    pub fn pending_count(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Whether the data of one more file can be read from its backing file
+    /// rather than loaded
+    pub fn can_defer(&self) -> bool {
+        self.pending_count() < MAX_PENDING_FILES
+    }
+
+    /// Spill the data of the snapshot files to the spill file once the cache
+    /// cannot grow
+    pub fn set_spill(&mut self, spill: SpillFile) {
+        self.spill = Some(spill);
+    }
+
+    /// Whether the data of the snapshot files is spilled once the cache
+    /// cannot grow
+    pub const fn has_spill(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Memory usage of the contiguous data only referenced by the snapshots,
+    /// the chunks are counted by the chunk store
+    pub fn usage(&self, store: &ChunkStore) -> CacheUsage {
+        self.nodes
+            .values()
+            .fold(CacheUsage::default(), |usage, node| match node.content {
+                SnapshotContent::File(ref data @ FileData::Flat(_)) if data.is_exclusive(store) => {
+                    usage.add(data.usage())
+                }
+                SnapshotContent::File(_)
+                | SnapshotContent::Dir(_)
+                | SnapshotContent::Spilled(_)
+                | SnapshotContent::Pending(_) => usage,
+            })
+    }
+
+    /// The attributes of the directory of the snapshots
+    pub fn root_attr(&self) -> FileAttr {
+        self.get_attr(SNAPSHOT_ROOT_INO)
+            .unwrap_or_else(|| panic!("Snapshots found the snapshot directory missing"))
+    }
+
+    /// Whether there is a snapshot of name
+    pub fn contains(&self, name: &OsStr) -> bool {
+        self.lookup(SNAPSHOT_ROOT_INO, name).is_some()
+    }
+
+    /// The attributes of the node of ino, `None` if it is dropped
+    pub fn get_attr(&self, ino: u64) -> Option<FileAttr> {
+        self.nodes.get(&ino).map(|node| node.attr)
+    }
+
+    /// Look up the child of name of the directory of parent
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<FileAttr> {
+        match self.nodes.get(&parent)?.content {
+            SnapshotContent::Dir(ref entries) => self.get_attr(*entries.get(name)?),
+            SnapshotContent::File(_)
+            | SnapshotContent::Spilled(_)
+            | SnapshotContent::Pending(_) => None,
+        }
+    }
+
+    /// Read the entries of the directory of ino from `offset`, each along with
+    /// the offset after it, until `func` returns true, returns false if the
+    /// directory is dropped
+    pub fn read_dir(
+        &self,
+        ino: u64,
+        offset: i64,
+        mut func: impl FnMut(i64, u64, FileType, &OsStr) -> bool,
+    ) -> bool {
+        let entries = match self.nodes.get(&ino).map(|node| &node.content) {
+            Some(SnapshotContent::Dir(entries)) => entries,
+            Some(
+                SnapshotContent::File(_)
+                | SnapshotContent::Spilled(_)
+                | SnapshotContent::Pending(_),
+            )
+            | None => return false,
+        };
+        for (idx, (name, child_ino)) in entries.iter().enumerate() {
+            let next_offset = idx.overflow_add(1).cast::<i64>();
+            if next_offset <= offset {
+                continue;
+            }
+            if let Some(child) = self.nodes.get(child_ino) {
+                if func(next_offset, *child_ino, child.attr.kind, name) {
+                    break;
+                }
+            }
+        }
+        true
+    }
+
+    /// Read at most `size` byte from `offset` of the file of ino and pass the
+    /// slices to `func`, or `ENOENT` if it is dropped and the error if the data
+    /// is spilled or not loaded and cannot be read
+    pub fn read_file<R>(
+        &self,
+        ino: u64,
+        store: &ChunkStore,
+        io: &BackingIo,
+        offset: usize,
+        size: usize,
+        func: impl FnOnce(nix::Result<&[&[u8]]>) -> R,
+    ) -> R {
+        let node = match self.nodes.get(&ino) {
+            Some(node) => node,
+            None => return func(Err(nix::Error::Sys(Errno::ENOENT))),
+        };
+        match node.content {
+            SnapshotContent::File(ref data) => {
+                data.read_slices(store, offset, size, |slices| func(Ok(slices)))
+            }
+            SnapshotContent::Spilled(extent) => {
+                match self.helper_spill().read(extent, offset, size) {
+                    Ok(data) => func(Ok(&[&data])),
+                    Err(e) => func(Err(e)),
+                }
+            }
+            SnapshotContent::Pending(ref file) => {
+                // the backing file may have grown behind memfs
+                let file_size: usize = node.attr.size.cast();
+                let size = size.min(file_size.saturating_sub(offset));
+                match io.pread(&file.backend, file.fd, size, offset.cast()) {
+                    Ok(data) => func(Ok(&[&data])),
+                    Err(e) => func(Err(e)),
+                }
+            }
+            SnapshotContent::Dir(_) => func(Err(nix::Error::Sys(Errno::ENOENT))),
+        }
+    }
+
+    /// Helper get the spill file, which holds the spilled data
+    fn helper_spill(&self) -> &SpillFile {
+        self.spill
+            .as_ref()
+            .unwrap_or_else(|| panic!("Snapshots found spilled data without a spill file"))
+    }
+
+    /// Spill the data of the file of ino out of memory, returns the estimated
+    /// byte size of the memory released, zero if the data is shared with a live
+    /// file or already spilled. Fails with `ENOSPC` if there is no spill file
+    /// or it is full.
+    pub fn spill_file(&mut self, ino: u64, store: &mut ChunkStore) -> nix::Result<usize> {
+        let spill = self.spill.as_mut().ok_or(nix::Error::Sys(Errno::ENOSPC))?;
+        let content = match self.nodes.get_mut(&ino) {
+            Some(node) => &mut node.content,
+            None => return Ok(0),
+        };
+        let data = match content {
+            SnapshotContent::File(data) if data.is_exclusive(store) => data,
+            SnapshotContent::File(_)
+            | SnapshotContent::Spilled(_)
+            | SnapshotContent::Dir(_)
+            | SnapshotContent::Pending(_) => return Ok(0),
+        };
+        let extent = data.read_slices(store, 0, data.len(), |slices| spill.spill(slices))?;
+        let released = data.usage().allocated.max(data.len());
+        data.release(store);
+        *content = SnapshotContent::Spilled(extent);
+        Ok(released)
+    }
+
+    /// Spill the data of the snapshot files out of memory until `excess` byte
+    /// of memory is released, returns the estimated byte size released
+    pub fn spill_files(&mut self, store: &mut ChunkStore, excess: usize) -> usize {
+        let inos: Vec<u64> = self
+            .nodes
+            .iter()
+            .filter_map(|(ino, node)| match node.content {
+                SnapshotContent::File(ref data) if !data.is_empty() => Some(*ino),
+                SnapshotContent::File(_)
+                | SnapshotContent::Spilled(_)
+                | SnapshotContent::Dir(_)
+                | SnapshotContent::Pending(_) => None,
+            })
+            .collect();
+        let mut released = 0_usize;
+        for ino in inos {
+            if released >= excess {
+                break;
+            }
+            match self.spill_file(ino, store) {
+                Ok(size) => released = released.overflow_add(size),
+                Err(e) => {
+                    debug!(
+                        "spill_files() failed to spill the snapshot file of ino={}, the error is: {:?}",
+                        ino, e
+                    );
+                    break;
+                }
+            }
+        }
+        debug!(
+            "spill_files() released {} byte of memory, {} byte spilled in total",
+            released,
+            self.spill.as_ref().map_or(0, SpillFile::used),
+        );
+        released
+    }
+
+    /// Add a snapshot of name, returns the i-node number of its directory, to
+    /// which the captured nodes are added
+    pub fn add_snapshot(&mut self, name: &OsStr, root_attr: &FileAttr) -> u64 {
+        let ino = self.helper_add_node(
+            SNAPSHOT_ROOT_INO,
+            name,
+            root_attr,
+            SnapshotContent::Dir(BTreeMap::new()),
+        );
+        if let Some(snapshot_dir) = self.nodes.get_mut(&SNAPSHOT_ROOT_INO) {
+            snapshot_dir.attr.nlink = snapshot_dir.attr.nlink.overflow_add(1);
+            snapshot_dir.attr.mtime = SystemTime::now();
+        }
+        ino
+    }
+
+    /// Add a directory of name under the snapshot directory of parent
+    pub fn add_dir(&mut self, parent: u64, name: &OsStr, attr: &FileAttr) -> u64 {
+        self.helper_add_node(parent, name, attr, SnapshotContent::Dir(BTreeMap::new()))
+    }
+
+    /// Add a file of name under the snapshot directory of parent, returns its
+    /// i-node number
+    pub fn add_file(&mut self, parent: u64, name: &OsStr, attr: &FileAttr, data: FileData) -> u64 {
+        self.helper_add_node(parent, name, attr, SnapshotContent::File(data))
+    }
+
+    /// Add a file of name under the snapshot directory of parent, whose data
+    /// is read from the backing file until loaded, returns its i-node number
+    pub fn add_pending_file(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        attr: &FileAttr,
+        file: PendingFile,
+    ) -> u64 {
+        let live_ino = file.live_ino;
+        let ino = self.helper_add_node(parent, name, attr, SnapshotContent::Pending(file));
+        self.pending.entry(live_ino).or_default().push(ino);
+        ino
+    }
+
+    /// Load the data of the snapshot files of the live file of `live_ino`,
+    /// before memfs modifies it, the data is spilled at once if there is a
+    /// spill file, as it is only held by the snapshots
+    pub fn load_pending(
+        &mut self,
+        live_ino: u64,
+        store: &mut ChunkStore,
+        io: &BackingIo,
+    ) -> nix::Result<()> {
+        let inos = match self.pending.remove(&live_ino) {
+            Some(inos) => inos,
+            None => return Ok(()),
+        };
+        let mut res = Ok(());
+        let mut left = Vec::new();
+        for ino in inos {
+            if res.is_err() {
+                left.push(ino);
+                continue;
+            }
+            res = self.helper_load_pending_file(ino, store, io);
+            if res.is_err() {
+                left.push(ino);
+            }
+        }
+        if !left.is_empty() {
+            self.pending.insert(live_ino, left);
+        }
+        res
+    }
+
+    /// Load the data of at most `count` snapshot files not loaded yet,
+    /// e.g. while memfs is idle, returns the number of the files loaded
+    pub fn load_some_pending(
+        &mut self,
+        count: usize,
+        store: &mut ChunkStore,
+        io: &BackingIo,
+    ) -> usize {
+        let live_inos: Vec<u64> = self.pending.keys().take(count).copied().collect();
+        let mut loaded = 0_usize;
+        for live_ino in live_inos {
+            let before = self.pending_count();
+            if let Err(e) = self.load_pending(live_ino, store, io) {
+                debug!(
+                    "load_some_pending() failed to load the snapshots of the live file of ino={}, \
+                        the error is: {:?}",
+                    live_ino, e,
+                );
+            }
+            loaded = loaded.overflow_add(before.overflow_sub(self.pending_count()));
+        }
+        loaded
+    }
+
+    /// Helper load the data of the snapshot file of ino from its backing file
+    fn helper_load_pending_file(
+        &mut self,
+        ino: u64,
+        store: &mut ChunkStore,
+        io: &BackingIo,
+    ) -> nix::Result<()> {
+        let node = match self.nodes.get_mut(&ino) {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        let file = match node.content {
+            SnapshotContent::Pending(ref file) => file,
+            SnapshotContent::File(_) | SnapshotContent::Spilled(_) | SnapshotContent::Dir(_) => {
+                return Ok(())
+            }
+        };
+        let bytes = io.pread(&file.backend, file.fd, node.attr.size.cast(), 0)?;
+        let mut data = FileData::new();
+        data.load(store, bytes);
+        let content = mem::replace(&mut node.content, SnapshotContent::File(data));
+        if let SnapshotContent::Pending(file) = content {
+            helper_close_pending(&file);
+        }
+        if self.has_spill() {
+            if let Err(e) = self.spill_file(ino, store) {
+                debug!(
+                    "helper_load_pending_file() failed to spill the snapshot file of ino={}, \
+                        kept it in memory, the error is: {:?}",
+                    ino, e,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper add a node under the directory of parent
+    fn helper_add_node(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        attr: &FileAttr,
+        content: SnapshotContent,
+    ) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino = self.next_ino.overflow_sub(1);
+        self.nodes.insert(
+            ino,
+            SnapshotNode {
+                attr: FileAttr { ino, ..*attr },
+                content,
+            },
+        );
+        match self.nodes.get_mut(&parent).map(|node| &mut node.content) {
+            Some(SnapshotContent::Dir(entries)) => {
+                entries.insert(name.to_os_string(), ino);
+            }
+            Some(
+                SnapshotContent::File(_)
+                | SnapshotContent::Spilled(_)
+                | SnapshotContent::Pending(_),
+            )
+            | None => panic!(
+                "helper_add_node() found the parent of ino={} is not a snapshot directory",
+                parent
+            ),
+        }
+        ino
+    }
+
+    /// Drop the snapshot of name and release its data, returns false if there
+    /// is no such snapshot
+    pub fn remove_snapshot(&mut self, name: &OsStr, store: &mut ChunkStore) -> bool {
+        let ino = match self.nodes.get_mut(&SNAPSHOT_ROOT_INO) {
+            Some(SnapshotNode {
+                attr,
+                content: SnapshotContent::Dir(entries),
+            }) => match entries.remove(name) {
+                Some(ino) => {
+                    attr.nlink = attr.nlink.overflow_sub(1);
+                    attr.mtime = SystemTime::now();
+                    ino
+                }
+                None => return false,
+            },
+            Some(_) | None => return false,
+        };
+        let mut dropped = vec![ino];
+        while let Some(ino) = dropped.pop() {
+            match self.nodes.remove(&ino).map(|node| node.content) {
+                Some(SnapshotContent::Dir(entries)) => dropped.extend(entries.values()),
+                Some(SnapshotContent::File(mut data)) => data.release(store),
+                Some(SnapshotContent::Spilled(extent)) => {
+                    if let Some(spill) = self.spill.as_mut() {
+                        spill.free(extent);
+                    }
+                }
+                Some(SnapshotContent::Pending(file)) => {
+                    if let Some(inos) = self.pending.get_mut(&file.live_ino) {
+                        inos.retain(|pending_ino| *pending_ino != ino);
+                        if inos.is_empty() {
+                            self.pending.remove(&file.live_ino);
+                        }
+                    }
+                    helper_close_pending(&file);
+                }
+                None => {}
+            }
+        }
+        true
+    }
+
+    /// Drop all the snapshots and release their data
+    pub fn clear(&mut self, store: &mut ChunkStore) {
+        let names: Vec<OsString> = match self.nodes.get(&SNAPSHOT_ROOT_INO) {
+            Some(SnapshotNode {
+                content: SnapshotContent::Dir(entries),
+                ..
+            }) => entries.keys().cloned().collect(),
+            Some(_) | None => Vec::new(),
+        };
+        for name in names {
+            self.remove_snapshot(&name, store);
+        }
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        for node in self.nodes.values() {
+            if let SnapshotContent::Pending(ref file) = node.content {
+                helper_close_pending(file);
+            }
+        }
+    }
+}
+
+/// Helper close the fd of the backing file of a snapshot file
+fn helper_close_pending(file: &PendingFile) {
+    if let Err(e) = file.backend.close(file.fd) {
+        debug!(
+            "helper_close_pending() failed to close the backing file of the live file of ino={}, \
+                the error is: {:?}",
+            file.live_ino, e,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OverflowArithmetic, Snapshots, SNAPSHOT_ROOT_INO};
+    use crate::fuse::{FileAttr, FileType};
+    use crate::memfs::backing::BackingIo;
+    use crate::memfs::chunk::{ChunkStore, FileData};
+    use std::ffi::OsStr;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_snapshots() {
+        let dir_attr = FileAttr {
+            ino: 1,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        };
+        let file_attr = FileAttr {
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            size: 5,
+            ..dir_attr
+        };
+        let mut store = ChunkStore::new(2);
+        let mut live = FileData::new();
+        live.write(&mut store, 0, b"hello");
+
+        let mut snapshots = Snapshots::new(&dir_attr);
+        // disabled until the directory is named
+        assert!(!snapshots.owns(SNAPSHOT_ROOT_INO));
+        snapshots.set_dir_name(OsStr::new(".snapshots"));
+        assert!(snapshots.is_dir_name(OsStr::new(".snapshots")));
+        assert!(snapshots.owns(snapshots.root_attr().ino));
+        let snapshot_ino = snapshots.add_snapshot(OsStr::new("daily"), &dir_attr);
+        let sub_ino = snapshots.add_dir(snapshot_ino, OsStr::new("sub"), &dir_attr);
+        snapshots.add_file(
+            sub_ino,
+            OsStr::new("file"),
+            &file_attr,
+            live.share(&mut store),
+        );
+        assert!(snapshots.contains(OsStr::new("daily")));
+        assert_eq!(snapshots.root_attr().nlink, 3);
+
+        // the live file copies the shared data on write
+        live.write(&mut store, 0, b"HE");
+        let file = snapshots
+            .lookup(sub_ino, OsStr::new("file"))
+            .unwrap_or_else(|| panic!());
+        assert!(snapshots.owns(file.ino));
+        assert!(!snapshots.owns(file.ino.overflow_sub(1)));
+        assert_eq!(file.size, 5);
+        let io = BackingIo::new();
+        let data = snapshots.read_file(file.ino, &store, &io, 0, 10, |res| res.map(<[_]>::concat));
+        assert_eq!(data, Ok(b"hello".to_vec()));
+        assert_eq!(live.read(&store, 0, 10).as_ref(), b"HEllo");
+
+        let mut names = Vec::new();
+        assert!(
+            snapshots.read_dir(SNAPSHOT_ROOT_INO, 0, |offset, ino, kind, name| {
+                names.push((offset, ino, kind, name.to_os_string()));
+                false
+            })
+        );
+        assert_eq!(
+            names,
+            vec![(1, snapshot_ino, FileType::Directory, "daily".into())]
+        );
+        assert!(snapshots.read_dir(SNAPSHOT_ROOT_INO, 1, |_, _, _, _| panic!()));
+
+        // the chunks only referenced by the snapshot are released with it
+        assert!(snapshots.remove_snapshot(OsStr::new("daily"), &mut store));
+        assert!(!snapshots.remove_snapshot(OsStr::new("daily"), &mut store));
+        assert!(snapshots.get_attr(file.ino).is_none());
+        // the i-node numbers of the dropped nodes are not reused
+        assert!(snapshots.owns(file.ino));
+        assert_eq!(store.chunk_count(), 3);
+        live.release(&mut store);
+        assert_eq!(store.chunk_count(), 0);
+    }
+}
@@ -0,0 +1,175 @@
+//! Reserve of the free space of the backing store
+//!
+//! Writes through the mount would fill the backing filesystem up to the last
+//! block, starving the other users of the host. With a reserve, statfs reports
+//! the free space of the backing filesystem less the reserve, and writes that
+//! would eat into the reserve fail with `ENOSPC`. The free space is queried at
+//! most once per interval, in between it is estimated by the bytes the files
+//! grow by, the data overwritten in place takes no more space, so a write into
+//! the reserve never goes unnoticed for longer than the interval. The
+//! preallocation ahead of the writers is held to the reserve as well.
+
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::backend::{Backend, BackendSpace};
+use super::{OverflowArithmetic, TryCast};
+
+/// The interval of querying the free space of the backing store
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The reserve of the free space
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpaceReserve {
+    /// A number of bytes
+    Bytes(u64),
+    /// A percentage of the size of the backing filesystem
+    Percent(u64),
+}
+
+impl SpaceReserve {
+    /// The number of bytes reserved on a filesystem of `total` bytes
+    fn bytes(self, total: u64) -> u64 {
+        match self {
+            Self::Bytes(bytes) => bytes,
+            // the percentage is at most 100, so the result is at most total
+            Self::Percent(percent) => u128::from(total)
+                .overflow_mul(percent.into())
+                .overflow_div(100)
+                .try_cast()
+                .unwrap_or(total),
+        }
+    }
+}
+
+impl FromStr for SpaceReserve {
+    type Err = String;
+
+    /// Parse a percentage ending with `%` or a number of bytes
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value.strip_suffix('%').map_or_else(
+            || {
+                value.parse().map(Self::Bytes).map_err(|e| {
+                    format!(
+                        "invalid reserve {:?}, expected bytes or a percentage: {}",
+                        value, e
+                    )
+                })
+            },
+            |percent| match percent.parse() {
+                Ok(percent) if percent <= 100 => Ok(Self::Percent(percent)),
+                _ => Err(format!(
+                    "invalid reserve {:?}, expected a percentage of at most 100%",
+                    value
+                )),
+            },
+        )
+    }
+}
+
+/// Guard of the reserve of the free space
+#[derive(Debug)]
+pub struct SpaceGuard {
+    /// The reserve
+    reserve: SpaceReserve,
+    /// The space queried last along with the time
+    checked: Option<(Instant, BackendSpace)>,
+    /// The bytes written since the space was queried
+    written: u64,
+}
+
+impl SpaceGuard {
+    /// New guard of `reserve`
+    pub const fn new(reserve: SpaceReserve) -> Self {
+        Self {
+            reserve,
+            checked: None,
+            written: 0,
+        }
+    }
+
+    /// Query the space of the filesystem of fd
+    fn helper_query(&mut self, backend: &dyn Backend, fd: RawFd) -> nix::Result<BackendSpace> {
+        let space = backend.space(fd)?;
+        self.checked = Some((Instant::now(), space));
+        self.written = 0;
+        Ok(space)
+    }
+
+    /// The space of the filesystem of fd with the reserve taken off the
+    /// available space
+    pub fn space(&mut self, backend: &dyn Backend, fd: RawFd) -> nix::Result<BackendSpace> {
+        let space = self.helper_query(backend, fd)?;
+        Ok(BackendSpace {
+            available: space
+                .available
+                .saturating_sub(self.reserve.bytes(space.total)),
+            ..space
+        })
+    }
+
+    /// Check whether writing `size` bytes to the filesystem of fd leaves the
+    /// reserve free, the space is queried again if the estimate says no or it
+    /// is out of date
+    pub fn allows_write(
+        &mut self,
+        backend: &dyn Backend,
+        fd: RawFd,
+        size: u64,
+    ) -> nix::Result<bool> {
+        let reserve = self.reserve;
+        let fits = |space: &BackendSpace, written: u64| {
+            space.available.saturating_sub(written) >= reserve.bytes(space.total).overflow_add(size)
+        };
+        let estimated = match self.checked {
+            Some((time, ref space)) if time.elapsed() < SPACE_CHECK_INTERVAL => {
+                fits(space, self.written)
+            }
+            _ => false,
+        };
+        if !estimated && !fits(&self.helper_query(backend, fd)?, 0) {
+            return Ok(false);
+        }
+        self.written = self.written.saturating_add(size);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SpaceGuard, SpaceReserve};
+    use crate::memfs::backend::{Backend, LocalBackend};
+    use std::path::Path;
+
+    #[test]
+    fn test_space_reserve() {
+        assert_eq!("5%".parse(), Ok(SpaceReserve::Percent(5)));
+        assert_eq!("4096".parse(), Ok(SpaceReserve::Bytes(4096)));
+        assert!("101%".parse::<SpaceReserve>().is_err());
+        assert!("5 %".parse::<SpaceReserve>().is_err());
+        assert_eq!(SpaceReserve::Percent(5).bytes(1010), 50);
+        assert_eq!(SpaceReserve::Percent(100).bytes(u64::MAX), u64::MAX);
+
+        let backend = LocalBackend::new();
+        let fd = backend
+            .open_dir(Path::new("/tmp"))
+            .unwrap_or_else(|_| panic!());
+        let mut guard = SpaceGuard::new(SpaceReserve::Bytes(0));
+        let space = guard.space(&backend, fd).unwrap_or_else(|_| panic!());
+        assert!(space.available <= space.total);
+        assert!(guard
+            .allows_write(&backend, fd, 1)
+            .unwrap_or_else(|_| panic!()));
+        // nothing fits into a full reserve
+        let mut guard = SpaceGuard::new(SpaceReserve::Percent(100));
+        assert_eq!(
+            guard.space(&backend, fd).map(|space| space.available),
+            Ok(0)
+        );
+        assert!(!guard
+            .allows_write(&backend, fd, 1)
+            .unwrap_or_else(|_| panic!()));
+        backend.close(fd).unwrap_or_else(|_| panic!());
+    }
+}
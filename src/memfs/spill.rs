@@ -0,0 +1,217 @@
+//! Spill file of the data evicted from memory
+//!
+//! memfs writes through to the backing directory, so the cached data of the
+//! live files is dropped and read again when the cache cannot grow. The data
+//! of the snapshots is only kept in memory though, it is spilled to a file in
+//! the spill directory instead, where it is read from by the snapshot files.
+//! The spill file is unlinked once created, so it is removed when memfs exits,
+//! and the space of the dropped extents is given back by punching holes.
+
+use super::{Cast, OverflowArithmetic};
+use log::debug;
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::fcntl::FallocateFlags;
+use nix::fcntl::{self, FcntlArg, FdFlag};
+use nix::sys::uio;
+use nix::unistd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// Name template of the spill file under the spill directory
+const SPILL_FILE_TEMPLATE: &str = "sync_fuse_spill.XXXXXX";
+
+/// Extent of the data spilled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SpillExtent {
+    /// Offset in the spill file
+    offset: u64,
+    /// Byte length
+    len: usize,
+}
+
+/// Spill file, appended to and read at extents
+#[derive(Debug)]
+pub struct SpillFile {
+    /// Fd of the unlinked spill file
+    fd: RawFd,
+    /// The offset to append at
+    end: u64,
+    /// Byte size of the extents in use
+    used: u64,
+    /// The limit of the byte size of the extents in use
+    limit: Option<u64>,
+}
+
+impl SpillFile {
+    /// Create a spill file under dir, holding at most `limit` byte if set
+    pub fn new(dir: &Path, limit: Option<u64>) -> nix::Result<Self> {
+        let (fd, path) = unistd::mkstemp(&dir.join(SPILL_FILE_TEMPLATE))?;
+        // never inherited by the processes spawned by the daemon
+        let res = unistd::unlink(&path)
+            .and_then(|()| fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)));
+        if let Err(e) = res {
+            unistd::close(fd).unwrap_or(());
+            return Err(e);
+        }
+        Ok(Self {
+            fd,
+            end: 0,
+            used: 0,
+            limit,
+        })
+    }
+
+    /// Byte size of the extents in use
+    pub const fn used(&self) -> u64 {
+        self.used
+    }
+
+    /// Spill the slices one after another into a new extent, fails with
+    /// `ENOSPC` beyond the limit or once the spill directory is full
+    pub fn spill(&mut self, slices: &[&[u8]]) -> nix::Result<SpillExtent> {
+        let len = slices
+            .iter()
+            .fold(0_usize, |len, slice| len.overflow_add(slice.len()));
+        if let Some(limit) = self.limit {
+            if self.used.overflow_add(len.cast()) > limit {
+                return Err(nix::Error::Sys(Errno::ENOSPC));
+            }
+        }
+        let extent = SpillExtent {
+            offset: self.end,
+            len,
+        };
+        let mut offset = self.end;
+        for slice in slices {
+            let mut written_size = 0_usize;
+            while let Some(buf) = slice.get(written_size..) {
+                if buf.is_empty() {
+                    break;
+                }
+                match uio::pwrite(self.fd, buf, offset.cast()) {
+                    Ok(0) => return self.helper_undo(extent, Errno::ENOSPC),
+                    Ok(n) => {
+                        written_size = written_size.overflow_add(n);
+                        offset = offset.overflow_add(n.cast());
+                    }
+                    Err(nix::Error::Sys(errno)) => return self.helper_undo(extent, errno),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        self.end = offset;
+        self.used = self.used.overflow_add(len.cast());
+        Ok(extent)
+    }
+
+    /// Helper give back the space of the extent failing to spill
+    fn helper_undo(&mut self, extent: SpillExtent, errno: Errno) -> nix::Result<SpillExtent> {
+        self.helper_punch_hole(extent);
+        Err(nix::Error::Sys(errno))
+    }
+
+    /// Read at most `size` byte from `offset` of the extent
+    pub fn read(&self, extent: SpillExtent, offset: usize, size: usize) -> nix::Result<Vec<u8>> {
+        let start = offset.min(extent.len);
+        let mut data = vec![0_u8; size.min(extent.len.overflow_sub(start))];
+        let mut read_size = 0_usize;
+        while let Some(buf) = data.get_mut(read_size..) {
+            if buf.is_empty() {
+                break;
+            }
+            let file_offset = extent
+                .offset
+                .overflow_add(start.overflow_add(read_size).cast());
+            match uio::pread(self.fd, buf, file_offset.cast())? {
+                0 => return Err(nix::Error::Sys(Errno::EIO)), // the spill file is truncated
+                n => read_size = read_size.overflow_add(n),
+            }
+        }
+        Ok(data)
+    }
+
+    /// Drop the extent and give back its space
+    pub fn free(&mut self, extent: SpillExtent) {
+        self.used = self.used.overflow_sub(extent.len.cast());
+        if self.used == 0 {
+            // nothing is in use, start over from the beginning
+            if let Err(e) = unistd::ftruncate(self.fd, 0) {
+                debug!(
+                    "free() failed to truncate the spill file, the error is: {:?}",
+                    e
+                );
+            }
+            self.end = 0;
+        } else {
+            self.helper_punch_hole(extent);
+        }
+    }
+
+    /// Helper give back the space of the extent to the spill directory, the
+    /// space is only given back once the spill file is closed without fallocate
+    #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+    fn helper_punch_hole(&self, extent: SpillExtent) {
+        #[cfg(target_os = "linux")]
+        if let Err(e) = fcntl::fallocate(
+            self.fd,
+            FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            extent.offset.cast(),
+            extent.len.cast(),
+        ) {
+            debug!(
+                "helper_punch_hole() failed to punch a hole of {:?} in the spill file, the error is: {:?}",
+                extent, e
+            );
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = unistd::close(self.fd) {
+            debug!(
+                "SpillFile::drop() failed to close the spill file, the error is: {:?}",
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SpillFile;
+    use nix::errno::Errno;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_spill_file() {
+        let dir = Path::new("/tmp/fuse_test_spill_file");
+        fs::create_dir_all(dir).unwrap_or_else(|_| panic!());
+        let mut spill = SpillFile::new(dir, Some(10)).unwrap_or_else(|_| panic!());
+        // the spill file is unlinked
+        assert_eq!(fs::read_dir(dir).unwrap_or_else(|_| panic!()).count(), 0);
+
+        let hello = spill
+            .spill(&[b"hel", b"", b"lo"])
+            .unwrap_or_else(|_| panic!());
+        let world = spill.spill(&[b"world"]).unwrap_or_else(|_| panic!());
+        assert_eq!(spill.used(), 10);
+        assert_eq!(spill.read(hello, 0, 100), Ok(b"hello".to_vec()));
+        assert_eq!(spill.read(world, 1, 3), Ok(b"orl".to_vec()));
+        assert_eq!(spill.read(world, 9, 3), Ok(Vec::new()));
+
+        // the limit is reached until an extent is dropped
+        assert_eq!(spill.spill(&[b"!"]), Err(nix::Error::Sys(Errno::ENOSPC)));
+        spill.free(hello);
+        assert_eq!(spill.used(), 5);
+        let bang = spill.spill(&[b"!"]).unwrap_or_else(|_| panic!());
+        assert_eq!(spill.read(world, 0, 100), Ok(b"world".to_vec()));
+        assert_eq!(spill.read(bang, 0, 100), Ok(b"!".to_vec()));
+        spill.free(world);
+        spill.free(bang);
+        assert_eq!(spill.used(), 0);
+        fs::remove_dir(dir).unwrap_or_else(|_| panic!());
+    }
+}
@@ -0,0 +1,135 @@
+//! Persisted i-node table for warm restarts
+//!
+//! The kernel keeps the inode numbers it looked up, and NFS clients keep the
+//! file handles made of them, across a restart of the daemon, e.g. an upgrade
+//! under a lazily unmounted mount point, while a new daemon only knows the
+//! root. On a clean shutdown the i-nodes looked up by the kernel are saved to
+//! a state file along with their paths relative to the root, generations and
+//! lookup counts, and the next daemon opens them again from their paths
+//! before serving any request.
+
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use super::Cast;
+
+/// The magic at the start of the state file, followed by the format version
+const STATE_MAGIC: &[u8; 8] = b"MEMFSINO";
+/// The version of the format of the state file
+const STATE_VERSION: u32 = 1;
+
+/// An i-node looked up by the kernel
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SavedInode {
+    /// The ino
+    pub ino: u64,
+    /// The generation
+    pub generation: u64,
+    /// The lookup count of the kernel
+    pub lookup_count: i64,
+    /// The path relative to the root
+    pub path: PathBuf,
+}
+
+/// Convert an error of bincode
+#[allow(clippy::boxed_local)] // the error of bincode is boxed
+fn bincode_error(err: bincode::Error) -> io::Error {
+    match *err {
+        bincode::ErrorKind::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// Save the i-nodes to the state file of `path`, replacing it at once so a
+/// crash while saving leaves the previous one
+pub fn save(path: &Path, inodes: &[SavedInode]) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(STATE_MAGIC)?;
+    bincode::serialize_into(&mut out, &(STATE_VERSION, inodes.len().cast::<u64>()))
+        .map_err(bincode_error)?;
+    for inode in inodes {
+        let record = (
+            inode.ino,
+            inode.generation,
+            inode.lookup_count,
+            inode.path.as_os_str().as_bytes(),
+        );
+        bincode::serialize_into(&mut out, &record).map_err(bincode_error)?;
+    }
+    out.into_inner()?.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Load the i-nodes from the state file of `path`, parents before children
+pub fn load(path: &Path) -> io::Result<Vec<SavedInode>> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0_u8; 8];
+    input.read_exact(&mut magic)?;
+    let (version, count): (u32, u64) =
+        bincode::deserialize_from(&mut input).map_err(bincode_error)?;
+    if &magic != STATE_MAGIC || version != STATE_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} is not a state file of version {}",
+                path, STATE_VERSION
+            ),
+        ));
+    }
+    let mut inodes = Vec::new();
+    for _ in 0..count {
+        let (ino, generation, lookup_count, path): (u64, u64, i64, Vec<u8>) =
+            bincode::deserialize_from(&mut input).map_err(bincode_error)?;
+        inodes.push(SavedInode {
+            ino,
+            generation,
+            lookup_count,
+            path: PathBuf::from(OsString::from_vec(path)),
+        });
+    }
+    inodes.sort_by_key(|inode| inode.path.components().count());
+    Ok(inodes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{load, save, SavedInode};
+
+    #[test]
+    fn test_save_load() {
+        let path = Path::new("/tmp/fuse_test_state_file");
+        let inodes = vec![
+            SavedInode {
+                ino: 12,
+                generation: 1,
+                lookup_count: 3,
+                path: PathBuf::from("a/b/c"),
+            },
+            SavedInode {
+                ino: 10,
+                generation: 1,
+                lookup_count: 1,
+                path: PathBuf::from("a"),
+            },
+        ];
+        save(path, &inodes).unwrap_or_else(|_| panic!());
+        let loaded = load(path).unwrap_or_else(|_| panic!());
+        // parents come first
+        assert_eq!(loaded.first(), inodes.get(1));
+        assert_eq!(loaded.get(1), inodes.first());
+        assert_eq!(loaded.len(), 2);
+
+        fs::write(path, "not a state file").unwrap_or_else(|_| panic!());
+        assert!(load(path).is_err());
+        fs::remove_file(path).unwrap_or_else(|_| panic!());
+    }
+}
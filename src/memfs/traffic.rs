@@ -0,0 +1,248 @@
+//! Accounting of the bytes of the file data
+//!
+//! The bytes the kernel asks to read and write are not the bytes read from and
+//! written to the backing files: the first read of a file loads the whole file
+//! into the cache, the reads after it are served by the cache without touching
+//! the disk, and a cloned range is written to disk without being requested by
+//! any write. The reads of a mapped file go through the page cache, which
+//! serves them without telling whether the disk was read, so they are counted
+//! apart from the requested and the backing bytes. The bytes are counted per file and
+//! in total, and their ratios show how much the cache amplifies or saves the
+//! backing I/O, e.g. to tune the chunk size and the caching of a workload.
+
+use rustc_hash::FxHashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::{Cast, OverflowArithmetic};
+
+/// The kind of the bytes counted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ByteKind {
+    /// The bytes the kernel asks to read, served by the cache or the disk
+    RequestedRead,
+    /// The bytes read from the backing file
+    BackingRead,
+    /// The bytes the kernel asks to read, served by the mapping of the backing
+    /// file from the page cache or the disk
+    MappedRead,
+    /// The bytes the kernel asks to write
+    RequestedWrite,
+    /// The bytes written to the backing file
+    BackingWrite,
+}
+
+/// The bytes requested by the kernel and moved from and to the backing files,
+/// the same layout is returned by `MEMFS_IOC_BYTE_STATS`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ByteStats {
+    /// The bytes the kernel asked to read
+    pub requested_read: u64,
+    /// The bytes read from the backing files
+    pub backing_read: u64,
+    /// The bytes the kernel asked to read from the mappings of the files
+    pub mapped_read: u64,
+    /// The bytes the kernel asked to write
+    pub requested_written: u64,
+    /// The bytes written to the backing files
+    pub backing_written: u64,
+}
+
+/// The ratio of the backing bytes to the requested bytes, `None` if nothing
+/// is requested
+fn ratio(backing: u64, requested: u64) -> Option<f64> {
+    if requested == 0 {
+        return None;
+    }
+    #[allow(clippy::as_conversions, clippy::cast_precision_loss)] // an approximate ratio
+    Some(backing as f64 / requested as f64)
+}
+
+/// Write the ratio as `N.NNx`, `-` if there is none
+fn fmt_ratio(f: &mut fmt::Formatter<'_>, ratio: Option<f64>) -> fmt::Result {
+    match ratio {
+        Some(ratio) => write!(f, "{:.2}x", ratio),
+        None => write!(f, "-"),
+    }
+}
+
+impl ByteStats {
+    /// Count `bytes` of `kind`
+    pub fn record(&mut self, kind: ByteKind, bytes: u64) {
+        let count = match kind {
+            ByteKind::RequestedRead => &mut self.requested_read,
+            ByteKind::BackingRead => &mut self.backing_read,
+            ByteKind::MappedRead => &mut self.mapped_read,
+            ByteKind::RequestedWrite => &mut self.requested_written,
+            ByteKind::BackingWrite => &mut self.backing_written,
+        };
+        *count = count.overflow_add(bytes);
+    }
+
+    /// The bytes read from the backing files per byte the kernel asked to read
+    pub fn read_amplification(&self) -> Option<f64> {
+        ratio(self.backing_read, self.requested_read)
+    }
+
+    /// The bytes written to the backing files per byte the kernel asked to write
+    pub fn write_amplification(&self) -> Option<f64> {
+        ratio(self.backing_written, self.requested_written)
+    }
+
+    /// Parse from the bytes returned by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != mem::size_of::<Self>() {
+            return None;
+        }
+        let mut fields = data
+            .chunks_exact(mem::size_of::<u64>())
+            .filter_map(|bytes| bytes.try_into().ok().map(u64::from_ne_bytes));
+        Some(Self {
+            requested_read: fields.next()?,
+            backing_read: fields.next()?,
+            mapped_read: fields.next()?,
+            requested_written: fields.next()?,
+            backing_written: fields.next()?,
+        })
+    }
+
+    /// Serialize the fields in order in native endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
+            self.requested_read,
+            self.backing_read,
+            self.mapped_read,
+            self.requested_written,
+            self.backing_written,
+        ]
+        .iter()
+        .flat_map(|field| field.to_ne_bytes().to_vec())
+        .collect()
+    }
+}
+
+impl fmt::Display for ByteStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bytes read: {} requested, {} from disk, {} mapped, amplification ",
+            self.requested_read, self.backing_read, self.mapped_read,
+        )?;
+        fmt_ratio(f, self.read_amplification())?;
+        write!(
+            f,
+            "\nbytes written: {} requested, {} to disk, amplification ",
+            self.requested_written, self.backing_written,
+        )?;
+        fmt_ratio(f, self.write_amplification())
+    }
+}
+
+/// Counter of the bytes of all the files and of each file, shared by the
+/// i-nodes
+#[derive(Debug, Default)]
+pub struct ByteCounter {
+    /// The bytes the kernel asked to read
+    requested_read: AtomicU64,
+    /// The bytes read from the backing files
+    backing_read: AtomicU64,
+    /// The bytes the kernel asked to read from the mappings of the files
+    mapped_read: AtomicU64,
+    /// The bytes the kernel asked to write
+    requested_written: AtomicU64,
+    /// The bytes written to the backing files
+    backing_written: AtomicU64,
+    /// The bytes of each file indexed by ino, kept while the i-node of the
+    /// file is evicted from the cache, until the file is deleted
+    files: Mutex<FxHashMap<u64, ByteStats>>,
+}
+
+impl ByteCounter {
+    /// Count `bytes` of `kind` of the file of ino, and in the total
+    pub fn record(&self, ino: u64, kind: ByteKind, bytes: usize) {
+        let count = match kind {
+            ByteKind::RequestedRead => &self.requested_read,
+            ByteKind::BackingRead => &self.backing_read,
+            ByteKind::MappedRead => &self.mapped_read,
+            ByteKind::RequestedWrite => &self.requested_written,
+            ByteKind::BackingWrite => &self.backing_written,
+        };
+        count.fetch_add(bytes.cast(), Ordering::Relaxed);
+        self.lock_files()
+            .entry(ino)
+            .or_default()
+            .record(kind, bytes.cast());
+    }
+
+    /// Lock the bytes of the files, a panic while holding them leaves them
+    /// consistent
+    fn lock_files(&self) -> std::sync::MutexGuard<'_, FxHashMap<u64, ByteStats>> {
+        self.files
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The bytes counted so far of the file of ino
+    pub fn file_stats(&self, ino: u64) -> ByteStats {
+        self.lock_files().get(&ino).copied().unwrap_or_default()
+    }
+
+    /// Drop the bytes of the deleted file of ino, they stay in the total
+    pub fn remove_file(&self, ino: u64) {
+        self.lock_files().remove(&ino);
+    }
+
+    /// The bytes counted so far, including the ones of the files dropped
+    pub fn stats(&self) -> ByteStats {
+        ByteStats {
+            requested_read: self.requested_read.load(Ordering::Relaxed),
+            backing_read: self.backing_read.load(Ordering::Relaxed),
+            mapped_read: self.mapped_read.load(Ordering::Relaxed),
+            requested_written: self.requested_written.load(Ordering::Relaxed),
+            backing_written: self.backing_written.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ByteCounter, ByteKind, ByteStats};
+
+    #[test]
+    fn test_byte_stats() {
+        let counter = ByteCounter::default();
+        assert_eq!(counter.stats(), ByteStats::default());
+        counter.record(2, ByteKind::RequestedRead, 4096);
+        counter.record(2, ByteKind::BackingRead, 16384);
+        counter.record(3, ByteKind::RequestedRead, 4096);
+        counter.record(3, ByteKind::BackingWrite, 100);
+        // served by the mapping, maybe from the page cache
+        counter.record(4, ByteKind::MappedRead, 8192);
+        let stats = counter.stats();
+        assert_eq!(stats.read_amplification(), Some(2.0));
+        // written without a request, e.g. by a clone
+        assert_eq!(stats.write_amplification(), None);
+        assert_eq!(ByteStats::from_bytes(&stats.to_bytes()), Some(stats));
+        assert_eq!(ByteStats::from_bytes(&[0; 8]), None);
+        assert_eq!(
+            stats.to_string(),
+            "bytes read: 8192 requested, 16384 from disk, 8192 mapped, amplification 2.00x\n\
+             bytes written: 0 requested, 100 to disk, amplification -"
+        );
+
+        assert_eq!(counter.file_stats(2).backing_read, 16384);
+        assert_eq!(counter.file_stats(4).read_amplification(), None);
+        counter.remove_file(2);
+        assert_eq!(counter.file_stats(2), ByteStats::default());
+        assert_eq!(counter.stats(), stats);
+
+        let mut file = ByteStats::default();
+        file.record(ByteKind::RequestedWrite, 10);
+        file.record(ByteKind::BackingWrite, 10);
+        assert_eq!(file.write_amplification(), Some(1.0));
+    }
+}
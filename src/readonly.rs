@@ -0,0 +1,287 @@
+//! Parts shared by the read-only filesystems of archives, git revisions and
+//! remote trees: the table of their nodes, the data of their open files, the
+//! listing of their directories and the replies to the requests changing them
+
+use crate::fuse::{Cast, FileType, OverflowArithmetic, ReplyDirectory, FUSE_ROOT_ID};
+use libc::{O_ACCMODE, O_RDONLY};
+use rustc_hash::FxHashMap;
+use std::ffi::OsStr;
+
+/// Nodes of a tree, numbered from the root in the order they are added
+#[derive(Debug)]
+pub struct NodeTable<N> {
+    /// Nodes indexed by ino
+    nodes: FxHashMap<u64, N>,
+    /// The ino of the next node added
+    next_ino: u64,
+}
+
+impl<N> NodeTable<N> {
+    /// New table of the root node
+    pub fn new(root: N) -> Self {
+        let mut nodes = FxHashMap::default();
+        nodes.insert(FUSE_ROOT_ID, root);
+        Self {
+            nodes,
+            next_ino: FUSE_ROOT_ID.overflow_add(1),
+        }
+    }
+
+    /// The number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Get node
+    pub fn get(&self, ino: u64) -> Option<&N> {
+        self.nodes.get(&ino)
+    }
+
+    /// Get node mut
+    #[cfg(feature = "http")]
+    pub fn get_mut(&mut self, ino: u64) -> Option<&mut N> {
+        self.nodes.get_mut(&ino)
+    }
+
+    /// Helper get node, the node must exist
+    pub fn helper_get(&self, ino: u64) -> &N {
+        self.nodes.get(&ino).unwrap_or_else(|| {
+            panic!(
+                "helper_get() found fs is inconsistent, no node of ino={}",
+                ino
+            )
+        })
+    }
+
+    /// Helper get node mut, the node must exist
+    pub fn helper_get_mut(&mut self, ino: u64) -> &mut N {
+        self.nodes.get_mut(&ino).unwrap_or_else(|| {
+            panic!(
+                "helper_get_mut() found fs is inconsistent, no node of ino={}",
+                ino
+            )
+        })
+    }
+
+    /// Add a node, returns its ino, which is never reused
+    pub fn add(&mut self, node: N) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino = ino.overflow_add(1);
+        self.nodes.insert(ino, node);
+        ino
+    }
+
+    /// Remove a node
+    #[cfg(feature = "http")]
+    pub fn remove(&mut self, ino: u64) -> Option<N> {
+        self.nodes.remove(&ino)
+    }
+}
+
+/// Data of an open file
+#[derive(Debug)]
+struct OpenFile {
+    /// Data
+    data: Vec<u8>,
+    /// Open count
+    open_count: u64,
+}
+
+/// Data of the open files, loaded on first open and dropped on last release
+#[derive(Debug, Default)]
+pub struct OpenFiles {
+    /// Open files indexed by ino
+    files: FxHashMap<u64, OpenFile>,
+}
+
+impl OpenFiles {
+    /// Open the file of ino, its data is loaded by `load` unless already open,
+    /// returns the errno to reply if failed
+    pub fn open(
+        &mut self,
+        ino: u64,
+        load: impl FnOnce() -> Result<Vec<u8>, i32>,
+    ) -> Result<(), i32> {
+        if let Some(open_file) = self.files.get_mut(&ino) {
+            open_file.open_count = open_file.open_count.overflow_add(1);
+        } else {
+            let data = load()?;
+            self.files.insert(
+                ino,
+                OpenFile {
+                    data,
+                    open_count: 1,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Read `size` bytes of the file of ino from `offset`, `None` if not open
+    pub fn read(&self, ino: u64, offset: i64, size: u32) -> Option<&[u8]> {
+        let data = &self.files.get(&ino)?.data;
+        let start = offset.cast::<usize>().min(data.len());
+        let end = start.overflow_add(size.cast()).min(data.len());
+        Some(data.get(start..end).unwrap_or(&[]))
+    }
+
+    /// Release the file of ino, its data is dropped on last release
+    pub fn release(&mut self, ino: u64) {
+        if let Some(open_file) = self.files.get_mut(&ino) {
+            open_file.open_count = open_file.open_count.overflow_sub(1);
+            if open_file.open_count == 0 {
+                self.files.remove(&ino);
+            }
+        }
+    }
+
+    /// The number of open files
+    #[cfg(feature = "git")]
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+/// Whether the flags of an open ask for writing
+pub fn opens_for_write(flags: u32) -> bool {
+    flags.cast::<i32>() & O_ACCMODE != O_RDONLY
+}
+
+/// Add the entries of the directory of ino from `offset`, `.` and `..` first,
+/// the offset of an entry is the offset to continue reading after it
+pub fn add_dir_entries<S: AsRef<OsStr>>(
+    reply: &mut ReplyDirectory,
+    ino: u64,
+    parent: u64,
+    offset: i64,
+    children: impl Iterator<Item = (u64, FileType, S)>,
+) {
+    let dots = [(ino, OsStr::new(".")), (parent, OsStr::new(".."))];
+    let dots = dots
+        .iter()
+        .map(|&(dot_ino, name)| (dot_ino, FileType::Directory, name));
+    for (i, (child_ino, kind, name)) in dots.enumerate().skip(offset.cast()) {
+        if reply.add(child_ino, i.overflow_add(1).cast(), kind, name) {
+            return;
+        }
+    }
+    let skip = offset.cast::<usize>().saturating_sub(2);
+    for (i, (child_ino, kind, name)) in children.enumerate().skip(skip) {
+        if reply.add(child_ino, i.overflow_add(3).cast(), kind, name.as_ref()) {
+            break;
+        }
+    }
+}
+
+/// Reply `EROFS` to the requests changing the tree or the data, expands to the
+/// methods in the `impl Filesystem` of a read-only filesystem
+macro_rules! impl_read_only {
+    () => {
+        fn setattr(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _param: $crate::fuse::FsSetattrParam,
+            reply: $crate::fuse::ReplyAttr,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn mknod(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            _mode: u32,
+            _rdev: u32,
+            reply: $crate::fuse::ReplyEntry,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn mkdir(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            _mode: u32,
+            reply: $crate::fuse::ReplyEntry,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn unlink(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            reply: $crate::fuse::ReplyEmpty,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn rmdir(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            reply: $crate::fuse::ReplyEmpty,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn symlink(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            _link: &std::path::Path,
+            reply: $crate::fuse::ReplyEntry,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn link(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _ino: u64,
+            _newparent: u64,
+            _newname: &std::ffi::OsStr,
+            reply: $crate::fuse::ReplyEntry,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn rename(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            _newparent: u64,
+            _newname: &std::ffi::OsStr,
+            reply: $crate::fuse::ReplyEmpty,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn write(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _param: $crate::fuse::FsWriteParam<'_>,
+            reply: $crate::fuse::ReplyWrite,
+        ) {
+            reply.error(libc::EROFS);
+        }
+
+        fn create(
+            &mut self,
+            _ctx: &$crate::fuse::Context,
+            _parent: u64,
+            _name: &std::ffi::OsStr,
+            _mode: u32,
+            _flags: u32,
+            reply: $crate::fuse::ReplyCreate,
+        ) {
+            reply.error(libc::EROFS);
+        }
+    };
+}
@@ -0,0 +1,117 @@
+use nix::fcntl::{self, OFlag};
+use nix::mount::{self as nix_mount, MsFlags};
+use nix::sys::socket::{self, AddressFamily, Shutdown, SockFlag, SockType};
+use nix::sys::stat::Mode;
+use nix::unistd;
+use std::convert::TryInto;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use fuse_ll::fuse::{self, Context, FileAttr, FileType, Filesystem, ReplyAttr, FUSE_ROOT_ID};
+
+pub mod test_util;
+use test_util::{init_request, request};
+
+const FROM_FD_MOUNT_DIR: &str = "../fuse_test_from_fd";
+
+/// Filesystem with an empty root directory owned by uid 1234
+struct EmptyFilesystem;
+
+impl Filesystem for EmptyFilesystem {
+    fn getattr(&mut self, _ctx: &Context, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        reply.attr(
+            &Duration::from_secs(1),
+            &FileAttr {
+                ino,
+                size: 0,
+                blocks: 0,
+                atime: UNIX_EPOCH,
+                mtime: UNIX_EPOCH,
+                ctime: UNIX_EPOCH,
+                crtime: UNIX_EPOCH,
+                kind: FileType::Directory,
+                perm: 0o755,
+                nlink: 2,
+                uid: 1234,
+                gid: 0,
+                rdev: 0,
+                flags: 0,
+            },
+        );
+    }
+}
+
+#[test]
+fn test_session_from_socket() {
+    // the fd handed over is any fd keeping the boundaries of the packets
+    #[cfg(target_os = "linux")]
+    let sock_type = SockType::SeqPacket;
+    #[cfg(target_os = "macos")]
+    let sock_type = SockType::Datagram;
+    let (fd, harness_fd) =
+        socket::socketpair(AddressFamily::Unix, sock_type, None, SockFlag::empty()).unwrap();
+    let th = thread::spawn(move || {
+        let mut se = unsafe { fuse::Session::from_fd(fd, EmptyFilesystem) };
+        assert!(se.mountpoint().is_none());
+        se.run()
+    });
+    let mut buf = [0_u8; 4096];
+    for (unique, req) in [init_request(8, 0), request(3, 2, 1, &[0; 16])]
+        .iter()
+        .enumerate()
+    {
+        unistd::write(harness_fd, req).unwrap();
+        let size = unistd::read(harness_fd, &mut buf).unwrap();
+        assert!(size >= 16);
+        assert_eq!(&buf[4..8], &0_i32.to_ne_bytes()); // error
+        assert_eq!(&buf[8..16], &(unique as u64 + 1).to_ne_bytes());
+    }
+    // the attributes of the root follow the header and the attribute timeout
+    assert_eq!(
+        u64::from_ne_bytes(buf[32..40].try_into().unwrap()),
+        FUSE_ROOT_ID
+    );
+
+    // the session ends when the peer hangs up
+    socket::shutdown(harness_fd, Shutdown::Write).unwrap();
+    th.join().unwrap().unwrap();
+    unistd::close(harness_fd).unwrap();
+}
+
+#[test]
+#[ignore = "mounts /dev/fuse directly, which needs root"]
+fn test_session_from_fd() {
+    let mount_dir = Path::new(FROM_FD_MOUNT_DIR);
+    fuse::unmount(mount_dir).unwrap_or(());
+    fs::create_dir_all(mount_dir).unwrap();
+    let abs_mount_path = fs::canonicalize(mount_dir).unwrap();
+
+    // mount as a container runtime would, then hand the fd over
+    let fd = fcntl::open("/dev/fuse", OFlag::O_RDWR, Mode::empty()).unwrap();
+    let data = format!("fd={},rootmode=40000,user_id=0,group_id=0", fd);
+    nix_mount::mount(
+        Some("from_fd"),
+        &abs_mount_path,
+        Some("fuse"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(data.as_str()),
+    )
+    .unwrap();
+    let th = thread::spawn(move || {
+        let mut se = unsafe { fuse::Session::from_fd(fd, EmptyFilesystem) };
+        assert!(se.mountpoint().is_none());
+        se.run()
+    });
+
+    let metadata = fs::metadata(&abs_mount_path).unwrap();
+    assert_eq!(metadata.uid(), 1234);
+    assert_eq!(metadata.ino(), FUSE_ROOT_ID);
+
+    // the session ends when the caller unmounts
+    fuse::unmount(&abs_mount_path).unwrap();
+    th.join().unwrap().unwrap();
+    fs::remove_dir(&abs_mount_path).unwrap();
+}
@@ -2,6 +2,7 @@ use log::info; // debug, error, warn
 use nix::dir::Dir;
 use nix::fcntl::{self, OFlag};
 use nix::sys::stat::Mode;
+use nix::sys::uio;
 use nix::unistd::{self, Whence};
 use std::collections::HashSet;
 use std::env;
@@ -47,6 +48,23 @@ fn test_file_manipulation_nix_way(mount_dir: &Path) {
     assert!(!file_path.exists());
 }
 
+fn test_file_random_write(mount_dir: &Path) {
+    info!("overwrite file data in place");
+    let file_path = Path::new(&mount_dir).join("random_write.test");
+    fs::write(&file_path, FILE_CONTENT).unwrap();
+
+    let fd = fcntl::open(&file_path, OFlag::O_RDWR, Mode::empty()).unwrap();
+    assert_eq!(uio::pwrite(fd, b"xyz", 4).unwrap(), 3);
+    assert_eq!(uio::pwrite(fd, b"XY", 14).unwrap(), 2);
+    assert_eq!(uio::pwrite(fd, b"!", 20).unwrap(), 1);
+    unistd::close(fd).unwrap();
+
+    // reopened files are read from the filesystem rather than the kernel cache
+    let bytes = fs::read(&file_path).unwrap();
+    assert_eq!(bytes, b"0123xyz789ABCDXY\0\0\0\0!");
+    fs::remove_file(&file_path).unwrap();
+}
+
 fn test_dir_manipulation_nix_way(mount_dir: &Path) {
     info!("directory manipulation C style");
     let dir_path = Path::new(&mount_dir).join("test_dir");
@@ -221,6 +239,50 @@ fn test_rename_dir(mount_dir: &Path) {
     assert!(!to_dir.exists());
 }
 
+fn test_rename_deep_dir(mount_dir: &Path) {
+    info!("rename deep directory tree");
+    let from_dir = Path::new(&mount_dir).join("deep_from");
+    let to_dir = Path::new(&mount_dir).join("deep_to");
+    for dir in &[&from_dir, &to_dir] {
+        if dir.exists() {
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+    let deep_dir = from_dir.join("a/b/c/d");
+    fs::create_dir_all(&deep_dir).unwrap();
+    fs::write(deep_dir.join("file"), FILE_CONTENT).unwrap();
+    // cache the whole subtree before moving it
+    assert_eq!(
+        fs::read_to_string(deep_dir.join("file")).unwrap(),
+        FILE_CONTENT
+    );
+
+    fs::create_dir(&to_dir).unwrap();
+    fs::rename(from_dir.join("a"), to_dir.join("moved")).unwrap();
+    let moved_dir = to_dir.join("moved/b/c/d");
+    assert!(!deep_dir.exists());
+    assert_eq!(
+        fs::read_to_string(moved_dir.join("file")).unwrap(),
+        FILE_CONTENT
+    );
+
+    // the moved subtree is still writable at every level
+    fs::write(moved_dir.join("new_file"), FILE_CONTENT).unwrap();
+    fs::create_dir(to_dir.join("moved/b/new_dir")).unwrap();
+    fs::rename(to_dir.join("moved/b/c"), &from_dir.join("c")).unwrap();
+    assert_eq!(
+        fs::read_to_string(from_dir.join("c/d/new_file")).unwrap(),
+        FILE_CONTENT
+    );
+    // a directory cannot move into its own subtree
+    assert!(fs::rename(&from_dir, from_dir.join("c/d/inside")).is_err());
+
+    fs::remove_dir_all(&from_dir).unwrap();
+    fs::remove_dir_all(&to_dir).unwrap();
+    assert!(!from_dir.exists());
+    assert!(!to_dir.exists());
+}
+
 #[test]
 fn run_test() {
     let mountpoint = match env::args_os().nth(1) {
@@ -234,11 +296,13 @@ fn run_test() {
     info!("begin integration test");
     test_file_manipulation_rust_way(&mount_dir);
     test_file_manipulation_nix_way(&mount_dir);
+    test_file_random_write(&mount_dir);
     test_dir_manipulation_nix_way(&mount_dir);
     test_deferred_deletion(&mount_dir);
     test_rename_file_no_replace(&mount_dir);
     test_rename_file(&mount_dir);
     test_rename_dir(&mount_dir);
+    test_rename_deep_dir(&mount_dir);
 
     test_util::teardown(&mount_dir, th);
 }
@@ -0,0 +1,78 @@
+use log::info; // debug, error, warn
+use nix::sys::statvfs;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use fuse_ll::fuse::{self, Context, Filesystem, ReplyStatfs, ReplyStatfsParam, SupervisorConfig};
+
+const SUPERVISOR_MOUNT_DIR: &str = "../fuse_test_supervisor";
+
+/// Filesystem whose statfs hangs for a while in the first mount
+struct WedgedFilesystem {
+    wedged: bool,
+}
+
+impl Filesystem for WedgedFilesystem {
+    fn statfs(&mut self, _ctx: &Context, _ino: u64, reply: ReplyStatfs) {
+        if self.wedged {
+            thread::sleep(Duration::from_millis(1500));
+        }
+        reply.statfs(&ReplyStatfsParam {
+            blocks: 0,
+            bfree: 0,
+            bavail: 0,
+            files: 0,
+            ffree: 0,
+            bsize: 512,
+            namelen: 255,
+            frsize: 0,
+        });
+    }
+}
+
+#[test]
+fn test_supervisor_remount() {
+    env_logger::init();
+    let mount_dir = Path::new(SUPERVISOR_MOUNT_DIR);
+    fuse::unmount(mount_dir).unwrap_or(());
+    fs::create_dir_all(mount_dir).unwrap();
+    let abs_mount_path = fs::canonicalize(mount_dir).unwrap();
+
+    let mounts = Arc::new(AtomicUsize::new(0));
+    let th = {
+        let mounts = Arc::clone(&mounts);
+        let mount_path = abs_mount_path.clone();
+        thread::spawn(move || {
+            let config = SupervisorConfig {
+                check_interval: Duration::from_millis(200),
+                check_timeout: Duration::from_secs(1),
+                max_remounts: 1,
+            };
+            fuse::supervise(&config, || {
+                let wedged = mounts.fetch_add(1, Ordering::SeqCst) == 0;
+                fuse::Session::new(WedgedFilesystem { wedged }, &mount_path, &[])
+            })
+        })
+    };
+
+    // the first mount gets wedged on the first health check and is replaced
+    for _ in 0..50 {
+        if mounts.load(Ordering::SeqCst) == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(mounts.load(Ordering::SeqCst), 2);
+    thread::sleep(Duration::from_millis(500));
+    info!("check the remounted mount point");
+    statvfs::statvfs(&abs_mount_path).unwrap();
+
+    fuse::unmount(&abs_mount_path).unwrap();
+    th.join().unwrap().unwrap();
+    assert_eq!(mounts.load(Ordering::SeqCst), 2);
+    fs::remove_dir(&abs_mount_path).unwrap();
+}
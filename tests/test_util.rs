@@ -124,3 +124,27 @@ pub fn benchmark(mount_dir: &Path, repeat_times: usize) {
     unistd::close(fd).unwrap();
     fs::remove_dir_all(&dir_path).unwrap();
 }
+
+/// Build a FUSE request of the opcode on the inode of nodeid, for the
+/// sessions served on a socket in place of `/dev/fuse`
+pub fn request(opcode: u32, unique: u64, nodeid: u64, arg: &[u8]) -> Vec<u8> {
+    let len = (arg.len() + 40) as u32;
+    let mut data = Vec::new();
+    data.extend_from_slice(&len.to_ne_bytes());
+    data.extend_from_slice(&opcode.to_ne_bytes());
+    data.extend_from_slice(&unique.to_ne_bytes());
+    data.extend_from_slice(&nodeid.to_ne_bytes());
+    data.extend_from_slice(&[0; 16]); // uid, gid, pid, padding
+    data.extend_from_slice(arg);
+    data
+}
+
+/// Build the init request of the unique id 1 offering the minor version of
+/// ABI 7 and the flags
+pub fn init_request(minor: u32, flags: u32) -> Vec<u8> {
+    let mut init_arg = Vec::new();
+    for field in &[7_u32, minor, 4096, flags] {
+        init_arg.extend_from_slice(&field.to_ne_bytes()); // major, minor, readahead, flags
+    }
+    request(26, 1, 0, &init_arg)
+}